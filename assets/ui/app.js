@@ -0,0 +1,186 @@
+// Dashboard for raspberry-web, served from /ui
+// Polls /status and switches outputs through /set/level/{id}/{level}
+"use strict";
+
+var REFRESH_INTERVAL_MS = 1000;
+
+// Physical pin layout of the 40-pin header, BCM numbering for the GPIOs
+// https://pinout.xyz/
+var HEADER = [
+  { power: "3V3" }, { power: "5V" },
+  { gpio: 2 }, { power: "5V" },
+  { gpio: 3 }, { ground: true },
+  { gpio: 4 }, { gpio: 14 },
+  { ground: true }, { gpio: 15 },
+  { gpio: 17 }, { gpio: 18 },
+  { gpio: 27 }, { ground: true },
+  { gpio: 22 }, { gpio: 23 },
+  { power: "3V3" }, { gpio: 24 },
+  { gpio: 10 }, { ground: true },
+  { gpio: 9 }, { gpio: 25 },
+  { gpio: 11 }, { gpio: 8 },
+  { ground: true }, { gpio: 7 },
+  { gpio: 0 }, { gpio: 1 },
+  { gpio: 5 }, { ground: true },
+  { gpio: 6 }, { gpio: 12 },
+  { gpio: 13 }, { ground: true },
+  { gpio: 19 }, { gpio: 16 },
+  { gpio: 26 }, { gpio: 20 },
+  { ground: true }, { gpio: 21 }
+];
+
+var gpios = {};
+
+function element(tag, className, text) {
+  var el = document.createElement(tag);
+  if (className) {
+    el.className = className;
+  }
+  if (text !== undefined) {
+    el.textContent = text;
+  }
+  return el;
+}
+
+function isOutput(gpio) {
+  return gpio && gpio.in_use === 1 && gpio.gpio_mode === "output";
+}
+
+function toggleButton(gpio) {
+  var target = gpio.gpio_level === "high" ? "low" : "high";
+  var button = element("button", null, target);
+  button.title = "Set GPIO " + gpio.gpio_id + " " + target;
+  button.onclick = function () {
+    setLevel(gpio.gpio_id, target, gpio.version);
+  };
+  return button;
+}
+
+function renderHeader() {
+  var container = document.getElementById("header");
+  container.innerHTML = "";
+
+  HEADER.forEach(function (pin, idx) {
+    var cell = element("div", "pin");
+    cell.appendChild(element("span", "number", String(idx + 1)));
+
+    if (pin.power) {
+      cell.className += " power";
+      cell.appendChild(element("span", "label", pin.power));
+    } else if (pin.ground) {
+      cell.className += " ground";
+      cell.appendChild(element("span", "label", "GND"));
+    } else {
+      var gpio = gpios[pin.gpio];
+      var label = gpio && gpio.name ? gpio.name : "GPIO " + pin.gpio;
+      if (gpio && gpio.in_use === 1) {
+        cell.className += " in-use " + (gpio.gpio_level || "");
+        label += " " + (gpio.gpio_mode || "") + " " + (gpio.gpio_level || "");
+      }
+      cell.appendChild(element("span", "dot", "●"));
+      cell.appendChild(element("span", "label", label));
+      if (gpio && gpio.description) {
+        cell.title = gpio.description;
+      }
+      if (isOutput(gpio)) {
+        cell.appendChild(toggleButton(gpio));
+      }
+    }
+
+    container.appendChild(cell);
+  });
+}
+
+function renderTable() {
+  var body = document.getElementById("gpios");
+  body.innerHTML = "";
+
+  Object.keys(gpios)
+    .map(function (key) { return gpios[key]; })
+    .filter(function (gpio) { return gpio.in_use === 1; })
+    .sort(function (a, b) { return a.gpio_id - b.gpio_id; })
+    .forEach(function (gpio) {
+      var row = element("tr");
+      row.appendChild(element("td", null, String(gpio.gpio_id)));
+      row.appendChild(element("td", null, gpio.name || ""));
+      row.appendChild(element("td", null, gpio.gpio_mode || ""));
+      row.appendChild(element("td", "level " + (gpio.gpio_level || ""), gpio.gpio_level || ""));
+      row.appendChild(element("td", null, gpio.last_change || ""));
+      var actions = element("td");
+      if (isOutput(gpio)) {
+        actions.appendChild(toggleButton(gpio));
+      }
+      row.appendChild(actions);
+      body.appendChild(row);
+    });
+}
+
+function render() {
+  renderHeader();
+  renderTable();
+}
+
+function showMessage(text) {
+  document.getElementById("message").textContent = text;
+}
+
+function showConnection(ok) {
+  var connection = document.getElementById("connection");
+  connection.textContent = ok ? "live" : "connection lost";
+  connection.className = ok ? "connection" : "connection lost";
+}
+
+// Resolve with parsed JSON, or reject with the error text and status from the server
+function request(url, headers) {
+  return fetch(url, { cache: "no-store", headers: headers || {} }).then(function (response) {
+    if (response.ok) {
+      return response.json();
+    }
+    return response.text().then(function (text) {
+      var err = new Error(text || response.statusText);
+      err.status = response.status;
+      throw err;
+    });
+  });
+}
+
+function refresh() {
+  return request("/status")
+    .then(function (list) {
+      gpios = {};
+      list.forEach(function (gpio) {
+        gpios[gpio.gpio_id] = gpio;
+      });
+      showConnection(true);
+      render();
+    })
+    .catch(function () {
+      showConnection(false);
+    });
+}
+
+// Only switch if the GPIO is still in the state that was shown
+function setLevel(id, level, version) {
+  request("/set/level/" + id + "/" + level, { "If-Match": '"' + version + '"' })
+    .then(function (gpio) {
+      gpios[gpio.gpio_id] = gpio;
+      showMessage("");
+      render();
+    })
+    .catch(function (err) {
+      if (err.status === 412) {
+        showMessage("GPIO " + id + " was changed by someone else, it is shown as it is now");
+        refresh();
+        return;
+      }
+      showMessage("GPIO " + id + ": " + err.message);
+    });
+}
+
+function poll() {
+  refresh().then(function () {
+    setTimeout(poll, REFRESH_INTERVAL_MS);
+  });
+}
+
+poll();
@@ -0,0 +1,6 @@
+-- Human-friendly names, descriptions and comma-separated tags for GPIOs
+ALTER TABLE gpio_state ADD COLUMN name TEXT;
+ALTER TABLE gpio_state ADD COLUMN description TEXT;
+ALTER TABLE gpio_state ADD COLUMN tags TEXT;
+
+CREATE UNIQUE INDEX gpio_state_name ON gpio_state (name);
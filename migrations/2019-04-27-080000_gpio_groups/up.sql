@@ -0,0 +1,6 @@
+-- Named groups of GPIOs that are switched together
+CREATE TABLE gpio_groups (
+    group_name TEXT NOT NULL,
+    gpio_id INTEGER NOT NULL REFERENCES gpio_state (gpio_id),
+    PRIMARY KEY (group_name, gpio_id)
+);
@@ -0,0 +1,8 @@
+-- GPIOs whose level on the pin differed from the database at the last drift check
+CREATE TABLE gpio_drift (
+    gpio_id INTEGER PRIMARY KEY NOT NULL REFERENCES gpio_state (gpio_id),
+    db_level TEXT NOT NULL,
+    hw_level TEXT NOT NULL,
+    detected_at TEXT NOT NULL,
+    correction TEXT
+);
@@ -0,0 +1,2 @@
+-- Incremented on every change of a GPIO, used as its ETag
+ALTER TABLE gpio_state ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
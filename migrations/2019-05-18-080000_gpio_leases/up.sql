@@ -0,0 +1,7 @@
+-- Exclusive, time-limited access to GPIOs. A lease on several GPIOs has one row per GPIO.
+CREATE TABLE gpio_leases (
+    gpio_id INTEGER PRIMARY KEY NOT NULL REFERENCES gpio_state (gpio_id),
+    lease_id TEXT NOT NULL,
+    expires_at TEXT NOT NULL,
+    on_expiry TEXT
+);
@@ -0,0 +1,9 @@
+-- History of changes the server made to GPIOs on its own, newest has the highest audit_id
+CREATE TABLE gpio_audit (
+    audit_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    gpio_id INTEGER NOT NULL REFERENCES gpio_state (gpio_id),
+    event TEXT NOT NULL,
+    gpio_level TEXT,
+    detail TEXT,
+    occurred_at TEXT NOT NULL
+);
@@ -0,0 +1,23 @@
+-- This file should undo anything in `up.sql`
+-- SQLite can not drop columns, so the table is rebuilt without them
+DROP INDEX gpio_state_name;
+
+CREATE TABLE gpio_state_old (
+	gpio_id	INTEGER NOT NULL UNIQUE PRIMARY KEY,
+    in_use	INTEGER NOT NULL DEFAULT 0,
+	gpio_mode  	TEXT,
+	gpio_level	TEXT,
+	last_change	TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+	name	TEXT,
+	description	TEXT,
+	tags	TEXT,
+	version	INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO gpio_state_old (gpio_id, in_use, gpio_mode, gpio_level, last_change, name, description, tags, version)
+SELECT gpio_id, in_use, gpio_mode, gpio_level, last_change, name, description, tags, version FROM gpio_state;
+
+DROP TABLE gpio_state;
+ALTER TABLE gpio_state_old RENAME TO gpio_state;
+
+CREATE UNIQUE INDEX gpio_state_name ON gpio_state (name);
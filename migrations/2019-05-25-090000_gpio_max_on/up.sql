@@ -0,0 +1,7 @@
+-- Maximum time a GPIO may be away from its safe level before it is forced back
+ALTER TABLE gpio_state ADD COLUMN max_on_duration INTEGER;
+ALTER TABLE gpio_state ADD COLUMN safe_level TEXT;
+-- Since when the GPIO is away from its safe level, NULL while it is at it
+ALTER TABLE gpio_state ADD COLUMN on_since TEXT;
+-- When the GPIO was last forced back to its safe level
+ALTER TABLE gpio_state ADD COLUMN forced_off_at TEXT;
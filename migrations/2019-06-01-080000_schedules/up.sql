@@ -0,0 +1,15 @@
+-- Actions run on a cron schedule, on either a GPIO or a group
+CREATE TABLE schedules (
+    schedule_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    cron TEXT NOT NULL,
+    gpio_id INTEGER REFERENCES gpio_state (gpio_id),
+    group_name TEXT,
+    action TEXT NOT NULL,
+    gpio_level TEXT,
+    duration_ms INTEGER,
+    -- 'skip' or 'run_once': what to do when the server was down at the scheduled time
+    missed_runs TEXT NOT NULL DEFAULT 'skip',
+    enabled INTEGER NOT NULL DEFAULT 1,
+    last_run TEXT,
+    next_run TEXT
+);
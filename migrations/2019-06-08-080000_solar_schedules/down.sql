@@ -0,0 +1,21 @@
+-- This file should undo anything in `up.sql`
+CREATE TABLE schedules_old (
+    schedule_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    cron TEXT NOT NULL,
+    gpio_id INTEGER REFERENCES gpio_state (gpio_id),
+    group_name TEXT,
+    action TEXT NOT NULL,
+    gpio_level TEXT,
+    duration_ms INTEGER,
+    missed_runs TEXT NOT NULL DEFAULT 'skip',
+    enabled INTEGER NOT NULL DEFAULT 1,
+    last_run TEXT,
+    next_run TEXT
+);
+INSERT INTO schedules_old (schedule_id, cron, gpio_id, group_name, action, gpio_level,
+    duration_ms, missed_runs, enabled, last_run, next_run)
+SELECT schedule_id, cron, gpio_id, group_name, action, gpio_level,
+    duration_ms, missed_runs, enabled, last_run, next_run FROM schedules
+WHERE cron IS NOT NULL;
+DROP TABLE schedules;
+ALTER TABLE schedules_old RENAME TO schedules;
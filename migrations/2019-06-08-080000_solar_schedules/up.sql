@@ -0,0 +1,25 @@
+-- Schedules at sunrise or sunset plus an offset, instead of a cron expression.
+-- SQLite can not drop NOT NULL from 'cron', so the table is copied.
+CREATE TABLE schedules_new (
+    schedule_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    cron TEXT,
+    gpio_id INTEGER REFERENCES gpio_state (gpio_id),
+    group_name TEXT,
+    action TEXT NOT NULL,
+    gpio_level TEXT,
+    duration_ms INTEGER,
+    -- 'skip' or 'run_once': what to do when the server was down at the scheduled time
+    missed_runs TEXT NOT NULL DEFAULT 'skip',
+    enabled INTEGER NOT NULL DEFAULT 1,
+    last_run TEXT,
+    next_run TEXT,
+    -- 'sunrise' or 'sunset' when 'cron' is NULL
+    solar_event TEXT,
+    offset_minutes INTEGER NOT NULL DEFAULT 0
+);
+INSERT INTO schedules_new (schedule_id, cron, gpio_id, group_name, action, gpio_level,
+    duration_ms, missed_runs, enabled, last_run, next_run)
+SELECT schedule_id, cron, gpio_id, group_name, action, gpio_level,
+    duration_ms, missed_runs, enabled, last_run, next_run FROM schedules;
+DROP TABLE schedules;
+ALTER TABLE schedules_new RENAME TO schedules;
@@ -0,0 +1,7 @@
+-- Presence simulation while the building is empty. A single row, kept across restarts.
+CREATE TABLE vacation_mode (
+    vacation_id INTEGER PRIMARY KEY NOT NULL CHECK (vacation_id = 1),
+    enabled INTEGER NOT NULL DEFAULT 0,
+    seed BIGINT
+);
+INSERT INTO vacation_mode (vacation_id, enabled) VALUES (1, 0);
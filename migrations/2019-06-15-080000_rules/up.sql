@@ -0,0 +1,19 @@
+-- Rules linking an input GPIO to an output GPIO, from the configuration or the API
+CREATE TABLE rules (
+    rule_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    name TEXT,
+    input_gpio INTEGER NOT NULL REFERENCES gpio_state (gpio_id),
+    -- 'rising', 'falling', 'both' or 'mirror'
+    trigger TEXT NOT NULL,
+    output_gpio INTEGER NOT NULL REFERENCES gpio_state (gpio_id),
+    -- 'set_level', 'pulse', 'toggle' or 'mirror'
+    action TEXT NOT NULL,
+    gpio_level TEXT,
+    duration_ms INTEGER,
+    inverted INTEGER NOT NULL DEFAULT 0,
+    enabled INTEGER NOT NULL DEFAULT 1,
+    -- Rules from the configuration are replaced at startup
+    from_config INTEGER NOT NULL DEFAULT 0,
+    fire_count INTEGER NOT NULL DEFAULT 0,
+    last_fired TEXT
+);
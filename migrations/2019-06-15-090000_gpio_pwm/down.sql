@@ -0,0 +1,42 @@
+-- This file should undo anything in `up.sql`
+-- SQLite can not drop columns, so the tables are rebuilt without them
+DROP INDEX gpio_state_name;
+
+CREATE TABLE gpio_state_old (
+	gpio_id	INTEGER NOT NULL UNIQUE PRIMARY KEY,
+    in_use	INTEGER NOT NULL DEFAULT 0,
+	gpio_mode  	TEXT,
+	gpio_level	TEXT,
+	last_change	TIMESTAMP DEFAULT CURRENT_TIMESTAMP,
+	name	TEXT,
+	description	TEXT,
+	tags	TEXT,
+	version	INTEGER NOT NULL DEFAULT 0,
+	max_on_duration	INTEGER,
+	safe_level	TEXT,
+	on_since	TEXT,
+	forced_off_at	TEXT
+);
+
+INSERT INTO gpio_state_old (gpio_id, in_use, gpio_mode, gpio_level, last_change, name, description, tags, version, max_on_duration, safe_level, on_since, forced_off_at)
+SELECT gpio_id, in_use, gpio_mode, gpio_level, last_change, name, description, tags, version, max_on_duration, safe_level, on_since, forced_off_at FROM gpio_state;
+
+DROP TABLE gpio_state;
+ALTER TABLE gpio_state_old RENAME TO gpio_state;
+
+CREATE UNIQUE INDEX gpio_state_name ON gpio_state (name);
+
+CREATE TABLE allowed_states_old (
+    state_id INTEGER NOT NULL UNIQUE PRIMARY KEY,
+    state_type TEXT NOT NULL,
+    input INTEGER NOT NULL DEFAULT 0,
+	output INTEGER NOT NULL DEFAULT 0,
+    high INTEGER NOT NULL DEFAULT 0,
+    low INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO allowed_states_old (state_id, state_type, input, output, high, low)
+SELECT state_id, state_type, input, output, high, low FROM allowed_states;
+
+DROP TABLE allowed_states;
+ALTER TABLE allowed_states_old RENAME TO allowed_states;
@@ -0,0 +1,9 @@
+-- GPIOs can be in mode 'pwm'
+ALTER TABLE allowed_states ADD COLUMN pwm INTEGER NOT NULL DEFAULT 0;
+UPDATE allowed_states SET pwm = 1 WHERE state_type = 'mode';
+
+-- Frequency in Hz and duty cycle in percent of a GPIO in mode 'pwm'
+ALTER TABLE gpio_state ADD COLUMN pwm_frequency DOUBLE;
+ALTER TABLE gpio_state ADD COLUMN pwm_duty_cycle DOUBLE;
+-- Hardware PWM channel driving the GPIO, NULL for software PWM
+ALTER TABLE gpio_state ADD COLUMN pwm_channel INTEGER;
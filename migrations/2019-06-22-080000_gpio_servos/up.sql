@@ -0,0 +1,9 @@
+-- PWM GPIOs driving hobby servos, set from the [[pins]] entries with kind 'servo'
+CREATE TABLE gpio_servos (
+    gpio_id INTEGER PRIMARY KEY NOT NULL REFERENCES gpio_state (gpio_id),
+    min_pulse_us INTEGER NOT NULL,
+    max_pulse_us INTEGER NOT NULL,
+    min_angle DOUBLE NOT NULL,
+    max_angle DOUBLE NOT NULL,
+    max_speed DOUBLE -- Degrees per second, no limit if NULL
+);
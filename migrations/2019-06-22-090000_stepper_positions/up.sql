@@ -0,0 +1,7 @@
+-- Position of each stepper from the [[steppers]] entries, kept over restarts
+CREATE TABLE stepper_positions (
+    name TEXT PRIMARY KEY NOT NULL,
+    position INTEGER NOT NULL DEFAULT 0, -- Steps from home
+    homed INTEGER NOT NULL DEFAULT 0, -- 1 if position 0 was found with the home switch
+    updated_at TEXT
+);
@@ -0,0 +1,7 @@
+-- GPIOs owned by the devices of the [[devices]] entries, as the pin with `role` in the device
+CREATE TABLE gpio_devices (
+    gpio_id INTEGER PRIMARY KEY NOT NULL REFERENCES gpio_state (gpio_id),
+    device_name TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    role TEXT NOT NULL
+);
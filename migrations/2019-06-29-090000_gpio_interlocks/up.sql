@@ -0,0 +1,10 @@
+-- Interlocks between pairs of outputs, set from the [[interlocks]] entries on every start
+CREATE TABLE gpio_interlocks (
+    interlock_id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- 'exclusive': not both high, 'requires': gpio_id only high while other_gpio is high,
+    -- 'dead_time': gpio_id only goes high once other_gpio has been low for dead_time_ms
+    kind TEXT NOT NULL,
+    gpio_id INTEGER NOT NULL REFERENCES gpio_state (gpio_id),
+    other_gpio INTEGER NOT NULL REFERENCES gpio_state (gpio_id),
+    dead_time_ms INTEGER
+);
@@ -1,97 +1,1205 @@
-use crate::handlers::{CheckGpioLevel, DbExecutor, GpioId, SetGpioLevel};
+use crate::handlers::{
+    AcquireLease, AllAudit, AllDevices, AllDrift, AllGpios, AllInterlocks, AllRules, AllSchedules,
+    ApplyBatch, CreateRule, CreateSchedule, DbExecutor, DeleteRule, DeleteSchedule, DeviceName,
+    EnableRule, GpioId, GroupName, LeaseId, OperateDevice, ReconcileState, ReleaseLease,
+    RenewLease, ResolveGpioName, RuleId, ScheduleId, ServoId, SetGpioLevel, SetGpioPwm,
+    SetGroupLevel, ToggleGpioLevel, UpdateSchedule,
+};
 use crate::models;
+use crate::ramp::{CancelRamp, MoveServo, Ramp, Ramper, StartRamp};
 use crate::rpi;
+use crate::scripting::ScriptRegistry;
+use crate::sequencer::{CancelSequence, Pattern, Sequencer, StartSequence};
+use crate::settings::LocationConfig;
+use crate::solar::solar_day;
+use crate::stepper::StepperDriver;
+use crate::ui;
+use crate::vacation::{GetVacation, SetVacation, VacationSimulator};
 use actix::Addr;
 use actix_web::Error as actixError;
-use actix_web::{http, middleware, App, AsyncResponder, FutureResponse, HttpResponse, Path, State};
+use actix_web::{
+    error, http, middleware, App, AsyncResponder, FutureResponse, HttpRequest, HttpResponse, Json,
+    Path, State,
+};
+use chrono::{Duration as ChronoDuration, Local};
 use futures::{future, Future};
 
-/// State with DbExecutor address
+/// State with DbExecutor, Sequencer, Ramper and VacationSimulator addresses, the location for
+/// sunrise and sunset, and the status of the scripts
 pub struct AppState {
     pub db: Addr<DbExecutor>,
+    pub sequencer: Addr<Sequencer>,
+    pub ramper: Addr<Ramper>,
+    pub vacation: Addr<VacationSimulator>,
     pub gpio_arc_mutex: rpi::GpioArcMutex,
+    pub location: Option<LocationConfig>,
+    pub scripts: ScriptRegistry,
+    pub steppers: StepperDriver,
+}
+
+/// Turn an error into a response with the error message as body
+pub fn error_response(err: actixError) -> HttpResponse {
+    let err_string = err.to_string();
+    let mut response = HttpResponse::from_error(err);
+    response.set_body(err_string);
+    response
+}
+
+/// Header with the id of the lease a client holds, for changes to leased GPIOs
+pub const LEASE_ID_HEADER: &str = "X-Lease-Id";
+
+/// Lease id in the `X-Lease-Id` header, if any
+fn lease_id_header(req: &HttpRequest<AppState>) -> Option<String> {
+    req.headers()
+        .get(LEASE_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_string())
+}
+
+/// ETag of a GPIO: its version, quoted
+pub fn gpio_etag(gpio: &models::Gpio) -> String {
+    format!("\"{}\"", gpio.version)
+}
+
+/// Respond with the state of a GPIO, and its version as ETag
+fn gpio_response(gpio: models::Gpio) -> HttpResponse {
+    HttpResponse::Ok()
+        .header(http::header::ETAG, gpio_etag(&gpio))
+        .json(gpio)
+}
+
+/// The GPIO version in the If-Match header, or None if there is no header or it is `*`.
+/// A weak ETag is a failed precondition.
+fn if_match_version(req: &HttpRequest<AppState>) -> Result<Option<i32>, actixError> {
+    let value = match req.headers().get(http::header::IF_MATCH) {
+        Some(value) => value
+            .to_str()
+            .map_err(|_| error::ErrorBadRequest("Invalid If-Match header"))?
+            .trim(),
+        None => return Ok(None),
+    };
+    if value == "*" {
+        return Ok(None);
+    }
+    // If-Match uses the strong comparison, which a weak ETag never passes
+    if value.starts_with("W/") {
+        return Err(error::ErrorPreconditionFailed(format!(
+            "If-Match {} is a weak ETag",
+            value
+        )));
+    }
+
+    value
+        .trim_matches('"')
+        .parse::<i32>()
+        .map(Some)
+        .map_err(|_| {
+            error::ErrorPreconditionFailed(format!("If-Match {} is not a GPIO version", value))
+        })
+}
+
+/// Resolve a path segment holding either a GPIO id or a GPIO name to the GPIO id
+pub fn resolve_gpio_id(
+    db: &Addr<DbExecutor>, identifier: String,
+) -> Box<dyn Future<Item = i32, Error = actixError>> {
+    match identifier.parse::<i32>() {
+        Ok(gpio_id) => Box::new(future::ok(gpio_id)),
+        Err(_) => Box::new(
+            db.send(ResolveGpioName { name: identifier })
+                .from_err()
+                .and_then(|res| res),
+        ),
+    }
 }
 
 /// Get status of GPIO
 pub fn gpio_status_route(
-    (req, state): (Path<i32>, State<AppState>),
+    (req, state): (Path<String>, State<AppState>),
 ) -> FutureResponse<HttpResponse> {
+    let db = state.db.clone();
+
+    resolve_gpio_id(&state.db, req.into_inner())
+        .and_then(move |gpio_id| db.send(GpioId { gpio_id }).from_err())
+        .and_then(|res| res)
+        .then(|res: Result<models::Gpio, actixError>| match res {
+            Ok(gpio) => Ok(gpio_response(gpio)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Get status of all GPIOs
+pub fn gpio_status_all_route(state: State<AppState>) -> FutureResponse<HttpResponse> {
     state
         .db
-        .send(GpioId {
-            gpio_id: req.into_inner(),
-        })
+        .send(AllGpios)
         .from_err()
         .and_then(|res| match res {
-            Ok(user) => Ok(HttpResponse::Ok().json(user)),
-            Err(err) => {
-                let err_string = err.to_string();
-                let mut response = HttpResponse::from_error(err);
-                response.set_body(err_string);
-                Ok(response)
-            }
+            Ok(gpios) => Ok(HttpResponse::Ok().json(gpios)),
+            Err(err) => Ok(error_response(err)),
         })
         .responder()
 }
 
-/// Set GPIO level to HIGH or LOW
+/// Set GPIO level to HIGH or LOW. With an If-Match header, the level is only set if the
+/// GPIO still has that version, else the response is 412 Precondition Failed. A leased
+/// GPIO can only be set with the id of its lease in the X-Lease-Id header, else 423 Locked.
 pub fn set_gpio_level_route(
-    (req, state): (Path<(i32, String)>, State<AppState>),
+    (http_req, req, state): (
+        HttpRequest<AppState>,
+        Path<(String, String)>,
+        State<AppState>,
+    ),
 ) -> FutureResponse<HttpResponse> {
-    let path_gpio_id: i32 = req.0;
-    let path_gpio_level = req.1.clone();
-    let path_gpio_level_copy = req.1.clone(); // TODO - OMG this is horrible
+    let expected_version = match if_match_version(&http_req) {
+        Ok(expected_version) => expected_version,
+        Err(err) => return Box::new(future::ok(error_response(err))),
+    };
+    let lease_id = lease_id_header(&http_req);
+    let (path_gpio, path_gpio_level) = req.into_inner();
     let gpio_arc_mutex = state.gpio_arc_mutex.clone();
+    let db = state.db.clone();
 
     // https://github.com/actix/examples/blob/master/async_db/src/main.rs
     // https://github.com/actix/examples/blob/master/actix_todo/src/api.rs
     // https://stackoverflow.com/questions/54164682/
-    state
-        .db
-        .send(CheckGpioLevel {
-            gpio_id: path_gpio_id,
-            gpio_level: (&path_gpio_level).to_string(),
+    resolve_gpio_id(&state.db, path_gpio)
+        .and_then(move |path_gpio_id| {
+            // Check, drive the pin and update the database as one change
+            db.send(SetGpioLevel {
+                gpio_id: path_gpio_id,
+                gpio_level: path_gpio_level,
+                expected_version,
+                lease_id,
+                gpio_arc_mutex,
+            })
+            .from_err()
         })
-        .from_err()
         .and_then(|res| future::result(res).from_err())
-        .and_then(move |_| {
-            // Update GPIO level on RPi
-            let level_updated =
-                rpi::set_gpio_level_rpi(path_gpio_id, &path_gpio_level, gpio_arc_mutex);
-            future::result(level_updated).from_err()
-        })
-        .and_then(move |_| {
-            // Update database to correspond with above
-            state
-                .db
-                .send(SetGpioLevel {
-                    gpio_id: path_gpio_id,
-                    gpio_level: (&path_gpio_level_copy).to_string(),
+        .then(|res: Result<models::Gpio, actixError>| match res {
+            Ok(response) => Ok(gpio_response(response)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Invert the level of a GPIO. If-Match and X-Lease-Id work as for `set_gpio_level_route`.
+pub fn toggle_gpio_level_route(
+    (http_req, req, state): (HttpRequest<AppState>, Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let expected_version = match if_match_version(&http_req) {
+        Ok(expected_version) => expected_version,
+        Err(err) => return Box::new(future::ok(error_response(err))),
+    };
+    let lease_id = lease_id_header(&http_req);
+    let gpio_arc_mutex = state.gpio_arc_mutex.clone();
+    let db = state.db.clone();
+
+    resolve_gpio_id(&state.db, req.into_inner())
+        .and_then(move |gpio_id| {
+            db.send(ToggleGpioLevel {
+                gpio_id,
+                expected_version,
+                lease_id,
+                gpio_arc_mutex,
+            })
+            .from_err()
+        })
+        .and_then(|res| res)
+        .then(|res: Result<models::Gpio, actixError>| match res {
+            Ok(gpio) => Ok(gpio_response(gpio)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Change the PWM of the GPIO given by id or name. If-Match and X-Lease-Id work as for
+/// `set_gpio_level_route`.
+fn set_gpio_pwm(
+    http_req: &HttpRequest<AppState>, path_gpio: String, frequency: Option<f64>,
+    duty_cycle: Option<f64>, state: &State<AppState>,
+) -> FutureResponse<HttpResponse> {
+    let expected_version = match if_match_version(http_req) {
+        Ok(expected_version) => expected_version,
+        Err(err) => return Box::new(future::ok(error_response(err))),
+    };
+    let lease_id = lease_id_header(http_req);
+    let gpio_arc_mutex = state.gpio_arc_mutex.clone();
+    let db = state.db.clone();
+
+    resolve_gpio_id(&state.db, path_gpio)
+        .and_then(move |gpio_id| {
+            db.send(SetGpioPwm {
+                gpio_id,
+                frequency,
+                duty_cycle,
+                expected_version,
+                lease_id,
+                gpio_arc_mutex,
+            })
+            .from_err()
+        })
+        .and_then(|res| res)
+        .then(|res: Result<models::Gpio, actixError>| match res {
+            Ok(gpio) => Ok(gpio_response(gpio)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Set the PWM frequency in Hz of a GPIO in mode 'pwm', keeping its duty cycle
+pub fn set_gpio_pwm_frequency_route(
+    (http_req, req, state): (HttpRequest<AppState>, Path<(String, f64)>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (path_gpio, frequency) = req.into_inner();
+    set_gpio_pwm(&http_req, path_gpio, Some(frequency), None, &state)
+}
+
+/// Set the PWM duty cycle in percent of a GPIO in mode 'pwm', keeping its frequency
+pub fn set_gpio_pwm_duty_route(
+    (http_req, req, state): (HttpRequest<AppState>, Path<(String, f64)>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (path_gpio, duty_cycle) = req.into_inner();
+    set_gpio_pwm(&http_req, path_gpio, None, Some(duty_cycle), &state)
+}
+
+/// Start `pattern` on the GPIO given by id or name, and respond with the GPIO after the
+/// first step. The rest of the pattern runs on the server.
+fn start_sequence(
+    http_req: &HttpRequest<AppState>, path_gpio: String, pattern: Result<Pattern, actixError>,
+    state: &State<AppState>,
+) -> FutureResponse<HttpResponse> {
+    let pattern = match pattern {
+        Ok(pattern) => pattern,
+        Err(err) => return Box::new(future::ok(error_response(err))),
+    };
+    let lease_id = lease_id_header(http_req);
+    let sequencer = state.sequencer.clone();
+
+    resolve_gpio_id(&state.db, path_gpio)
+        .and_then(move |gpio_id| {
+            sequencer
+                .send(StartSequence {
+                    gpio_id,
+                    pattern,
+                    lease_id,
                 })
                 .from_err()
         })
-        .and_then(|res| future::result(res).from_err())
+        .and_then(|res| res)
+        .then(|res: Result<models::Gpio, actixError>| match res {
+            Ok(gpio) => Ok(gpio_response(gpio)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// GPIO id or name, level and duration in ms
+type PulsePath = Path<(String, String, u64)>;
+
+/// GPIO id or name, period in ms, duty percentage and count
+type BlinkPath = Path<(String, u64, u32, u32)>;
+
+/// Set GPIO level to HIGH or LOW for a number of milliseconds, then back to the level before
+pub fn pulse_route(
+    (http_req, req, state): (HttpRequest<AppState>, PulsePath, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (path_gpio, gpio_level, duration_ms) = req.into_inner();
+    start_sequence(
+        &http_req,
+        path_gpio,
+        Pattern::pulse(&gpio_level, duration_ms),
+        &state,
+    )
+}
+
+/// Blink a GPIO a number of times, HIGH for the duty percentage of each period, then set
+/// it back to the level before
+pub fn blink_route(
+    (http_req, req, state): (HttpRequest<AppState>, BlinkPath, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (path_gpio, period_ms, duty_percent, count) = req.into_inner();
+    start_sequence(
+        &http_req,
+        path_gpio,
+        Pattern::blink(period_ms, duty_percent, count),
+        &state,
+    )
+}
+
+/// Cancel the pulse or blink running on a GPIO, setting it back to the level before
+pub fn cancel_sequence_route(
+    (req, state): (Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let sequencer = state.sequencer.clone();
+
+    resolve_gpio_id(&state.db, req.into_inner())
+        .and_then(move |gpio_id| sequencer.send(CancelSequence { gpio_id }).from_err())
+        .and_then(|res| res)
+        .then(|res: Result<models::Sequence, actixError>| match res {
+            Ok(sequence) => Ok(HttpResponse::Ok().json(sequence)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// GPIO id or name, duty cycle in percent and duration in ms
+type RampPath = Path<(String, f64, u64)>;
+
+/// GPIO id or name, duty cycle in percent, duration in ms and easing
+type EasedRampPath = Path<(String, f64, u64, String)>;
+
+/// Start a ramp on the GPIO given by id or name, and respond with the GPIO after the first
+/// step. If-Match and X-Lease-Id work as for `set_gpio_level_route`.
+fn start_ramp(
+    http_req: &HttpRequest<AppState>, path_gpio: String, ramp: Result<Ramp, actixError>,
+    state: &State<AppState>,
+) -> FutureResponse<HttpResponse> {
+    let ramp = match ramp {
+        Ok(ramp) => ramp,
+        Err(err) => return Box::new(future::ok(error_response(err))),
+    };
+    let expected_version = match if_match_version(http_req) {
+        Ok(expected_version) => expected_version,
+        Err(err) => return Box::new(future::ok(error_response(err))),
+    };
+    let lease_id = lease_id_header(http_req);
+    let ramper = state.ramper.clone();
+
+    resolve_gpio_id(&state.db, path_gpio)
+        .and_then(move |gpio_id| {
+            ramper
+                .send(StartRamp {
+                    gpio_id,
+                    ramp,
+                    expected_version,
+                    lease_id,
+                })
+                .from_err()
+        })
+        .and_then(|res| res)
+        .then(|res: Result<models::Gpio, actixError>| match res {
+            Ok(gpio) => Ok(gpio_response(gpio)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Move the duty cycle of a PWM GPIO linearly to a target over a number of milliseconds
+pub fn ramp_route(
+    (http_req, req, state): (HttpRequest<AppState>, RampPath, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (path_gpio, duty_cycle, duration_ms) = req.into_inner();
+    start_ramp(
+        &http_req,
+        path_gpio,
+        Ramp::new(duty_cycle, duration_ms, "linear"),
+        &state,
+    )
+}
+
+/// Move the duty cycle of a PWM GPIO to a target over a number of milliseconds, following
+/// the easing 'linear', 'ease-in', 'ease-out' or 'ease-in-out'
+pub fn eased_ramp_route(
+    (http_req, req, state): (HttpRequest<AppState>, EasedRampPath, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (path_gpio, duty_cycle, duration_ms, easing) = req.into_inner();
+    start_ramp(
+        &http_req,
+        path_gpio,
+        Ramp::new(duty_cycle, duration_ms, &easing),
+        &state,
+    )
+}
+
+/// Cancel the ramp running on a GPIO, leaving the duty cycle where it got to
+pub fn cancel_ramp_route(
+    (req, state): (Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let ramper = state.ramper.clone();
+
+    resolve_gpio_id(&state.db, req.into_inner())
+        .and_then(move |gpio_id| ramper.send(CancelRamp { gpio_id }).from_err())
+        .and_then(|res| res)
+        .then(|res: Result<models::Ramp, actixError>| match res {
+            Ok(ramp) => Ok(HttpResponse::Ok().json(ramp)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Get the servo on a GPIO, and the angle it is set to
+pub fn servo_status_route(
+    (req, state): (Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let db = state.db.clone();
+
+    resolve_gpio_id(&state.db, req.into_inner())
+        .and_then(move |gpio_id| db.send(ServoId { gpio_id }).from_err())
+        .and_then(|res| res)
+        .then(|res: Result<models::ServoStatus, actixError>| match res {
+            Ok(servo) => Ok(HttpResponse::Ok().json(servo)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Turn the servo on a GPIO to an angle in degrees, at its maximum speed if it has one, and
+/// respond with the GPIO after the first step. If-Match and X-Lease-Id work as for
+/// `set_gpio_level_route`.
+pub fn servo_angle_route(
+    (http_req, req, state): (HttpRequest<AppState>, Path<(String, f64)>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let expected_version = match if_match_version(&http_req) {
+        Ok(expected_version) => expected_version,
+        Err(err) => return Box::new(future::ok(error_response(err))),
+    };
+    let lease_id = lease_id_header(&http_req);
+    let (path_gpio, angle) = req.into_inner();
+    let ramper = state.ramper.clone();
+
+    resolve_gpio_id(&state.db, path_gpio)
+        .and_then(move |gpio_id| {
+            ramper
+                .send(MoveServo {
+                    gpio_id,
+                    angle,
+                    expected_version,
+                    lease_id,
+                })
+                .from_err()
+        })
+        .and_then(|res| res)
         .then(|res: Result<models::Gpio, actixError>| match res {
-            Ok(response) => Ok(HttpResponse::Ok().json(response)),
-            Err(err) => {
-                let err_string = err.to_string();
-                let mut response = HttpResponse::from_error(err);
-                response.set_body(err_string);
-                Ok(response)
-            }
+            Ok(gpio) => Ok(gpio_response(gpio)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Get status of a group of GPIOs
+pub fn group_status_route(
+    (req, state): (Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(GroupName {
+            // Group names are lowercase in the configuration
+            group_name: req.into_inner().to_lowercase(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(group) => Ok(HttpResponse::Ok().json(group)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Set level of all GPIOs in a group to HIGH or LOW, or change none of them
+pub fn set_group_level_route(
+    (http_req, req, state): (
+        HttpRequest<AppState>,
+        Path<(String, String)>,
+        State<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let (group_name, gpio_level) = req.into_inner();
+
+    state
+        .db
+        .send(SetGroupLevel {
+            group_name: group_name.to_lowercase(),
+            gpio_level,
+            lease_id: lease_id_header(&http_req),
+            gpio_arc_mutex: state.gpio_arc_mutex.clone(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(group) => Ok(HttpResponse::Ok().json(group)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Get all devices built from several GPIOs, with the state of their GPIOs
+pub fn devices_route(state: State<AppState>) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(AllDevices)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(devices) => Ok(HttpResponse::Ok().json(devices)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Get a device, what it is doing and the state of its GPIOs
+pub fn device_status_route(
+    (req, state): (Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(DeviceName {
+            device_name: req.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(device) => Ok(HttpResponse::Ok().json(device)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Run an operation of a device on its GPIOs, like 'forward' on an H-bridge. The GPIOs are
+/// owned by the device, but X-Lease-Id works as for `set_gpio_level_route`.
+fn operate_device(
+    http_req: &HttpRequest<AppState>, device_name: String, operation: String,
+    value: Option<String>, state: &State<AppState>,
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(OperateDevice {
+            device_name,
+            operation,
+            value,
+            lease_id: lease_id_header(http_req),
+            gpio_arc_mutex: state.gpio_arc_mutex.clone(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(device) => Ok(HttpResponse::Ok().json(device)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Run an operation without a value, like 'off'
+pub fn device_operation_route(
+    (http_req, req, state): (
+        HttpRequest<AppState>,
+        Path<(String, String)>,
+        State<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    let (device_name, operation) = req.into_inner();
+    operate_device(&http_req, device_name, operation, None, &state)
+}
+
+/// Device name, operation and value
+type DeviceOperationPath = Path<(String, String, String)>;
+
+/// Run an operation with a value, like 'color' with 'ff8000'
+pub fn device_operation_value_route(
+    (http_req, req, state): (HttpRequest<AppState>, DeviceOperationPath, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let (device_name, operation, value) = req.into_inner();
+    operate_device(&http_req, device_name, operation, Some(value), &state)
+}
+
+/// GPIOs whose pin was not at the level in the database at the last drift check
+pub fn drift_route(state: State<AppState>) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(AllDrift)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(drifts) => Ok(HttpResponse::Ok().json(drifts)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// OK for an applied batch, else the status of the first operation that failed
+fn batch_status(batch: &models::BatchResult) -> http::StatusCode {
+    if batch.applied {
+        return http::StatusCode::OK;
+    }
+
+    batch
+        .results
+        .iter()
+        .map(|item| item.status)
+        .find(|status| *status != 200 && *status != 424)
+        .and_then(|status| http::StatusCode::from_u16(status).ok())
+        .unwrap_or(http::StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Set the level of several GPIOs in one request. All operations are checked before any is
+/// applied, and they are applied together or not at all.
+pub fn batch_route(
+    (http_req, body, state): (
+        HttpRequest<AppState>,
+        Json<Vec<models::BatchOperation>>,
+        State<AppState>,
+    ),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(ApplyBatch {
+            operations: body.into_inner(),
+            lease_id: lease_id_header(&http_req),
+            gpio_arc_mutex: state.gpio_arc_mutex.clone(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(batch) => Ok(HttpResponse::build(batch_status(&batch)).json(batch)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Bring all GPIOs to the state in the document, changing only what differs.
+/// Responds with the changes that were made, so repeating a request changes nothing.
+pub fn desired_state_route(
+    (body, state): (Json<models::DesiredState>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(ReconcileState {
+            desired: body.into_inner(),
+            gpio_arc_mutex: state.gpio_arc_mutex.clone(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(changes) => Ok(HttpResponse::Ok().json(changes)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Latest changes the server made to GPIOs on its own, newest first
+pub fn audit_route(state: State<AppState>) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(AllAudit)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(entries) => Ok(HttpResponse::Ok().json(entries)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Interlocks between outputs, one per pair of GPIOs
+pub fn interlocks_route(state: State<AppState>) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(AllInterlocks)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(interlocks) => Ok(HttpResponse::Ok().json(interlocks)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Most days shown by `/solar/{days}`
+const MAX_SOLAR_DAYS: u32 = 366;
+
+/// Sunrise and sunset for today and the following days, as used by the schedules
+pub fn solar_route((req, state): (Path<u32>, State<AppState>)) -> HttpResponse {
+    let days = req.into_inner();
+    if !(1..=MAX_SOLAR_DAYS).contains(&days) {
+        return error_response(error::ErrorBadRequest(format!(
+            "Sunrise and sunset are shown for 1 to {} days",
+            MAX_SOLAR_DAYS
+        )));
+    }
+    let location = match state.location {
+        Some(location) => location,
+        None => return error_response(error::ErrorNotFound("No [location] in the configuration")),
+    };
+
+    let today = Local::now().naive_local().date();
+    let solar_days: Vec<models::SolarDay> = (0..i64::from(days))
+        .map(|day| solar_day(&location, today + ChronoDuration::days(day)))
+        .collect();
+    HttpResponse::Ok().json(solar_days)
+}
+
+/// Status of all scripts, with errors and what they printed
+pub fn scripts_route(state: State<AppState>) -> HttpResponse {
+    let scripts: Vec<models::ScriptStatus> = state.scripts.lock().values().cloned().collect();
+    HttpResponse::Ok().json(scripts)
+}
+
+/// Status of one script, by the name of its file without '.rhai'
+pub fn script_status_route((req, state): (Path<String>, State<AppState>)) -> HttpResponse {
+    let name = req.into_inner();
+    match state.scripts.lock().get(&name) {
+        Some(script) => HttpResponse::Ok().json(script),
+        None => error_response(error::ErrorNotFound(format!(
+            "No script is named '{}'",
+            name
+        ))),
+    }
+}
+
+/// Respond with `status`, or the error
+fn stepper_response(status: Result<models::StepperStatus, actixError>) -> HttpResponse {
+    match status {
+        Ok(status) => HttpResponse::Ok().json(status),
+        Err(err) => error_response(err),
+    }
+}
+
+/// Status of all steppers
+pub fn steppers_route(state: State<AppState>) -> HttpResponse {
+    HttpResponse::Ok().json(state.steppers.statuses())
+}
+
+/// Status of one stepper, by name
+pub fn stepper_status_route((req, state): (Path<String>, State<AppState>)) -> HttpResponse {
+    stepper_response(state.steppers.status(&req.into_inner()))
+}
+
+/// Start moving a stepper by a number of steps or to a position. The response is sent when
+/// the move starts; the status of the stepper shows when it is done.
+pub fn stepper_move_route(
+    (req, body, state): (Path<String>, Json<models::StepperMove>, State<AppState>),
+) -> HttpResponse {
+    stepper_response(
+        state
+            .steppers
+            .start_move(&req.into_inner(), &body.into_inner()),
+    )
+}
+
+/// Start moving a stepper home
+pub fn stepper_home_route((req, state): (Path<String>, State<AppState>)) -> HttpResponse {
+    stepper_response(state.steppers.start_home(&req.into_inner()))
+}
+
+/// Stop a moving stepper where it is
+pub fn stepper_stop_route((req, state): (Path<String>, State<AppState>)) -> HttpResponse {
+    stepper_response(state.steppers.stop(&req.into_inner()))
+}
+
+/// Whether the presence simulation is on, and when its lights are switched next
+pub fn vacation_status_route(state: State<AppState>) -> FutureResponse<HttpResponse> {
+    state
+        .vacation
+        .send(GetVacation)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(status) => Ok(HttpResponse::Ok().json(status)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Turn the presence simulation on or off
+pub fn set_vacation_route(
+    (body, state): (Json<models::VacationRequest>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    let request = body.into_inner();
+    state
+        .vacation
+        .send(SetVacation {
+            enabled: request.enabled,
+            seed: request.seed,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(status) => Ok(HttpResponse::Ok().json(status)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Lease a GPIO or a group, so only the holder of the lease id can change it until the
+/// lease expires or is released
+pub fn acquire_lease_route(
+    (body, state): (Json<models::LeaseRequest>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(AcquireLease {
+            request: body.into_inner(),
+            gpio_arc_mutex: state.gpio_arc_mutex.clone(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(lease) => Ok(HttpResponse::Ok().json(lease)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Get an active lease
+pub fn lease_status_route(
+    (req, state): (Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(LeaseId {
+            lease_id: req.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(lease) => Ok(HttpResponse::Ok().json(lease)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Extend an active lease
+pub fn renew_lease_route(
+    (req, body, state): (Path<String>, Json<models::LeaseRenewal>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(RenewLease {
+            lease_id: req.into_inner(),
+            duration_seconds: body.duration_seconds,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(lease) => Ok(HttpResponse::Ok().json(lease)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// End an active lease
+pub fn release_lease_route(
+    (req, state): (Path<String>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(ReleaseLease {
+            lease_id: req.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(lease) => Ok(HttpResponse::Ok().json(lease)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// List all schedules
+pub fn schedules_route(state: State<AppState>) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(AllSchedules)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(schedules) => Ok(HttpResponse::Ok().json(schedules)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Create a schedule running an action on a GPIO or a group at the times of a cron expression
+pub fn create_schedule_route(
+    (body, state): (Json<models::ScheduleRequest>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(CreateSchedule {
+            request: body.into_inner(),
+            location: state.location,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(schedule) => Ok(HttpResponse::Ok().json(schedule)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Get a schedule
+pub fn schedule_status_route(
+    (req, state): (Path<i32>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(ScheduleId {
+            schedule_id: req.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(schedule) => Ok(HttpResponse::Ok().json(schedule)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Replace a schedule
+pub fn update_schedule_route(
+    (req, body, state): (Path<i32>, Json<models::ScheduleRequest>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(UpdateSchedule {
+            schedule_id: req.into_inner(),
+            request: body.into_inner(),
+            location: state.location,
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(schedule) => Ok(HttpResponse::Ok().json(schedule)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Delete a schedule
+pub fn delete_schedule_route(
+    (req, state): (Path<i32>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(DeleteSchedule {
+            schedule_id: req.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(schedule) => Ok(HttpResponse::Ok().json(schedule)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// List all rules, with the number of times each has fired
+pub fn rules_route(state: State<AppState>) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(AllRules)
+        .from_err()
+        .and_then(|res| match res {
+            Ok(rules) => Ok(HttpResponse::Ok().json(rules)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Create a rule running an action on an output when its input changes
+pub fn create_rule_route(
+    (body, state): (Json<models::RuleRequest>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(CreateRule {
+            request: body.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(rule) => Ok(HttpResponse::Ok().json(rule)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Get a rule
+pub fn rule_status_route(
+    (req, state): (Path<i32>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(RuleId {
+            rule_id: req.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(rule) => Ok(HttpResponse::Ok().json(rule)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+/// Delete a rule
+pub fn delete_rule_route(
+    (req, state): (Path<i32>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(DeleteRule {
+            rule_id: req.into_inner(),
+        })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(rule) => Ok(HttpResponse::Ok().json(rule)),
+            Err(err) => Ok(error_response(err)),
+        })
+        .responder()
+}
+
+fn enable_rule(
+    rule_id: i32, enabled: bool, state: &State<AppState>,
+) -> FutureResponse<HttpResponse> {
+    state
+        .db
+        .send(EnableRule { rule_id, enabled })
+        .from_err()
+        .and_then(|res| match res {
+            Ok(rule) => Ok(HttpResponse::Ok().json(rule)),
+            Err(err) => Ok(error_response(err)),
         })
         .responder()
 }
 
+/// Enable a rule
+pub fn enable_rule_route(
+    (req, state): (Path<i32>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    enable_rule(req.into_inner(), true, &state)
+}
+
+/// Disable a rule, keeping it for later
+pub fn disable_rule_route(
+    (req, state): (Path<i32>, State<AppState>),
+) -> FutureResponse<HttpResponse> {
+    enable_rule(req.into_inner(), false, &state)
+}
+
 /// creates and returns the app after mounting all routes/resources
 pub fn create_app(app_state: AppState) -> App<AppState> {
     App::with_state(app_state)
         // enable logger
         .middleware(middleware::Logger::default())
+        .resource("/status", |r| {
+            r.method(http::Method::GET).with(gpio_status_all_route)
+        })
         .resource("/status/{id}", |r| {
             r.method(http::Method::GET).with(gpio_status_route)
         })
         .resource("/set/level/{id}/{level}", |r| {
             r.method(http::Method::GET).with(set_gpio_level_route)
         })
+        .resource("/toggle/{id}", |r| {
+            r.method(http::Method::GET).with(toggle_gpio_level_route)
+        })
+        .resource("/pwm/{id}/frequency/{frequency}", |r| {
+            r.method(http::Method::GET)
+                .with(set_gpio_pwm_frequency_route)
+        })
+        .resource("/pwm/{id}/duty/{duty_cycle}", |r| {
+            r.method(http::Method::GET).with(set_gpio_pwm_duty_route)
+        })
+        .resource("/ramp/{id}/{duty_cycle}/{duration_ms}", |r| {
+            r.method(http::Method::GET).with(ramp_route)
+        })
+        .resource("/ramp/{id}/{duty_cycle}/{duration_ms}/{easing}", |r| {
+            r.method(http::Method::GET).with(eased_ramp_route)
+        })
+        .resource("/ramp/{id}", |r| {
+            r.method(http::Method::DELETE).with(cancel_ramp_route)
+        })
+        .resource("/servo/{id}", |r| {
+            r.method(http::Method::GET).with(servo_status_route)
+        })
+        .resource("/servo/{id}/angle/{angle}", |r| {
+            r.method(http::Method::GET).with(servo_angle_route)
+        })
+        .resource("/pulse/{id}/{level}/{duration_ms}", |r| {
+            r.method(http::Method::GET).with(pulse_route)
+        })
+        .resource("/blink/{id}/{period_ms}/{duty_percent}/{count}", |r| {
+            r.method(http::Method::GET).with(blink_route)
+        })
+        .resource("/sequence/{id}", |r| {
+            r.method(http::Method::DELETE).with(cancel_sequence_route)
+        })
+        .resource("/group/{name}", |r| {
+            r.method(http::Method::GET).with(group_status_route)
+        })
+        .resource("/group/{name}/set/level/{level}", |r| {
+            r.method(http::Method::GET).with(set_group_level_route)
+        })
+        .resource("/devices", |r| {
+            r.method(http::Method::GET).with(devices_route)
+        })
+        .resource("/devices/{name}", |r| {
+            r.method(http::Method::GET).with(device_status_route)
+        })
+        .resource("/devices/{name}/{operation}", |r| {
+            r.method(http::Method::GET).with(device_operation_route)
+        })
+        .resource("/devices/{name}/{operation}/{value}", |r| {
+            r.method(http::Method::GET)
+                .with(device_operation_value_route)
+        })
+        .resource("/batch", |r| r.method(http::Method::POST).with(batch_route))
+        .resource("/drift", |r| r.method(http::Method::GET).with(drift_route))
+        .resource("/audit", |r| r.method(http::Method::GET).with(audit_route))
+        .resource("/interlocks", |r| {
+            r.method(http::Method::GET).with(interlocks_route)
+        })
+        .resource("/solar/{days}", |r| {
+            r.method(http::Method::GET).with(solar_route)
+        })
+        .resource("/vacation", |r| {
+            r.method(http::Method::GET).with(vacation_status_route);
+            r.method(http::Method::PUT).with(set_vacation_route)
+        })
+        .resource("/scripts", |r| {
+            r.method(http::Method::GET).with(scripts_route)
+        })
+        .resource("/scripts/{name}", |r| {
+            r.method(http::Method::GET).with(script_status_route)
+        })
+        .resource("/steppers", |r| {
+            r.method(http::Method::GET).with(steppers_route)
+        })
+        .resource("/steppers/{name}", |r| {
+            r.method(http::Method::GET).with(stepper_status_route)
+        })
+        .resource("/steppers/{name}/move", |r| {
+            r.method(http::Method::POST).with(stepper_move_route)
+        })
+        .resource("/steppers/{name}/home", |r| {
+            r.method(http::Method::POST).with(stepper_home_route)
+        })
+        .resource("/steppers/{name}/stop", |r| {
+            r.method(http::Method::POST).with(stepper_stop_route)
+        })
+        .resource("/lease", |r| {
+            r.method(http::Method::POST).with(acquire_lease_route)
+        })
+        .resource("/lease/{lease_id}", |r| {
+            r.method(http::Method::GET).with(lease_status_route);
+            r.method(http::Method::DELETE).with(release_lease_route)
+        })
+        .resource("/lease/{lease_id}/renew", |r| {
+            r.method(http::Method::POST).with(renew_lease_route)
+        })
+        .resource("/schedules", |r| {
+            r.method(http::Method::GET).with(schedules_route);
+            r.method(http::Method::POST).with(create_schedule_route)
+        })
+        .resource("/schedules/{schedule_id}", |r| {
+            r.method(http::Method::GET).with(schedule_status_route);
+            r.method(http::Method::PUT).with(update_schedule_route);
+            r.method(http::Method::DELETE).with(delete_schedule_route)
+        })
+        .resource("/rules", |r| {
+            r.method(http::Method::GET).with(rules_route);
+            r.method(http::Method::POST).with(create_rule_route)
+        })
+        .resource("/rules/{rule_id}", |r| {
+            r.method(http::Method::GET).with(rule_status_route);
+            r.method(http::Method::DELETE).with(delete_rule_route)
+        })
+        .resource("/rules/{rule_id}/enable", |r| {
+            r.method(http::Method::POST).with(enable_rule_route)
+        })
+        .resource("/rules/{rule_id}/disable", |r| {
+            r.method(http::Method::POST).with(disable_rule_route)
+        })
+        .resource("/state", |r| {
+            r.method(http::Method::PUT).with(desired_state_route)
+        })
+        .resource("/ui", |r| r.method(http::Method::GET).f(ui::ui_index_route))
+        .resource("/ui/", |r| {
+            r.method(http::Method::GET).f(ui::ui_index_route)
+        })
+        .resource("/ui/app.js", |r| {
+            r.method(http::Method::GET).f(ui::ui_script_route)
+        })
+        .resource("/ui/style.css", |r| {
+            r.method(http::Method::GET).f(ui::ui_style_route)
+        })
 }
@@ -0,0 +1,741 @@
+// Outputs driven by iCalendar files: a GPIO or a group is high during the events of its
+// calendar and low otherwise. The files are read again when they change.
+
+use crate::errors::RpWebError;
+use crate::handlers::{DbExecutor, SetGpioLevel, SetGroupLevel};
+use crate::rpi::GpioArcMutex;
+use crate::settings::CalendarConfig;
+use crate::utilities::utc_to_local;
+use actix::fut::wrap_future;
+use actix::{Actor, Addr, AsyncContext, Context};
+use actix_web::Error as actixError;
+use chrono::{Datelike, Duration as ChronoDuration, Local, NaiveDate, NaiveDateTime, Weekday};
+use futures::Future;
+use ical::parser::ical::component::IcalEvent;
+use ical::property::Property;
+use ical::IcalParser;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::time::{Duration, SystemTime};
+
+/// How often the calendars are checked for changes and events starting or ending
+const CALENDAR_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Frequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// The parts of an `RRULE` that are supported: FREQ (DAILY to YEARLY), INTERVAL, COUNT,
+/// UNTIL, BYDAY, BYMONTHDAY and BYMONTH
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecurrenceRule {
+    frequency: Frequency,
+    interval: u32,
+    count: Option<u32>,
+    until: Option<NaiveDateTime>,
+    // Weekday, with the n'th (from the end if negative) in the month, or in the year for
+    // YEARLY without BYMONTH
+    by_day: Vec<(Option<i32>, Weekday)>,
+    by_month_day: Vec<i32>,
+    by_month: Vec<u32>,
+}
+
+/// A VEVENT: from `start` for `duration`, repeated by `rule` except at `exceptions`
+#[derive(Debug, Clone, PartialEq)]
+pub struct CalendarEvent {
+    pub start: NaiveDateTime,
+    pub duration: ChronoDuration,
+    pub rule: Option<RecurrenceRule>,
+    pub exceptions: Vec<NaiveDateTime>,
+}
+
+fn weekday(code: &str) -> Option<Weekday> {
+    match code {
+        "MO" => Some(Weekday::Mon),
+        "TU" => Some(Weekday::Tue),
+        "WE" => Some(Weekday::Wed),
+        "TH" => Some(Weekday::Thu),
+        "FR" => Some(Weekday::Fri),
+        "SA" => Some(Weekday::Sat),
+        "SU" => Some(Weekday::Sun),
+        _ => None,
+    }
+}
+
+fn days_in_month(year: i32, month: u32) -> i32 {
+    let first = NaiveDate::from_ymd(year, month, 1);
+    let next = if month == 12 {
+        NaiveDate::from_ymd(year + 1, 1, 1)
+    } else {
+        NaiveDate::from_ymd(year, month + 1, 1)
+    };
+    (next - first).num_days() as i32
+}
+
+/// A DATE (all day, true) or DATE-TIME value in local time. Times in UTC end with 'Z';
+/// times with a TZID are taken as local time.
+fn parse_date_time(text: &str) -> Result<(NaiveDateTime, bool), RpWebError> {
+    let invalid = || RpWebError::new(&format!("Invalid date or time '{}' in calendar", text));
+    let text = text.trim();
+
+    if text.len() == 8 {
+        let date = NaiveDate::parse_from_str(text, "%Y%m%d").map_err(|_| invalid())?;
+        return Ok((date.and_hms(0, 0, 0), true));
+    }
+    match text.strip_suffix('Z') {
+        Some(utc) => NaiveDateTime::parse_from_str(utc, "%Y%m%dT%H%M%S")
+            .map(|time| (utc_to_local(time), false))
+            .map_err(|_| invalid()),
+        None => NaiveDateTime::parse_from_str(text, "%Y%m%dT%H%M%S")
+            .map(|time| (time, false))
+            .map_err(|_| invalid()),
+    }
+}
+
+/// A DURATION value like 'PT1H30M' or 'P1D'
+fn parse_duration(text: &str) -> Result<ChronoDuration, RpWebError> {
+    let invalid = || RpWebError::new(&format!("Invalid duration '{}' in calendar", text));
+    let rest = text.trim().trim_start_matches('+');
+    let rest = rest.strip_prefix('P').ok_or_else(invalid)?;
+
+    let mut total = ChronoDuration::zero();
+    let mut number = String::new();
+    let mut in_time = false;
+    for c in rest.chars() {
+        match c {
+            '0'..='9' => number.push(c),
+            'T' if !in_time && number.is_empty() => in_time = true,
+            'W' | 'D' | 'H' | 'M' | 'S' => {
+                let n: i64 = number.parse().map_err(|_| invalid())?;
+                number.clear();
+                total = total
+                    + match (c, in_time) {
+                        ('W', false) => ChronoDuration::weeks(n),
+                        ('D', false) => ChronoDuration::days(n),
+                        ('H', true) => ChronoDuration::hours(n),
+                        ('M', true) => ChronoDuration::minutes(n),
+                        ('S', true) => ChronoDuration::seconds(n),
+                        _ => return Err(invalid()),
+                    };
+            }
+            _ => return Err(invalid()),
+        }
+    }
+
+    if number.is_empty() {
+        Ok(total)
+    } else {
+        Err(invalid())
+    }
+}
+
+impl RecurrenceRule {
+    pub fn parse(text: &str) -> Result<RecurrenceRule, RpWebError> {
+        let invalid = |part: &str| {
+            RpWebError::new(&format!("Invalid or unsupported '{}' in RRULE '{}'", part, text))
+        };
+
+        let mut rule = RecurrenceRule {
+            frequency: Frequency::Daily,
+            interval: 1,
+            count: None,
+            until: None,
+            by_day: vec![],
+            by_month_day: vec![],
+            by_month: vec![],
+        };
+        let mut frequency = None;
+
+        for part in text.trim().split(';').filter(|part| !part.is_empty()) {
+            let mut key_value = part.splitn(2, '=');
+            let key = key_value.next().unwrap_or_default().to_uppercase();
+            let value = key_value.next().ok_or_else(|| invalid(part))?.to_uppercase();
+
+            match key.as_str() {
+                "FREQ" => {
+                    frequency = Some(match value.as_str() {
+                        "DAILY" => Frequency::Daily,
+                        "WEEKLY" => Frequency::Weekly,
+                        "MONTHLY" => Frequency::Monthly,
+                        "YEARLY" => Frequency::Yearly,
+                        _ => return Err(invalid(part)),
+                    })
+                }
+                "INTERVAL" => {
+                    rule.interval = value
+                        .parse()
+                        .ok()
+                        .filter(|interval| *interval > 0)
+                        .ok_or_else(|| invalid(part))?
+                }
+                "COUNT" => rule.count = Some(value.parse().map_err(|_| invalid(part))?),
+                "UNTIL" => rule.until = Some(parse_date_time(&value)?.0),
+                "BYDAY" => {
+                    for day in value.split(',') {
+                        let split = day.len().checked_sub(2).ok_or_else(|| invalid(part))?;
+                        let (ordinal, code) = day.split_at(split);
+                        let ordinal = match ordinal {
+                            "" => None,
+                            ordinal => Some(
+                                ordinal
+                                    .trim_start_matches('+')
+                                    .parse::<i32>()
+                                    .ok()
+                                    .filter(|n| *n != 0 && (-5..=5).contains(n))
+                                    .ok_or_else(|| invalid(part))?,
+                            ),
+                        };
+                        rule.by_day.push((ordinal, weekday(code).ok_or_else(|| invalid(part))?));
+                    }
+                }
+                "BYMONTHDAY" => {
+                    for day in value.split(',') {
+                        rule.by_month_day.push(
+                            day.parse::<i32>()
+                                .ok()
+                                .filter(|day| *day != 0 && (-31..=31).contains(day))
+                                .ok_or_else(|| invalid(part))?,
+                        );
+                    }
+                }
+                "BYMONTH" => {
+                    for month in value.split(',') {
+                        rule.by_month.push(
+                            month
+                                .parse::<u32>()
+                                .ok()
+                                .filter(|month| (1..=12).contains(month))
+                                .ok_or_else(|| invalid(part))?,
+                        );
+                    }
+                }
+                // Only makes a difference for weekly rules with an interval, starting on Sunday
+                "WKST" => {}
+                _ => return Err(invalid(part)),
+            }
+        }
+
+        rule.frequency = frequency.ok_or_else(|| invalid("FREQ"))?;
+        Ok(rule)
+    }
+
+    fn in_by_month(&self, date: NaiveDate) -> bool {
+        self.by_month.is_empty() || self.by_month.contains(&date.month())
+    }
+
+    fn in_by_month_day(&self, date: NaiveDate) -> bool {
+        let length = days_in_month(date.year(), date.month());
+        let day = date.day() as i32;
+        self.by_month_day.is_empty()
+            || self
+                .by_month_day
+                .iter()
+                .any(|n| *n == day || (*n < 0 && length + n + 1 == day))
+    }
+
+    /// True if `date` is on a weekday in BYDAY. With `position`, the day of `date` in its
+    /// period and the length of the period, ordinals count within that period.
+    fn in_by_day(&self, date: NaiveDate, position: Option<(i32, i32)>) -> bool {
+        self.by_day.is_empty()
+            || self.by_day.iter().any(|(ordinal, weekday)| {
+                date.weekday() == *weekday
+                    && match (ordinal, position) {
+                        (Some(n), Some((day, _))) if *n > 0 => (day - 1) / 7 + 1 == *n,
+                        (Some(n), Some((day, length))) => (length - day) / 7 + 1 == -n,
+                        _ => true,
+                    }
+            })
+    }
+
+    /// The days of `month` in a MONTHLY or YEARLY rule starting on `start`
+    fn month_days(&self, year: i32, month: u32, start: NaiveDate) -> Vec<NaiveDate> {
+        (1..=days_in_month(year, month) as u32)
+            .map(|day| NaiveDate::from_ymd(year, month, day))
+            .filter(|date| {
+                if self.by_month_day.is_empty() && self.by_day.is_empty() {
+                    date.day() == start.day()
+                } else {
+                    let position = (date.day() as i32, days_in_month(year, month));
+                    self.in_by_month_day(*date) && self.in_by_day(*date, Some(position))
+                }
+            })
+            .collect()
+    }
+
+    /// The first day of the `period`'th period of the rule from `start`, and the days of the
+    /// rule in that period, in order. A YEARLY rule is in the months in BYMONTH, with BYDAY
+    /// ordinals counting within the month. Without BYMONTH, it is on the days of the whole year
+    /// matching BYMONTHDAY and BYDAY, with ordinals counting within the year, or on the day
+    /// and month of `start` if neither is given.
+    fn period_days(&self, start: NaiveDate, period: i64) -> (NaiveDate, Vec<NaiveDate>) {
+        let step = period * i64::from(self.interval);
+
+        match self.frequency {
+            Frequency::Daily => {
+                let day = start + ChronoDuration::days(step);
+                let matches =
+                    self.in_by_month(day) && self.in_by_month_day(day) && self.in_by_day(day, None);
+                (day, if matches { vec![day] } else { vec![] })
+            }
+            Frequency::Weekly => {
+                let monday = start
+                    - ChronoDuration::days(i64::from(start.weekday().num_days_from_monday()))
+                    + ChronoDuration::weeks(step);
+                let days = (0..7)
+                    .map(|day| monday + ChronoDuration::days(day))
+                    .filter(|day| {
+                        let weekday_matches = if self.by_day.is_empty() {
+                            day.weekday() == start.weekday()
+                        } else {
+                            self.in_by_day(*day, None)
+                        };
+                        weekday_matches && self.in_by_month(*day)
+                    })
+                    .collect();
+                (monday, days)
+            }
+            Frequency::Monthly => {
+                let months = i64::from(start.year()) * 12 + i64::from(start.month0()) + step;
+                let (year, month) = ((months / 12) as i32, (months % 12) as u32 + 1);
+                let first = NaiveDate::from_ymd(year, month, 1);
+                let days = if self.in_by_month(first) {
+                    self.month_days(year, month, start)
+                } else {
+                    vec![]
+                };
+                (first, days)
+            }
+            Frequency::Yearly => {
+                let year = start.year() + step as i32;
+                let first = NaiveDate::from_ymd(year, 1, 1);
+                let days = if !self.by_month.is_empty() {
+                    let mut months = self.by_month.clone();
+                    months.sort();
+                    months
+                        .into_iter()
+                        .flat_map(|month| self.month_days(year, month, start))
+                        .collect()
+                } else if self.by_month_day.is_empty() && self.by_day.is_empty() {
+                    self.month_days(year, start.month(), start)
+                } else {
+                    let length = (NaiveDate::from_ymd(year + 1, 1, 1) - first).num_days() as i32;
+                    (0..length)
+                        .map(|day| first + ChronoDuration::days(i64::from(day)))
+                        .filter(|date| {
+                            let position = (date.ordinal() as i32, length);
+                            self.in_by_month_day(*date) && self.in_by_day(*date, Some(position))
+                        })
+                        .collect()
+                };
+                (first, days)
+            }
+        }
+    }
+
+    /// The starts of the occurrences of the rule from `start` up to and including `last`
+    fn occurrences(&self, start: NaiveDateTime, last: NaiveDateTime) -> Vec<NaiveDateTime> {
+        let mut found = vec![];
+        let mut count = 0;
+
+        for period in 0.. {
+            let (period_start, days) = self.period_days(start.date(), period);
+            if period_start > last.date() {
+                break;
+            }
+
+            for day in days {
+                let at = day.and_time(start.time());
+                if at < start {
+                    continue;
+                }
+                if at > last
+                    || self.until.is_some_and(|until| at > until)
+                    || self.count.is_some_and(|max| count >= max)
+                {
+                    return found;
+                }
+                count += 1;
+                found.push(at);
+            }
+        }
+
+        found
+    }
+}
+
+impl CalendarEvent {
+    /// True if an occurrence of the event is going on at `now`
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        let starts = match &self.rule {
+            Some(rule) => rule.occurrences(self.start, now),
+            None => vec![self.start],
+        };
+
+        starts.iter().any(|start| {
+            *start <= now && now < *start + self.duration && !self.exceptions.contains(start)
+        })
+    }
+}
+
+fn property<'a>(event: &'a IcalEvent, name: &str) -> Option<&'a Property> {
+    event.properties.iter().find(|property| property.name == name)
+}
+
+fn property_value<'a>(event: &'a IcalEvent, name: &str) -> Option<&'a str> {
+    property(event, name).and_then(|property| property.value.as_deref())
+}
+
+fn parse_event(event: &IcalEvent) -> Result<CalendarEvent, RpWebError> {
+    let (start, all_day) = property_value(event, "DTSTART")
+        .ok_or_else(|| RpWebError::new("Event without DTSTART in calendar"))
+        .and_then(parse_date_time)?;
+
+    let duration = if let Some(end) = property_value(event, "DTEND") {
+        parse_date_time(end)?.0 - start
+    } else if let Some(duration) = property_value(event, "DURATION") {
+        parse_duration(duration)?
+    } else if all_day {
+        ChronoDuration::days(1)
+    } else {
+        ChronoDuration::zero()
+    };
+
+    let rule = match property_value(event, "RRULE") {
+        Some(rule) => Some(RecurrenceRule::parse(rule)?),
+        None => None,
+    };
+
+    let mut exceptions = vec![];
+    let exdates = event
+        .properties
+        .iter()
+        .filter(|property| property.name == "EXDATE")
+        .filter_map(|property| property.value.as_deref());
+    for exdate in exdates {
+        for value in exdate.split(',') {
+            exceptions.push(parse_date_time(value)?.0);
+        }
+    }
+
+    Ok(CalendarEvent {
+        start,
+        duration,
+        rule,
+        exceptions,
+    })
+}
+
+/// The events in an iCalendar file. Cancelled events are left out, and so are occurrences of
+/// recurring events that are moved or cancelled by an event with the same UID and a
+/// RECURRENCE-ID.
+pub fn parse_calendar<B: BufRead>(reader: B) -> Result<Vec<CalendarEvent>, RpWebError> {
+    let mut events = vec![];
+    let mut moved = vec![];
+
+    for calendar in IcalParser::new(reader) {
+        let calendar =
+            calendar.map_err(|err| RpWebError::new(&format!("Invalid calendar: {}", err)))?;
+
+        for event in calendar.events.iter() {
+            let uid = property_value(event, "UID").unwrap_or_default().to_string();
+            if let Some(recurrence_id) = property_value(event, "RECURRENCE-ID") {
+                moved.push((uid.clone(), parse_date_time(recurrence_id)?.0));
+            }
+
+            let cancelled = property_value(event, "STATUS")
+                .is_some_and(|status| status.eq_ignore_ascii_case("CANCELLED"));
+            if !cancelled {
+                events.push((uid, parse_event(event)?));
+            }
+        }
+    }
+
+    for (uid, at) in moved {
+        for (event_uid, event) in events.iter_mut() {
+            if *event_uid == uid && event.rule.is_some() {
+                event.exceptions.push(at);
+            }
+        }
+    }
+
+    Ok(events.into_iter().map(|(_, event)| event).collect())
+}
+
+pub fn read_calendar_file(path: &str) -> Result<Vec<CalendarEvent>, RpWebError> {
+    let file = File::open(path)?;
+    parse_calendar(BufReader::new(file))
+}
+
+/// 'high' during an event in `events`, 'low' otherwise
+pub fn calendar_level(events: &[CalendarEvent], now: NaiveDateTime) -> &'static str {
+    if events.iter().any(|event| event.is_active(now)) {
+        "high"
+    } else {
+        "low"
+    }
+}
+
+/// A calendar file, the events last read from it and the level last set from it
+struct Calendar {
+    config: CalendarConfig,
+    modified: Option<SystemTime>,
+    readable: bool,
+    events: Option<Vec<CalendarEvent>>,
+    level: Option<&'static str>,
+}
+
+impl Calendar {
+    fn target(&self) -> String {
+        match (self.config.gpio_id, self.config.group.as_ref()) {
+            (Some(gpio_id), _) => format!("GPIO #{}", gpio_id),
+            (None, Some(group)) => format!("group '{}'", group),
+            (None, None) => "nothing".to_string(),
+        }
+    }
+
+    /// Read the file again if it was modified since it was last read. If it can not be
+    /// read or parsed, the events read before are kept.
+    fn reload_if_changed(&mut self) {
+        let modified = match fs::metadata(&self.config.path).and_then(|meta| meta.modified()) {
+            Ok(modified) => modified,
+            Err(err) => {
+                if self.readable {
+                    error!("Can not read calendar '{}': {}", self.config.path, err);
+                    self.readable = false;
+                }
+                return;
+            }
+        };
+        self.readable = true;
+        if self.modified == Some(modified) {
+            return;
+        }
+        self.modified = Some(modified);
+
+        match read_calendar_file(&self.config.path) {
+            Ok(events) => {
+                info!(
+                    "Read {} events for {} from '{}'",
+                    events.len(),
+                    self.target(),
+                    self.config.path
+                );
+                self.events = Some(events);
+                // Set the level again, even if it did not change
+                self.level = None;
+            }
+            Err(err) => error!("Keeping calendar '{}' as it was: {}", self.config.path, err),
+        }
+    }
+}
+
+/// Actor reading the calendar files and setting their GPIOs and groups when an event starts
+/// or ends, through the DbExecutor like requests from clients. Levels set by clients in the
+/// meantime are kept until the next change in the calendar.
+pub struct CalendarMonitor {
+    db: Addr<DbExecutor>,
+    gpio_arc_mutex: GpioArcMutex,
+    calendars: Vec<Calendar>,
+}
+
+impl CalendarMonitor {
+    pub fn new(
+        db: Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex, calendars: &[CalendarConfig],
+    ) -> Self {
+        CalendarMonitor {
+            db,
+            gpio_arc_mutex,
+            calendars: calendars
+                .iter()
+                .map(|config| Calendar {
+                    config: config.clone(),
+                    modified: None,
+                    readable: true,
+                    events: None,
+                    level: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+fn set_calendar_level(
+    db: &Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex, config: &CalendarConfig,
+    level: &str,
+) -> Box<dyn Future<Item = (), Error = actixError>> {
+    match (config.gpio_id, config.group.clone()) {
+        (Some(gpio_id), _) => Box::new(
+            db.send(SetGpioLevel {
+                gpio_id,
+                gpio_level: level.to_string(),
+                expected_version: None,
+                lease_id: None,
+                gpio_arc_mutex,
+            })
+            .from_err()
+            .and_then(|res| res.map(|_| ())),
+        ),
+        (None, Some(group_name)) => Box::new(
+            db.send(SetGroupLevel {
+                group_name,
+                gpio_level: level.to_string(),
+                lease_id: None,
+                gpio_arc_mutex,
+            })
+            .from_err()
+            .and_then(|res| res.map(|_| ())),
+        ),
+        (None, None) => Box::new(futures::future::ok(())),
+    }
+}
+
+impl Actor for CalendarMonitor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(CALENDAR_INTERVAL, |act, ctx| {
+            let now = Local::now().naive_local();
+
+            for calendar in act.calendars.iter_mut() {
+                calendar.reload_if_changed();
+                let level = match &calendar.events {
+                    Some(events) => calendar_level(events, now),
+                    None => continue,
+                };
+                if calendar.level == Some(level) {
+                    continue;
+                }
+                calendar.level = Some(level);
+
+                let target = calendar.target();
+                info!("Calendar '{}' sets {} '{}'", calendar.config.path, target, level);
+                let set = set_calendar_level(
+                    &act.db,
+                    act.gpio_arc_mutex.clone(),
+                    &calendar.config,
+                    level,
+                )
+                .map_err(move |err| error!("Calendar failed to set {}: {}", target, err));
+                ctx.spawn(wrap_future(set));
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn calendar(events: &str) -> Vec<CalendarEvent> {
+        let text = format!(
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\n{}END:VCALENDAR\r\n",
+            events
+        );
+        parse_calendar(text.as_bytes()).unwrap()
+    }
+
+    // Opening hours 8 to 17 on weekdays, not on the holiday on Monday 2019-06-10, and from 10
+    // instead of 8 on Wednesday 2019-06-12
+    const OPENING_HOURS: &str = "BEGIN:VEVENT\r\n\
+        UID:opening-hours\r\n\
+        DTSTART:20190603T080000\r\n\
+        DTEND:20190603T170000\r\n\
+        RRULE:FREQ=WEEKLY;BYDAY=MO,TU,WE,TH,FR\r\n\
+        EXDATE:20190610T080000\r\n\
+        END:VEVENT\r\n\
+        BEGIN:VEVENT\r\n\
+        UID:opening-hours\r\n\
+        RECURRENCE-ID:20190612T080000\r\n\
+        DTSTART:20190612T100000\r\n\
+        DURATION:PT7H\r\n\
+        END:VEVENT\r\n";
+
+    #[test]
+    fn weekly_events_must_follow_rule_and_exceptions() {
+        let events = calendar(OPENING_HOURS);
+
+        assert_eq!(calendar_level(&events, time("2019-06-04 09:00")), "high");
+        assert_eq!(calendar_level(&events, time("2019-06-04 17:00")), "low");
+        assert_eq!(calendar_level(&events, time("2019-06-08 09:00")), "low");
+        assert_eq!(calendar_level(&events, time("2019-06-10 09:00")), "low");
+        assert_eq!(calendar_level(&events, time("2019-06-11 09:00")), "high");
+        assert_eq!(calendar_level(&events, time("2019-06-12 09:00")), "low");
+        assert_eq!(calendar_level(&events, time("2019-06-12 10:30")), "high");
+    }
+
+    #[test]
+    fn monthly_rule_must_handle_last_weekday_and_count() {
+        // The last Friday of the month, three times from June 2019
+        let rule = RecurrenceRule::parse("FREQ=MONTHLY;BYDAY=-1FR;COUNT=3").unwrap();
+        let starts = rule.occurrences(time("2019-06-28 18:00"), time("2020-12-31 00:00"));
+
+        assert_eq!(
+            starts,
+            vec![
+                time("2019-06-28 18:00"),
+                time("2019-07-26 18:00"),
+                time("2019-08-30 18:00")
+            ]
+        );
+    }
+
+    #[test]
+    fn yearly_rule_without_month_must_cover_whole_year() {
+        // Every Monday, and the first and last Monday of the year
+        let mondays = RecurrenceRule::parse("FREQ=YEARLY;BYDAY=MO").unwrap();
+        let starts = mondays.occurrences(time("2019-01-07 07:00"), time("2019-12-31 00:00"));
+        assert_eq!(starts.len(), 52);
+        assert_eq!(starts[51], time("2019-12-30 07:00"));
+
+        let rule = RecurrenceRule::parse("FREQ=YEARLY;BYDAY=1MO,-1MO;COUNT=3").unwrap();
+        let starts = rule.occurrences(time("2019-01-07 07:00"), time("2021-01-01 00:00"));
+        assert_eq!(
+            starts,
+            vec![
+                time("2019-01-07 07:00"),
+                time("2019-12-30 07:00"),
+                time("2020-01-06 07:00")
+            ]
+        );
+
+        // The 15th of every month
+        let rule = RecurrenceRule::parse("FREQ=YEARLY;BYMONTHDAY=15").unwrap();
+        let starts = rule.occurrences(time("2019-01-15 07:00"), time("2019-12-31 00:00"));
+        assert_eq!(starts.len(), 12);
+    }
+
+    #[test]
+    fn all_day_events_must_last_whole_days() {
+        let events = calendar(
+            "BEGIN:VEVENT\r\n\
+             UID:christmas\r\n\
+             DTSTART;VALUE=DATE:20191224\r\n\
+             DTEND;VALUE=DATE:20191226\r\n\
+             RRULE:FREQ=YEARLY;UNTIL=20201231\r\n\
+             END:VEVENT\r\n",
+        );
+
+        assert_eq!(calendar_level(&events, time("2019-12-25 23:59")), "high");
+        assert_eq!(calendar_level(&events, time("2019-12-26 00:00")), "low");
+        assert_eq!(calendar_level(&events, time("2020-12-24 12:00")), "high");
+        assert_eq!(calendar_level(&events, time("2021-12-24 12:00")), "low");
+    }
+
+    #[test]
+    fn invalid_calendar_values_must_fail() {
+        assert!(RecurrenceRule::parse("FREQ=HOURLY").is_err());
+        assert!(RecurrenceRule::parse("BYDAY=MO").is_err());
+        assert!(RecurrenceRule::parse("FREQ=WEEKLY;BYDAY=XX").is_err());
+        assert!(RecurrenceRule::parse("FREQ=DAILY;BYSETPOS=1").is_err());
+        assert!(parse_duration("PT1D").is_err());
+        assert_eq!(parse_duration("P1DT2H").unwrap(), ChronoDuration::hours(26));
+    }
+}
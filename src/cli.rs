@@ -1,4 +1,4 @@
-use clap::{App, Arg, ArgMatches};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
 /// Parse command line arguments
 pub fn get_cli_args() -> ArgMatches<'static> {
@@ -14,5 +14,27 @@ pub fn get_cli_args() -> ArgMatches<'static> {
                 .takes_value(true)
                 .required(false),
         )
+        .subcommand(
+            SubCommand::with_name("tui")
+                .about("Show a live dashboard of all GPIOs in the terminal")
+                .arg(
+                    Arg::with_name("remote")
+                        .short("r")
+                        .long("remote")
+                        .value_name("URL")
+                        .help("Use the HTTP API at URL instead of the local database")
+                        .takes_value(true)
+                        .required(false),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .short("i")
+                        .long("interval")
+                        .value_name("MILLISECONDS")
+                        .help("Time between refreshes, default 1000")
+                        .takes_value(true)
+                        .required(false),
+                ),
+        )
         .get_matches()
 }
@@ -0,0 +1,151 @@
+// Changes that touch both the GPIO pins and the database
+
+use crate::errors::RpWebError;
+use crate::interlocks::{check_interlocks_db, dependents_going_low_db};
+use crate::leases::check_gpio_lease_db;
+use crate::models::LevelChange;
+use crate::rpi::{
+    get_gpio_pwm_rpi, hardware_pwm_channel, revert_gpio_levels_rpi, set_gpio_levels_rpi,
+    set_gpio_pwm_rpi, GpioArcMutex,
+};
+use crate::utilities::{check_gpio_version_db, set_gpio_level_db, set_gpio_pwm_db, toggled_level};
+use diesel::prelude::*;
+use parking_lot::{ReentrantMutex, ReentrantMutexGuard};
+use std::sync::OnceLock;
+
+/// Held by `apply_gpio_levels` around writing the rows, driving the pins and committing
+static GPIO_LEVELS: OnceLock<ReentrantMutex<()>> = OnceLock::new();
+
+/// Keep other threads from changing levels with `apply_gpio_levels` until the guard is
+/// dropped, so the pins and the committed rows can be compared, or a level read and checked
+/// before it is changed, without a change being half-way. The thread holding the guard can
+/// still change levels itself.
+pub fn lock_gpio_levels() -> ReentrantMutexGuard<'static, ()> {
+    GPIO_LEVELS.get_or_init(|| ReentrantMutex::new(())).lock()
+}
+
+/// Apply `changes` to the GPIO pins and the database, all or nothing.
+///
+/// The rows are updated in a transaction before the pins are driven, and the transaction
+/// is only committed when all pins were driven. Nothing is changed if a GPIO no longer has
+/// the version a change expects, is leased by someone else, or if the changes together would
+/// break an interlock. GPIOs requiring a GPIO that goes low go low with it, whoever holds
+/// them, so turning an output off is never refused by an interlock. If a pin fails, the pins
+/// already driven are reverted and the transaction is rolled back. If the commit fails, all
+/// pins are reverted. All of it happens under `lock_gpio_levels`.
+pub fn apply_gpio_levels(
+    changes: &[LevelChange], conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    apply_planned_gpio_levels(|| Ok(changes.to_vec()), conn, gpio_arc_mutex).map(|_| ())
+}
+
+/// Like `apply_gpio_levels`, with the changes made by `plan` in the same transaction and
+/// under the same lock, so they are based on the levels they replace. Rows `plan` updates
+/// itself are committed or rolled back with the levels. Returns the planned changes.
+pub fn apply_planned_gpio_levels<F>(
+    plan: F, conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<Vec<LevelChange>, RpWebError>
+where
+    F: FnOnce() -> Result<Vec<LevelChange>, RpWebError>,
+{
+    let _levels = lock_gpio_levels();
+    let mut driven = vec![];
+
+    let result = conn.transaction::<_, RpWebError, _>(|| {
+        let changes = plan()?;
+        let mut levels: Vec<(i32, &str)> = changes
+            .iter()
+            .map(|change| (change.gpio_id, change.level.as_str()))
+            .collect();
+        let dependents = dependents_going_low_db(&levels, conn)?;
+        levels.extend(dependents.iter().map(|(id, _)| (*id, "low")));
+        check_interlocks_db(&levels, conn)?;
+
+        for change in changes.iter() {
+            check_gpio_lease_db(change.gpio_id, change.lease_id.as_deref(), conn)?;
+            if let Some(expected) = change.expected_version {
+                check_gpio_version_db(change.gpio_id, expected, conn)?;
+            }
+            set_gpio_level_db(change.gpio_id, &change.level, conn)?;
+        }
+        let mut all_changes = changes.clone();
+        for (id, level_before) in dependents.iter() {
+            info!("GPIO #{} goes low with the GPIO it requires", id);
+            set_gpio_level_db(*id, "low", conn)?;
+            all_changes.push(LevelChange::new(*id, "low", level_before.as_deref()));
+        }
+
+        set_gpio_levels_rpi(&all_changes, gpio_arc_mutex.clone())?;
+        driven = all_changes;
+        Ok(changes)
+    });
+
+    if result.is_err() && !driven.is_empty() {
+        error!("Database commit failed, reverting {} GPIO(s)", driven.len());
+        revert_gpio_levels_rpi(&driven, gpio_arc_mutex);
+    }
+    result
+}
+
+/// Invert the level of GPIO #`id` and return the new level.
+///
+/// The level is read and inverted in the transaction that writes it, under
+/// `lock_gpio_levels`, so concurrent toggles are made one after the other. With
+/// `expected_version`, the toggle fails with `PreconditionFailed` unless the GPIO has that
+/// version.
+pub fn toggle_gpio_level(
+    id: i32, expected_version: Option<i32>, lease_id: Option<&str>, conn: &SqliteConnection,
+    gpio_arc_mutex: GpioArcMutex,
+) -> Result<String, RpWebError> {
+    use crate::schema::gpio_state::dsl::*;
+
+    let plan = || {
+        let level_before = gpio_state
+            .filter(gpio_id.eq(id))
+            .select(gpio_level)
+            .first::<Option<String>>(conn)?;
+        let level = toggled_level(level_before.as_deref())
+            .ok_or_else(|| RpWebError::new(&format!("GPIO #{} has no level to toggle", id)))?;
+
+        let change = LevelChange::new(id, level, level_before.as_deref())
+            .expecting_version(expected_version)
+            .with_lease(lease_id);
+        Ok(vec![change])
+    };
+    let changes = apply_planned_gpio_levels(plan, conn, gpio_arc_mutex)?;
+    Ok(changes[0].level.clone())
+}
+
+/// Set the PWM of GPIO #`id` to `frequency` Hz and `duty_cycle` percent, in the database and
+/// on the pin, like `apply_gpio_levels`: the lease and `expected_version` are checked first,
+/// and the pin gets its previous PWM back if the commit fails. It holds `lock_gpio_levels`
+/// and runs in an immediate transaction, so the version and the previous PWM can not change
+/// before the pin is set.
+pub fn apply_gpio_pwm(
+    id: i32, frequency: f64, duty_cycle: f64, expected_version: Option<i32>,
+    lease_id: Option<&str>, conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    let _levels = lock_gpio_levels();
+    let previous = get_gpio_pwm_rpi(id, gpio_arc_mutex.clone())?;
+    let mut driven = false;
+
+    let result = conn.immediate_transaction::<_, RpWebError, _>(|| {
+        check_gpio_lease_db(id, lease_id, conn)?;
+        if let Some(expected) = expected_version {
+            check_gpio_version_db(id, expected, conn)?;
+        }
+        let channel = hardware_pwm_channel(id);
+        set_gpio_pwm_db(id, Some(frequency), Some(duty_cycle), channel, conn)?;
+        set_gpio_pwm_rpi(id, frequency, duty_cycle, gpio_arc_mutex.clone())?;
+        driven = true;
+        Ok(())
+    });
+
+    if let (Err(_), true, Some((frequency, duty_cycle))) = (&result, driven, previous) {
+        error!("Database commit failed, reverting the PWM of GPIO #{}", id);
+        if let Err(err) = set_gpio_pwm_rpi(id, frequency, duty_cycle, gpio_arc_mutex) {
+            error!("Failed to revert the PWM of GPIO #{}: {}", id, err);
+        }
+    }
+    result
+}
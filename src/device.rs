@@ -0,0 +1,435 @@
+// Devices built from several GPIOs, like an RGB LED or an H-bridge, with operations of their
+// own. The GPIOs of a device are owned by it, so they cannot be set directly.
+
+use crate::control::{apply_gpio_levels, apply_gpio_pwm};
+use crate::errors::RpWebError;
+use crate::models::{DevicePin, DeviceStatus, Gpio, LevelChange};
+use crate::rpi::{GpioArcMutex, DEFAULT_PWM_FREQUENCY};
+use crate::settings::DeviceConfig;
+use diesel::prelude::*;
+use std::collections::BTreeMap;
+
+/// The kinds of devices, each with pins in fixed roles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DeviceKind {
+    /// PWM pins 'red', 'green' and 'blue'. Operations: 'color' with a value like 'ff8000',
+    /// and 'off'.
+    RgbLed,
+    /// Output pins 'in1' and 'in2' for the direction, and PWM pin 'enable' for the speed.
+    /// Operations: 'forward' and 'reverse' with a speed in percent (100 by default),
+    /// 'brake' and 'coast'.
+    HBridge,
+    /// Output pins 'red', 'yellow' and 'green'. Operations: 'stop', 'prepare' (red and
+    /// yellow), 'go', 'caution' (yellow) and 'off'.
+    TrafficLight,
+}
+
+/// One change to a pin of a device, by role
+#[derive(Debug, Clone, PartialEq)]
+pub enum PinSetting {
+    Level(&'static str, &'static str),
+    DutyCycle(&'static str, f64),
+}
+
+const RGB_LED_ROLES: [(&str, &str); 3] = [("red", "pwm"), ("green", "pwm"), ("blue", "pwm")];
+const H_BRIDGE_ROLES: [(&str, &str); 3] = [("in1", "output"), ("in2", "output"), ("enable", "pwm")];
+const TRAFFIC_LIGHT_ROLES: [(&str, &str); 3] =
+    [("red", "output"), ("yellow", "output"), ("green", "output")];
+
+/// The lights of a traffic light for each of its operations: red, yellow, green
+const TRAFFIC_LIGHT_ASPECTS: [(&str, [bool; 3]); 5] = [
+    ("stop", [true, false, false]),
+    ("prepare", [true, true, false]),
+    ("go", [false, false, true]),
+    ("caution", [false, true, false]),
+    ("off", [false, false, false]),
+];
+
+fn level(on: bool) -> &'static str {
+    if on {
+        "high"
+    } else {
+        "low"
+    }
+}
+
+impl DeviceKind {
+    pub fn parse(name: &str) -> Result<DeviceKind, RpWebError> {
+        match name.to_lowercase().as_str() {
+            "rgb_led" => Ok(DeviceKind::RgbLed),
+            "h_bridge" => Ok(DeviceKind::HBridge),
+            "traffic_light" => Ok(DeviceKind::TrafficLight),
+            _ => {
+                let errs = format!(
+                    "Invalid device kind: '{}', use 'rgb_led', 'h_bridge' or 'traffic_light'",
+                    name
+                );
+                Err(RpWebError::new(&errs))
+            }
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceKind::RgbLed => "rgb_led",
+            DeviceKind::HBridge => "h_bridge",
+            DeviceKind::TrafficLight => "traffic_light",
+        }
+    }
+
+    /// The roles of the pins, with the mode the GPIO in each role must be in
+    pub fn roles(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            DeviceKind::RgbLed => &RGB_LED_ROLES,
+            DeviceKind::HBridge => &H_BRIDGE_ROLES,
+            DeviceKind::TrafficLight => &TRAFFIC_LIGHT_ROLES,
+        }
+    }
+
+    /// The changes to the pins for `operation` with `value`, in the order they are made.
+    /// A motor gets no power while its direction changes.
+    pub fn plan(self, operation: &str, value: Option<&str>) -> Result<Vec<PinSetting>, RpWebError> {
+        let operation = operation.to_lowercase();
+        let settings = match (self, operation.as_str(), value) {
+            (DeviceKind::RgbLed, "color", Some(value)) => {
+                let [red, green, blue] = parse_color(value)?;
+                vec![
+                    PinSetting::DutyCycle("red", red),
+                    PinSetting::DutyCycle("green", green),
+                    PinSetting::DutyCycle("blue", blue),
+                ]
+            }
+            (DeviceKind::RgbLed, "off", None) => vec![
+                PinSetting::DutyCycle("red", 0.0),
+                PinSetting::DutyCycle("green", 0.0),
+                PinSetting::DutyCycle("blue", 0.0),
+            ],
+            (DeviceKind::HBridge, "forward", _) | (DeviceKind::HBridge, "reverse", _) => {
+                let speed = parse_speed(value)?;
+                let forward = operation == "forward";
+                vec![
+                    PinSetting::DutyCycle("enable", 0.0),
+                    PinSetting::Level("in1", level(forward)),
+                    PinSetting::Level("in2", level(!forward)),
+                    PinSetting::DutyCycle("enable", speed),
+                ]
+            }
+            (DeviceKind::HBridge, "brake", None) => vec![
+                PinSetting::Level("in1", "high"),
+                PinSetting::Level("in2", "high"),
+                PinSetting::DutyCycle("enable", 100.0),
+            ],
+            (DeviceKind::HBridge, "coast", None) => vec![
+                PinSetting::DutyCycle("enable", 0.0),
+                PinSetting::Level("in1", "low"),
+                PinSetting::Level("in2", "low"),
+            ],
+            (DeviceKind::TrafficLight, _, None) => {
+                let lights = TRAFFIC_LIGHT_ASPECTS
+                    .iter()
+                    .find(|(aspect, _)| *aspect == operation)
+                    .map(|(_, lights)| lights)
+                    .ok_or_else(|| self.unknown_operation(&operation, value))?;
+                vec![
+                    PinSetting::Level("red", level(lights[0])),
+                    PinSetting::Level("yellow", level(lights[1])),
+                    PinSetting::Level("green", level(lights[2])),
+                ]
+            }
+            _ => return Err(self.unknown_operation(&operation, value)),
+        };
+        Ok(settings)
+    }
+
+    fn unknown_operation(self, operation: &str, value: Option<&str>) -> RpWebError {
+        let operations = match self {
+            DeviceKind::RgbLed => "'color/{rrggbb}' or 'off'",
+            DeviceKind::HBridge => {
+                "'forward', 'reverse', 'forward/{speed}', 'reverse/{speed}', 'brake' or 'coast'"
+            }
+            DeviceKind::TrafficLight => "'stop', 'prepare', 'go', 'caution' or 'off'",
+        };
+        let operation = match value {
+            Some(value) => format!("{}/{}", operation, value),
+            None => operation.to_string(),
+        };
+        RpWebError::new(&format!(
+            "Invalid operation '{}' for a device of kind '{}', use {}",
+            operation,
+            self.name(),
+            operations
+        ))
+    }
+
+    /// What a device of this kind is doing with `pins` by role, and the speed of a motor
+    pub fn state(self, pins: &BTreeMap<String, Gpio>) -> (Option<String>, Option<f64>) {
+        let duty_cycle = |role: &str| pins.get(role).and_then(|gpio| gpio.pwm_duty_cycle);
+        let is_high = |role: &str| {
+            pins.get(role)
+                .and_then(|gpio| gpio.gpio_level.as_deref())
+                .map(|level| level == "high")
+        };
+
+        match self {
+            DeviceKind::RgbLed => {
+                let color: Option<Vec<String>> = ["red", "green", "blue"]
+                    .iter()
+                    .map(|role| {
+                        duty_cycle(role).map(|duty| format!("{:02x}", (duty * 2.55).round() as u8))
+                    })
+                    .collect();
+                (color.map(|color| color.concat()), None)
+            }
+            DeviceKind::HBridge => match (is_high("in1"), is_high("in2")) {
+                (Some(true), Some(false)) => (Some("forward".to_string()), duty_cycle("enable")),
+                (Some(false), Some(true)) => (Some("reverse".to_string()), duty_cycle("enable")),
+                (Some(true), Some(true)) => (Some("brake".to_string()), None),
+                (Some(false), Some(false)) => (Some("coast".to_string()), None),
+                _ => (None, None),
+            },
+            DeviceKind::TrafficLight => {
+                let lights = [is_high("red"), is_high("yellow"), is_high("green")];
+                let aspect = TRAFFIC_LIGHT_ASPECTS
+                    .iter()
+                    .find(|(_, aspect)| lights.iter().zip(aspect).all(|(on, lit)| *on == Some(*lit)))
+                    .map(|(aspect, _)| aspect.to_string());
+                (aspect, None)
+            }
+        }
+    }
+}
+
+/// The duty cycles in percent of a color like 'ff8000', red, green and blue
+fn parse_color(value: &str) -> Result<[f64; 3], RpWebError> {
+    let hex = value.trim_start_matches('#');
+    let invalid = || RpWebError::new(&format!("Invalid color: '{}', use 6 hex digits", value));
+    if hex.len() != 6 || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut duty_cycles = [0.0; 3];
+    for (index, duty_cycle) in duty_cycles.iter_mut().enumerate() {
+        let byte = u8::from_str_radix(&hex[2 * index..2 * index + 2], 16).map_err(|_| invalid())?;
+        *duty_cycle = f64::from(byte) * 100.0 / 255.0;
+    }
+    Ok(duty_cycles)
+}
+
+/// The speed of a motor in percent, full speed by default
+fn parse_speed(value: Option<&str>) -> Result<f64, RpWebError> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(100.0),
+    };
+    match value.parse::<f64>() {
+        Ok(speed) if (0.0..=100.0).contains(&speed) => Ok(speed),
+        _ => Err(RpWebError::new(&format!(
+            "Invalid speed: '{}', use 0 to 100 percent",
+            value
+        ))),
+    }
+}
+
+/// Store which GPIOs the devices own. Devices are set from the configuration file on every
+/// start.
+pub fn setup_devices_db(
+    devices: &[DeviceConfig], conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    use crate::schema::gpio_devices::dsl::*;
+
+    diesel::delete(gpio_devices).execute(conn)?;
+    for device in devices.iter() {
+        let device_kind = DeviceKind::parse(&device.kind)?;
+        let rows: Vec<DevicePin> = device
+            .pins
+            .iter()
+            .map(|(pin_role, pin_gpio)| DevicePin {
+                gpio_id: *pin_gpio,
+                device_name: device.name.clone(),
+                kind: device_kind.name().to_string(),
+                role: pin_role.to_lowercase(),
+            })
+            .collect();
+        diesel::insert_into(gpio_devices).values(&rows).execute(conn)?;
+    }
+
+    Ok(())
+}
+
+/// Name of the device owning GPIO #`id`, if any
+pub fn device_owner_db(id: i32, conn: &SqliteConnection) -> Result<Option<String>, RpWebError> {
+    use crate::schema::gpio_devices::dsl::*;
+
+    let owner = gpio_devices
+        .filter(gpio_id.eq(id))
+        .select(device_name)
+        .first::<String>(conn)
+        .optional()?;
+    Ok(owner)
+}
+
+/// Fail with `Locked` if GPIO #`id` is owned by a device, and can only be set through it
+pub fn check_gpio_owner_db(id: i32, conn: &SqliteConnection) -> Result<(), RpWebError> {
+    match device_owner_db(id, conn)? {
+        Some(owner) => {
+            let errs = format!(
+                "GPIO #{} is owned by device '{}', use /devices/{}",
+                id, owner, owner
+            );
+            info!("{}", errs);
+            Err(RpWebError::Locked(errs))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Names of all devices, in order
+pub fn device_names_db(conn: &SqliteConnection) -> Result<Vec<String>, RpWebError> {
+    use crate::schema::gpio_devices::dsl::*;
+
+    let names = gpio_devices
+        .select(device_name)
+        .distinct()
+        .order(device_name.asc())
+        .load::<String>(conn)?;
+    Ok(names)
+}
+
+/// The pins of device `name`, or `NotFound`
+pub fn load_device_pins_db(
+    name: &str, conn: &SqliteConnection,
+) -> Result<Vec<DevicePin>, RpWebError> {
+    use crate::schema::gpio_devices::dsl::*;
+
+    let pins = gpio_devices
+        .filter(device_name.eq(name))
+        .order(gpio_id.asc())
+        .load::<DevicePin>(conn)?;
+    if pins.is_empty() {
+        return Err(RpWebError::NotFound(format!("No device is named '{}'", name)));
+    }
+    Ok(pins)
+}
+
+/// Device `name` with the state of its GPIOs
+pub fn load_device_db(name: &str, conn: &SqliteConnection) -> Result<DeviceStatus, RpWebError> {
+    use crate::schema::gpio_state::dsl as gpios;
+
+    let device_pins = load_device_pins_db(name, conn)?;
+    let device_kind = DeviceKind::parse(&device_pins[0].kind)?;
+    let mut pins = BTreeMap::new();
+    for pin in device_pins.iter() {
+        let gpio = gpios::gpio_state.filter(gpios::gpio_id.eq(pin.gpio_id)).first::<Gpio>(conn)?;
+        pins.insert(pin.role.clone(), gpio);
+    }
+
+    let (state, speed) = device_kind.state(&pins);
+    Ok(DeviceStatus {
+        device_name: name.to_string(),
+        kind: device_kind.name().to_string(),
+        state,
+        speed,
+        pins,
+    })
+}
+
+/// Make the changes in `settings` to the pins of device `name`, in order. Levels next to each
+/// other are set together. Each change is checked against `lease_id` like a direct one.
+pub fn apply_device_settings(
+    name: &str, settings: &[PinSetting], lease_id: Option<&str>, conn: &SqliteConnection,
+    gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    use crate::schema::gpio_state::dsl as gpios;
+
+    let device_pins = load_device_pins_db(name, conn)?;
+    let gpio_of = |pin_role: &str| {
+        device_pins
+            .iter()
+            .find(|pin| pin.role == pin_role)
+            .map(|pin| pin.gpio_id)
+            .ok_or_else(|| RpWebError::new(&format!("Device '{}' has no pin '{}'", name, pin_role)))
+    };
+
+    let mut levels: Vec<LevelChange> = vec![];
+    for setting in settings.iter() {
+        match setting {
+            PinSetting::Level(pin_role, pin_level) => {
+                let id = gpio_of(pin_role)?;
+                let level_before = gpios::gpio_state
+                    .filter(gpios::gpio_id.eq(id))
+                    .select(gpios::gpio_level)
+                    .first::<Option<String>>(conn)?;
+                levels.push(
+                    LevelChange::new(id, pin_level, level_before.as_deref()).with_lease(lease_id),
+                );
+            }
+            PinSetting::DutyCycle(pin_role, duty_cycle) => {
+                if !levels.is_empty() {
+                    apply_gpio_levels(&levels, conn, gpio_arc_mutex.clone())?;
+                    levels.clear();
+                }
+
+                let id = gpio_of(pin_role)?;
+                let frequency = gpios::gpio_state
+                    .filter(gpios::gpio_id.eq(id))
+                    .select(gpios::pwm_frequency)
+                    .first::<Option<f64>>(conn)?
+                    .unwrap_or(DEFAULT_PWM_FREQUENCY);
+                let gpio_arc_mutex = gpio_arc_mutex.clone();
+                apply_gpio_pwm(id, frequency, *duty_cycle, None, lease_id, conn, gpio_arc_mutex)?;
+            }
+        }
+    }
+    if !levels.is_empty() {
+        apply_gpio_levels(&levels, conn, gpio_arc_mutex)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn device_color_must_map_to_duty_cycles() {
+        let settings = DeviceKind::RgbLed.plan("color", Some("ff0033")).unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                PinSetting::DutyCycle("red", 100.0),
+                PinSetting::DutyCycle("green", 0.0),
+                PinSetting::DutyCycle("blue", 20.0),
+            ]
+        );
+        assert!(DeviceKind::RgbLed.plan("color", Some("ff00")).is_err());
+        assert!(DeviceKind::RgbLed.plan("color", Some("gg0000")).is_err());
+        assert!(DeviceKind::RgbLed.plan("color", None).is_err());
+    }
+
+    #[test]
+    fn device_h_bridge_must_cut_power_while_turning() {
+        let settings = DeviceKind::HBridge.plan("reverse", Some("60")).unwrap();
+        assert_eq!(
+            settings,
+            vec![
+                PinSetting::DutyCycle("enable", 0.0),
+                PinSetting::Level("in1", "low"),
+                PinSetting::Level("in2", "high"),
+                PinSetting::DutyCycle("enable", 60.0),
+            ]
+        );
+        let full_speed = DeviceKind::HBridge.plan("forward", None).unwrap();
+        assert_eq!(full_speed[3], PinSetting::DutyCycle("enable", 100.0));
+        assert!(DeviceKind::HBridge.plan("forward", Some("101")).is_err());
+        assert!(DeviceKind::HBridge.plan("brake", Some("50")).is_err());
+    }
+
+    #[test]
+    fn device_unknown_kind_or_operation_must_fail() {
+        assert!(DeviceKind::parse("lava_lamp").is_err());
+        assert_eq!(DeviceKind::parse("Traffic_Light").unwrap(), DeviceKind::TrafficLight);
+        assert!(DeviceKind::TrafficLight.plan("prepare", None).is_ok());
+        assert!(DeviceKind::TrafficLight.plan("blink", None).is_err());
+        assert!(DeviceKind::TrafficLight.plan("go", Some("now")).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+// Drift between the GPIO levels in the database and the levels of the pins
+
+use crate::control::lock_gpio_levels;
+use crate::errors::RpWebError;
+use crate::handlers::{CheckDrift, DbExecutor};
+use crate::interlocks::check_interlocks_db;
+use crate::models::{Gpio, GpioDrift};
+use crate::rpi::{get_gpio_level_rpi, set_gpio_level_rpi, GpioArcMutex};
+use crate::settings::DriftConfig;
+use crate::utilities::set_gpio_level_db;
+use crate::validation::vec_option_to_vec;
+use actix::fut::wrap_future;
+use actix::{Actor, Addr, AsyncContext, Context};
+use chrono::Local;
+use diesel::prelude::*;
+use futures::Future;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// What to do when the level of a pin differs from the database
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DriftPolicy {
+    Report,
+    CorrectHardware,
+    CorrectDatabase,
+}
+
+/// Drift policy of each GPIO with one in `drift`. Other GPIOs are only reported.
+pub fn drift_policies(drift: &DriftConfig) -> HashMap<i32, DriftPolicy> {
+    let mut policies = HashMap::new();
+    for idx in vec_option_to_vec(&drift.correct_hardware) {
+        policies.insert(idx, DriftPolicy::CorrectHardware);
+    }
+    for idx in vec_option_to_vec(&drift.correct_database) {
+        policies.insert(idx, DriftPolicy::CorrectDatabase);
+    }
+    policies
+}
+
+/// Read back every output in use and compare its level with the database. Mismatches are
+/// logged, corrected according to `policies`, and replace the contents of `gpio_drift`. A
+/// correction that would break an interlock is not made, and recorded as 'refused'.
+///
+/// The check holds `lock_gpio_levels` and runs in an immediate transaction, so it reads the
+/// rows after any change of a level has driven its pins and committed, and no change is made
+/// while it compares and corrects.
+pub fn check_drift(
+    policies: &HashMap<i32, DriftPolicy>, conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<Vec<GpioDrift>, RpWebError> {
+    use crate::schema::gpio_state::dsl::*;
+
+    let _levels = lock_gpio_levels();
+    conn.immediate_transaction::<_, RpWebError, _>(|| {
+        let gpios = gpio_state
+            .filter(in_use.eq(1))
+            .filter(gpio_mode.eq("output"))
+            .order(gpio_id.asc())
+            .load::<Gpio>(conn)?;
+        let detected_at = Local::now().naive_local().to_string();
+        let mut drifts = vec![];
+
+        for gpio in gpios.iter() {
+            // Outputs that have not been set yet have no level to compare with
+            let db_level = match gpio.gpio_level.as_deref() {
+                Some(level @ "high") | Some(level @ "low") => level,
+                _ => continue,
+            };
+            let hw_level = get_gpio_level_rpi(gpio.gpio_id, gpio_arc_mutex.clone())?;
+            if hw_level == db_level {
+                continue;
+            }
+
+            warn!(
+                "GPIO #{} is '{}' in the database, but '{}' on the pin",
+                gpio.gpio_id, db_level, hw_level
+            );
+            let policy = policies
+                .get(&gpio.gpio_id)
+                .cloned()
+                .unwrap_or(DriftPolicy::Report);
+            let refused = match policy {
+                DriftPolicy::Report => false,
+                DriftPolicy::CorrectHardware => refused_by_interlock(gpio.gpio_id, db_level, conn)?,
+                DriftPolicy::CorrectDatabase => {
+                    refused_by_interlock(gpio.gpio_id, &hw_level, conn)?
+                }
+            };
+            let correction = match policy {
+                DriftPolicy::Report => None,
+                _ if refused => Some("refused".to_string()),
+                DriftPolicy::CorrectHardware => {
+                    set_gpio_level_rpi(gpio.gpio_id, db_level, gpio_arc_mutex.clone())?;
+                    info!("Set pin of GPIO #{} back to '{}'", gpio.gpio_id, db_level);
+                    Some("hardware".to_string())
+                }
+                DriftPolicy::CorrectDatabase => {
+                    set_gpio_level_db(gpio.gpio_id, &hw_level, conn)?;
+                    Some("database".to_string())
+                }
+            };
+
+            drifts.push(GpioDrift {
+                gpio_id: gpio.gpio_id,
+                db_level: db_level.to_string(),
+                hw_level,
+                detected_at: detected_at.clone(),
+                correction,
+            });
+        }
+
+        {
+            use crate::schema::gpio_drift::dsl::*;
+            diesel::delete(gpio_drift).execute(conn)?;
+            diesel::insert_into(gpio_drift)
+                .values(&drifts)
+                .execute(conn)?;
+        }
+        Ok(drifts)
+    })
+}
+
+/// Whether correcting GPIO #`id` to `level` would break an interlock, which is logged
+fn refused_by_interlock(id: i32, level: &str, conn: &SqliteConnection) -> Result<bool, RpWebError> {
+    match check_interlocks_db(&[(id, level)], conn) {
+        Ok(()) => Ok(false),
+        Err(RpWebError::Interlock(errs)) => {
+            error!("Drift of GPIO #{} is not corrected: {}", id, errs);
+            Ok(true)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Actor asking the DbExecutor to check for drift every `interval`
+pub struct DriftMonitor {
+    db: Addr<DbExecutor>,
+    gpio_arc_mutex: GpioArcMutex,
+    interval: Duration,
+    policies: HashMap<i32, DriftPolicy>,
+}
+
+impl DriftMonitor {
+    pub fn new(db: Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex, drift: &DriftConfig) -> Self {
+        DriftMonitor {
+            db,
+            gpio_arc_mutex,
+            interval: Duration::from_secs(drift.interval_seconds),
+            policies: drift_policies(drift),
+        }
+    }
+}
+
+impl Actor for DriftMonitor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        info!("Checking for drift every {} s", self.interval.as_secs());
+
+        ctx.run_interval(self.interval, |act, ctx| {
+            let check = act
+                .db
+                .send(CheckDrift {
+                    policies: act.policies.clone(),
+                    gpio_arc_mutex: act.gpio_arc_mutex.clone(),
+                })
+                .map(|res| {
+                    if let Err(err) = res {
+                        error!("Drift check failed: {}", err);
+                    }
+                })
+                .map_err(|err| error!("Drift check failed: {}", err));
+            ctx.spawn(wrap_future(check));
+        });
+    }
+}
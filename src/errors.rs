@@ -5,6 +5,8 @@ use actix_web::error::ResponseError;
 use diesel::result::Error as dieselError;
 #[cfg(target_arch = "arm")]
 use rppal::gpio::Error as rppalError;
+#[cfg(target_arch = "arm")]
+use rppal::pwm::Error as rppalPwmError;
 use std::env::VarError as stdVarError;
 use std::io::Error as stdIoError;
 use std::num::ParseIntError as numParseIntError;
@@ -21,6 +23,15 @@ pub enum RpWebError {
     DbError(dieselError),
     #[cfg(target_arch = "arm")]
     GpioError(rppalError),
+    #[cfg(target_arch = "arm")]
+    PwmError(rppalPwmError),
+    // The state changed since the client read it
+    PreconditionFailed(String),
+    // A GPIO is leased by another client, or owned by a device
+    Locked(String),
+    NotFound(String),
+    // A change would break an interlock between GPIOs
+    Interlock(String),
     Generic(String),
 }
 
@@ -41,6 +52,12 @@ impl fmt::Display for RpWebError {
             RpWebError::DbError(ref err) => err.fmt(formatter),
             #[cfg(target_arch = "arm")]
             RpWebError::GpioError(ref err) => err.fmt(formatter),
+            #[cfg(target_arch = "arm")]
+            RpWebError::PwmError(ref err) => err.fmt(formatter),
+            RpWebError::PreconditionFailed(ref errs) => write!(formatter, "{}", errs),
+            RpWebError::Locked(ref errs) => write!(formatter, "{}", errs),
+            RpWebError::NotFound(ref errs) => write!(formatter, "{}", errs),
+            RpWebError::Interlock(ref errs) => write!(formatter, "{}", errs),
             RpWebError::Generic(ref errs) => write!(formatter, "{}", errs),
         }
     }
@@ -56,6 +73,12 @@ impl error::Error for RpWebError {
             RpWebError::DbError(ref err) => err.description(),
             #[cfg(target_arch = "arm")]
             RpWebError::GpioError(ref err) => err.description(),
+            #[cfg(target_arch = "arm")]
+            RpWebError::PwmError(ref err) => err.description(),
+            RpWebError::PreconditionFailed(ref _errs) => "Precondition failed",
+            RpWebError::Locked(ref _errs) => "Locked",
+            RpWebError::NotFound(ref _errs) => "Not found",
+            RpWebError::Interlock(ref _errs) => "Interlock",
             RpWebError::Generic(ref _errs) => "Generic RpWebError",
         }
     }
@@ -71,6 +94,12 @@ impl error::Error for RpWebError {
             RpWebError::DbError(ref err) => Some(err),
             #[cfg(target_arch = "arm")]
             RpWebError::GpioError(ref err) => Some(err),
+            #[cfg(target_arch = "arm")]
+            RpWebError::PwmError(ref err) => Some(err),
+            RpWebError::PreconditionFailed(ref _errs) => None,
+            RpWebError::Locked(ref _errs) => None,
+            RpWebError::NotFound(ref _errs) => None,
+            RpWebError::Interlock(ref _errs) => None,
             RpWebError::Generic(ref _errs) => None,
         }
     }
@@ -110,6 +139,13 @@ impl From<rppalError> for RpWebError {
     }
 }
 
+#[cfg(target_arch = "arm")]
+impl From<rppalPwmError> for RpWebError {
+    fn from(err: rppalPwmError) -> RpWebError {
+        RpWebError::PwmError(err)
+    }
+}
+
 // Avoids the trait `actix_web::error::ResponseError` is not implemented for `RpWebError`
 // https://github.com/actix/actix-website/blob/master/content/docs/errors.md
 // Use default implementation for `error_response()` method
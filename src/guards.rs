@@ -0,0 +1,175 @@
+// Maximum on-time of outputs driving heaters, pumps and the like: an output that stays away
+// from its safe level for longer than its `max_on_duration` is forced back to it
+
+use crate::control::apply_gpio_levels;
+use crate::errors::RpWebError;
+use crate::handlers::{DbExecutor, EnforceMaxOn};
+use crate::leases::lease_holder_db;
+use crate::models::{Gpio, LevelChange};
+use crate::rpi::GpioArcMutex;
+use crate::settings::PinConfig;
+use crate::utilities::{current_time, parse_timestamp, record_audit_db, set_gpio_max_on_db};
+use actix::fut::wrap_future;
+use actix::{Actor, Addr, AsyncContext, Context};
+use chrono::{Duration as ChronoDuration, Local, NaiveDateTime};
+use diesel::prelude::*;
+use futures::Future;
+use std::time::Duration;
+
+/// How often outputs are checked against their maximum on-time
+const GUARD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Event in the audit history when an output is forced back to its safe level
+pub const MAX_ON_EVENT: &str = "max_on_duration";
+
+pub fn setup_gpio_guards_db(pins: &[PinConfig], conn: &SqliteConnection) -> Result<(), RpWebError> {
+    for pin in pins.iter() {
+        if let Some(max_on) = pin.max_on_duration {
+            let safe = pin.safe_level.as_deref().unwrap_or("low").to_lowercase();
+            set_gpio_max_on_db(pin.gpio_id, max_on as i32, &safe, conn)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// True if `gpio` has been away from its safe level for longer than its maximum on-time at `now`
+pub fn max_on_exceeded(gpio: &Gpio, now: NaiveDateTime) -> bool {
+    let (max_on, since) = match (gpio.max_on_duration, gpio.on_since.as_ref()) {
+        (Some(max_on), Some(since)) => (max_on, since),
+        _ => return false,
+    };
+
+    match parse_timestamp(since) {
+        Ok(since) => since + ChronoDuration::seconds(i64::from(max_on)) <= now,
+        Err(err) => {
+            error!("Invalid on_since '{}' for GPIO #{}: {}", since, gpio.gpio_id, err);
+            false
+        }
+    }
+}
+
+/// Set the outputs that exceeded their maximum on-time back to their safe level, and record
+/// it in the audit history. Leases do not protect against this. Returns the GPIOs forced back.
+pub fn enforce_max_on(
+    conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<Vec<Gpio>, RpWebError> {
+    use crate::schema::gpio_state::dsl::*;
+
+    let now = Local::now().naive_local();
+    let guarded = gpio_state
+        .filter(max_on_duration.is_not_null())
+        .filter(on_since.is_not_null())
+        .load::<Gpio>(conn)?;
+
+    let mut forced = vec![];
+    for gpio in guarded.iter().filter(|gpio| max_on_exceeded(gpio, now)) {
+        let level = gpio.safe_level.as_deref().unwrap_or("low");
+        let holder = lease_holder_db(gpio.gpio_id, conn)?;
+        let change = LevelChange::new(gpio.gpio_id, level, gpio.gpio_level.as_deref())
+            .with_lease(holder.as_deref());
+
+        if let Err(err) = apply_gpio_levels(&[change], conn, gpio_arc_mutex.clone()) {
+            error!("Failed to force GPIO #{} back to '{}': {}", gpio.gpio_id, level, err);
+            continue;
+        }
+
+        let message = format!(
+            "Forced back to '{}' after {} s at '{}'",
+            level,
+            gpio.max_on_duration.unwrap_or_default(),
+            gpio.gpio_level.as_deref().unwrap_or_default()
+        );
+        warn!("GPIO #{}: {}", gpio.gpio_id, message);
+        diesel::update(gpio_state.filter(gpio_id.eq(gpio.gpio_id)))
+            .set(forced_off_at.eq(current_time()))
+            .execute(conn)?;
+        record_audit_db(gpio.gpio_id, MAX_ON_EVENT, Some(level), Some(&message), conn)?;
+
+        forced.push(gpio.gpio_id);
+    }
+
+    let forced = gpio_state
+        .filter(gpio_id.eq_any(forced))
+        .order(gpio_id.asc())
+        .load::<Gpio>(conn)?;
+    Ok(forced)
+}
+
+/// Actor asking the DbExecutor to enforce maximum on-times every second
+pub struct GuardMonitor {
+    db: Addr<DbExecutor>,
+    gpio_arc_mutex: GpioArcMutex,
+}
+
+impl GuardMonitor {
+    pub fn new(db: Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex) -> Self {
+        GuardMonitor { db, gpio_arc_mutex }
+    }
+}
+
+impl Actor for GuardMonitor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(GUARD_INTERVAL, |act, ctx| {
+            let enforce = act
+                .db
+                .send(EnforceMaxOn {
+                    gpio_arc_mutex: act.gpio_arc_mutex.clone(),
+                })
+                .map(|res| {
+                    if let Err(err) = res {
+                        error!("Enforcing maximum on-time failed: {}", err);
+                    }
+                })
+                .map_err(|err| error!("Enforcing maximum on-time failed: {}", err));
+            ctx.spawn(wrap_future(enforce));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guarded_gpio(on_since: Option<&str>) -> Gpio {
+        Gpio {
+            gpio_id: 5,
+            in_use: 1,
+            gpio_mode: Some("output".to_string()),
+            gpio_level: Some("high".to_string()),
+            last_change: None,
+            name: None,
+            description: None,
+            tags: None,
+            version: 0,
+            max_on_duration: Some(60),
+            safe_level: Some("low".to_string()),
+            on_since: on_since.map(str::to_string),
+            forced_off_at: None,
+            pwm_frequency: None,
+            pwm_duty_cycle: None,
+            pwm_channel: None,
+        }
+    }
+
+    fn time(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    #[test]
+    fn max_on_exceeded_must_follow_duration() {
+        let gpio = guarded_gpio(Some("2019-05-25 12:00:00.000"));
+
+        assert!(!max_on_exceeded(&gpio, time("2019-05-25 12:00:59")));
+        assert!(max_on_exceeded(&gpio, time("2019-05-25 12:01:00")));
+    }
+
+    #[test]
+    fn max_on_exceeded_at_safe_level_must_be_false() {
+        let gpio = guarded_gpio(None);
+
+        assert!(!max_on_exceeded(&gpio, time("2030-01-01 00:00:00")));
+    }
+}
@@ -1,13 +1,42 @@
+use crate::control::{apply_gpio_levels, apply_gpio_pwm, lock_gpio_levels, toggle_gpio_level};
+use crate::device::{
+    apply_device_settings, check_gpio_owner_db, device_names_db, load_device_db, DeviceKind,
+};
+use crate::drift::{check_drift, DriftPolicy};
+use crate::errors::RpWebError;
+use crate::guards::enforce_max_on;
+use crate::interlocks::{check_interlocks_db, load_interlocks_db};
+use crate::leases::{acquire_lease, expire_leases, load_lease, release_lease, renew_lease};
 use crate::models;
-use crate::utilities::get_allowed_states;
+use crate::rpi::{check_pwm, GpioArcMutex, DEFAULT_PWM_FREQUENCY};
+use crate::rules::{
+    create_rule_db, delete_rule_db, enable_rule_db, load_rule_db, new_rule, record_rule_firing_db,
+};
+use crate::scheduler::{
+    claim_due_schedules, create_schedule_db, delete_schedule_db, load_schedule_db, new_schedule,
+    update_schedule_db,
+};
+use crate::servo::{load_servo_db, servo_status};
+use crate::settings::LocationConfig;
+use crate::stepper::save_stepper_position_db;
+use crate::setup::reconcile_rpi_and_db;
+use crate::utilities::{
+    get_allowed_states, get_gpio_group_members, get_gpio_id_by_name, toggled_level,
+};
+use crate::vacation::{load_vacation_db, save_vacation_db};
+use crate::validation::validate_setup;
 use actix::{Actor, Handler, Message, SyncContext};
 use actix_web::{error, Error as actixError};
 use chrono::Local;
 use diesel::prelude::*;
 use diesel::r2d2::{ConnectionManager, Pool};
+use std::collections::HashMap;
 
 //use utilities::get_allowed_states;
 
+/// Number of entries of the audit history returned, newest first
+const AUDIT_LIMIT: i64 = 100;
+
 /// This is db executor actor. We are going to run 3 of them in parallel.
 pub struct DbExecutor(pub Pool<ConnectionManager<SqliteConnection>>);
 
@@ -23,6 +52,20 @@ impl Message for GpioId {
     type Result = Result<models::Gpio, actixError>;
 }
 
+pub struct ResolveGpioName {
+    pub name: String,
+}
+
+impl Message for ResolveGpioName {
+    type Result = Result<i32, actixError>;
+}
+
+pub struct AllGpios;
+
+impl Message for AllGpios {
+    type Result = Result<Vec<models::Gpio>, actixError>;
+}
+
 pub struct CheckGpioLevel {
     pub gpio_id: i32,
     pub gpio_level: String,
@@ -35,16 +78,361 @@ impl Message for CheckGpioLevel {
 pub struct SetGpioLevel {
     pub gpio_id: i32,
     pub gpio_level: String,
+    pub expected_version: Option<i32>, // From If-Match
+    pub lease_id: Option<String>,
+    pub gpio_arc_mutex: GpioArcMutex,
 }
 
 impl Message for SetGpioLevel {
     type Result = Result<models::Gpio, actixError>;
 }
 
+pub struct ToggleGpioLevel {
+    pub gpio_id: i32,
+    pub expected_version: Option<i32>, // From If-Match
+    pub lease_id: Option<String>,
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for ToggleGpioLevel {
+    type Result = Result<models::Gpio, actixError>;
+}
+
+/// Change the frequency and/or duty cycle of a GPIO in mode 'pwm'; a value left out keeps
+/// its current setting
+pub struct SetGpioPwm {
+    pub gpio_id: i32,
+    pub frequency: Option<f64>,
+    pub duty_cycle: Option<f64>,
+    pub expected_version: Option<i32>, // From If-Match
+    pub lease_id: Option<String>,
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for SetGpioPwm {
+    type Result = Result<models::Gpio, actixError>;
+}
+
+/// Save where a stepper is, from the thread moving it
+pub struct SaveStepperPosition {
+    pub name: String,
+    pub position: i32,
+    pub homed: bool,
+}
+
+impl Message for SaveStepperPosition {
+    type Result = Result<(), actixError>;
+}
+
+/// A device built from several GPIOs, with the state of its GPIOs
+pub struct DeviceName {
+    pub device_name: String,
+}
+
+impl Message for DeviceName {
+    type Result = Result<models::DeviceStatus, actixError>;
+}
+
+pub struct AllDevices;
+
+impl Message for AllDevices {
+    type Result = Result<Vec<models::DeviceStatus>, actixError>;
+}
+
+/// Run `operation` of a device, like 'color' with value 'ff8000' on an RGB LED
+pub struct OperateDevice {
+    pub device_name: String,
+    pub operation: String,
+    pub value: Option<String>,
+    pub lease_id: Option<String>,
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for OperateDevice {
+    type Result = Result<models::DeviceStatus, actixError>;
+}
+
+/// The servo on a GPIO and its angle
+pub struct ServoId {
+    pub gpio_id: i32,
+}
+
+impl Message for ServoId {
+    type Result = Result<models::ServoStatus, actixError>;
+}
+
+pub struct GroupName {
+    pub group_name: String,
+}
+
+impl Message for GroupName {
+    type Result = Result<models::GpioGroupState, actixError>;
+}
+
+pub struct SetGroupLevel {
+    pub group_name: String,
+    pub gpio_level: String,
+    pub lease_id: Option<String>,
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for SetGroupLevel {
+    type Result = Result<models::GpioGroupState, actixError>;
+}
+
+pub struct ApplyBatch {
+    pub operations: Vec<models::BatchOperation>,
+    pub lease_id: Option<String>,
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for ApplyBatch {
+    type Result = Result<models::BatchResult, actixError>;
+}
+
+pub struct ReconcileState {
+    pub desired: models::DesiredState,
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for ReconcileState {
+    type Result = Result<Vec<models::StateChange>, actixError>;
+}
+
+pub struct CheckDrift {
+    pub policies: HashMap<i32, DriftPolicy>,
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for CheckDrift {
+    type Result = Result<Vec<models::GpioDrift>, actixError>;
+}
+
+pub struct AllDrift;
+
+impl Message for AllDrift {
+    type Result = Result<Vec<models::GpioDrift>, actixError>;
+}
+
+pub struct AcquireLease {
+    pub request: models::LeaseRequest,
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for AcquireLease {
+    type Result = Result<models::Lease, actixError>;
+}
+
+pub struct LeaseId {
+    pub lease_id: String,
+}
+
+impl Message for LeaseId {
+    type Result = Result<models::Lease, actixError>;
+}
+
+pub struct RenewLease {
+    pub lease_id: String,
+    pub duration_seconds: u32,
+}
+
+impl Message for RenewLease {
+    type Result = Result<models::Lease, actixError>;
+}
+
+pub struct ReleaseLease {
+    pub lease_id: String,
+}
+
+impl Message for ReleaseLease {
+    type Result = Result<models::Lease, actixError>;
+}
+
+pub struct ExpireLeases {
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for ExpireLeases {
+    type Result = Result<Vec<models::Lease>, actixError>;
+}
+
+pub struct EnforceMaxOn {
+    pub gpio_arc_mutex: GpioArcMutex,
+}
+
+impl Message for EnforceMaxOn {
+    type Result = Result<Vec<models::Gpio>, actixError>;
+}
+
+pub struct AllInterlocks;
+
+impl Message for AllInterlocks {
+    type Result = Result<Vec<models::Interlock>, actixError>;
+}
+
+/// Check that setting the GPIOs of `levels`, all at once, keeps the interlocks
+pub struct CheckInterlocks {
+    pub levels: Vec<(i32, String)>,
+}
+
+impl Message for CheckInterlocks {
+    type Result = Result<(), actixError>;
+}
+
+pub struct AllAudit;
+
+impl Message for AllAudit {
+    type Result = Result<Vec<models::AuditEntry>, actixError>;
+}
+
+pub struct AllSchedules;
+
+impl Message for AllSchedules {
+    type Result = Result<Vec<models::Schedule>, actixError>;
+}
+
+pub struct ScheduleId {
+    pub schedule_id: i32,
+}
+
+impl Message for ScheduleId {
+    type Result = Result<models::Schedule, actixError>;
+}
+
+pub struct CreateSchedule {
+    pub request: models::ScheduleRequest,
+    pub location: Option<LocationConfig>,
+}
+
+impl Message for CreateSchedule {
+    type Result = Result<models::Schedule, actixError>;
+}
+
+pub struct UpdateSchedule {
+    pub schedule_id: i32,
+    pub request: models::ScheduleRequest,
+    pub location: Option<LocationConfig>,
+}
+
+impl Message for UpdateSchedule {
+    type Result = Result<models::Schedule, actixError>;
+}
+
+pub struct DeleteSchedule {
+    pub schedule_id: i32,
+}
+
+impl Message for DeleteSchedule {
+    type Result = Result<models::Schedule, actixError>;
+}
+
+pub struct LoadVacation;
+
+impl Message for LoadVacation {
+    type Result = Result<models::VacationMode, actixError>;
+}
+
+pub struct SaveVacation {
+    pub enabled: bool,
+    pub seed: Option<u32>,
+}
+
+impl Message for SaveVacation {
+    type Result = Result<models::VacationMode, actixError>;
+}
+
+pub struct AllRules;
+
+impl Message for AllRules {
+    type Result = Result<Vec<models::Rule>, actixError>;
+}
+
+pub struct RuleId {
+    pub rule_id: i32,
+}
+
+impl Message for RuleId {
+    type Result = Result<models::Rule, actixError>;
+}
+
+pub struct CreateRule {
+    pub request: models::RuleRequest,
+}
+
+impl Message for CreateRule {
+    type Result = Result<models::Rule, actixError>;
+}
+
+pub struct DeleteRule {
+    pub rule_id: i32,
+}
+
+impl Message for DeleteRule {
+    type Result = Result<models::Rule, actixError>;
+}
+
+pub struct EnableRule {
+    pub rule_id: i32,
+    pub enabled: bool,
+}
+
+impl Message for EnableRule {
+    type Result = Result<models::Rule, actixError>;
+}
+
+pub struct EnabledRules;
+
+impl Message for EnabledRules {
+    type Result = Result<Vec<models::Rule>, actixError>;
+}
+
+pub struct RecordRuleFiring {
+    pub rule: models::Rule,
+    pub gpio_level: Option<String>,
+}
+
+impl Message for RecordRuleFiring {
+    type Result = Result<(), actixError>;
+}
+
+pub struct DueSchedules {
+    pub location: Option<LocationConfig>,
+}
+
+impl Message for DueSchedules {
+    type Result = Result<Vec<models::Schedule>, actixError>;
+}
+
 impl Handler<GpioId> for DbExecutor {
     type Result = Result<models::Gpio, actixError>;
 
     fn handle(&mut self, msg: GpioId, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        load_gpio(msg.gpio_id, connection)
+    }
+}
+
+impl Handler<ResolveGpioName> for DbExecutor {
+    type Result = Result<i32, actixError>;
+
+    fn handle(&mut self, msg: ResolveGpioName, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        resolve_gpio_identifier(&models::GpioIdentifier::Name(msg.name), connection)
+    }
+}
+
+impl Handler<AllGpios> for DbExecutor {
+    type Result = Result<Vec<models::Gpio>, actixError>;
+
+    fn handle(&mut self, _: AllGpios, _: &mut Self::Context) -> Self::Result {
         use crate::schema::gpio_state::dsl::*;
 
         let connection = &self
@@ -52,18 +440,10 @@ impl Handler<GpioId> for DbExecutor {
             .get()
             .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
 
-        let mut gpio_vec = gpio_state
-            .filter(gpio_id.eq(msg.gpio_id))
+        gpio_state
+            .order(gpio_id.asc())
             .load::<models::Gpio>(connection)
-            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?;
-
-        gpio_vec.pop().ok_or({
-            // GPIO not set up in database
-            error::ErrorNotFound(format!(
-                "raspberry-web has not been configured to work with GPIO #{}",
-                msg.gpio_id
-            ))
-        })
+            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))
     }
 }
 
@@ -71,102 +451,1022 @@ impl Handler<CheckGpioLevel> for DbExecutor {
     type Result = Result<models::Gpio, actixError>;
 
     fn handle(&mut self, msg: CheckGpioLevel, _: &mut Self::Context) -> Self::Result {
-        let required_gpio_mode = "output";
-        use crate::schema::gpio_state::dsl::*;
         let connection = &self
             .0
             .get()
             .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
 
-        // 1. Load Vec<Gpio> from database
-        let gpio_before = gpio_state
-            .filter(gpio_id.eq(msg.gpio_id))
-            .load::<models::Gpio>(connection)
-            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?
-            .pop()
-            .ok_or({
-                error::ErrorNotFound(format!(
-                    "raspberry-web has not been configured to work with GPIO #{}",
-                    msg.gpio_id
-                ))
-            })?;
+        let gpio = check_gpio_level(msg.gpio_id, &msg.gpio_level, connection)?;
+        let level = msg.gpio_level.to_lowercase();
+        check_interlocks_db(&[(msg.gpio_id, level.as_str())], connection).map_err(client_error)?;
+        Ok(gpio)
+    }
+}
+
+impl Handler<SetGpioLevel> for DbExecutor {
+    type Result = Result<models::Gpio, actixError>;
+
+    fn handle(&mut self, msg: SetGpioLevel, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        // 1. Check that the level can be set, and that the client saw the current state
+        let gpio_before = check_gpio_level(msg.gpio_id, &msg.gpio_level, connection)?;
+        check_gpio_version(&gpio_before, msg.expected_version)?;
+
+        // 2. Write the row, drive the pin and commit. The pin is set back to its previous
+        // level if the commit fails.
+        let change = models::LevelChange::new(
+            msg.gpio_id,
+            &msg.gpio_level,
+            gpio_before.gpio_level.as_deref(),
+        )
+        .expecting_version(msg.expected_version)
+        .with_lease(msg.lease_id.as_deref());
+        apply_gpio_levels(&[change], connection, msg.gpio_arc_mutex.clone()).map_err(|err| {
+            error!("Failed to set level of GPIO #{}: {}", msg.gpio_id, err);
+            client_error(err)
+        })?;
+
+        // 3. Return Gpio state after update
+        load_gpio(msg.gpio_id, connection)
+    }
+}
+
+impl Handler<ToggleGpioLevel> for DbExecutor {
+    type Result = Result<models::Gpio, actixError>;
+
+    fn handle(&mut self, msg: ToggleGpioLevel, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        // 1. Check that the GPIO is an output with a level to invert, and that the inverted
+        // level is allowed. The lock keeps other threads from changing the level until it
+        // is toggled.
+        let _levels = lock_gpio_levels();
+        check_gpio_toggle(msg.gpio_id, msg.expected_version, connection)?;
 
-        // 2. Check if the GPIO is in use
-        let bool_in_use = gpio_before.in_use == 1;
-        if !bool_in_use {
+        // 2. Read, invert and write the level as one change
+        toggle_gpio_level(
+            msg.gpio_id,
+            msg.expected_version,
+            msg.lease_id.as_deref(),
+            connection,
+            msg.gpio_arc_mutex.clone(),
+        )
+        .map_err(|err| {
+            error!("Failed to toggle GPIO #{}: {}", msg.gpio_id, err);
+            client_error(err)
+        })?;
+
+        // 3. Return Gpio state after update
+        load_gpio(msg.gpio_id, connection)
+    }
+}
+
+impl Handler<SetGpioPwm> for DbExecutor {
+    type Result = Result<models::Gpio, actixError>;
+
+    fn handle(&mut self, msg: SetGpioPwm, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        // 1. Check that the GPIO is in use and in mode 'pwm'
+        let gpio_before = load_gpio(msg.gpio_id, connection)?;
+        if gpio_before.in_use != 1 {
             info!("GPIO #{} is not in use.", msg.gpio_id);
             return Err(error::ErrorForbidden(format!(
                 "GPIO #{} is not in use.",
                 msg.gpio_id
             )));
         }
-
-        // 3. check if gpio_mode = 'output'
-        let none_replacement = "".to_string();
-        // https://stackoverflow.com/questions/22282117/how-do-i-borrow-a-reference-to-what-is-inside-an-optiont
-        let gpio_mode_before = gpio_before.gpio_mode.as_ref().unwrap_or(&none_replacement);
-        if gpio_mode_before != required_gpio_mode {
-            let message = format!(
-                "Level '{}' is not allowed for mode '{}'",
-                msg.gpio_level, gpio_mode_before
-            );
+        if gpio_before.gpio_mode.as_deref() != Some("pwm") {
+            let message = format!("GPIO #{} is not in mode 'pwm'", msg.gpio_id);
             info!("{}", message);
             return Err(error::ErrorForbidden(message));
         }
+        check_gpio_owner_db(msg.gpio_id, connection).map_err(client_error)?;
+        check_gpio_version(&gpio_before, msg.expected_version)?;
 
-        // 4. Check if desired level 'msg.gpio_level' is allowed
-        let desired_level = msg.gpio_level.to_lowercase();
-        let state_map = get_allowed_states(connection, "level")
-            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?;
-
-        let allowed = state_map
-            .get::<str>(&desired_level)
-            .ok_or_else(|| error::ErrorNotFound(format!(
-                "Level '{}' is not a recognized GPIO state'",
-                desired_level
-            )))?;
-
-        if !allowed {
-            info!(
-                "Level '{}' is not an allowed state for GPIO #{}",
-                desired_level, msg.gpio_id
-            );
-            Err(error::ErrorForbidden("State not allowed"))?
-        }
+        // 2. Keep the current setting for the value that is not changed
+        let frequency = msg
+            .frequency
+            .or(gpio_before.pwm_frequency)
+            .unwrap_or(DEFAULT_PWM_FREQUENCY);
+        let duty_cycle = msg.duty_cycle.or(gpio_before.pwm_duty_cycle).unwrap_or(0.0);
+        check_pwm(msg.gpio_id, frequency, duty_cycle)
+            .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
 
-        Ok(gpio_before)
+        // 3. Drive the pin and update the database
+        apply_gpio_pwm(
+            msg.gpio_id,
+            frequency,
+            duty_cycle,
+            msg.expected_version,
+            msg.lease_id.as_deref(),
+            connection,
+            msg.gpio_arc_mutex.clone(),
+        )
+        .map_err(|err| {
+            error!("Failed to set the PWM of GPIO #{}: {}", msg.gpio_id, err);
+            client_error(err)
+        })?;
+
+        // 4. Return Gpio state after update
+        load_gpio(msg.gpio_id, connection)
     }
 }
 
-impl Handler<SetGpioLevel> for DbExecutor {
-    type Result = Result<models::Gpio, actixError>;
+impl Handler<ServoId> for DbExecutor {
+    type Result = Result<models::ServoStatus, actixError>;
 
-    fn handle(&mut self, msg: SetGpioLevel, _: &mut Self::Context) -> Self::Result {
-        use crate::schema::gpio_state::dsl::*;
+    fn handle(&mut self, msg: ServoId, _: &mut Self::Context) -> Self::Result {
         let connection = &self
             .0
             .get()
             .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
 
-        // 5. Change the level
-        let target = gpio_state.filter(gpio_id.eq(msg.gpio_id));
+        let gpio = load_gpio(msg.gpio_id, connection)?;
+        let servo = load_servo_db(msg.gpio_id, connection)
+            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?
+            .ok_or_else(|| {
+                error::ErrorNotFound(format!("GPIO #{} is not a servo", msg.gpio_id))
+            })?;
 
-        let _result = diesel::update(target)
-            .set((
-                last_change.eq(Local::now().naive_local().to_string()),
-                gpio_level.eq(msg.gpio_level.to_lowercase()),
-            ))
-            .execute(connection);
+        Ok(servo_status(servo, &gpio))
+    }
+}
 
-        // 6. Return Gpio state after update
-        let mut gpio_vec_after = gpio_state
-            .filter(gpio_id.eq(msg.gpio_id))
-            .load::<models::Gpio>(connection)
-            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?;
+impl Handler<SaveStepperPosition> for DbExecutor {
+    type Result = Result<(), actixError>;
 
-        gpio_vec_after.pop().ok_or_else(||
-            // At this point we msg.gpio.id is in db
-            error::ErrorInternalServerError("Could not connect to database"),
-        )
+    fn handle(&mut self, msg: SaveStepperPosition, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        save_stepper_position_db(&msg.name, msg.position, msg.homed, connection)
+            .map_err(|err| error::ErrorInternalServerError(err.to_string()))
+    }
+}
+
+impl Handler<DeviceName> for DbExecutor {
+    type Result = Result<models::DeviceStatus, actixError>;
+
+    fn handle(&mut self, msg: DeviceName, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        load_device_db(&msg.device_name, connection).map_err(client_error)
     }
 }
+
+impl Handler<AllDevices> for DbExecutor {
+    type Result = Result<Vec<models::DeviceStatus>, actixError>;
+
+    fn handle(&mut self, _: AllDevices, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        device_names_db(connection)
+            .and_then(|names| {
+                names
+                    .iter()
+                    .map(|name| load_device_db(name, connection))
+                    .collect::<Result<Vec<_>, RpWebError>>()
+            })
+            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))
+    }
+}
+
+impl Handler<OperateDevice> for DbExecutor {
+    type Result = Result<models::DeviceStatus, actixError>;
+
+    fn handle(&mut self, msg: OperateDevice, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        // 1. Turn the operation into changes to the pins of the device
+        let device = load_device_db(&msg.device_name, connection).map_err(client_error)?;
+        let settings = DeviceKind::parse(&device.kind)
+            .and_then(|kind| kind.plan(&msg.operation, msg.value.as_deref()))
+            .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+
+        // 2. Make the changes, bypassing the ownership of the pins, but not their leases
+        apply_device_settings(
+            &msg.device_name,
+            &settings,
+            msg.lease_id.as_deref(),
+            connection,
+            msg.gpio_arc_mutex.clone(),
+        )
+        .map_err(|err| {
+            error!("Failed to operate device '{}': {}", msg.device_name, err);
+            client_error(err)
+        })?;
+
+        // 3. Return the device after the changes
+        load_device_db(&msg.device_name, connection).map_err(client_error)
+    }
+}
+
+impl Handler<GroupName> for DbExecutor {
+    type Result = Result<models::GpioGroupState, actixError>;
+
+    fn handle(&mut self, msg: GroupName, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        load_group_state(&msg.group_name, connection)
+    }
+}
+
+impl Handler<SetGroupLevel> for DbExecutor {
+    type Result = Result<models::GpioGroupState, actixError>;
+
+    fn handle(&mut self, msg: SetGroupLevel, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        // 1. Check all members before changing any of them
+        let members = group_members(&msg.group_name, connection)?;
+        let mut changes = vec![];
+        for id in members.iter() {
+            let gpio_before = check_gpio_level(*id, &msg.gpio_level, connection)?;
+            changes.push(
+                models::LevelChange::new(*id, &msg.gpio_level, gpio_before.gpio_level.as_deref())
+                    .with_lease(msg.lease_id.as_deref()),
+            );
+        }
+
+        // 2. Change the level on the RPi holding the GPIO lock once, and in the database
+        // in a single transaction
+        apply_gpio_levels(&changes, connection, msg.gpio_arc_mutex.clone()).map_err(|err| {
+            error!("Failed to set level of group '{}': {}", msg.group_name, err);
+            client_error(err)
+        })?;
+
+        load_group_state(&msg.group_name, connection)
+    }
+}
+
+impl Handler<ApplyBatch> for DbExecutor {
+    type Result = Result<models::BatchResult, actixError>;
+
+    fn handle(&mut self, msg: ApplyBatch, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        // 1. Check every operation before changing anything
+        let mut seen: Vec<i32> = vec![];
+        let lease_id = msg.lease_id.as_deref();
+        let checked: Vec<Result<models::LevelChange, actixError>> = msg
+            .operations
+            .iter()
+            .map(|operation| {
+                let id = resolve_gpio_identifier(&operation.id, connection)?;
+                if seen.contains(&id) {
+                    return Err(error::ErrorBadRequest(format!(
+                        "GPIO #{} appears more than once in the batch",
+                        id
+                    )));
+                }
+                seen.push(id);
+
+                let gpio_before = check_gpio_level(id, &operation.level, connection)?;
+                check_gpio_version(&gpio_before, operation.version)?;
+                Ok(models::LevelChange::new(
+                    id,
+                    &operation.level,
+                    gpio_before.gpio_level.as_deref(),
+                )
+                .expecting_version(operation.version)
+                .with_lease(lease_id))
+            })
+            .collect();
+
+        // 2. Apply all of them if all checks passed
+        let all_checked = checked.iter().all(Result::is_ok);
+        let apply_error = if all_checked {
+            let changes: Vec<models::LevelChange> =
+                checked.iter().filter_map(|res| res.as_ref().ok().cloned()).collect();
+            apply_gpio_levels(&changes, connection, msg.gpio_arc_mutex)
+                .err()
+                .map(|err| {
+                    error!("Failed to apply batch: {}", err);
+                    let message = err.to_string();
+                    let status = client_error(err).as_response_error().error_response().status();
+                    (status.as_u16(), message)
+                })
+        } else {
+            None
+        };
+        let applied = all_checked && apply_error.is_none();
+
+        // 3. Report on every operation
+        let results = msg
+            .operations
+            .into_iter()
+            .zip(checked)
+            .map(|(operation, check)| {
+                let (status, error, gpio) = match check {
+                    Err(err) => {
+                        let status = err.as_response_error().error_response().status();
+                        (status.as_u16(), Some(err.to_string()), None)
+                    }
+                    Ok(ref change) if applied => {
+                        (200, None, load_gpio(change.gpio_id, connection).ok())
+                    }
+                    Ok(_) => match apply_error {
+                        Some((status, ref err)) => (status, Some(err.clone()), None),
+                        None => (
+                            424,
+                            Some("Not applied because another operation failed".to_string()),
+                            None,
+                        ),
+                    },
+                };
+
+                models::BatchItemResult {
+                    id: operation.id,
+                    level: operation.level,
+                    status,
+                    error,
+                    gpio,
+                }
+            })
+            .collect();
+
+        Ok(models::BatchResult { applied, results })
+    }
+}
+
+impl Handler<ReconcileState> for DbExecutor {
+    type Result = Result<Vec<models::StateChange>, actixError>;
+
+    fn handle(&mut self, msg: ReconcileState, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        // 1. The document must be a valid configuration of GPIOs in the database
+        let desired = msg
+            .desired
+            .to_gpioconfig()
+            .and_then(|gpioconfig| validate_setup(&gpioconfig).map(|_| gpioconfig))
+            .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+        for gpio in msg.desired.gpios.iter() {
+            load_gpio(gpio.gpio_id, connection)?;
+        }
+
+        // 2. Apply what differs
+        reconcile_rpi_and_db(&desired, connection, msg.gpio_arc_mutex).map_err(|err| {
+            error!("Failed to reconcile desired state: {}", err);
+            client_error(err)
+        })
+    }
+}
+
+impl Handler<CheckDrift> for DbExecutor {
+    type Result = Result<Vec<models::GpioDrift>, actixError>;
+
+    fn handle(&mut self, msg: CheckDrift, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        check_drift(&msg.policies, connection, msg.gpio_arc_mutex)
+            .map_err(|err| error::ErrorInternalServerError(err.to_string()))
+    }
+}
+
+impl Handler<AllDrift> for DbExecutor {
+    type Result = Result<Vec<models::GpioDrift>, actixError>;
+
+    fn handle(&mut self, _: AllDrift, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::gpio_drift::dsl::*;
+
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        gpio_drift
+            .order(gpio_id.asc())
+            .load::<models::GpioDrift>(connection)
+            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))
+    }
+}
+
+impl Handler<AcquireLease> for DbExecutor {
+    type Result = Result<models::Lease, actixError>;
+
+    fn handle(&mut self, msg: AcquireLease, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+        let request = msg.request;
+
+        // 1. Find the GPIOs to lease
+        let gpios = match (&request.gpio, &request.group) {
+            (Some(identifier), None) => vec![resolve_gpio_identifier(identifier, connection)?],
+            (None, Some(group)) => group_members(&group.to_lowercase(), connection)?,
+            _ => {
+                return Err(error::ErrorBadRequest(
+                    "A lease is on either a 'gpio' or a 'group'",
+                ))
+            }
+        };
+        if request.duration_seconds == 0 {
+            return Err(error::ErrorBadRequest("duration_seconds must be at least 1"));
+        }
+
+        // 2. They must be outputs that can be set to the level on expiry
+        let on_expiry = request.on_expiry.as_deref();
+        for id in gpios.iter() {
+            check_gpio_level(*id, on_expiry.unwrap_or("low"), connection)?;
+        }
+
+        // 3. Run the actions of expired leases before their GPIOs can be leased again
+        if let Err(err) = expire_leases(connection, msg.gpio_arc_mutex) {
+            error!("Expiring leases failed: {}", err);
+        }
+
+        acquire_lease(&gpios, request.duration_seconds, on_expiry, connection)
+            .map_err(client_error)
+    }
+}
+
+impl Handler<LeaseId> for DbExecutor {
+    type Result = Result<models::Lease, actixError>;
+
+    fn handle(&mut self, msg: LeaseId, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        load_lease(&msg.lease_id, connection).map_err(client_error)
+    }
+}
+
+impl Handler<RenewLease> for DbExecutor {
+    type Result = Result<models::Lease, actixError>;
+
+    fn handle(&mut self, msg: RenewLease, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        if msg.duration_seconds == 0 {
+            return Err(error::ErrorBadRequest("duration_seconds must be at least 1"));
+        }
+        renew_lease(&msg.lease_id, msg.duration_seconds, connection).map_err(client_error)
+    }
+}
+
+impl Handler<ReleaseLease> for DbExecutor {
+    type Result = Result<models::Lease, actixError>;
+
+    fn handle(&mut self, msg: ReleaseLease, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        release_lease(&msg.lease_id, connection).map_err(client_error)
+    }
+}
+
+impl Handler<ExpireLeases> for DbExecutor {
+    type Result = Result<Vec<models::Lease>, actixError>;
+
+    fn handle(&mut self, msg: ExpireLeases, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        expire_leases(connection, msg.gpio_arc_mutex)
+            .map_err(|err| error::ErrorInternalServerError(err.to_string()))
+    }
+}
+
+impl Handler<EnforceMaxOn> for DbExecutor {
+    type Result = Result<Vec<models::Gpio>, actixError>;
+
+    fn handle(&mut self, msg: EnforceMaxOn, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        enforce_max_on(connection, msg.gpio_arc_mutex)
+            .map_err(|err| error::ErrorInternalServerError(err.to_string()))
+    }
+}
+
+impl Handler<AllInterlocks> for DbExecutor {
+    type Result = Result<Vec<models::Interlock>, actixError>;
+
+    fn handle(&mut self, _: AllInterlocks, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        load_interlocks_db(connection)
+            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))
+    }
+}
+
+impl Handler<CheckInterlocks> for DbExecutor {
+    type Result = Result<(), actixError>;
+
+    fn handle(&mut self, msg: CheckInterlocks, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        let levels: Vec<(i32, &str)> =
+            msg.levels.iter().map(|(id, level)| (*id, level.as_str())).collect();
+        check_interlocks_db(&levels, connection).map_err(client_error)
+    }
+}
+
+impl Handler<AllAudit> for DbExecutor {
+    type Result = Result<Vec<models::AuditEntry>, actixError>;
+
+    fn handle(&mut self, _: AllAudit, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::gpio_audit::dsl::*;
+
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        gpio_audit
+            .order(audit_id.desc())
+            .limit(AUDIT_LIMIT)
+            .load::<models::AuditEntry>(connection)
+            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))
+    }
+}
+
+impl Handler<AllSchedules> for DbExecutor {
+    type Result = Result<Vec<models::Schedule>, actixError>;
+
+    fn handle(&mut self, _: AllSchedules, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::schedules::dsl::*;
+
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        schedules
+            .order(schedule_id.asc())
+            .load::<models::Schedule>(connection)
+            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))
+    }
+}
+
+impl Handler<ScheduleId> for DbExecutor {
+    type Result = Result<models::Schedule, actixError>;
+
+    fn handle(&mut self, msg: ScheduleId, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        load_schedule_db(msg.schedule_id, connection).map_err(client_error)
+    }
+}
+
+impl Handler<CreateSchedule> for DbExecutor {
+    type Result = Result<models::Schedule, actixError>;
+
+    fn handle(&mut self, msg: CreateSchedule, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        let target = schedule_target(&msg.request, connection)?;
+        let now = Local::now().naive_local();
+        let new = new_schedule(&msg.request, target, msg.location.as_ref(), now)?;
+        create_schedule_db(&new, connection).map_err(client_error)
+    }
+}
+
+impl Handler<UpdateSchedule> for DbExecutor {
+    type Result = Result<models::Schedule, actixError>;
+
+    fn handle(&mut self, msg: UpdateSchedule, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        let target = schedule_target(&msg.request, connection)?;
+        let now = Local::now().naive_local();
+        let new = new_schedule(&msg.request, target, msg.location.as_ref(), now)?;
+        update_schedule_db(msg.schedule_id, &new, connection).map_err(client_error)
+    }
+}
+
+impl Handler<DeleteSchedule> for DbExecutor {
+    type Result = Result<models::Schedule, actixError>;
+
+    fn handle(&mut self, msg: DeleteSchedule, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        delete_schedule_db(msg.schedule_id, connection).map_err(client_error)
+    }
+}
+
+impl Handler<LoadVacation> for DbExecutor {
+    type Result = Result<models::VacationMode, actixError>;
+
+    fn handle(&mut self, _: LoadVacation, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        load_vacation_db(connection).map_err(client_error)
+    }
+}
+
+impl Handler<SaveVacation> for DbExecutor {
+    type Result = Result<models::VacationMode, actixError>;
+
+    fn handle(&mut self, msg: SaveVacation, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        save_vacation_db(msg.enabled, msg.seed, connection).map_err(client_error)
+    }
+}
+
+impl Handler<AllRules> for DbExecutor {
+    type Result = Result<Vec<models::Rule>, actixError>;
+
+    fn handle(&mut self, _: AllRules, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::rules::dsl::*;
+
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        rules
+            .order(rule_id.asc())
+            .load::<models::Rule>(connection)
+            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))
+    }
+}
+
+impl Handler<RuleId> for DbExecutor {
+    type Result = Result<models::Rule, actixError>;
+
+    fn handle(&mut self, msg: RuleId, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        load_rule_db(msg.rule_id, connection).map_err(client_error)
+    }
+}
+
+impl Handler<CreateRule> for DbExecutor {
+    type Result = Result<models::Rule, actixError>;
+
+    fn handle(&mut self, msg: CreateRule, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        let input = resolve_gpio_identifier(&msg.request.input, connection)?;
+        if load_gpio(input, connection)?.in_use != 1 {
+            return Err(error::ErrorForbidden(format!("GPIO #{} is not in use.", input)));
+        }
+        let output = resolve_gpio_identifier(&msg.request.output, connection)?;
+        check_gpio_level(output, msg.request.level.as_deref().unwrap_or("low"), connection)?;
+
+        let new = new_rule(&msg.request, input, output, false)?;
+        create_rule_db(&new, connection).map_err(client_error)
+    }
+}
+
+impl Handler<DeleteRule> for DbExecutor {
+    type Result = Result<models::Rule, actixError>;
+
+    fn handle(&mut self, msg: DeleteRule, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        delete_rule_db(msg.rule_id, connection).map_err(client_error)
+    }
+}
+
+impl Handler<EnableRule> for DbExecutor {
+    type Result = Result<models::Rule, actixError>;
+
+    fn handle(&mut self, msg: EnableRule, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        enable_rule_db(msg.rule_id, msg.enabled, connection).map_err(client_error)
+    }
+}
+
+impl Handler<EnabledRules> for DbExecutor {
+    type Result = Result<Vec<models::Rule>, actixError>;
+
+    fn handle(&mut self, _: EnabledRules, _: &mut Self::Context) -> Self::Result {
+        use crate::schema::rules::dsl::*;
+
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        rules
+            .filter(enabled.eq(1))
+            .order(rule_id.asc())
+            .load::<models::Rule>(connection)
+            .map_err(|_| error::ErrorInternalServerError("Error loading from database"))
+    }
+}
+
+impl Handler<RecordRuleFiring> for DbExecutor {
+    type Result = Result<(), actixError>;
+
+    fn handle(&mut self, msg: RecordRuleFiring, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        record_rule_firing_db(&msg.rule, msg.gpio_level.as_deref(), connection)
+            .map_err(|err| error::ErrorInternalServerError(err.to_string()))
+    }
+}
+
+impl Handler<DueSchedules> for DbExecutor {
+    type Result = Result<Vec<models::Schedule>, actixError>;
+
+    fn handle(&mut self, msg: DueSchedules, _: &mut Self::Context) -> Self::Result {
+        let connection = &self
+            .0
+            .get()
+            .map_err(|_| error::ErrorInternalServerError("Error obtaining database connection"))?;
+
+        claim_due_schedules(Local::now().naive_local(), msg.location.as_ref(), connection)
+            .map_err(|err| error::ErrorInternalServerError(err.to_string()))
+    }
+}
+
+/// PreconditionFailed if a version is expected and `gpio` does not have it
+fn check_gpio_version(gpio: &models::Gpio, expected: Option<i32>) -> Result<(), actixError> {
+    match expected {
+        Some(expected) if expected != gpio.version => {
+            info!("GPIO #{} has version {}, not {}", gpio.gpio_id, gpio.version, expected);
+            Err(error::ErrorPreconditionFailed(format!(
+                "GPIO #{} changed since it was read",
+                gpio.gpio_id
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The response for an error from `apply_gpio_levels`, the lease functions or an interlock
+fn client_error(err: RpWebError) -> actixError {
+    match err {
+        RpWebError::PreconditionFailed(errs) => error::ErrorPreconditionFailed(errs),
+        RpWebError::Locked(errs) => error::ErrorLocked(errs),
+        RpWebError::NotFound(errs) => error::ErrorNotFound(errs),
+        RpWebError::Interlock(errs) => error::ErrorConflict(errs),
+        _ => error::ErrorInternalServerError(err.to_string()),
+    }
+}
+
+/// Return the state of GPIO #`id`, or NotFound if it is not in the database
+fn load_gpio(id: i32, connection: &SqliteConnection) -> Result<models::Gpio, actixError> {
+    use crate::schema::gpio_state::dsl::*;
+
+    let mut gpio_vec = gpio_state
+        .filter(gpio_id.eq(id))
+        .load::<models::Gpio>(connection)
+        .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?;
+
+    gpio_vec.pop().ok_or({
+        // GPIO not set up in database
+        error::ErrorNotFound(format!(
+            "raspberry-web has not been configured to work with GPIO #{}",
+            id
+        ))
+    })
+}
+
+/// Return the id of a GPIO given by id or by name
+fn resolve_gpio_identifier(
+    identifier: &models::GpioIdentifier, connection: &SqliteConnection,
+) -> Result<i32, actixError> {
+    match identifier {
+        models::GpioIdentifier::Id(id) => Ok(*id),
+        models::GpioIdentifier::Name(name) => match name.parse::<i32>() {
+            Ok(id) => Ok(id),
+            Err(_) => get_gpio_id_by_name(name, connection)
+                .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?
+                .ok_or_else(|| error::ErrorNotFound(format!("No GPIO is named '{}'", name))),
+        },
+    }
+}
+
+/// Ids of the GPIOs in group `group`, or NotFound if there is no such group
+fn group_members(group: &str, connection: &SqliteConnection) -> Result<Vec<i32>, actixError> {
+    let members = get_gpio_group_members(group, connection)
+        .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?;
+
+    if members.is_empty() {
+        return Err(error::ErrorNotFound(format!("No group is named '{}'", group)));
+    }
+    Ok(members)
+}
+
+/// The GPIO a schedule is on, or None for a group, after checking that it exists and can be
+/// set to the level of the schedule
+fn schedule_target(
+    request: &models::ScheduleRequest, connection: &SqliteConnection,
+) -> Result<Option<i32>, actixError> {
+    match (&request.gpio, &request.group) {
+        (Some(identifier), None) => {
+            let id = resolve_gpio_identifier(identifier, connection)?;
+            check_gpio_level(id, request.level.as_deref().unwrap_or("low"), connection)?;
+            Ok(Some(id))
+        }
+        (None, Some(group)) => {
+            group_members(&group.to_lowercase(), connection)?;
+            Ok(None)
+        }
+        _ => Err(error::ErrorBadRequest(
+            "A schedule is on either a 'gpio' or a 'group'",
+        )),
+    }
+}
+
+fn load_group_state(
+    group: &str, connection: &SqliteConnection,
+) -> Result<models::GpioGroupState, actixError> {
+    use crate::schema::gpio_state::dsl::*;
+
+    let members = group_members(group, connection)?;
+    let gpios = gpio_state
+        .filter(gpio_id.eq_any(members))
+        .order(gpio_id.asc())
+        .load::<models::Gpio>(connection)
+        .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?;
+
+    Ok(models::GpioGroupState::new(group, gpios))
+}
+
+/// Check that GPIO #`id` is in use, in mode 'output' and that `level` is allowed.
+/// Returns the state of the GPIO before any change.
+pub fn check_gpio_level(
+    id: i32, level: &str, connection: &SqliteConnection,
+) -> Result<models::Gpio, actixError> {
+    // 1.-4. The GPIO must be an output that can be set
+    let gpio_before = check_gpio_output(id, &format!("Level '{}'", level), connection)?;
+
+    // 5. Check if desired level 'level' is allowed
+    check_level_allowed(id, level, connection)?;
+
+    Ok(gpio_before)
+}
+
+/// Check that GPIO #`id` is in use, in mode 'output', has a level to invert and that the
+/// inverted level is allowed, and with `expected_version` that the GPIO has that version.
+/// Hold `lock_gpio_levels` until the GPIO is toggled, so the level does not change in between.
+pub fn check_gpio_toggle(
+    id: i32, expected_version: Option<i32>, connection: &SqliteConnection,
+) -> Result<models::Gpio, actixError> {
+    let gpio_before = check_gpio_output(id, "Toggling", connection)?;
+    check_gpio_version(&gpio_before, expected_version)?;
+    let toggled = toggled_level(gpio_before.gpio_level.as_deref())
+        .ok_or_else(|| error::ErrorConflict(format!("GPIO #{} has no level to toggle", id)))?;
+    check_level_allowed(id, toggled, connection)?;
+
+    Ok(gpio_before)
+}
+
+/// Check that GPIO #`id` is in use, in mode 'output' and not owned by a device, so that
+/// `change` can be made to it. Returns the state of the GPIO before any change.
+fn check_gpio_output(
+    id: i32, change: &str, connection: &SqliteConnection,
+) -> Result<models::Gpio, actixError> {
+    let required_gpio_mode = "output";
+    use crate::schema::gpio_state::dsl::*;
+
+    // 1. Load Vec<Gpio> from database
+    let gpio_before = gpio_state
+        .filter(gpio_id.eq(id))
+        .load::<models::Gpio>(connection)
+        .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?
+        .pop()
+        .ok_or({
+            error::ErrorNotFound(format!(
+                "raspberry-web has not been configured to work with GPIO #{}",
+                id
+            ))
+        })?;
+
+    // 2. Check if the GPIO is in use
+    let bool_in_use = gpio_before.in_use == 1;
+    if !bool_in_use {
+        info!("GPIO #{} is not in use.", id);
+        return Err(error::ErrorForbidden(format!(
+            "GPIO #{} is not in use.",
+            id
+        )));
+    }
+
+    // 3. Check that the GPIO is not owned by a device, which sets it itself
+    check_gpio_owner_db(id, connection).map_err(client_error)?;
+
+    // 4. check if gpio_mode = 'output'
+    let none_replacement = "".to_string();
+    // https://stackoverflow.com/questions/22282117/how-do-i-borrow-a-reference-to-what-is-inside-an-optiont
+    let gpio_mode_before = gpio_before.gpio_mode.as_ref().unwrap_or(&none_replacement);
+    if gpio_mode_before != required_gpio_mode {
+        let message = format!("{} is not allowed for mode '{}'", change, gpio_mode_before);
+        info!("{}", message);
+        return Err(error::ErrorForbidden(message));
+    }
+
+    Ok(gpio_before)
+}
+
+/// Check that `level` is a recognized level that GPIO #`id` may be set to
+fn check_level_allowed(
+    id: i32, level: &str, connection: &SqliteConnection,
+) -> Result<(), actixError> {
+    let desired_level = level.to_lowercase();
+    let state_map = get_allowed_states(connection, "level")
+        .map_err(|_| error::ErrorInternalServerError("Error loading from database"))?;
+
+    let allowed = state_map
+        .get::<str>(&desired_level)
+        .ok_or_else(|| error::ErrorNotFound(format!(
+            "Level '{}' is not a recognized GPIO state'",
+            desired_level
+        )))?;
+
+    if !allowed {
+        info!(
+            "Level '{}' is not an allowed state for GPIO #{}",
+            desired_level, id
+        );
+        Err(error::ErrorForbidden("State not allowed"))?
+    }
+
+    Ok(())
+}
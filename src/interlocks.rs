@@ -0,0 +1,249 @@
+// Interlocks between outputs, like the forward and reverse relays of a motor, checked on
+// every change of a level
+
+use crate::errors::RpWebError;
+use crate::models::{Gpio, Interlock, NewInterlock};
+use crate::settings::InterlockConfig;
+use chrono::{Duration as ChronoDuration, Local, NaiveDateTime};
+use diesel::prelude::*;
+
+pub const EXCLUSIVE: &str = "exclusive";
+pub const REQUIRES: &str = "requires";
+pub const DEAD_TIME: &str = "dead_time";
+
+/// Format of `last_change` in `gpio_state`
+const LAST_CHANGE_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// The interlocks of `config`, one per pair of GPIOs
+pub fn new_interlocks(config: &InterlockConfig) -> Result<Vec<NewInterlock>, RpWebError> {
+    let pair = |gpio_id: i32, other_gpio: i32, dead_time_ms: Option<i32>| NewInterlock {
+        kind: config.kind.to_lowercase(),
+        gpio_id,
+        other_gpio,
+        dead_time_ms,
+    };
+
+    match (config.kind.to_lowercase().as_str(), config.gpios.as_slice()) {
+        (EXCLUSIVE, gpios) if gpios.len() >= 2 => {
+            let mut interlocks = vec![];
+            for (index, gpio) in gpios.iter().enumerate() {
+                for other in gpios[index + 1..].iter() {
+                    interlocks.push(pair(*gpio, *other, None));
+                }
+            }
+            Ok(interlocks)
+        }
+        // The first only high while the second is high
+        (REQUIRES, [gpio, required]) => Ok(vec![pair(*gpio, *required, None)]),
+        // The first low for the dead-time before the second goes high
+        (DEAD_TIME, [first, second]) => {
+            let dead_time_ms = config
+                .dead_time_ms
+                .ok_or_else(|| RpWebError::new("A 'dead_time' interlock needs dead_time_ms"))?;
+            Ok(vec![pair(*second, *first, Some(dead_time_ms as i32))])
+        }
+        (kind, gpios) => {
+            let errs = format!(
+                "Invalid interlock '{}' of GPIOs {:?}: use 'exclusive' with two or more GPIOs, or 'requires' or 'dead_time' with two",
+                kind, gpios
+            );
+            Err(RpWebError::new(&errs))
+        }
+    }
+}
+
+/// Replace the interlocks with those of `configs`
+pub fn setup_interlocks_db(
+    configs: &[InterlockConfig], conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    use crate::schema::gpio_interlocks::dsl::*;
+
+    diesel::delete(gpio_interlocks).execute(conn)?;
+    for config in configs.iter() {
+        diesel::insert_into(gpio_interlocks)
+            .values(&new_interlocks(config)?)
+            .execute(conn)?;
+    }
+
+    Ok(())
+}
+
+pub fn load_interlocks_db(conn: &SqliteConnection) -> Result<Vec<Interlock>, RpWebError> {
+    use crate::schema::gpio_interlocks::dsl::*;
+
+    let interlocks = gpio_interlocks
+        .order(interlock_id.asc())
+        .load::<Interlock>(conn)?;
+    Ok(interlocks)
+}
+
+/// Fail with `Interlock` if setting the GPIOs of `changes` to their levels, all at once,
+/// would break an interlock. GPIOs not in `changes` are taken at their level in the database.
+/// Changes that only drive GPIOs low are never refused, as that is how outputs are made safe;
+/// the GPIOs requiring them go low with them, see `dependents_going_low_db`.
+pub fn check_interlocks_db(
+    changes: &[(i32, &str)], conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    use crate::schema::gpio_interlocks::dsl::*;
+
+    if changes.iter().all(|(_, level)| level.eq_ignore_ascii_case("low")) {
+        return Ok(());
+    }
+
+    let changed: Vec<i32> = changes.iter().map(|(id, _)| *id).collect();
+    let interlocks = gpio_interlocks
+        .filter(gpio_id.eq_any(&changed).or(other_gpio.eq_any(&changed)))
+        .order(interlock_id.asc())
+        .load::<Interlock>(conn)?;
+    if interlocks.is_empty() {
+        return Ok(());
+    }
+
+    let now = Local::now().naive_local();
+    for interlock in interlocks.iter() {
+        let gpio = level_after(interlock.gpio_id, changes, conn)?;
+        let other = level_after(interlock.other_gpio, changes, conn)?;
+        let (gpio_high, other_high) = (gpio.0 == "high", other.0 == "high");
+
+        let broken = match interlock.kind.as_str() {
+            EXCLUSIVE if gpio_high && other_high => Some(format!(
+                "GPIO #{} and GPIO #{} must not be high together",
+                interlock.gpio_id, interlock.other_gpio
+            )),
+            REQUIRES if gpio_high && !other_high => Some(format!(
+                "GPIO #{} can only be high while GPIO #{} is high",
+                interlock.gpio_id, interlock.other_gpio
+            )),
+            DEAD_TIME if gpio_high && changed.contains(&interlock.gpio_id) => {
+                let dead_time = ChronoDuration::milliseconds(
+                    i64::from(interlock.dead_time_ms.unwrap_or_default()),
+                );
+                let low_long_enough = !other_high
+                    && other.1.is_none_or(|low_since| low_since + dead_time <= now);
+                if low_long_enough {
+                    None
+                } else {
+                    Some(format!(
+                        "GPIO #{} can only go high once GPIO #{} has been low for {} ms",
+                        interlock.gpio_id,
+                        interlock.other_gpio,
+                        dead_time.num_milliseconds()
+                    ))
+                }
+            }
+            _ => None,
+        };
+
+        if let Some(errs) = broken {
+            let errs = format!("Interlock #{}: {}", interlock.interlock_id, errs);
+            info!("{}", errs);
+            return Err(RpWebError::Interlock(errs));
+        }
+    }
+
+    Ok(())
+}
+
+/// The GPIOs that must go low with `changes` to keep the 'requires' interlocks: those that are
+/// high and require a GPIO going low, and in turn those requiring them. Returned with their
+/// level before, in the order they were found. GPIOs in `changes` are left as they are.
+pub fn dependents_going_low_db(
+    changes: &[(i32, &str)], conn: &SqliteConnection,
+) -> Result<Vec<(i32, Option<String>)>, RpWebError> {
+    let mut going_low: Vec<i32> = changes
+        .iter()
+        .filter(|(_, level)| level.eq_ignore_ascii_case("low"))
+        .map(|(id, _)| *id)
+        .collect();
+    let mut dependents: Vec<(i32, Option<String>)> = vec![];
+
+    while let Some(required) = going_low.pop() {
+        let requiring = {
+            use crate::schema::gpio_interlocks::dsl::*;
+            gpio_interlocks
+                .filter(kind.eq(REQUIRES))
+                .filter(other_gpio.eq(required))
+                .order(interlock_id.asc())
+                .select(gpio_id)
+                .load::<i32>(conn)?
+        };
+        for dependent in requiring {
+            let known = changes.iter().any(|(id, _)| *id == dependent)
+                || dependents.iter().any(|(id, _)| *id == dependent);
+            if known {
+                continue;
+            }
+
+            let level = {
+                use crate::schema::gpio_state::dsl::*;
+                gpio_state
+                    .filter(gpio_id.eq(dependent))
+                    .select(gpio_level)
+                    .first::<Option<String>>(conn)?
+            };
+            if level.as_deref() == Some("high") {
+                dependents.push((dependent, level));
+                going_low.push(dependent);
+            }
+        }
+    }
+
+    Ok(dependents)
+}
+
+/// The level of GPIO #`id` after `changes`, and when it last changed. A GPIO changing now
+/// has changed just now.
+fn level_after(
+    id: i32, changes: &[(i32, &str)], conn: &SqliteConnection,
+) -> Result<(String, Option<NaiveDateTime>), RpWebError> {
+    use crate::schema::gpio_state::dsl::*;
+
+    if let Some((_, level)) = changes.iter().find(|(changed, _)| *changed == id) {
+        return Ok((level.to_lowercase(), Some(Local::now().naive_local())));
+    }
+
+    let gpio = gpio_state.filter(gpio_id.eq(id)).first::<Gpio>(conn)?;
+    let changed_at = gpio
+        .last_change
+        .as_ref()
+        .and_then(|text| NaiveDateTime::parse_from_str(text, LAST_CHANGE_FORMAT).ok());
+    Ok((gpio.gpio_level.unwrap_or_default(), changed_at))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(kind: &str, gpios: Vec<i32>, dead_time_ms: Option<u32>) -> InterlockConfig {
+        InterlockConfig {
+            kind: kind.to_string(),
+            gpios,
+            dead_time_ms,
+        }
+    }
+
+    #[test]
+    fn interlock_exclusive_must_pair_all_gpios() {
+        let interlocks = new_interlocks(&config("Exclusive", vec![5, 6, 13], None)).unwrap();
+        let pairs: Vec<(i32, i32)> =
+            interlocks.iter().map(|interlock| (interlock.gpio_id, interlock.other_gpio)).collect();
+        assert_eq!(pairs, vec![(5, 6), (5, 13), (6, 13)]);
+        assert_eq!(interlocks[0].kind, EXCLUSIVE);
+    }
+
+    #[test]
+    fn interlock_dead_time_must_guard_second_gpio() {
+        let interlocks = new_interlocks(&config("dead_time", vec![5, 6], Some(200))).unwrap();
+        assert_eq!(interlocks.len(), 1);
+        assert_eq!((interlocks[0].gpio_id, interlocks[0].other_gpio), (6, 5));
+        assert_eq!(interlocks[0].dead_time_ms, Some(200));
+    }
+
+    #[test]
+    fn interlock_invalid_must_fail() {
+        assert!(new_interlocks(&config("exclusive", vec![5], None)).is_err());
+        assert!(new_interlocks(&config("requires", vec![5, 6, 13], None)).is_err());
+        assert!(new_interlocks(&config("dead_time", vec![5, 6], None)).is_err());
+        assert!(new_interlocks(&config("before", vec![5, 6], None)).is_err());
+    }
+}
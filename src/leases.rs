@@ -0,0 +1,213 @@
+// Exclusive, time-limited access to GPIOs for one client at a time
+
+use crate::control::apply_gpio_levels;
+use crate::errors::RpWebError;
+use crate::handlers::{DbExecutor, ExpireLeases};
+use crate::models::{GpioLease, Lease, LevelChange};
+use crate::rpi::GpioArcMutex;
+use crate::utilities::{current_time, timestamp};
+use actix::fut::wrap_future;
+use actix::{Actor, Addr, AsyncContext, Context};
+use chrono::{Duration as ChronoDuration, Local};
+use diesel::prelude::*;
+use futures::Future;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// How often expired leases are looked for
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+
+fn expiry_time(duration_seconds: u32) -> String {
+    timestamp(Local::now().naive_local() + ChronoDuration::seconds(i64::from(duration_seconds)))
+}
+
+/// Id of the active lease on GPIO #`id`, if any
+pub fn lease_holder_db(id: i32, conn: &SqliteConnection) -> Result<Option<String>, RpWebError> {
+    use crate::schema::gpio_leases::dsl as leases;
+
+    let holder = leases::gpio_leases
+        .filter(leases::gpio_id.eq(id))
+        .filter(leases::expires_at.gt(current_time()))
+        .select(leases::lease_id)
+        .first::<String>(conn)
+        .optional()?;
+    Ok(holder)
+}
+
+/// Fail with `Locked` if GPIO #`id` is under an active lease other than `lease_id`
+pub fn check_gpio_lease_db(
+    id: i32, lease_id: Option<&str>, conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    match lease_holder_db(id, conn)? {
+        Some(ref holder) if Some(holder.as_str()) != lease_id => {
+            let errs = format!("GPIO #{} is leased by another client", id);
+            info!("{}", errs);
+            Err(RpWebError::Locked(errs))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Lease `gpios` for `duration_seconds`. Fails with `Locked` if any of them is leased already.
+pub fn acquire_lease(
+    gpios: &[i32], duration_seconds: u32, on_expiry: Option<&str>, conn: &SqliteConnection,
+) -> Result<Lease, RpWebError> {
+    use crate::schema::gpio_leases::dsl as leases;
+
+    let new_lease_id = Uuid::new_v4().to_string();
+    let rows: Vec<GpioLease> = gpios
+        .iter()
+        .map(|idx| GpioLease {
+            gpio_id: *idx,
+            lease_id: new_lease_id.clone(),
+            expires_at: expiry_time(duration_seconds),
+            on_expiry: on_expiry.map(str::to_lowercase),
+        })
+        .collect();
+
+    conn.transaction::<_, RpWebError, _>(|| {
+        for idx in gpios.iter() {
+            check_gpio_lease_db(*idx, None, conn)?;
+        }
+        // Leases that have expired, but were not cleaned up yet
+        diesel::delete(leases::gpio_leases.filter(leases::gpio_id.eq_any(gpios))).execute(conn)?;
+        diesel::insert_into(leases::gpio_leases)
+            .values(&rows)
+            .execute(conn)?;
+        Ok(())
+    })?;
+
+    info!("Lease {} acquired on GPIO(s) {:?}", new_lease_id, gpios);
+    load_lease(&new_lease_id, conn)
+}
+
+/// The active lease `id`, or `NotFound`
+pub fn load_lease(id: &str, conn: &SqliteConnection) -> Result<Lease, RpWebError> {
+    use crate::schema::gpio_leases::dsl::*;
+
+    let rows = gpio_leases
+        .filter(lease_id.eq(id))
+        .filter(expires_at.gt(current_time()))
+        .order(gpio_id.asc())
+        .load::<GpioLease>(conn)?;
+
+    Lease::from_rows(rows).ok_or_else(|| RpWebError::NotFound(format!("No active lease '{}'", id)))
+}
+
+/// Extend the active lease `id` to expire `duration_seconds` from now
+pub fn renew_lease(
+    id: &str, duration_seconds: u32, conn: &SqliteConnection,
+) -> Result<Lease, RpWebError> {
+    use crate::schema::gpio_leases::dsl::*;
+
+    conn.transaction::<_, RpWebError, _>(|| {
+        load_lease(id, conn)?;
+        diesel::update(gpio_leases.filter(lease_id.eq(id)))
+            .set(expires_at.eq(expiry_time(duration_seconds)))
+            .execute(conn)?;
+        Ok(())
+    })?;
+
+    info!("Lease {} renewed for {} s", id, duration_seconds);
+    load_lease(id, conn)
+}
+
+/// End the active lease `id` without running its expiry action
+pub fn release_lease(id: &str, conn: &SqliteConnection) -> Result<Lease, RpWebError> {
+    use crate::schema::gpio_leases::dsl::*;
+
+    let lease = load_lease(id, conn)?;
+    diesel::delete(gpio_leases.filter(lease_id.eq(id))).execute(conn)?;
+
+    info!("Lease {} released", id);
+    Ok(lease)
+}
+
+/// Remove the leases that have expired, after setting their GPIOs to the `on_expiry` level
+pub fn expire_leases(
+    conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<Vec<Lease>, RpWebError> {
+    use crate::schema::gpio_leases::dsl::*;
+
+    let expired_ids = gpio_leases
+        .filter(expires_at.le(current_time()))
+        .select(lease_id)
+        .distinct()
+        .load::<String>(conn)?;
+
+    let mut expired = vec![];
+    for id in expired_ids.iter() {
+        let rows = gpio_leases
+            .filter(lease_id.eq(id))
+            .order(gpio_id.asc())
+            .load::<GpioLease>(conn)?;
+        let lease = match Lease::from_rows(rows) {
+            Some(lease) => lease,
+            None => continue,
+        };
+
+        if let Some(level) = lease.on_expiry.as_ref() {
+            if let Err(err) = run_expiry_action(&lease, level, conn, gpio_arc_mutex.clone()) {
+                error!("Failed to set GPIOs of expired lease {} '{}': {}", id, level, err);
+            }
+        }
+
+        diesel::delete(gpio_leases.filter(lease_id.eq(id))).execute(conn)?;
+        info!("Lease {} on GPIO(s) {:?} expired", id, lease.gpios);
+        expired.push(lease);
+    }
+
+    Ok(expired)
+}
+
+fn run_expiry_action(
+    lease: &Lease, level: &str, conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    use crate::schema::gpio_state::dsl::*;
+
+    let mut changes = vec![];
+    for idx in lease.gpios.iter() {
+        let level_before = gpio_state
+            .filter(gpio_id.eq(idx))
+            .select(gpio_level)
+            .first::<Option<String>>(conn)?;
+        changes.push(
+            LevelChange::new(*idx, level, level_before.as_deref()).with_lease(Some(&lease.lease_id)),
+        );
+    }
+
+    apply_gpio_levels(&changes, conn, gpio_arc_mutex)
+}
+
+/// Actor asking the DbExecutor to expire leases every second
+pub struct LeaseMonitor {
+    db: Addr<DbExecutor>,
+    gpio_arc_mutex: GpioArcMutex,
+}
+
+impl LeaseMonitor {
+    pub fn new(db: Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex) -> Self {
+        LeaseMonitor { db, gpio_arc_mutex }
+    }
+}
+
+impl Actor for LeaseMonitor {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(EXPIRY_INTERVAL, |act, ctx| {
+            let expire = act
+                .db
+                .send(ExpireLeases {
+                    gpio_arc_mutex: act.gpio_arc_mutex.clone(),
+                })
+                .map(|res| {
+                    if let Err(err) = res {
+                        error!("Expiring leases failed: {}", err);
+                    }
+                })
+                .map_err(|err| error!("Expiring leases failed: {}", err));
+            ctx.spawn(wrap_future(expire));
+        });
+    }
+}
@@ -10,24 +10,64 @@ extern crate log;
 extern crate serde_derive;
 
 pub mod app;
+pub mod calendar;
 pub mod cli;
+pub mod control;
+pub mod device;
+pub mod drift;
 pub mod errors;
+pub mod guards;
 pub mod handlers;
+pub mod interlocks;
+pub mod leases;
 pub mod models;
+pub mod ramp;
 pub mod rpi;
+pub mod rules;
+pub mod scheduler;
 pub mod schema;
+pub mod scripting;
+pub mod sequencer;
+pub mod servo;
 pub mod settings;
 pub mod setup;
+pub mod solar;
+pub mod stepper;
+pub mod tui;
+pub mod ui;
 pub mod utilities;
+pub mod vacation;
 pub mod validation;
 
 use crate::app::AppState;
+use crate::calendar::CalendarMonitor;
 use crate::cli::get_cli_args;
+use crate::device::setup_devices_db;
+use crate::drift::DriftMonitor;
+use crate::guards::{setup_gpio_guards_db, GuardMonitor};
 use crate::handlers::DbExecutor;
-use crate::setup::setup_rpi_and_db;
-use crate::utilities::reset_table_gpio_state;
-use crate::validation::validate_setup;
-use actix::SyncArbiter;
+use crate::interlocks::setup_interlocks_db;
+use crate::leases::LeaseMonitor;
+use crate::ramp::Ramper;
+use crate::rules::{setup_rules_db, RuleEngine};
+use crate::scheduler::Scheduler;
+use crate::scripting::{new_script_registry, start_scripts, ScriptHost};
+use crate::sequencer::Sequencer;
+use crate::servo::setup_servos;
+use crate::stepper::{setup_steppers_db, StepperDriver};
+use crate::setup::{setup_gpio_groups_db, setup_pin_names_db, setup_rpi_and_db};
+use crate::utilities::{
+    open_database_lock, reset_table_gpio_drift, reset_table_gpio_groups, reset_table_gpio_leases,
+    reset_table_gpio_state,
+};
+use crate::vacation::VacationSimulator;
+use crate::validation::{
+    validate_calendars, validate_devices, validate_drift, validate_groups, validate_interlocks,
+    validate_location, validate_max_on,
+    validate_pins, validate_rules, validate_scripts, validate_servos, validate_setup,
+    validate_steppers, validate_vacation,
+};
+use actix::{Actor, SyncArbiter};
 use actix_web::server;
 use diesel::{r2d2::ConnectionManager, SqliteConnection};
 use dotenv::dotenv;
@@ -35,9 +75,19 @@ use dotenv::dotenv;
 pub fn setup_and_run() {
     // Get CLI args
     let cli_args = get_cli_args();
+    let tui_options = cli_args
+        .subcommand_matches("tui")
+        .map(tui::TuiOptions::from_args);
 
     // Get settings from configuration file
     let config = settings::Settings::new(cli_args).expect("Could not read config file");
+
+    // The terminal dashboard is a client: no logging to draw over the screen, no server
+    if let Some(options) = tui_options {
+        tui::run(&config, options).expect("Error running terminal dashboard");
+        return;
+    }
+
     let database_url = &config.database.database_url;
     let hostname = config.webserver.hostname;
     let port = config.webserver.port;
@@ -48,6 +98,11 @@ pub fn setup_and_run() {
     // Initialize logger
     env_logger::init();
 
+    // Hold the database lock while running, so the terminal dashboard leaves the pins alone.
+    // A dashboard driving a pin right now is waited for.
+    let database_lock = open_database_lock(database_url).expect("Could not open database lock");
+    database_lock.lock().expect("Could not lock database");
+
     // Create database connection pool
     let manager = ConnectionManager::<SqliteConnection>::new(database_url.to_string());
     let pool = r2d2::Pool::builder()
@@ -57,27 +112,131 @@ pub fn setup_and_run() {
 
     // Reset database
     reset_table_gpio_state(&connection).expect("Unable to update table 'gpio_state'");
+    reset_table_gpio_groups(&connection).expect("Unable to update table 'gpio_groups'");
+    reset_table_gpio_drift(&connection).expect("Unable to update table 'gpio_drift'");
+    reset_table_gpio_leases(&connection).expect("Unable to update table 'gpio_leases'");
 
     // Check consistency of parsed_variables
     validate_setup(&config.gpioconfig).expect("Provided setup variables are inconsistent");
+    let pins = config.pins.as_ref().map_or(&[][..], Vec::as_slice);
+    validate_pins(pins).expect("Provided pin names are inconsistent");
+    validate_max_on(pins, &config.gpioconfig).expect("Provided maximum on-times are inconsistent");
+    validate_servos(pins, &config.gpioconfig).expect("Provided servos are inconsistent");
+    let groups = config.groups.clone().unwrap_or_default();
+    validate_groups(&groups, &config.gpioconfig).expect("Provided groups are inconsistent");
+    if let Some(drift) = &config.drift {
+        validate_drift(drift, &config.gpioconfig).expect("Provided drift policies are inconsistent");
+    }
+    if let Some(location) = &config.location {
+        validate_location(location).expect("Provided location is invalid");
+    }
+    let calendars = config.calendars.clone().unwrap_or_default();
+    validate_calendars(&calendars, &groups, &config.gpioconfig)
+        .expect("Provided calendars are inconsistent");
+    if let Some(vacation) = &config.vacation {
+        validate_vacation(vacation, &config.gpioconfig).expect("Provided vacation mode is invalid");
+    }
+    let rules = config.rules.clone().unwrap_or_default();
+    validate_rules(&rules, &config.gpioconfig).expect("Provided rules are inconsistent");
+    if let Some(scripts) = &config.scripts {
+        validate_scripts(scripts).expect("Provided scripts are invalid");
+    }
+    let steppers = config.steppers.clone().unwrap_or_default();
+    validate_steppers(&steppers, &config.gpioconfig).expect("Provided steppers are inconsistent");
+    let devices = config.devices.clone().unwrap_or_default();
+    validate_devices(&devices, pins, &steppers, &config.gpioconfig)
+        .expect("Provided devices are inconsistent");
+    let interlocks = config.interlocks.clone().unwrap_or_default();
+    validate_interlocks(&interlocks, &config.gpioconfig)
+        .expect("Provided interlocks are inconsistent");
 
-    // Arc<Mutex<rppal::gpio::Gpio>> or ARM, Arc<Mutex<i32>> on other architectures
+    // Arc<Mutex<rppal::gpio::Gpio>> or ARM, Arc<Mutex<SimulatedGpio>> on other architectures
     let gpio_arc_mutex = rpi::create_gpio_arc_mutex().expect("Could not acquire GPIO");
 
+    // Guards first, so outputs that start away from their safe level are timed from the start
+    setup_gpio_guards_db(pins, &connection).expect("Error when setting up guards in database");
+
     // If variables are consistent, setup Raspberry Pi and database
     setup_rpi_and_db(&config.gpioconfig, &connection, gpio_arc_mutex.clone())
         .expect("Error when setting up Raspberry Pi and database");
+    setup_pin_names_db(pins, &connection).expect("Error when setting pin names in database");
+    setup_servos(pins, &connection, gpio_arc_mutex.clone())
+        .expect("Error when setting up servos in Raspberry Pi and database");
+    setup_gpio_groups_db(&groups, &connection).expect("Error when setting up groups in database");
+    setup_rules_db(&rules, &connection).expect("Error when setting up rules in database");
+    let stepper_positions = setup_steppers_db(&steppers, &connection)
+        .expect("Error when setting up steppers in database");
+    setup_devices_db(&devices, &connection).expect("Error when setting up devices in database");
+    setup_interlocks_db(&interlocks, &connection)
+        .expect("Error when setting up interlocks in database");
 
     let sys = actix::System::new("raspberry-web");
     // https://github.com/actix/actix-website/blob/master/content/docs/databases.md
     // https://docs.rs/actix-web/0.6.3/actix_web/struct.State.html
     let addr = SyncArbiter::start(3, move || DbExecutor(pool.clone()));
 
+    // Run the actions of leases that expire
+    LeaseMonitor::new(addr.clone(), gpio_arc_mutex.clone()).start();
+
+    // Compare the pins with the database in the background
+    if let Some(drift) = &config.drift {
+        DriftMonitor::new(addr.clone(), gpio_arc_mutex.clone(), drift).start();
+    }
+
+    // Force outputs back to their safe level after their maximum on-time
+    if pins.iter().any(|pin| pin.max_on_duration.is_some()) {
+        GuardMonitor::new(addr.clone(), gpio_arc_mutex.clone()).start();
+    }
+
+    // Timing of pulses and blinks
+    let sequencer = Sequencer::new(addr.clone(), gpio_arc_mutex.clone()).start();
+
+    // Timing of ramps on PWM GPIOs
+    let ramper = Ramper::new(addr.clone(), gpio_arc_mutex.clone()).start();
+
+    // Run the actions of rules on edges of their inputs, enabled or disabled at runtime
+    RuleEngine::new(addr.clone(), sequencer.clone(), gpio_arc_mutex.clone()).start();
+
+    // Run the actions of schedules, including the runs missed while the server was down
+    let location = config.location;
+    Scheduler::new(addr.clone(), sequencer.clone(), gpio_arc_mutex.clone(), location).start();
+
+    // Set outputs from calendar files, read again when they change
+    if !calendars.is_empty() {
+        CalendarMonitor::new(addr.clone(), gpio_arc_mutex.clone(), &calendars).start();
+    }
+
+    // Presence simulation, picked up again if it was on before a restart
+    let vacation =
+        VacationSimulator::new(addr.clone(), gpio_arc_mutex.clone(), config.vacation.clone())
+            .start();
+
+    // User scripts, each in its own thread
+    let scripts = new_script_registry();
+    if let Some(scripts_config) = &config.scripts {
+        let host = ScriptHost {
+            db: addr.clone(),
+            gpio_arc_mutex: gpio_arc_mutex.clone(),
+            gpios_in_use: config.gpioconfig.gpios_in_use.clone().unwrap_or_default(),
+        };
+        start_scripts(scripts_config, &host, &scripts).expect("Error when starting scripts");
+    }
+
+    // Stepper moves, each in its own thread, from the positions saved before a restart
+    let steppers =
+        StepperDriver::new(addr.clone(), gpio_arc_mutex.clone(), &steppers, &stepper_positions);
+
     let ip_port = format!("{}:{}", hostname, port);
     let _server = server::new(move || {
         app::create_app(AppState {
             db: addr.clone(),
+            sequencer: sequencer.clone(),
+            ramper: ramper.clone(),
+            vacation: vacation.clone(),
             gpio_arc_mutex: gpio_arc_mutex.clone(),
+            location,
+            scripts: scripts.clone(),
+            steppers: steppers.clone(),
         })
     })
     .bind(&ip_port)
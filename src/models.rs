@@ -1,14 +1,576 @@
-use super::schema::{allowed_states, gpio_state};
-use std::collections::HashMap;
+use super::errors::RpWebError;
+use super::schema::{
+    allowed_states, gpio_audit, gpio_devices, gpio_drift, gpio_groups, gpio_interlocks,
+    gpio_leases, gpio_servos, gpio_state, rules, schedules, stepper_positions,
+};
+use super::settings::GpioConfig;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
 #[table_name = "gpio_state"]
 pub struct Gpio {
     pub gpio_id: i32,                // 0..16 + 21..31
     pub in_use: i32,                 // 0 or 1
-    pub gpio_mode: Option<String>,   // INPUT or OUTPUT
+    pub gpio_mode: Option<String>,   // INPUT, OUTPUT or PWM
     pub gpio_level: Option<String>,  // HIGH or LOW
     pub last_change: Option<String>, // Timestamp
+    pub name: Option<String>,        // Unique, usable instead of gpio_id in routes
+    pub description: Option<String>,
+    pub tags: Option<String>, // Comma separated
+    pub version: i32,         // Incremented on every change
+    pub max_on_duration: Option<i32>, // Seconds away from safe_level before forced back to it
+    pub safe_level: Option<String>,   // HIGH or LOW
+    pub on_since: Option<String>,     // Timestamp, None while at safe_level
+    pub forced_off_at: Option<String>, // Timestamp of the last time it was forced back
+    pub pwm_frequency: Option<f64>,   // Hz, in mode PWM
+    pub pwm_duty_cycle: Option<f64>,  // Percent, in mode PWM
+    pub pwm_channel: Option<i32>,     // Hardware PWM channel, None for software PWM
+}
+
+/// A GPIO given either by its id or by its name
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum GpioIdentifier {
+    Id(i32),
+    Name(String),
+}
+
+/// A level change for one GPIO, with the level to return to if the change is undone.
+/// If `expected_version` is set, the change is only made if the GPIO still has that version.
+/// A GPIO under an active lease is only changed if `lease_id` is that lease.
+#[derive(Debug, Clone)]
+pub struct LevelChange {
+    pub gpio_id: i32,
+    pub level: String,
+    pub previous_level: Option<String>,
+    pub expected_version: Option<i32>,
+    pub lease_id: Option<String>,
+}
+
+impl LevelChange {
+    pub fn new(gpio_id: i32, level: &str, previous_level: Option<&str>) -> LevelChange {
+        LevelChange {
+            gpio_id,
+            level: level.to_lowercase(),
+            previous_level: previous_level.map(str::to_string),
+            expected_version: None,
+            lease_id: None,
+        }
+    }
+
+    pub fn expecting_version(mut self, version: Option<i32>) -> LevelChange {
+        self.expected_version = version;
+        self
+    }
+
+    pub fn with_lease(mut self, lease_id: Option<&str>) -> LevelChange {
+        self.lease_id = lease_id.map(str::to_string);
+        self
+    }
+}
+
+/// One operation of a batch request. If `version` is set, the operation fails with 412
+/// unless the GPIO still has that version.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchOperation {
+    pub id: GpioIdentifier,
+    pub level: String,
+    pub version: Option<i32>,
+}
+
+/// Outcome of one operation of a batch request. `status` is the HTTP status code the
+/// operation would have had on its own; 424 if it was not applied because another failed.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchItemResult {
+    pub id: GpioIdentifier,
+    pub level: String,
+    pub status: u16,
+    pub error: Option<String>,
+    pub gpio: Option<Gpio>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub applied: bool,
+    pub results: Vec<BatchItemResult>,
+}
+
+/// Desired state of one GPIO in a desired-state document
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DesiredGpio {
+    pub gpio_id: i32,
+    pub in_use: i32,
+    pub gpio_mode: Option<String>,
+    pub gpio_level: Option<String>,
+}
+
+/// Desired state of all GPIOs. GPIOs that are not listed should not be in use.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DesiredState {
+    pub gpios: Vec<DesiredGpio>,
+}
+
+impl DesiredState {
+    /// The document as a `GpioConfig`, so it can be checked with `validate_setup`
+    pub fn to_gpioconfig(&self) -> Result<GpioConfig, RpWebError> {
+        let mut gpios_in_use = vec![];
+        let mut gpios_mode_output = vec![];
+        let mut gpios_mode_input = vec![];
+        let mut gpios_mode_pwm = vec![];
+        let mut gpios_level_low = vec![];
+        let mut gpios_level_high = vec![];
+        let mut seen: Vec<i32> = vec![];
+
+        for gpio in self.gpios.iter() {
+            if seen.contains(&gpio.gpio_id) {
+                let errs = format!("GPIO #{} appears more than once", gpio.gpio_id);
+                return Err(RpWebError::new(&errs));
+            }
+            seen.push(gpio.gpio_id);
+
+            match gpio.in_use {
+                0 => {}
+                1 => gpios_in_use.push(gpio.gpio_id),
+                other => {
+                    let errs = format!("Invalid in_use for GPIO #{}: {}", gpio.gpio_id, other);
+                    return Err(RpWebError::new(&errs));
+                }
+            }
+
+            match gpio.gpio_mode.as_ref().map(|mode| mode.to_lowercase()).as_deref() {
+                None => {}
+                Some("output") => gpios_mode_output.push(gpio.gpio_id),
+                Some("input") => gpios_mode_input.push(gpio.gpio_id),
+                Some("pwm") => gpios_mode_pwm.push(gpio.gpio_id),
+                Some(other) => {
+                    let errs = format!("Invalid mode for GPIO #{}: '{}'", gpio.gpio_id, other);
+                    return Err(RpWebError::new(&errs));
+                }
+            }
+
+            match gpio.gpio_level.as_ref().map(|level| level.to_lowercase()).as_deref() {
+                None => {}
+                Some("low") => gpios_level_low.push(gpio.gpio_id),
+                Some("high") => gpios_level_high.push(gpio.gpio_id),
+                Some(other) => {
+                    let errs = format!("Invalid level for GPIO #{}: '{}'", gpio.gpio_id, other);
+                    return Err(RpWebError::new(&errs));
+                }
+            }
+        }
+
+        Ok(GpioConfig {
+            gpios_in_use: Some(gpios_in_use),
+            gpios_mode_output: Some(gpios_mode_output),
+            gpios_mode_input: Some(gpios_mode_input),
+            gpios_mode_pwm: Some(gpios_mode_pwm),
+            gpios_level_low: Some(gpios_level_low),
+            gpios_level_high: Some(gpios_level_high),
+        })
+    }
+}
+
+/// One field of one GPIO changed while reconciling a desired-state document
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StateChange {
+    pub gpio_id: i32,
+    pub field: String, // in_use, gpio_mode or gpio_level
+    pub before: Option<String>,
+    pub after: String,
+}
+
+impl StateChange {
+    pub fn new(gpio_id: i32, field: &str, before: Option<&str>, after: &str) -> StateChange {
+        StateChange {
+            gpio_id,
+            field: field.to_string(),
+            before: before.map(str::to_string),
+            after: after.to_string(),
+        }
+    }
+}
+
+/// A GPIO whose level on the pin differs from the level in the database
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "gpio_drift"]
+pub struct GpioDrift {
+    pub gpio_id: i32,
+    pub db_level: String,
+    pub hw_level: String,
+    pub detected_at: String,
+    pub correction: Option<String>, // 'hardware', 'database', or 'refused' by an interlock
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "gpio_leases"]
+pub struct GpioLease {
+    pub gpio_id: i32,
+    pub lease_id: String,
+    pub expires_at: String,
+    pub on_expiry: Option<String>, // Level to set when the lease expires
+}
+
+/// Request for a lease on one GPIO, given by id or name, or on all GPIOs of a group
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaseRequest {
+    pub gpio: Option<GpioIdentifier>,
+    pub group: Option<String>,
+    pub duration_seconds: u32,
+    pub on_expiry: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaseRenewal {
+    pub duration_seconds: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Lease {
+    pub lease_id: String,
+    pub gpios: Vec<i32>,
+    pub expires_at: String,
+    pub on_expiry: Option<String>,
+}
+
+impl Lease {
+    /// The lease made of `rows`, which must all have the same lease id. None if `rows` is empty.
+    pub fn from_rows(rows: Vec<GpioLease>) -> Option<Lease> {
+        let first = rows.first()?;
+        Some(Lease {
+            lease_id: first.lease_id.clone(),
+            expires_at: first.expires_at.clone(),
+            on_expiry: first.on_expiry.clone(),
+            gpios: rows.iter().map(|row| row.gpio_id).collect(),
+        })
+    }
+}
+
+/// Something the server did to a GPIO on its own, e.g. forcing it back to its safe level
+#[derive(Debug, Serialize, Deserialize, Queryable)]
+pub struct AuditEntry {
+    pub audit_id: i32,
+    pub gpio_id: i32,
+    pub event: String,
+    pub gpio_level: Option<String>,
+    pub detail: Option<String>,
+    pub occurred_at: String,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "gpio_audit"]
+pub struct NewAuditEntry<'a> {
+    pub gpio_id: i32,
+    pub event: &'a str,
+    pub gpio_level: Option<&'a str>,
+    pub detail: Option<&'a str>,
+    pub occurred_at: String,
+}
+
+/// An action run on a cron schedule, or at sunrise or sunset plus `offset_minutes`.
+/// `next_run` is None while the schedule is disabled.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable)]
+pub struct Schedule {
+    pub schedule_id: i32,
+    pub cron: Option<String>,
+    pub gpio_id: Option<i32>,
+    pub group_name: Option<String>,
+    pub action: String, // set_level, toggle or pulse
+    pub gpio_level: Option<String>,
+    pub duration_ms: Option<i32>, // Of a pulse
+    pub missed_runs: String,      // skip or run_once
+    pub enabled: i32,             // 0 or 1
+    pub last_run: Option<String>, // Timestamp
+    pub next_run: Option<String>, // Timestamp
+    pub solar_event: Option<String>, // sunrise or sunset
+    pub offset_minutes: i32,
+}
+
+#[derive(Debug, Insertable, AsChangeset)]
+#[table_name = "schedules"]
+#[changeset_options(treat_none_as_null = "true")]
+pub struct NewSchedule {
+    pub cron: Option<String>,
+    pub gpio_id: Option<i32>,
+    pub group_name: Option<String>,
+    pub action: String,
+    pub gpio_level: Option<String>,
+    pub duration_ms: Option<i32>,
+    pub missed_runs: String,
+    pub enabled: i32,
+    pub next_run: Option<String>,
+    pub solar_event: Option<String>,
+    pub offset_minutes: i32,
+}
+
+/// Body of a request creating or replacing a schedule, on either a GPIO or a group, at
+/// either a `cron` expression or a `solar_event`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ScheduleRequest {
+    pub cron: Option<String>,
+    pub solar_event: Option<String>,
+    pub offset_minutes: Option<i32>,
+    pub gpio: Option<GpioIdentifier>,
+    pub group: Option<String>,
+    pub action: String,
+    pub level: Option<String>,
+    pub duration_ms: Option<u32>,
+    pub missed_runs: Option<String>,
+    pub enabled: Option<bool>,
+}
+
+/// A rule: when `trigger` happens on `input_gpio`, run `action` on `output_gpio`. With the
+/// 'mirror' trigger, the output follows the input, or its opposite if `inverted`.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable)]
+pub struct Rule {
+    pub rule_id: i32,
+    pub name: Option<String>,
+    pub input_gpio: i32,
+    pub trigger: String, // rising, falling, both or mirror
+    pub output_gpio: i32,
+    pub action: String, // set_level, pulse, toggle or mirror
+    pub gpio_level: Option<String>,
+    pub duration_ms: Option<i32>, // Of a pulse
+    pub inverted: i32,            // 0 or 1
+    pub enabled: i32,             // 0 or 1
+    pub from_config: i32,         // 0 or 1
+    pub fire_count: i32,
+    pub last_fired: Option<String>, // Timestamp
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "rules"]
+pub struct NewRule {
+    pub name: Option<String>,
+    pub input_gpio: i32,
+    pub trigger: String,
+    pub output_gpio: i32,
+    pub action: String,
+    pub gpio_level: Option<String>,
+    pub duration_ms: Option<i32>,
+    pub inverted: i32,
+    pub enabled: i32,
+    pub from_config: i32,
+}
+
+/// Body of a request creating a rule
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RuleRequest {
+    pub name: Option<String>,
+    pub input: GpioIdentifier,
+    pub trigger: String,
+    pub output: GpioIdentifier,
+    pub action: Option<String>,
+    pub level: Option<String>,
+    pub duration_ms: Option<u32>,
+    pub inverted: Option<bool>,
+    pub enabled: Option<bool>,
+}
+
+/// Whether the presence simulation is on, and the seed of its random numbers
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable)]
+pub struct VacationMode {
+    pub vacation_id: i32,
+    pub enabled: i32, // 0 or 1
+    pub seed: Option<i64>,
+}
+
+/// Body of a request turning the presence simulation on or off. Without a seed, a random
+/// one is used.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VacationRequest {
+    pub enabled: bool,
+    pub seed: Option<u32>,
+}
+
+/// A GPIO switched by the presence simulation, and when it is switched next
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SimulatedGpio {
+    pub gpio_id: i32,
+    pub gpio_level: String,
+    pub next_change: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VacationStatus {
+    pub enabled: bool,
+    pub seed: Option<u32>,
+    pub gpios: Vec<SimulatedGpio>,
+}
+
+/// A line printed by a script
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptLog {
+    pub time: String,
+    pub message: String,
+}
+
+/// A script from the scripts directory: `state` is 'running', 'finished' or 'failed', with
+/// the error in `error`. `logs` has the latest lines printed, oldest first.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptStatus {
+    pub name: String,
+    pub state: String,
+    pub error: Option<String>,
+    pub started: String,
+    pub finished: Option<String>,
+    pub subscriptions: Vec<i32>,
+    pub logs: Vec<ScriptLog>,
+}
+
+/// The saved position of a stepper
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "stepper_positions"]
+pub struct StepperPosition {
+    pub name: String,
+    pub position: i32,
+    pub homed: i32,                 // 0 or 1
+    pub updated_at: Option<String>, // Timestamp
+}
+
+/// A stepper and where it is: `target` is the position it is moving to, if it is moving.
+/// `error` is why the last move stopped early, if it did.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepperStatus {
+    pub name: String,
+    pub position: i32,
+    pub homed: bool,
+    pub target: Option<i32>,
+    pub profile: String,
+    pub min_position: Option<i32>,
+    pub max_position: Option<i32>,
+    pub error: Option<String>,
+}
+
+/// A move of a stepper by `steps`, or to `position`, with the profile of the stepper
+/// unless `profile` is given
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StepperMove {
+    pub steps: Option<i32>,
+    pub position: Option<i32>,
+    pub profile: Option<String>,
+}
+
+/// Sunrise and sunset on a day in local time, or 'polar_day' / 'polar_night' in `polar`
+/// when the sun does not rise or set
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SolarDay {
+    pub date: String,
+    pub sunrise: Option<String>,
+    pub sunset: Option<String>,
+    pub polar: Option<String>,
+}
+
+/// A pulse or blink that was cancelled, and the level its GPIO was set back to
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Sequence {
+    pub gpio_id: i32,
+    pub pattern: String,
+    pub final_level: String,
+}
+
+/// A ramp that was cancelled, and the duty cycle its GPIO was left at
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ramp {
+    pub gpio_id: i32,
+    pub easing: String,
+    pub target_duty_cycle: f64,
+    pub duty_cycle: f64,
+}
+
+/// A PWM GPIO driving a hobby servo: `min_angle` is a pulse of `min_pulse_us` and
+/// `max_angle` a pulse of `max_pulse_us`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "gpio_servos"]
+pub struct Servo {
+    pub gpio_id: i32,
+    pub min_pulse_us: i32,
+    pub max_pulse_us: i32,
+    pub min_angle: f64,
+    pub max_angle: f64,
+    pub max_speed: Option<f64>, // Degrees per second
+}
+
+/// A servo and the angle it is set to, if it was set since the start
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ServoStatus {
+    #[serde(flatten)]
+    pub servo: Servo,
+    pub angle: Option<f64>,
+}
+
+/// An interlock between two outputs. 'exclusive': they are not high together. 'requires':
+/// `gpio_id` is only high while `other_gpio` is high. 'dead_time': `gpio_id` only goes high
+/// once `other_gpio` has been low for `dead_time_ms`.
+#[derive(Debug, Clone, Serialize, Deserialize, Queryable)]
+pub struct Interlock {
+    pub interlock_id: i32,
+    pub kind: String,
+    pub gpio_id: i32,
+    pub other_gpio: i32,
+    pub dead_time_ms: Option<i32>,
+}
+
+#[derive(Debug, Insertable)]
+#[table_name = "gpio_interlocks"]
+pub struct NewInterlock {
+    pub kind: String,
+    pub gpio_id: i32,
+    pub other_gpio: i32,
+    pub dead_time_ms: Option<i32>,
+}
+
+/// A GPIO owned by a device, as the pin with `role` in it
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "gpio_devices"]
+pub struct DevicePin {
+    pub gpio_id: i32,
+    pub device_name: String,
+    pub kind: String,
+    pub role: String,
+}
+
+/// A device with its GPIOs by role, and what it is doing: a color, a direction or a light,
+/// if the pins show one. `speed` is the duty cycle of a motor.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeviceStatus {
+    pub device_name: String,
+    pub kind: String,
+    pub state: Option<String>,
+    pub speed: Option<f64>,
+    pub pins: BTreeMap<String, Gpio>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
+#[table_name = "gpio_groups"]
+pub struct GpioGroupMember {
+    pub group_name: String,
+    pub gpio_id: i32,
+}
+
+/// State of a group: 'high' or 'low' if all members agree, else 'mixed'
+#[derive(Debug, Serialize, Deserialize)]
+pub struct GpioGroupState {
+    pub group_name: String,
+    pub gpio_level: String,
+    pub gpios: Vec<Gpio>,
+}
+
+impl GpioGroupState {
+    pub fn new(group_name: &str, gpios: Vec<Gpio>) -> GpioGroupState {
+        let mut levels = gpios.iter().map(|gpio| gpio.gpio_level.as_ref());
+        let first = levels.next().and_then(|level| level);
+        let gpio_level = if levels.all(|level| level == first) {
+            first.cloned().unwrap_or_default()
+        } else {
+            "mixed".to_string()
+        };
+
+        GpioGroupState {
+            group_name: group_name.to_string(),
+            gpio_level,
+            gpios,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Queryable, Insertable)]
@@ -21,6 +583,7 @@ pub struct AllowedStates {
     pub output: i32,
     pub high: i32,
     pub low: i32,
+    pub pwm: i32,
 }
 
 impl AllowedStates {
@@ -30,6 +593,88 @@ impl AllowedStates {
         hashed.insert("output", self.output == 1);
         hashed.insert("high", self.high == 1);
         hashed.insert("low", self.low == 1);
+        hashed.insert("pwm", self.pwm == 1);
         hashed
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gpio(gpio_id: i32, gpio_level: &str) -> Gpio {
+        Gpio {
+            gpio_id,
+            in_use: 1,
+            gpio_mode: Some("output".to_string()),
+            gpio_level: Some(gpio_level.to_string()),
+            last_change: None,
+            name: None,
+            description: None,
+            tags: None,
+            version: 0,
+            max_on_duration: None,
+            safe_level: None,
+            on_since: None,
+            forced_off_at: None,
+            pwm_frequency: None,
+            pwm_duty_cycle: None,
+            pwm_channel: None,
+        }
+    }
+
+    #[test]
+    fn group_state_same_level_must_be_that_level() {
+        let group = GpioGroupState::new("zone-a", vec![gpio(5, "high"), gpio(6, "high")]);
+        assert_eq!(group.gpio_level, "high");
+    }
+
+    #[test]
+    fn group_state_different_levels_must_be_mixed() {
+        let group = GpioGroupState::new("zone-a", vec![gpio(5, "high"), gpio(6, "low")]);
+        assert_eq!(group.gpio_level, "mixed");
+    }
+
+    fn desired(gpio_id: i32, gpio_mode: Option<&str>, gpio_level: Option<&str>) -> DesiredGpio {
+        DesiredGpio {
+            gpio_id,
+            in_use: 1,
+            gpio_mode: gpio_mode.map(str::to_string),
+            gpio_level: gpio_level.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn desired_state_to_gpioconfig_must_succeed() {
+        let state = DesiredState {
+            gpios: vec![
+                desired(5, Some("OUTPUT"), Some("high")),
+                desired(6, Some("input"), None),
+                desired(7, Some("pwm"), None),
+            ],
+        };
+        let gpioconfig = state.to_gpioconfig().unwrap();
+
+        assert_eq!(gpioconfig.gpios_in_use, Some(vec![5, 6, 7]));
+        assert_eq!(gpioconfig.gpios_mode_output, Some(vec![5]));
+        assert_eq!(gpioconfig.gpios_mode_input, Some(vec![6]));
+        assert_eq!(gpioconfig.gpios_mode_pwm, Some(vec![7]));
+        assert_eq!(gpioconfig.gpios_level_high, Some(vec![5]));
+    }
+
+    #[test]
+    fn desired_state_unknown_mode_must_fail() {
+        let state = DesiredState {
+            gpios: vec![desired(5, Some("analog"), None)],
+        };
+        assert!(state.to_gpioconfig().is_err());
+    }
+
+    #[test]
+    fn desired_state_duplicate_gpio_must_fail() {
+        let state = DesiredState {
+            gpios: vec![desired(5, Some("output"), None), desired(5, Some("input"), None)],
+        };
+        assert!(state.to_gpioconfig().is_err());
+    }
+}
@@ -0,0 +1,463 @@
+// Ramps: the duty cycle of a PWM GPIO moved to a target in small steps on a server-side
+// timer, so a client fades a LED or turns a servo slowly with a single request
+
+use crate::handlers::{DbExecutor, GpioId, ServoId, SetGpioPwm};
+use crate::models;
+use crate::rpi::GpioArcMutex;
+use crate::servo::SERVO_FREQUENCY;
+use actix::fut::{self, wrap_future, ActorFuture};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, ResponseActFuture, SpawnHandle};
+use actix_web::{error, Error as actixError};
+use futures::Future;
+use std::collections::HashMap;
+use std::time::Duration;
+
+const MAX_DURATION_MS: u64 = 3_600_000;
+
+/// Time between two steps of a ramp; each step is a write to the database
+const STEP_MS: u64 = 50;
+
+/// How the duty cycle moves from where it was to the target over the duration of a ramp
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Easing {
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+}
+
+impl Easing {
+    pub fn parse(name: &str) -> Result<Easing, actixError> {
+        match name.to_lowercase().as_str() {
+            "linear" => Ok(Easing::Linear),
+            "ease-in" => Ok(Easing::EaseIn),
+            "ease-out" => Ok(Easing::EaseOut),
+            "ease-in-out" => Ok(Easing::EaseInOut),
+            _ => Err(error::ErrorBadRequest(format!(
+                "Invalid easing: '{}', use 'linear', 'ease-in', 'ease-out' or 'ease-in-out'",
+                name
+            ))),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Easing::Linear => "linear",
+            Easing::EaseIn => "ease-in",
+            Easing::EaseOut => "ease-out",
+            Easing::EaseInOut => "ease-in-out",
+        }
+    }
+
+    /// The part of the way done at `progress`, both from 0 to 1. Ease-in starts slow,
+    /// ease-out ends slow, and ease-in-out does both.
+    pub fn apply(self, progress: f64) -> f64 {
+        let t = progress.clamp(0.0, 1.0);
+        match self {
+            Easing::Linear => t,
+            Easing::EaseIn => t * t,
+            Easing::EaseOut => 1.0 - (1.0 - t) * (1.0 - t),
+            Easing::EaseInOut if t < 0.5 => 2.0 * t * t,
+            Easing::EaseInOut => 1.0 - 2.0 * (1.0 - t) * (1.0 - t),
+        }
+    }
+}
+
+/// A ramp to `duty_cycle` percent over `duration`
+#[derive(Debug, Clone)]
+pub struct Ramp {
+    pub duty_cycle: f64,
+    pub duration: Duration,
+    pub easing: Easing,
+}
+
+impl Ramp {
+    pub fn new(duty_cycle: f64, duration_ms: u64, easing: &str) -> Result<Ramp, actixError> {
+        if !(0.0..=100.0).contains(&duty_cycle) {
+            return Err(error::ErrorBadRequest("The duty cycle of a ramp is from 0 to 100 %"));
+        }
+        if !(1..=MAX_DURATION_MS).contains(&duration_ms) {
+            return Err(error::ErrorBadRequest(format!(
+                "A ramp lasts from 1 to {} ms",
+                MAX_DURATION_MS
+            )));
+        }
+
+        Ok(Ramp {
+            duty_cycle,
+            duration: Duration::from_millis(duration_ms),
+            easing: Easing::parse(easing)?,
+        })
+    }
+
+    /// The number of steps after the start, at least one
+    pub fn steps(&self) -> u32 {
+        let duration_ms = self.duration.as_millis() as u64;
+        duration_ms.div_ceil(STEP_MS).max(1) as u32
+    }
+
+    /// The time between two steps
+    pub fn interval(&self) -> Duration {
+        self.duration / self.steps()
+    }
+
+    /// The duty cycle at `step` of a ramp that started at `from`; the last step is the target
+    pub fn duty_cycle_at(&self, from: f64, step: u32) -> f64 {
+        let steps = self.steps();
+        if step >= steps {
+            return self.duty_cycle;
+        }
+        let progress = self.easing.apply(f64::from(step) / f64::from(steps));
+        from + (self.duty_cycle - from) * progress
+    }
+}
+
+/// Set the duty cycle, and the frequency if given, of GPIO #`gpio_id` through the DbExecutor,
+/// like a request from a client
+fn set_duty_cycle(
+    db: &Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex, gpio_id: i32, frequency: Option<f64>,
+    duty_cycle: f64, expected_version: Option<i32>, lease_id: Option<String>,
+) -> impl Future<Item = models::Gpio, Error = actixError> {
+    db.send(SetGpioPwm {
+        gpio_id,
+        frequency,
+        duty_cycle: Some(duty_cycle),
+        expected_version,
+        lease_id,
+        gpio_arc_mutex,
+    })
+    .from_err()
+    .and_then(|res| res)
+}
+
+/// Start `ramp` on GPIO #`gpio_id`, replacing the one running on it
+pub struct StartRamp {
+    pub gpio_id: i32,
+    pub ramp: Ramp,
+    pub expected_version: Option<i32>, // From If-Match
+    pub lease_id: Option<String>,
+}
+
+impl Message for StartRamp {
+    type Result = Result<models::Gpio, actixError>;
+}
+
+/// Turn the servo on GPIO #`gpio_id` to `angle` degrees, at its `max_speed` if it has one
+pub struct MoveServo {
+    pub gpio_id: i32,
+    pub angle: f64,
+    pub expected_version: Option<i32>, // From If-Match
+    pub lease_id: Option<String>,
+}
+
+impl Message for MoveServo {
+    type Result = Result<models::Gpio, actixError>;
+}
+
+/// Stop the ramp running on GPIO #`gpio_id`, leaving the duty cycle where it got to
+pub struct CancelRamp {
+    pub gpio_id: i32,
+}
+
+impl Message for CancelRamp {
+    type Result = Result<models::Ramp, actixError>;
+}
+
+struct Running {
+    id: u64,
+    ramp: Ramp,
+    from: f64,
+    step: u32,
+    duty_cycle: f64,
+    version: i32,
+    lease_id: Option<String>,
+    handle: Option<SpawnHandle>,
+}
+
+/// Actor running the ramps, one at a time per GPIO. Every step goes through `SetGpioPwm`,
+/// expecting the version the previous step left, so any other write to the GPIO stops the
+/// ramp.
+pub struct Ramper {
+    db: Addr<DbExecutor>,
+    gpio_arc_mutex: GpioArcMutex,
+    running: HashMap<i32, Running>,
+    next_id: u64,
+}
+
+impl Ramper {
+    pub fn new(db: Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex) -> Self {
+        Ramper {
+            db,
+            gpio_arc_mutex,
+            running: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn stop(&mut self, gpio_id: i32, ctx: &mut Context<Self>) -> Option<Running> {
+        let running = self.running.remove(&gpio_id)?;
+        if let Some(handle) = running.handle {
+            ctx.cancel_future(handle);
+        }
+        Some(running)
+    }
+
+    fn start(
+        &mut self, gpio_id: i32, ramp: Ramp, from: f64, version: i32, lease_id: Option<String>,
+        ctx: &mut Context<Self>,
+    ) {
+        self.stop(gpio_id, ctx);
+        self.next_id += 1;
+        info!(
+            "Ramping GPIO #{} from {} to {} % in {:?} ({})",
+            gpio_id,
+            from,
+            ramp.duty_cycle,
+            ramp.duration,
+            ramp.easing.name()
+        );
+
+        self.running.insert(
+            gpio_id,
+            Running {
+                id: self.next_id,
+                ramp,
+                from,
+                step: 0,
+                duty_cycle: from,
+                version,
+                lease_id,
+                handle: None,
+            },
+        );
+        self.schedule_next(gpio_id, ctx);
+    }
+
+    fn schedule_next(&mut self, gpio_id: i32, ctx: &mut Context<Self>) {
+        let running = match self.running.get_mut(&gpio_id) {
+            Some(running) => running,
+            None => return,
+        };
+        if running.step >= running.ramp.steps() {
+            info!("Finished ramp on GPIO #{} at {} %", gpio_id, running.duty_cycle);
+            self.running.remove(&gpio_id);
+            return;
+        }
+
+        let id = running.id;
+        running.handle = Some(ctx.run_later(running.ramp.interval(), move |act, ctx| {
+            act.run_step(gpio_id, id, ctx)
+        }));
+    }
+
+    fn run_step(&mut self, gpio_id: i32, id: u64, ctx: &mut Context<Self>) {
+        let running = match self.running.get(&gpio_id) {
+            Some(running) => running,
+            None => return,
+        };
+        let step = running.step + 1;
+        let duty_cycle = running.ramp.duty_cycle_at(running.from, step);
+        let set = set_duty_cycle(
+            &self.db,
+            self.gpio_arc_mutex.clone(),
+            gpio_id,
+            None,
+            duty_cycle,
+            Some(running.version),
+            running.lease_id.clone(),
+        );
+
+        ctx.spawn(wrap_future::<_, Self>(set).then(move |res, act, ctx| {
+            // The ramp may have been cancelled or replaced in the meantime
+            let running = match act.running.get_mut(&gpio_id) {
+                Some(running) if running.id == id => running,
+                _ => return fut::ok(()),
+            };
+            match res {
+                Ok(gpio) => {
+                    running.step = step;
+                    running.duty_cycle = duty_cycle;
+                    running.version = gpio.version;
+                    act.schedule_next(gpio_id, ctx);
+                }
+                Err(err) => {
+                    info!("Stopped ramp on GPIO #{}: {}", gpio_id, err);
+                    act.running.remove(&gpio_id);
+                }
+            }
+            fut::ok(())
+        }));
+    }
+}
+
+impl Actor for Ramper {
+    type Context = Context<Self>;
+}
+
+impl Handler<StartRamp> for Ramper {
+    type Result = ResponseActFuture<Self, models::Gpio, actixError>;
+
+    fn handle(&mut self, msg: StartRamp, ctx: &mut Self::Context) -> Self::Result {
+        let StartRamp {
+            gpio_id,
+            ramp,
+            expected_version,
+            lease_id,
+        } = msg;
+        self.stop(gpio_id, ctx);
+
+        // The first step writes the duty cycle the ramp starts from, so a GPIO that is not
+        // in mode 'pwm', is leased by someone else or changed since it was read fails here
+        let first_lease_id = lease_id.clone();
+        let db = self.db.clone();
+        let gpio_arc_mutex = self.gpio_arc_mutex.clone();
+        let start = self
+            .db
+            .send(GpioId { gpio_id })
+            .from_err()
+            .and_then(|res| res)
+            .and_then(move |gpio| {
+                let from = gpio.pwm_duty_cycle.unwrap_or(0.0);
+                let first = set_duty_cycle(
+                    &db,
+                    gpio_arc_mutex,
+                    gpio_id,
+                    None,
+                    from,
+                    expected_version,
+                    first_lease_id,
+                );
+                first.map(move |gpio| (gpio, from))
+            });
+
+        Box::new(wrap_future::<_, Self>(start).map(move |(gpio, from), act, ctx| {
+            act.start(gpio_id, ramp, from, gpio.version, lease_id, ctx);
+            gpio
+        }))
+    }
+}
+
+impl Handler<MoveServo> for Ramper {
+    type Result = ResponseActFuture<Self, models::Gpio, actixError>;
+
+    fn handle(&mut self, msg: MoveServo, ctx: &mut Self::Context) -> Self::Result {
+        let MoveServo {
+            gpio_id,
+            angle,
+            expected_version,
+            lease_id,
+        } = msg;
+        self.stop(gpio_id, ctx);
+
+        // A servo whose angle is not known yet, or without a maximum speed, is sent straight
+        // to the target; otherwise it is ramped there from its angle in a straight line
+        let first_lease_id = lease_id.clone();
+        let db = self.db.clone();
+        let gpio_arc_mutex = self.gpio_arc_mutex.clone();
+        let start = self
+            .db
+            .send(ServoId { gpio_id })
+            .from_err()
+            .and_then(|res| res)
+            .and_then(move |status| {
+                let servo = status.servo;
+                servo.check_angle(angle).map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+                let target = servo.duty_cycle(angle);
+                let duration = status
+                    .angle
+                    .map(|from| servo.travel_time(from, angle))
+                    .unwrap_or_default()
+                    .min(Duration::from_millis(MAX_DURATION_MS));
+                let from = match status.angle {
+                    Some(from) if duration.as_millis() > 0 => servo.duty_cycle(from),
+                    _ => target,
+                };
+                let ramp = Ramp {
+                    duty_cycle: target,
+                    duration,
+                    easing: Easing::Linear,
+                };
+                Ok((ramp, from))
+            })
+            .and_then(move |(ramp, from)| {
+                let first = set_duty_cycle(
+                    &db,
+                    gpio_arc_mutex,
+                    gpio_id,
+                    Some(SERVO_FREQUENCY),
+                    from,
+                    expected_version,
+                    first_lease_id,
+                );
+                first.map(move |gpio| (gpio, ramp, from))
+            });
+
+        Box::new(wrap_future::<_, Self>(start).map(move |(gpio, ramp, from), act, ctx| {
+            if ramp.duration.as_millis() > 0 {
+                act.start(gpio_id, ramp, from, gpio.version, lease_id, ctx);
+            }
+            gpio
+        }))
+    }
+}
+
+impl Handler<CancelRamp> for Ramper {
+    type Result = Result<models::Ramp, actixError>;
+
+    fn handle(&mut self, msg: CancelRamp, ctx: &mut Self::Context) -> Self::Result {
+        let running = self.stop(msg.gpio_id, ctx).ok_or_else(|| {
+            error::ErrorNotFound(format!("No ramp is running on GPIO #{}", msg.gpio_id))
+        })?;
+        info!("Cancelled ramp on GPIO #{} at {} %", msg.gpio_id, running.duty_cycle);
+
+        Ok(models::Ramp {
+            gpio_id: msg.gpio_id,
+            easing: running.ramp.easing.name().to_string(),
+            target_duty_cycle: running.ramp.duty_cycle,
+            duty_cycle: running.duty_cycle,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn easings_must_start_at_zero_and_end_at_one() {
+        for name in ["linear", "ease-in", "ease-out", "EASE-IN-OUT"].iter() {
+            let easing = Easing::parse(name).unwrap();
+            assert_eq!(easing.apply(0.0), 0.0);
+            assert_eq!(easing.apply(1.0), 1.0);
+            assert_eq!(easing.apply(2.0), 1.0);
+        }
+        assert_eq!(Easing::Linear.apply(0.5), 0.5);
+        assert_eq!(Easing::EaseIn.apply(0.5), 0.25);
+        assert_eq!(Easing::EaseOut.apply(0.5), 0.75);
+        assert_eq!(Easing::EaseInOut.apply(0.25), 0.125);
+        assert_eq!(Easing::EaseInOut.apply(0.75), 0.875);
+    }
+
+    #[test]
+    fn ramp_steps_must_end_at_target() {
+        let ramp = Ramp::new(100.0, 1000, "linear").unwrap();
+
+        assert_eq!(ramp.steps(), 20);
+        assert_eq!(ramp.interval(), Duration::from_millis(50));
+        assert_eq!(ramp.duty_cycle_at(20.0, 0), 20.0);
+        assert_eq!(ramp.duty_cycle_at(20.0, 10), 60.0);
+        assert_eq!(ramp.duty_cycle_at(20.0, 20), 100.0);
+
+        let short = Ramp::new(0.0, 10, "ease-out").unwrap();
+        assert_eq!(short.steps(), 1);
+        assert_eq!(short.duty_cycle_at(80.0, 1), 0.0);
+    }
+
+    #[test]
+    fn invalid_ramps_must_fail() {
+        assert!(Ramp::new(101.0, 1000, "linear").is_err());
+        assert!(Ramp::new(-1.0, 1000, "linear").is_err());
+        assert!(Ramp::new(50.0, 0, "linear").is_err());
+        assert!(Ramp::new(50.0, 1000, "bounce").is_err());
+    }
+}
@@ -1,44 +1,155 @@
 use crate::errors::RpWebError;
+use crate::models::LevelChange;
 use crate::utilities::i32_to_u8;
 use parking_lot::Mutex;
 #[cfg(target_arch = "arm")]
-use rppal::gpio::Gpio;
+use rppal::gpio::{Gpio, Level, OutputPin};
+#[cfg(target_arch = "arm")]
+use rppal::pwm::{Channel, Polarity, Pwm};
+use std::collections::HashMap;
 use std::sync::Arc;
 
+/// Frequency of a GPIO when it is set up in mode 'pwm'
+pub const DEFAULT_PWM_FREQUENCY: f64 = 1000.0;
+
+/// Highest frequency for software PWM, which is timed by a thread
+pub const MAX_SOFTWARE_PWM_FREQUENCY: f64 = 10_000.0;
+
+/// Highest frequency for hardware PWM
+pub const MAX_HARDWARE_PWM_FREQUENCY: f64 = 1_000_000.0;
+
+/// Stands in for the GPIO peripheral on other architectures, and remembers the level
+/// each pin was set to, and the PWM of each pin in mode 'pwm', so they can be read back
 #[cfg(not(target_arch = "arm"))]
-pub type GpioArcMutex = Arc<Mutex<i32>>;
+#[derive(Debug, Default)]
+pub struct SimulatedGpio {
+    levels: HashMap<u8, &'static str>,
+    // GPIO -> (frequency, duty cycle in percent)
+    pwm: HashMap<u8, (f64, f64)>,
+}
+
+#[cfg(not(target_arch = "arm"))]
+type GpioHandle = SimulatedGpio;
+
+/// The GPIO peripheral, with the pins and channels running PWM: a software PWM stops when
+/// its pin is dropped, a hardware PWM when its channel is
+#[cfg(target_arch = "arm")]
+pub struct RpiGpio {
+    gpio: Gpio,
+    software_pwm: HashMap<u8, OutputPin>,
+    hardware_pwm: HashMap<u8, Pwm>,
+    // GPIO -> (frequency, duty cycle in percent)
+    pwm: HashMap<u8, (f64, f64)>,
+}
 
 #[cfg(target_arch = "arm")]
-pub type GpioArcMutex = Arc<Mutex<Gpio>>;
+type GpioHandle = RpiGpio;
+
+pub type GpioArcMutex = Arc<Mutex<GpioHandle>>;
 
 #[cfg(not(target_arch = "arm"))]
-pub fn create_gpio_arc_mutex() -> Result<GpioArcMutex, String> {
-    Ok(Arc::new(Mutex::new(0)))
+pub fn create_gpio_arc_mutex() -> Result<GpioArcMutex, RpWebError> {
+    Ok(Arc::new(Mutex::new(SimulatedGpio::default())))
 }
 
 #[cfg(target_arch = "arm")]
 pub fn create_gpio_arc_mutex() -> Result<GpioArcMutex, RpWebError> {
     let gpio = Gpio::new()?;
-    Ok(Arc::new(Mutex::new(gpio)))
+    Ok(Arc::new(Mutex::new(RpiGpio {
+        gpio,
+        software_pwm: HashMap::new(),
+        hardware_pwm: HashMap::new(),
+        pwm: HashMap::new(),
+    })))
 }
 
 #[cfg(not(target_arch = "arm"))]
-pub fn set_gpio_level_rpi(
-    gpio_id: i32, level: &str, gpio_arc_mutex: GpioArcMutex,
+fn set_gpio_level_locked(
+    gpio_id: i32, level: &str, data: &mut GpioHandle,
 ) -> Result<(), RpWebError> {
-    let _gpio_id_u8 = i32_to_u8(gpio_id)?;
-    let mut data = gpio_arc_mutex.lock();
+    let gpio_id_u8 = i32_to_u8(gpio_id)?;
     match level {
-        "high" => *data += 1,
-        "low" => *data += 1,
+        "high" => data.levels.insert(gpio_id_u8, "high"),
+        "low" => data.levels.insert(gpio_id_u8, "low"),
         _ => {
             let errs = format!("Invalid level: '{}'", level);
             return Err(RpWebError::new(&errs));
         }
+    };
+    Ok(())
+}
+
+pub fn set_gpio_level_rpi(
+    gpio_id: i32, level: &str, gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    let mut data = gpio_arc_mutex.lock();
+    set_gpio_level_locked(gpio_id, level, &mut data)
+}
+
+/// Read back the level of GPIO #`gpio_id`, 'high' or 'low'. Simulated pins that were
+/// never set are 'low'.
+#[cfg(not(target_arch = "arm"))]
+pub fn get_gpio_level_rpi(gpio_id: i32, gpio_arc_mutex: GpioArcMutex) -> Result<String, RpWebError> {
+    let data = gpio_arc_mutex.lock();
+    let gpio_id_u8 = i32_to_u8(gpio_id)?;
+    let level = data.levels.get(&gpio_id_u8).cloned().unwrap_or("low");
+    Ok(level.to_string())
+}
+
+/// Read back the level of GPIO #`gpio_id`, 'high' or 'low'
+#[cfg(target_arch = "arm")]
+pub fn get_gpio_level_rpi(gpio_id: i32, gpio_arc_mutex: GpioArcMutex) -> Result<String, RpWebError> {
+    let data = gpio_arc_mutex.lock();
+    let gpio_id_u8 = i32_to_u8(gpio_id)?;
+    let level = match data.gpio.get(gpio_id_u8)?.read() {
+        Level::High => "high",
+        Level::Low => "low",
+    };
+    Ok(level.to_string())
+}
+
+/// Set the level of several GPIOs while holding the GPIO lock only once. If one of them
+/// fails, the GPIOs already changed are set back to their previous level.
+pub fn set_gpio_levels_rpi(
+    changes: &[LevelChange], gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    let mut data = gpio_arc_mutex.lock();
+    for (idx, change) in changes.iter().enumerate() {
+        if let Err(err) = set_gpio_level_locked(change.gpio_id, &change.level, &mut data) {
+            error!("Failed to set GPIO #{} to '{}': {}", change.gpio_id, change.level, err);
+            revert_gpio_levels_locked(&changes[..idx], &mut data);
+            return Err(err);
+        }
     }
     Ok(())
 }
 
+/// Set GPIOs back to their level before `changes`, in reverse order
+pub fn revert_gpio_levels_rpi(changes: &[LevelChange], gpio_arc_mutex: GpioArcMutex) {
+    let mut data = gpio_arc_mutex.lock();
+    revert_gpio_levels_locked(changes, &mut data);
+}
+
+fn revert_gpio_levels_locked(changes: &[LevelChange], data: &mut GpioHandle) {
+    for change in changes.iter().rev() {
+        match change.previous_level.as_deref() {
+            Some(level @ "high") | Some(level @ "low") => {
+                match set_gpio_level_locked(change.gpio_id, level, data) {
+                    Ok(_) => info!("Reverted GPIO #{} to '{}'", change.gpio_id, level),
+                    Err(err) => error!(
+                        "Failed to revert GPIO #{} to '{}': {}",
+                        change.gpio_id, level, err
+                    ),
+                }
+            }
+            _ => warn!(
+                "GPIO #{} had no level before '{}', it is left as is",
+                change.gpio_id, change.level
+            ),
+        }
+    }
+}
+
 #[cfg(not(target_arch = "arm"))]
 pub fn reset_gpio_output_pin_rpi(
     _gpio_id: i32, _gpio_arc_mutex: GpioArcMutex
@@ -53,7 +164,7 @@ pub fn reset_gpio_output_pin_rpi(
 
     let data = gpio_arc_mutex.lock();
     let gpio_id_u8 = i32_to_u8(gpio_id)?;
-    let mut output_pin = (*data).get(gpio_id_u8)?.into_output();
+    let mut output_pin = data.gpio.get(gpio_id_u8)?.into_output();
     output_pin.set_reset_on_drop(true);
 
     Ok(())
@@ -73,7 +184,7 @@ pub fn set_reset_on_drop_false_for_output_pin_rpi(
 
     let data = gpio_arc_mutex.lock();
     let gpio_id_u8 = i32_to_u8(gpio_id)?;
-    let mut output_pin = (*data).get(gpio_id_u8)?.into_output();
+    let mut output_pin = data.gpio.get(gpio_id_u8)?.into_output();
     output_pin.set_reset_on_drop(false);
 
     Ok(())
@@ -81,13 +192,12 @@ pub fn set_reset_on_drop_false_for_output_pin_rpi(
 
 //#[allow(unused_mut)] // output_pin needs mut but generates a warning
 #[cfg(target_arch = "arm")]
-pub fn set_gpio_level_rpi(
-    gpio_id: i32, level: &str, gpio_arc_mutex: GpioArcMutex,
+fn set_gpio_level_locked(
+    gpio_id: i32, level: &str, data: &mut GpioHandle,
 ) -> Result<(), RpWebError> {
     let gpio_id_u8 = i32_to_u8(gpio_id)?;
-    let data = gpio_arc_mutex.lock();
 
-    let mut output_pin = (*data).get(gpio_id_u8)?.into_output();
+    let mut output_pin = data.gpio.get(gpio_id_u8)?.into_output();
 
     match level {
         "high" => {
@@ -107,6 +217,132 @@ pub fn set_gpio_level_rpi(
     Ok(())
 }
 
+/// Hardware PWM channel of GPIO #`gpio_id`, if it has one: PWM0 is on GPIO 12 and 18, PWM1
+/// on GPIO 13 and 19. Only one GPIO per channel can use it, as set up in /boot/config.txt;
+/// `validate_setup` rejects a configuration with two.
+pub fn hardware_pwm_channel(gpio_id: i32) -> Option<i32> {
+    match gpio_id {
+        12 | 18 => Some(0),
+        13 | 19 => Some(1),
+        _ => None,
+    }
+}
+
+/// Check a frequency in Hz and a duty cycle in percent for GPIO #`gpio_id`
+pub fn check_pwm(gpio_id: i32, frequency: f64, duty_cycle: f64) -> Result<(), RpWebError> {
+    let max_frequency = match hardware_pwm_channel(gpio_id) {
+        Some(_) => MAX_HARDWARE_PWM_FREQUENCY,
+        None => MAX_SOFTWARE_PWM_FREQUENCY,
+    };
+    if !(frequency > 0.0 && frequency <= max_frequency) {
+        let errs = format!(
+            "Invalid PWM frequency for GPIO #{}: {} - use more than 0 and at most {} Hz",
+            gpio_id, frequency, max_frequency
+        );
+        return Err(RpWebError::new(&errs));
+    }
+    if !(0.0..=100.0).contains(&duty_cycle) {
+        let errs = format!(
+            "Invalid PWM duty cycle for GPIO #{}: {} - use 0 to 100 percent",
+            gpio_id, duty_cycle
+        );
+        return Err(RpWebError::new(&errs));
+    }
+    Ok(())
+}
+
+/// Run PWM on GPIO #`gpio_id` at `frequency` Hz with `duty_cycle` percent, replacing the PWM
+/// running on it
+#[cfg(not(target_arch = "arm"))]
+pub fn set_gpio_pwm_rpi(
+    gpio_id: i32, frequency: f64, duty_cycle: f64, gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    check_pwm(gpio_id, frequency, duty_cycle)?;
+    let gpio_id_u8 = i32_to_u8(gpio_id)?;
+    gpio_arc_mutex.lock().pwm.insert(gpio_id_u8, (frequency, duty_cycle));
+    Ok(())
+}
+
+/// Run PWM on GPIO #`gpio_id` at `frequency` Hz with `duty_cycle` percent, replacing the PWM
+/// running on it. GPIOs with a hardware channel use it, the others get software PWM.
+#[cfg(target_arch = "arm")]
+pub fn set_gpio_pwm_rpi(
+    gpio_id: i32, frequency: f64, duty_cycle: f64, gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    check_pwm(gpio_id, frequency, duty_cycle)?;
+    let gpio_id_u8 = i32_to_u8(gpio_id)?;
+    let mut data = gpio_arc_mutex.lock();
+
+    match hardware_pwm_channel(gpio_id) {
+        Some(channel) => {
+            if let Some(pwm) = data.hardware_pwm.get(&gpio_id_u8) {
+                pwm.set_frequency(frequency, duty_cycle / 100.0)?;
+            } else {
+                let channel = if channel == 0 { Channel::Pwm0 } else { Channel::Pwm1 };
+                let pwm = Pwm::with_frequency(
+                    channel,
+                    frequency,
+                    duty_cycle / 100.0,
+                    Polarity::Normal,
+                    true,
+                )?;
+                data.hardware_pwm.insert(gpio_id_u8, pwm);
+            }
+        }
+        None => {
+            if !data.software_pwm.contains_key(&gpio_id_u8) {
+                let output_pin = data.gpio.get(gpio_id_u8)?.into_output();
+                data.software_pwm.insert(gpio_id_u8, output_pin);
+            }
+            if let Some(output_pin) = data.software_pwm.get_mut(&gpio_id_u8) {
+                output_pin.set_pwm_frequency(frequency, duty_cycle / 100.0)?;
+            }
+        }
+    }
+
+    info!(
+        "Set PWM of GPIO #{} to {} Hz at {}%",
+        gpio_id, frequency, duty_cycle
+    );
+    data.pwm.insert(gpio_id_u8, (frequency, duty_cycle));
+    Ok(())
+}
+
+/// Frequency and duty cycle of the PWM running on GPIO #`gpio_id`, if any
+pub fn get_gpio_pwm_rpi(
+    gpio_id: i32, gpio_arc_mutex: GpioArcMutex,
+) -> Result<Option<(f64, f64)>, RpWebError> {
+    let gpio_id_u8 = i32_to_u8(gpio_id)?;
+    Ok(gpio_arc_mutex.lock().pwm.get(&gpio_id_u8).cloned())
+}
+
+/// Stop the PWM running on GPIO #`gpio_id`, if any, when it leaves mode 'pwm'
+#[cfg(not(target_arch = "arm"))]
+pub fn stop_gpio_pwm_rpi(gpio_id: i32, gpio_arc_mutex: GpioArcMutex) -> Result<(), RpWebError> {
+    let gpio_id_u8 = i32_to_u8(gpio_id)?;
+    gpio_arc_mutex.lock().pwm.remove(&gpio_id_u8);
+    Ok(())
+}
+
+/// Stop the PWM running on GPIO #`gpio_id`, if any, when it leaves mode 'pwm'
+#[cfg(target_arch = "arm")]
+pub fn stop_gpio_pwm_rpi(gpio_id: i32, gpio_arc_mutex: GpioArcMutex) -> Result<(), RpWebError> {
+    let gpio_id_u8 = i32_to_u8(gpio_id)?;
+    let mut data = gpio_arc_mutex.lock();
+
+    if let Some(pwm) = data.hardware_pwm.remove(&gpio_id_u8) {
+        pwm.disable()?;
+    }
+    if let Some(mut output_pin) = data.software_pwm.remove(&gpio_id_u8) {
+        output_pin.clear_pwm()?;
+        output_pin.set_low();
+    }
+    if data.pwm.remove(&gpio_id_u8).is_some() {
+        info!("Stopped PWM of GPIO #{}", gpio_id);
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,4 +370,63 @@ mod tests {
 
         assert!(res.is_err());
     }
+
+    #[test]
+    fn get_gpio_level_rpi_must_return_level_set() {
+        let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+        set_gpio_level_rpi(1, "high", gpio_arc_mutex.clone()).unwrap();
+
+        assert_eq!(get_gpio_level_rpi(1, gpio_arc_mutex).unwrap(), "high");
+    }
+
+    #[test]
+    fn set_gpio_levels_rpi_must_succeed() {
+        let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+        let changes = vec![
+            LevelChange::new(1, "high", Some("low")),
+            LevelChange::new(2, "low", Some("high")),
+        ];
+        let res = set_gpio_levels_rpi(&changes, gpio_arc_mutex);
+
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn set_gpio_levels_rpi_unknown_must_fail() {
+        let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+        let changes = vec![
+            LevelChange::new(1, "high", Some("low")),
+            LevelChange::new(2, "unknown_level", Some("high")),
+        ];
+        let res = set_gpio_levels_rpi(&changes, gpio_arc_mutex);
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn hardware_pwm_channel_must_match_pins() {
+        assert_eq!(hardware_pwm_channel(18), Some(0));
+        assert_eq!(hardware_pwm_channel(13), Some(1));
+        assert_eq!(hardware_pwm_channel(17), None);
+    }
+
+    #[test]
+    fn set_gpio_pwm_rpi_must_be_read_back_until_stopped() {
+        let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+        set_gpio_pwm_rpi(17, 500.0, 25.0, gpio_arc_mutex.clone()).unwrap();
+        assert_eq!(get_gpio_pwm_rpi(17, gpio_arc_mutex.clone()).unwrap(), Some((500.0, 25.0)));
+
+        stop_gpio_pwm_rpi(17, gpio_arc_mutex.clone()).unwrap();
+        assert_eq!(get_gpio_pwm_rpi(17, gpio_arc_mutex).unwrap(), None);
+    }
+
+    #[test]
+    fn set_gpio_pwm_rpi_out_of_range_must_fail() {
+        let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+
+        assert!(set_gpio_pwm_rpi(17, 0.0, 25.0, gpio_arc_mutex.clone()).is_err());
+        assert!(set_gpio_pwm_rpi(17, 50_000.0, 25.0, gpio_arc_mutex.clone()).is_err());
+        assert!(set_gpio_pwm_rpi(18, 50_000.0, 25.0, gpio_arc_mutex.clone()).is_ok());
+        assert!(set_gpio_pwm_rpi(17, 500.0, 100.5, gpio_arc_mutex).is_err());
+    }
 }
@@ -0,0 +1,454 @@
+// Rules linking input GPIOs to outputs, e.g. "when GPIO #4 goes high, pulse GPIO #17 for
+// 30 s" or "GPIO #22 mirrors GPIO #5 inverted", run by the server itself
+
+use crate::errors::RpWebError;
+use crate::handlers::{DbExecutor, EnabledRules, RecordRuleFiring, ToggleGpioLevel};
+use crate::models::{NewRule, Rule, RuleRequest};
+use crate::rpi::{get_gpio_level_rpi, GpioArcMutex};
+use crate::sequencer::{set_level, Pattern, Sequencer, StartSequence};
+use crate::settings::RuleConfig;
+use crate::utilities::{current_time, record_audit_db};
+use actix::fut::{wrap_future, ActorFuture};
+use actix::{Actor, Addr, AsyncContext, Context};
+use actix_web::Error as actixError;
+use diesel::prelude::*;
+use futures::Future;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// How often the inputs of the rules are read
+const RULE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often the rules are loaded again, to pick up rules created, enabled or disabled
+const RULE_RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Event in the audit history when a rule fires
+pub const RULE_EVENT: &str = "rule";
+
+/// What a rule does to its output when it fires
+#[derive(Debug, Clone)]
+pub enum RuleAction {
+    SetLevel(String),
+    Pulse(Pattern),
+    Toggle,
+}
+
+/// Check `request` and turn it into a row for a rule from GPIO #`input_gpio` to
+/// GPIO #`output_gpio`. The GPIOs must have been checked by the caller.
+pub fn new_rule(
+    request: &RuleRequest, input_gpio: i32, output_gpio: i32, from_config: bool,
+) -> Result<NewRule, RpWebError> {
+    let trigger = request.trigger.to_lowercase();
+    let action = request.action.as_ref().map(|action| action.to_lowercase());
+    let level = request.level.as_ref().map(|level| level.to_lowercase());
+
+    if input_gpio == output_gpio {
+        return Err(RpWebError::new(&format!(
+            "A rule can not have GPIO #{} as both input and output",
+            input_gpio
+        )));
+    }
+
+    let action = match (trigger.as_str(), action.as_deref()) {
+        ("mirror", None) | ("mirror", Some("mirror")) => "mirror".to_string(),
+        ("mirror", Some(_)) => {
+            return Err(RpWebError::new("A 'mirror' rule has no other action"));
+        }
+        ("rising", Some(action)) | ("falling", Some(action)) | ("both", Some(action)) => {
+            action.to_string()
+        }
+        ("rising", None) | ("falling", None) | ("both", None) => {
+            return Err(RpWebError::new(&format!("A '{}' rule needs an 'action'", trigger)));
+        }
+        _ => {
+            return Err(RpWebError::new(&format!(
+                "Invalid trigger '{}' - use 'rising', 'falling', 'both' or 'mirror'",
+                trigger
+            )));
+        }
+    };
+
+    match action.as_str() {
+        "set_level" | "pulse" if level.is_none() => {
+            return Err(RpWebError::new(&format!("Action '{}' needs a 'level'", action)));
+        }
+        "set_level" | "toggle" | "mirror" => {}
+        "pulse" => {
+            let duration_ms = request
+                .duration_ms
+                .ok_or_else(|| RpWebError::new("Action 'pulse' needs a 'duration_ms'"))?;
+            Pattern::pulse(level.as_deref().unwrap_or_default(), u64::from(duration_ms))
+                .map_err(|err| RpWebError::new(&err.to_string()))?;
+        }
+        _ => {
+            return Err(RpWebError::new(&format!(
+                "Invalid action '{}' - use 'set_level', 'pulse' or 'toggle'",
+                action
+            )));
+        }
+    }
+
+    Ok(NewRule {
+        name: request.name.clone(),
+        input_gpio,
+        trigger,
+        output_gpio,
+        gpio_level: if action == "set_level" || action == "pulse" { level } else { None },
+        duration_ms: if action == "pulse" {
+            request.duration_ms.map(|duration_ms| duration_ms as i32)
+        } else {
+            None
+        },
+        inverted: (action == "mirror" && request.inverted.unwrap_or(false)) as i32,
+        action,
+        enabled: request.enabled.unwrap_or(true) as i32,
+        from_config: from_config as i32,
+    })
+}
+
+/// What `rule` does when its input goes from `previous` to `current`. The first time an input
+/// is read, there is no previous level: edges are not detected, but mirrors are set.
+pub fn rule_action(rule: &Rule, previous: Option<&str>, current: &str) -> Option<RuleAction> {
+    if previous == Some(current) {
+        return None;
+    }
+
+    if rule.trigger == "mirror" {
+        let level = match (current, rule.inverted == 1) {
+            ("high", false) | ("low", true) => "high",
+            _ => "low",
+        };
+        return Some(RuleAction::SetLevel(level.to_string()));
+    }
+
+    previous?;
+    let fires = match rule.trigger.as_str() {
+        "rising" => current == "high",
+        "falling" => current == "low",
+        "both" => true,
+        _ => false,
+    };
+    if !fires {
+        return None;
+    }
+
+    let level = rule.gpio_level.clone().unwrap_or_default();
+    match rule.action.as_str() {
+        "set_level" => Some(RuleAction::SetLevel(level)),
+        "toggle" => Some(RuleAction::Toggle),
+        "pulse" => {
+            let duration_ms = rule.duration_ms.unwrap_or_default() as u64;
+            match Pattern::pulse(&level, duration_ms) {
+                Ok(pattern) => Some(RuleAction::Pulse(pattern)),
+                Err(err) => {
+                    error!("Rule #{} has an invalid pulse: {}", rule.rule_id, err);
+                    None
+                }
+            }
+        }
+        _ => None,
+    }
+}
+
+pub fn create_rule_db(new: &NewRule, conn: &SqliteConnection) -> Result<Rule, RpWebError> {
+    use crate::schema::rules::dsl::*;
+
+    let created = conn.transaction::<_, RpWebError, _>(|| {
+        diesel::insert_into(rules).values(new).execute(conn)?;
+        let created = rules.order(rule_id.desc()).first::<Rule>(conn)?;
+        Ok(created)
+    })?;
+
+    info!(
+        "Created rule #{}: GPIO #{} {} -> {} GPIO #{}",
+        created.rule_id, created.input_gpio, created.trigger, created.action, created.output_gpio
+    );
+    Ok(created)
+}
+
+/// Rule `id`, or `NotFound`
+pub fn load_rule_db(id: i32, conn: &SqliteConnection) -> Result<Rule, RpWebError> {
+    use crate::schema::rules::dsl::*;
+
+    rules
+        .filter(rule_id.eq(id))
+        .first::<Rule>(conn)
+        .optional()?
+        .ok_or_else(|| RpWebError::NotFound(format!("No rule #{}", id)))
+}
+
+pub fn delete_rule_db(id: i32, conn: &SqliteConnection) -> Result<Rule, RpWebError> {
+    use crate::schema::rules::dsl::*;
+
+    let rule = load_rule_db(id, conn)?;
+    diesel::delete(rules.filter(rule_id.eq(id))).execute(conn)?;
+
+    info!("Deleted rule #{}", id);
+    Ok(rule)
+}
+
+pub fn enable_rule_db(
+    id: i32, is_enabled: bool, conn: &SqliteConnection,
+) -> Result<Rule, RpWebError> {
+    use crate::schema::rules::dsl::*;
+
+    let n_updated = diesel::update(rules.filter(rule_id.eq(id)))
+        .set(enabled.eq(is_enabled as i32))
+        .execute(conn)?;
+    if n_updated == 0 {
+        return Err(RpWebError::NotFound(format!("No rule #{}", id)));
+    }
+
+    info!("Rule #{} {}", id, if is_enabled { "enabled" } else { "disabled" });
+    load_rule_db(id, conn)
+}
+
+/// Replace the rules from the configuration. Rules created through the API are kept.
+pub fn setup_rules_db(configs: &[RuleConfig], conn: &SqliteConnection) -> Result<(), RpWebError> {
+    use crate::schema::rules::dsl::*;
+
+    diesel::delete(rules.filter(from_config.eq(1))).execute(conn)?;
+    for config in configs.iter() {
+        let new = new_rule(&config.to_request(), config.input, config.output, true)?;
+        create_rule_db(&new, conn)?;
+    }
+
+    Ok(())
+}
+
+/// Count a firing of `rule`, and record it in the audit history of its output
+pub fn record_rule_firing_db(
+    rule: &Rule, level: Option<&str>, conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    use crate::schema::rules::dsl::*;
+
+    diesel::update(rules.filter(rule_id.eq(rule.rule_id)))
+        .set((fire_count.eq(fire_count + 1), last_fired.eq(current_time())))
+        .execute(conn)?;
+
+    let detail = format!(
+        "Rule #{}{}: GPIO #{} {}",
+        rule.rule_id,
+        rule.name
+            .as_ref()
+            .map(|rule_name| format!(" '{}'", rule_name))
+            .unwrap_or_default(),
+        rule.input_gpio,
+        rule.trigger
+    );
+    record_audit_db(rule.output_gpio, RULE_EVENT, level, Some(&detail), conn)
+}
+
+/// Actor reading the inputs of the enabled rules every 50 ms and running the actions of the
+/// rules that fire: levels through the DbExecutor and pulses through the Sequencer, like
+/// requests from clients.
+pub struct RuleEngine {
+    db: Addr<DbExecutor>,
+    sequencer: Addr<Sequencer>,
+    gpio_arc_mutex: GpioArcMutex,
+    rules: Vec<Rule>,
+    // Level of the input of each rule when it was last read
+    levels: HashMap<i32, String>,
+}
+
+impl RuleEngine {
+    pub fn new(
+        db: Addr<DbExecutor>, sequencer: Addr<Sequencer>, gpio_arc_mutex: GpioArcMutex,
+    ) -> Self {
+        RuleEngine {
+            db,
+            sequencer,
+            gpio_arc_mutex,
+            rules: vec![],
+            levels: HashMap::new(),
+        }
+    }
+
+    fn poll(&mut self, ctx: &mut Context<Self>) {
+        let mut current: HashMap<i32, String> = HashMap::new();
+        let mut fired = vec![];
+
+        for rule in self.rules.iter() {
+            let level = match current.entry(rule.input_gpio) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    match get_gpio_level_rpi(rule.input_gpio, self.gpio_arc_mutex.clone()) {
+                        Ok(level) => entry.insert(level),
+                        Err(err) => {
+                            error!(
+                                "Can not read GPIO #{} for rule #{}: {}",
+                                rule.input_gpio, rule.rule_id, err
+                            );
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            let previous = self.levels.insert(rule.rule_id, level.clone());
+            if let Some(action) = rule_action(rule, previous.as_deref(), level) {
+                fired.push((rule.clone(), action));
+            }
+        }
+
+        for (rule, action) in fired {
+            self.fire(rule, action, ctx);
+        }
+    }
+
+    fn fire(&self, rule: Rule, action: RuleAction, ctx: &mut Context<Self>) {
+        let output = rule.output_gpio;
+        let gpio_arc_mutex = self.gpio_arc_mutex.clone();
+
+        let run: Box<dyn Future<Item = Option<String>, Error = actixError>> = match action {
+            RuleAction::SetLevel(level) => Box::new(
+                set_level(&self.db, gpio_arc_mutex, output, level, None)
+                    .map(|gpio| gpio.gpio_level),
+            ),
+            RuleAction::Toggle => Box::new(
+                self.db
+                    .send(ToggleGpioLevel {
+                        gpio_id: output,
+                        expected_version: None,
+                        lease_id: None,
+                        gpio_arc_mutex,
+                    })
+                    .from_err()
+                    .and_then(|res| res.map(|gpio| gpio.gpio_level)),
+            ),
+            RuleAction::Pulse(pattern) => Box::new(
+                self.sequencer
+                    .send(StartSequence {
+                        gpio_id: output,
+                        pattern,
+                        lease_id: None,
+                    })
+                    .from_err()
+                    .and_then(|res| res.map(|gpio| gpio.gpio_level)),
+            ),
+        };
+
+        let id = rule.rule_id;
+        let db = self.db.clone();
+        let run = run
+            .and_then(move |gpio_level| {
+                info!("Rule #{} fired", rule.rule_id);
+                db.send(RecordRuleFiring { rule, gpio_level })
+                    .from_err()
+                    .and_then(|res| res)
+            })
+            .map_err(move |err| error!("Rule #{} failed: {}", id, err));
+        ctx.spawn(wrap_future(run));
+    }
+
+    fn reload(&mut self, ctx: &mut Context<Self>) {
+        let load = self.db.send(EnabledRules).from_err::<actixError>().and_then(|res| res);
+
+        ctx.spawn(
+            wrap_future::<_, Self>(load)
+                .map(|rules, act, _| {
+                    // A rule that is enabled again starts without a previous level
+                    act.levels
+                        .retain(|id, _| rules.iter().any(|rule| rule.rule_id == *id));
+                    act.rules = rules;
+                })
+                .map_err(|err, _, _| error!("Loading rules failed: {}", err)),
+        );
+    }
+}
+
+impl Actor for RuleEngine {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.reload(ctx);
+        ctx.run_interval(RULE_RELOAD_INTERVAL, |act, ctx| act.reload(ctx));
+        ctx.run_interval(RULE_POLL_INTERVAL, |act, ctx| act.poll(ctx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::GpioIdentifier;
+
+    fn request(trigger: &str, action: Option<&str>, level: Option<&str>) -> RuleRequest {
+        RuleRequest {
+            name: None,
+            input: GpioIdentifier::Id(4),
+            trigger: trigger.to_string(),
+            output: GpioIdentifier::Id(17),
+            action: action.map(str::to_string),
+            level: level.map(str::to_string),
+            duration_ms: None,
+            inverted: None,
+            enabled: None,
+        }
+    }
+
+    fn rule(new: NewRule) -> Rule {
+        Rule {
+            rule_id: 1,
+            name: new.name,
+            input_gpio: new.input_gpio,
+            trigger: new.trigger,
+            output_gpio: new.output_gpio,
+            action: new.action,
+            gpio_level: new.gpio_level,
+            duration_ms: new.duration_ms,
+            inverted: new.inverted,
+            enabled: new.enabled,
+            from_config: new.from_config,
+            fire_count: 0,
+            last_fired: None,
+        }
+    }
+
+    #[test]
+    fn new_rule_must_check_trigger_and_action() {
+        let set_level = request("rising", Some("set_level"), Some("HIGH"));
+        assert!(new_rule(&set_level, 4, 17, false).is_ok());
+        assert!(new_rule(&request("mirror", None, None), 4, 17, false).is_ok());
+        assert!(new_rule(&request("rising", None, None), 4, 17, false).is_err());
+        assert!(new_rule(&request("mirror", Some("toggle"), None), 4, 17, false).is_err());
+        assert!(new_rule(&request("sometimes", Some("toggle"), None), 4, 17, false).is_err());
+        assert!(new_rule(&request("both", Some("pulse"), Some("high")), 4, 17, false).is_err());
+        assert!(new_rule(&request("both", Some("toggle"), None), 4, 4, false).is_err());
+    }
+
+    #[test]
+    fn pulse_rule_must_fire_on_rising_edge_only() {
+        let mut pulse = request("rising", Some("pulse"), Some("high"));
+        pulse.duration_ms = Some(30_000);
+        let rule = rule(new_rule(&pulse, 4, 17, false).unwrap());
+
+        assert!(rule_action(&rule, None, "high").is_none());
+        assert!(rule_action(&rule, Some("high"), "high").is_none());
+        assert!(rule_action(&rule, Some("high"), "low").is_none());
+        match rule_action(&rule, Some("low"), "high") {
+            Some(RuleAction::Pulse(Pattern::Pulse { level, duration })) => {
+                assert_eq!(level, "high");
+                assert_eq!(duration, Duration::from_secs(30));
+            }
+            other => panic!("Expected a pulse, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn inverted_mirror_must_follow_input() {
+        let mut mirror = request("mirror", None, None);
+        mirror.inverted = Some(true);
+        let rule = rule(new_rule(&mirror, 5, 22, false).unwrap());
+
+        match rule_action(&rule, None, "high") {
+            Some(RuleAction::SetLevel(level)) => assert_eq!(level, "low"),
+            other => panic!("Expected a level, got {:?}", other),
+        }
+        match rule_action(&rule, Some("high"), "low") {
+            Some(RuleAction::SetLevel(level)) => assert_eq!(level, "high"),
+            other => panic!("Expected a level, got {:?}", other),
+        }
+        assert!(rule_action(&rule, Some("low"), "low").is_none());
+    }
+}
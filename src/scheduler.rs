@@ -0,0 +1,501 @@
+// Actions on GPIOs and groups at times given by cron expressions or at sunrise and sunset,
+// stored in `schedules`
+
+use crate::errors::RpWebError;
+use crate::handlers::{DbExecutor, DueSchedules, SetGpioLevel, SetGroupLevel, ToggleGpioLevel};
+use crate::models::{NewSchedule, Schedule, ScheduleRequest};
+use crate::rpi::GpioArcMutex;
+use crate::sequencer::{Pattern, Sequencer, StartSequence};
+use crate::settings::LocationConfig;
+use crate::solar::{next_solar_event_after, SolarEvent};
+use crate::utilities::{parse_timestamp, timestamp};
+use actix::fut::{wrap_future, ActorFuture};
+use actix::{Actor, Addr, AsyncContext, Context};
+use actix_web::{error, Error as actixError};
+use chrono::{Duration as ChronoDuration, Local, NaiveDateTime, TimeZone};
+use cron::Schedule as CronSchedule;
+use diesel::prelude::*;
+use futures::{future, Future};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How often due schedules are looked for
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Largest offset from sunrise or sunset, in minutes
+const MAX_OFFSET_MINUTES: i32 = 720;
+
+/// A run is missed if it is due for longer than this, e.g. because the server was down
+const MISSED_AFTER_SECONDS: i64 = 60;
+
+/// Parse a cron expression with 5 fields (minute to day of week), 6 fields (seconds first)
+/// or 7 fields (year last)
+pub fn parse_cron(expression: &str) -> Result<CronSchedule, RpWebError> {
+    let expression = expression.trim();
+    let full = if expression.split_whitespace().count() == 5 {
+        format!("0 {}", expression)
+    } else {
+        expression.to_string()
+    };
+
+    CronSchedule::from_str(&full).map_err(|err| {
+        RpWebError::new(&format!("Invalid cron expression '{}': {}", expression, err))
+    })
+}
+
+/// The first time after `time` matching `cron`, if any
+pub fn next_run_after(cron: &CronSchedule, time: NaiveDateTime) -> Option<String> {
+    let local = Local.from_local_datetime(&time).earliest()?;
+    cron.after(&local)
+        .next()
+        .map(|next| timestamp(next.naive_local()))
+}
+
+/// When a schedule runs
+pub enum Timing {
+    Cron(Box<CronSchedule>),
+    Solar {
+        location: LocationConfig,
+        event: SolarEvent,
+        offset_minutes: i32,
+    },
+}
+
+impl Timing {
+    /// The timing of a schedule with either a `cron` expression or a `solar_event`. Solar
+    /// events need the `[location]` from the configuration.
+    pub fn new(
+        cron: Option<&str>, solar_event: Option<&str>, offset_minutes: i32,
+        location: Option<&LocationConfig>,
+    ) -> Result<Timing, RpWebError> {
+        match (cron, solar_event) {
+            (Some(expression), None) => {
+                parse_cron(expression).map(|cron| Timing::Cron(Box::new(cron)))
+            }
+            (None, Some(name)) => {
+                let event = SolarEvent::parse(name)?;
+                if !(-MAX_OFFSET_MINUTES..=MAX_OFFSET_MINUTES).contains(&offset_minutes) {
+                    return Err(RpWebError::new(&format!(
+                        "The offset from {} is from -{} to {} minutes",
+                        name, MAX_OFFSET_MINUTES, MAX_OFFSET_MINUTES
+                    )));
+                }
+                let location = location.ok_or_else(|| {
+                    RpWebError::new(
+                        "Schedules at sunrise or sunset need a [location] in the configuration",
+                    )
+                })?;
+                Ok(Timing::Solar {
+                    location: *location,
+                    event,
+                    offset_minutes,
+                })
+            }
+            _ => Err(RpWebError::new(
+                "A schedule has either a 'cron' expression or a 'solar_event'",
+            )),
+        }
+    }
+
+    /// The timing of a stored schedule
+    pub fn of(
+        schedule: &Schedule, location: Option<&LocationConfig>,
+    ) -> Result<Timing, RpWebError> {
+        Timing::new(
+            schedule.cron.as_deref(),
+            schedule.solar_event.as_deref(),
+            schedule.offset_minutes,
+            location,
+        )
+    }
+
+    /// The first run after `time`, if any
+    pub fn next_run_after(&self, time: NaiveDateTime) -> Option<String> {
+        match self {
+            Timing::Cron(cron) => next_run_after(cron, time),
+            Timing::Solar {
+                location,
+                event,
+                offset_minutes,
+            } => next_solar_event_after(location, *event, *offset_minutes, time).map(timestamp),
+        }
+    }
+}
+
+/// Check `request` and turn it into a row for the schedule, on GPIO #`gpio_id` or the group
+/// in the request. The target must have been checked by the caller.
+pub fn new_schedule(
+    request: &ScheduleRequest, gpio_id: Option<i32>, location: Option<&LocationConfig>,
+    now: NaiveDateTime,
+) -> Result<NewSchedule, actixError> {
+    let offset_minutes = request.offset_minutes.unwrap_or(0);
+    let solar_event = request.solar_event.as_ref().map(|event| event.to_lowercase());
+    let timing = Timing::new(
+        request.cron.as_deref(),
+        solar_event.as_deref(),
+        offset_minutes,
+        location,
+    )
+    .map_err(|err| error::ErrorBadRequest(err.to_string()))?;
+
+    let action = request.action.to_lowercase();
+    let level = request.level.as_ref().map(|level| level.to_lowercase());
+    match action.as_str() {
+        "set_level" | "pulse" if level.is_none() => {
+            return Err(error::ErrorBadRequest(format!(
+                "Action '{}' needs a 'level'",
+                action
+            )));
+        }
+        "toggle" | "pulse" if gpio_id.is_none() => {
+            return Err(error::ErrorBadRequest(format!(
+                "Action '{}' is only for a 'gpio'",
+                action
+            )));
+        }
+        "set_level" | "toggle" => {}
+        "pulse" => {
+            let duration_ms = request
+                .duration_ms
+                .ok_or_else(|| error::ErrorBadRequest("Action 'pulse' needs a 'duration_ms'"))?;
+            Pattern::pulse(level.as_deref().unwrap_or_default(), u64::from(duration_ms))?;
+        }
+        _ => {
+            return Err(error::ErrorBadRequest(format!(
+                "Invalid action '{}' - use 'set_level', 'toggle' or 'pulse'",
+                action
+            )));
+        }
+    }
+
+    let missed_runs = request
+        .missed_runs
+        .as_deref()
+        .unwrap_or("skip")
+        .to_lowercase();
+    if missed_runs != "skip" && missed_runs != "run_once" {
+        return Err(error::ErrorBadRequest(format!(
+            "Invalid missed_runs '{}' - use 'skip' or 'run_once'",
+            missed_runs
+        )));
+    }
+
+    let enabled = request.enabled.unwrap_or(true);
+    Ok(NewSchedule {
+        cron: request.cron.as_ref().map(|cron| cron.trim().to_string()),
+        gpio_id,
+        group_name: request.group.as_ref().map(|group| group.to_lowercase()),
+        action: action.clone(),
+        gpio_level: if action == "toggle" { None } else { level },
+        duration_ms: if action == "pulse" {
+            request.duration_ms.map(|duration_ms| duration_ms as i32)
+        } else {
+            None
+        },
+        missed_runs,
+        enabled: enabled as i32,
+        next_run: if enabled {
+            timing.next_run_after(now)
+        } else {
+            None
+        },
+        solar_event,
+        offset_minutes: if request.solar_event.is_some() { offset_minutes } else { 0 },
+    })
+}
+
+pub fn create_schedule_db(new: &NewSchedule, conn: &SqliteConnection) -> Result<Schedule, RpWebError> {
+    use crate::schema::schedules::dsl::*;
+
+    let created = conn.transaction::<_, RpWebError, _>(|| {
+        diesel::insert_into(schedules).values(new).execute(conn)?;
+        let created = schedules
+            .order(schedule_id.desc())
+            .first::<Schedule>(conn)?;
+        Ok(created)
+    })?;
+
+    info!("Created schedule #{}, next run at {:?}", created.schedule_id, created.next_run);
+    Ok(created)
+}
+
+/// Schedule `id`, or `NotFound`
+pub fn load_schedule_db(id: i32, conn: &SqliteConnection) -> Result<Schedule, RpWebError> {
+    use crate::schema::schedules::dsl::*;
+
+    schedules
+        .filter(schedule_id.eq(id))
+        .first::<Schedule>(conn)
+        .optional()?
+        .ok_or_else(|| RpWebError::NotFound(format!("No schedule #{}", id)))
+}
+
+pub fn update_schedule_db(
+    id: i32, new: &NewSchedule, conn: &SqliteConnection,
+) -> Result<Schedule, RpWebError> {
+    use crate::schema::schedules::dsl::*;
+
+    let n_updated = diesel::update(schedules.filter(schedule_id.eq(id)))
+        .set(new)
+        .execute(conn)?;
+    if n_updated == 0 {
+        return Err(RpWebError::NotFound(format!("No schedule #{}", id)));
+    }
+
+    info!("Updated schedule #{}, next run at {:?}", id, new.next_run);
+    load_schedule_db(id, conn)
+}
+
+pub fn delete_schedule_db(id: i32, conn: &SqliteConnection) -> Result<Schedule, RpWebError> {
+    use crate::schema::schedules::dsl::*;
+
+    let schedule = load_schedule_db(id, conn)?;
+    diesel::delete(schedules.filter(schedule_id.eq(id))).execute(conn)?;
+
+    info!("Deleted schedule #{}", id);
+    Ok(schedule)
+}
+
+/// Move the enabled schedules due at `now` to their next run, and return the ones to run now.
+/// Runs that are more than a minute late were missed, e.g. while the server was down: they
+/// are run once if the schedule says `run_once`, and skipped otherwise.
+pub fn claim_due_schedules(
+    now: NaiveDateTime, location: Option<&LocationConfig>, conn: &SqliteConnection,
+) -> Result<Vec<Schedule>, RpWebError> {
+    use crate::schema::schedules::dsl::*;
+
+    conn.transaction::<_, RpWebError, _>(|| {
+        let due = schedules
+            .filter(enabled.eq(1))
+            .filter(next_run.le(timestamp(now)))
+            .load::<Schedule>(conn)?;
+
+        let mut to_run = vec![];
+        for schedule in due.into_iter() {
+            let target = schedules.filter(schedule_id.eq(schedule.schedule_id));
+            let following = match Timing::of(&schedule, location) {
+                Ok(timing) => timing.next_run_after(now),
+                Err(err) => {
+                    error!("Schedule #{} disabled: {}", schedule.schedule_id, err);
+                    None
+                }
+            };
+
+            let scheduled = schedule.next_run.as_deref().and_then(|at| parse_timestamp(at).ok());
+            let missed = scheduled
+                .is_some_and(|at| at + ChronoDuration::seconds(MISSED_AFTER_SECONDS) < now);
+            if missed {
+                warn!(
+                    "Schedule #{} missed its run at {}, {}",
+                    schedule.schedule_id,
+                    schedule.next_run.as_deref().unwrap_or_default(),
+                    if schedule.missed_runs == "run_once" { "running it now" } else { "skipping it" }
+                );
+            }
+
+            if !missed || schedule.missed_runs == "run_once" {
+                diesel::update(target)
+                    .set((next_run.eq(following), last_run.eq(timestamp(now))))
+                    .execute(conn)?;
+                to_run.push(schedule);
+            } else {
+                diesel::update(target)
+                    .set(next_run.eq(following))
+                    .execute(conn)?;
+            }
+        }
+        Ok(to_run)
+    })
+}
+
+/// Actor claiming due schedules every second and running their actions. Levels are set
+/// through the DbExecutor and pulses through the Sequencer, like requests from clients.
+pub struct Scheduler {
+    db: Addr<DbExecutor>,
+    sequencer: Addr<Sequencer>,
+    gpio_arc_mutex: GpioArcMutex,
+    location: Option<LocationConfig>,
+}
+
+impl Scheduler {
+    pub fn new(
+        db: Addr<DbExecutor>, sequencer: Addr<Sequencer>, gpio_arc_mutex: GpioArcMutex,
+        location: Option<LocationConfig>,
+    ) -> Self {
+        Scheduler {
+            db,
+            sequencer,
+            gpio_arc_mutex,
+            location,
+        }
+    }
+
+    fn run(&self, schedule: Schedule, ctx: &mut Context<Self>) {
+        let id = schedule.schedule_id;
+        let level = schedule.gpio_level.clone().unwrap_or_default();
+
+        let action: Box<dyn Future<Item = (), Error = actixError>> =
+            match (schedule.action.as_str(), schedule.gpio_id, schedule.group_name) {
+                ("set_level", Some(gpio_id), _) => Box::new(
+                    self.db
+                        .send(SetGpioLevel {
+                            gpio_id,
+                            gpio_level: level,
+                            expected_version: None,
+                            lease_id: None,
+                            gpio_arc_mutex: self.gpio_arc_mutex.clone(),
+                        })
+                        .from_err()
+                        .and_then(|res| res.map(|_| ())),
+                ),
+                ("set_level", None, Some(group_name)) => Box::new(
+                    self.db
+                        .send(SetGroupLevel {
+                            group_name,
+                            gpio_level: level,
+                            lease_id: None,
+                            gpio_arc_mutex: self.gpio_arc_mutex.clone(),
+                        })
+                        .from_err()
+                        .and_then(|res| res.map(|_| ())),
+                ),
+                ("toggle", Some(gpio_id), _) => Box::new(
+                    self.db
+                        .send(ToggleGpioLevel {
+                            gpio_id,
+                            expected_version: None,
+                            lease_id: None,
+                            gpio_arc_mutex: self.gpio_arc_mutex.clone(),
+                        })
+                        .from_err()
+                        .and_then(|res| res.map(|_| ())),
+                ),
+                ("pulse", Some(gpio_id), _) => {
+                    let duration_ms = schedule.duration_ms.unwrap_or_default() as u64;
+                    match Pattern::pulse(&level, duration_ms) {
+                        Ok(pattern) => Box::new(
+                            self.sequencer
+                                .send(StartSequence {
+                                    gpio_id,
+                                    pattern,
+                                    lease_id: None,
+                                })
+                                .from_err()
+                                .and_then(|res| res.map(|_| ())),
+                        ),
+                        Err(err) => Box::new(future::err(err)),
+                    }
+                }
+                (action, _, _) => Box::new(future::err(error::ErrorInternalServerError(format!(
+                    "Invalid action '{}'",
+                    action
+                )))),
+            };
+
+        let run = action
+            .map(move |_| info!("Ran schedule #{}", id))
+            .map_err(move |err| error!("Schedule #{} failed: {}", id, err));
+        ctx.spawn(wrap_future(run));
+    }
+}
+
+impl Actor for Scheduler {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(SCHEDULER_INTERVAL, |act, ctx| {
+            let claim = act
+                .db
+                .send(DueSchedules {
+                    location: act.location,
+                })
+                .from_err::<actixError>()
+                .and_then(|res| res);
+
+            ctx.spawn(
+                wrap_future::<_, Self>(claim)
+                    .map(|due, act, ctx| {
+                        for schedule in due.into_iter() {
+                            act.run(schedule, ctx);
+                        }
+                    })
+                    .map_err(|err, _, _| error!("Looking for due schedules failed: {}", err)),
+            );
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::GpioIdentifier;
+
+    fn time(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+
+    fn request(cron: &str, action: &str, level: Option<&str>) -> ScheduleRequest {
+        ScheduleRequest {
+            cron: Some(cron.to_string()),
+            solar_event: None,
+            offset_minutes: None,
+            gpio: Some(GpioIdentifier::Id(5)),
+            group: None,
+            action: action.to_string(),
+            level: level.map(str::to_string),
+            duration_ms: None,
+            missed_runs: None,
+            enabled: None,
+        }
+    }
+
+    #[test]
+    fn next_run_five_fields_must_follow_cron() {
+        let cron = parse_cron("30 7 * * *").unwrap();
+
+        let next = next_run_after(&cron, time("2019-06-01 08:00:00"));
+        assert_eq!(next.as_deref(), Some("2019-06-02 07:30:00.000"));
+    }
+
+    #[test]
+    fn invalid_cron_must_fail() {
+        assert!(parse_cron("61 * * * *").is_err());
+        assert!(parse_cron("every day").is_err());
+    }
+
+    #[test]
+    fn new_schedule_must_check_action() {
+        let now = time("2019-06-01 08:00:00");
+
+        assert!(new_schedule(&request("0 7 * * *", "set_level", Some("HIGH")), Some(5), None, now).is_ok());
+        assert!(new_schedule(&request("0 7 * * *", "toggle", None), Some(5), None, now).is_ok());
+        assert!(new_schedule(&request("0 7 * * *", "set_level", None), Some(5), None, now).is_err());
+        assert!(new_schedule(&request("0 7 * * *", "pulse", Some("high")), Some(5), None, now).is_err());
+        assert!(new_schedule(&request("0 7 * * *", "explode", None), Some(5), None, now).is_err());
+    }
+
+    #[test]
+    fn new_solar_schedule_must_check_timing() {
+        let now = time("2019-06-01 08:00:00");
+        let location = LocationConfig {
+            latitude: 55.68,
+            longitude: 12.57,
+        };
+        let mut sunset = request("0 7 * * *", "toggle", None);
+        sunset.cron = None;
+        sunset.solar_event = Some("Sunset".to_string());
+        sunset.offset_minutes = Some(-30);
+
+        let new = new_schedule(&sunset, Some(5), Some(&location), now).unwrap();
+        assert_eq!(new.solar_event.as_deref(), Some("sunset"));
+        assert!(new.next_run.is_some());
+
+        // Needs a location, an offset of at most 12 hours, and no cron expression as well
+        assert!(new_schedule(&sunset, Some(5), None, now).is_err());
+        sunset.offset_minutes = Some(721);
+        assert!(new_schedule(&sunset, Some(5), Some(&location), now).is_err());
+        sunset.offset_minutes = None;
+        sunset.cron = Some("0 7 * * *".to_string());
+        assert!(new_schedule(&sunset, Some(5), Some(&location), now).is_err());
+    }
+}
@@ -6,6 +6,74 @@ table! {
         output -> Integer,
         high -> Integer,
         low -> Integer,
+        pwm -> Integer,
+    }
+}
+
+table! {
+    gpio_audit (audit_id) {
+        audit_id -> Integer,
+        gpio_id -> Integer,
+        event -> Text,
+        gpio_level -> Nullable<Text>,
+        detail -> Nullable<Text>,
+        occurred_at -> Text,
+    }
+}
+
+table! {
+    gpio_devices (gpio_id) {
+        gpio_id -> Integer,
+        device_name -> Text,
+        kind -> Text,
+        role -> Text,
+    }
+}
+
+table! {
+    gpio_drift (gpio_id) {
+        gpio_id -> Integer,
+        db_level -> Text,
+        hw_level -> Text,
+        detected_at -> Text,
+        correction -> Nullable<Text>,
+    }
+}
+
+table! {
+    gpio_groups (group_name, gpio_id) {
+        group_name -> Text,
+        gpio_id -> Integer,
+    }
+}
+
+table! {
+    gpio_interlocks (interlock_id) {
+        interlock_id -> Integer,
+        kind -> Text,
+        gpio_id -> Integer,
+        other_gpio -> Integer,
+        dead_time_ms -> Nullable<Integer>,
+    }
+}
+
+table! {
+    gpio_leases (gpio_id) {
+        gpio_id -> Integer,
+        lease_id -> Text,
+        expires_at -> Text,
+        on_expiry -> Nullable<Text>,
+    }
+}
+
+table! {
+    gpio_servos (gpio_id) {
+        gpio_id -> Integer,
+        min_pulse_us -> Integer,
+        max_pulse_us -> Integer,
+        min_angle -> Double,
+        max_angle -> Double,
+        max_speed -> Nullable<Double>,
     }
 }
 
@@ -16,7 +84,93 @@ table! {
         gpio_mode -> Nullable<Text>,
         gpio_level -> Nullable<Text>,
         last_change -> Nullable<Timestamp>,
+        name -> Nullable<Text>,
+        description -> Nullable<Text>,
+        tags -> Nullable<Text>,
+        version -> Integer,
+        max_on_duration -> Nullable<Integer>,
+        safe_level -> Nullable<Text>,
+        on_since -> Nullable<Text>,
+        forced_off_at -> Nullable<Text>,
+        pwm_frequency -> Nullable<Double>,
+        pwm_duty_cycle -> Nullable<Double>,
+        pwm_channel -> Nullable<Integer>,
     }
 }
 
-allow_tables_to_appear_in_same_query!(allowed_states, gpio_state,);
+table! {
+    rules (rule_id) {
+        rule_id -> Integer,
+        name -> Nullable<Text>,
+        input_gpio -> Integer,
+        trigger -> Text,
+        output_gpio -> Integer,
+        action -> Text,
+        gpio_level -> Nullable<Text>,
+        duration_ms -> Nullable<Integer>,
+        inverted -> Integer,
+        enabled -> Integer,
+        from_config -> Integer,
+        fire_count -> Integer,
+        last_fired -> Nullable<Text>,
+    }
+}
+
+table! {
+    schedules (schedule_id) {
+        schedule_id -> Integer,
+        cron -> Nullable<Text>,
+        gpio_id -> Nullable<Integer>,
+        group_name -> Nullable<Text>,
+        action -> Text,
+        gpio_level -> Nullable<Text>,
+        duration_ms -> Nullable<Integer>,
+        missed_runs -> Text,
+        enabled -> Integer,
+        last_run -> Nullable<Text>,
+        next_run -> Nullable<Text>,
+        solar_event -> Nullable<Text>,
+        offset_minutes -> Integer,
+    }
+}
+
+table! {
+    stepper_positions (name) {
+        name -> Text,
+        position -> Integer,
+        homed -> Integer,
+        updated_at -> Nullable<Text>,
+    }
+}
+
+table! {
+    vacation_mode (vacation_id) {
+        vacation_id -> Integer,
+        enabled -> Integer,
+        seed -> Nullable<BigInt>,
+    }
+}
+
+joinable!(gpio_audit -> gpio_state (gpio_id));
+joinable!(gpio_devices -> gpio_state (gpio_id));
+joinable!(gpio_drift -> gpio_state (gpio_id));
+joinable!(gpio_groups -> gpio_state (gpio_id));
+joinable!(gpio_leases -> gpio_state (gpio_id));
+joinable!(gpio_servos -> gpio_state (gpio_id));
+joinable!(schedules -> gpio_state (gpio_id));
+
+allow_tables_to_appear_in_same_query!(
+    allowed_states,
+    gpio_audit,
+    gpio_devices,
+    gpio_drift,
+    gpio_groups,
+    gpio_interlocks,
+    gpio_leases,
+    gpio_servos,
+    gpio_state,
+    rules,
+    schedules,
+    stepper_positions,
+    vacation_mode,
+);
@@ -0,0 +1,372 @@
+// Rhai scripts (https://rhai.rs) for automation the rules can not express. Every `.rhai` file
+// in the scripts directory runs in its own thread, so a busy or sleeping script never blocks
+// the actix workers. Scripts only reach the pins through the functions registered here:
+// no files, modules, network or `eval`.
+
+use crate::errors::RpWebError;
+use crate::handlers::DbExecutor;
+use crate::models::{ScriptLog, ScriptStatus};
+use crate::rpi::{get_gpio_level_rpi, GpioArcMutex};
+use crate::sequencer::set_level;
+use crate::settings::ScriptsConfig;
+use crate::utilities::current_time;
+use actix::Addr;
+use futures::Future;
+use parking_lot::Mutex;
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Extension of the files run as scripts
+const SCRIPT_EXTENSION: &str = "rhai";
+
+/// Number of lines kept of what each script prints
+const SCRIPT_LOG_LIMIT: usize = 100;
+
+/// How often the inputs a script subscribed to are read
+const SCRIPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Name of the function called when an input a script subscribed to changes
+const ON_CHANGE_FN: &str = "on_change";
+
+/// Status of every script by name, shared by the script threads and the `/scripts` routes
+pub type ScriptRegistry = Arc<Mutex<BTreeMap<String, ScriptStatus>>>;
+
+pub fn new_script_registry() -> ScriptRegistry {
+    Arc::new(Mutex::new(BTreeMap::new()))
+}
+
+/// The `.rhai` files in `directory`, sorted by name
+pub fn script_files(directory: &Path) -> Result<Vec<PathBuf>, RpWebError> {
+    let mut files = vec![];
+    for entry in fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new(SCRIPT_EXTENSION)) {
+            files.push(path);
+        }
+    }
+
+    files.sort();
+    Ok(files)
+}
+
+fn script_name(path: &Path) -> String {
+    path.file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+fn update_status<F: FnOnce(&mut ScriptStatus)>(registry: &ScriptRegistry, name: &str, f: F) {
+    if let Some(status) = registry.lock().get_mut(name) {
+        f(status);
+    }
+}
+
+/// Add a line printed by script `name` to its log, dropping the oldest lines beyond the limit
+pub fn push_log(registry: &ScriptRegistry, name: &str, message: &str) {
+    info!("Script '{}': {}", name, message);
+    update_status(registry, name, |status| {
+        status.logs.push(ScriptLog {
+            time: current_time(),
+            message: message.to_string(),
+        });
+        if status.logs.len() > SCRIPT_LOG_LIMIT {
+            let excess = status.logs.len() - SCRIPT_LOG_LIMIT;
+            status.logs.drain(..excess);
+        }
+    });
+}
+
+fn gpio_arg(gpio_id: i64) -> Result<i32, Box<EvalAltResult>> {
+    i32::try_from(gpio_id).map_err(|_| format!("Invalid GPIO #{}", gpio_id).into())
+}
+
+/// Engine for script `name`, without access to the pins: `print` and `debug` go to the log of
+/// the script, `sleep(ms)` pauses it and `subscribe(gpio)` adds an input to `subscriptions`
+pub fn script_engine(
+    name: &str, registry: &ScriptRegistry, max_operations: Option<u64>,
+    subscriptions: &Rc<RefCell<Vec<i32>>>,
+) -> Engine {
+    let mut engine = Engine::new();
+    engine.set_module_resolver(DummyModuleResolver::new());
+    engine.disable_symbol("eval");
+    engine.set_max_call_levels(32);
+    engine.set_max_expr_depths(64, 32);
+    engine.set_max_string_size(10_000);
+    engine.set_max_array_size(10_000);
+    engine.set_max_map_size(10_000);
+    if let Some(max_operations) = max_operations {
+        engine.set_max_operations(max_operations);
+    }
+
+    let (log_registry, log_name) = (registry.clone(), name.to_string());
+    engine.on_print(move |text| push_log(&log_registry, &log_name, text));
+    let (log_registry, log_name) = (registry.clone(), name.to_string());
+    engine.on_debug(move |text, _, pos| {
+        push_log(&log_registry, &log_name, &format!("{} {}", pos, text))
+    });
+
+    engine.register_fn("sleep", |ms: i64| -> Result<(), Box<EvalAltResult>> {
+        let ms = u64::try_from(ms).map_err(|_| format!("Can not sleep {} ms", ms))?;
+        thread::sleep(Duration::from_millis(ms));
+        Ok(())
+    });
+
+    let (registry, name) = (registry.clone(), name.to_string());
+    let subscriptions = subscriptions.clone();
+    engine.register_fn("subscribe", move |gpio_id: i64| -> Result<(), Box<EvalAltResult>> {
+        let gpio_id = gpio_arg(gpio_id)?;
+        let mut subscriptions = subscriptions.borrow_mut();
+        if !subscriptions.contains(&gpio_id) {
+            subscriptions.push(gpio_id);
+            let subscribed = subscriptions.clone();
+            update_status(&registry, &name, |status| status.subscriptions = subscribed);
+        }
+        Ok(())
+    });
+
+    engine
+}
+
+/// What scripts reach the pins through: levels are read from the pins in use, and set through
+/// the DbExecutor with the same checks as `/set/level`
+#[derive(Clone)]
+pub struct ScriptHost {
+    pub db: Addr<DbExecutor>,
+    pub gpio_arc_mutex: GpioArcMutex,
+    pub gpios_in_use: Vec<i32>,
+}
+
+impl ScriptHost {
+    pub fn read_pin(&self, gpio_id: i32) -> Result<String, String> {
+        if !self.gpios_in_use.contains(&gpio_id) {
+            return Err(format!("GPIO #{} is not in use", gpio_id));
+        }
+        get_gpio_level_rpi(gpio_id, self.gpio_arc_mutex.clone()).map_err(|err| err.to_string())
+    }
+
+    /// Set GPIO #`gpio_id` to `level`, waiting for the DbExecutor. Returns the new level.
+    pub fn set_level(&self, gpio_id: i32, level: &str) -> Result<String, String> {
+        set_level(&self.db, self.gpio_arc_mutex.clone(), gpio_id, level.to_string(), None)
+            .wait()
+            .map(|gpio| gpio.gpio_level.unwrap_or_default())
+            .map_err(|err| err.to_string())
+    }
+}
+
+/// Add `read_pin(gpio)` and `set_level(gpio, level)` to `engine`
+pub fn register_gpio_api(engine: &mut Engine, host: &ScriptHost) {
+    let read_host = host.clone();
+    engine.register_fn("read_pin", move |gpio_id: i64| -> Result<String, Box<EvalAltResult>> {
+        Ok(read_host.read_pin(gpio_arg(gpio_id)?)?)
+    });
+
+    let set_host = host.clone();
+    engine.register_fn(
+        "set_level",
+        move |gpio_id: i64, level: &str| -> Result<String, Box<EvalAltResult>> {
+            Ok(set_host.set_level(gpio_arg(gpio_id)?, level)?)
+        },
+    );
+}
+
+/// Run `source` to the end. If it subscribed to inputs, keep reading them with `read_level`
+/// and call its `on_change(gpio, level)` whenever one changes, until a read fails.
+pub fn run_script<R>(
+    engine: &Engine, source: &str, subscriptions: &Rc<RefCell<Vec<i32>>>, mut read_level: R,
+    poll_interval: Duration,
+) -> Result<(), String>
+where
+    R: FnMut(i32) -> Result<String, String>,
+{
+    let ast = engine.compile(source).map_err(|err| err.to_string())?;
+    let mut scope = Scope::new();
+    engine
+        .run_ast_with_scope(&mut scope, &ast)
+        .map_err(|err| err.to_string())?;
+
+    if subscriptions.borrow().is_empty() {
+        return Ok(());
+    }
+    if !ast
+        .iter_functions()
+        .any(|f| f.name == ON_CHANGE_FN && f.params.len() == 2)
+    {
+        return Err(format!(
+            "The script subscribes to inputs, but has no {}(gpio, level)",
+            ON_CHANGE_FN
+        ));
+    }
+
+    // Changes are reported from the first reading on; scripts read the start level themselves
+    let mut levels: HashMap<i32, String> = HashMap::new();
+    loop {
+        let gpio_ids = subscriptions.borrow().clone();
+        for gpio_id in gpio_ids {
+            let level = read_level(gpio_id)?;
+            let previous = levels.insert(gpio_id, level.clone());
+            if previous.is_some_and(|previous| previous != level) {
+                let options = CallFnOptions::new().eval_ast(false).rewind_scope(false);
+                let _: Dynamic = engine
+                    .call_fn_with_options(
+                        options,
+                        &mut scope,
+                        &ast,
+                        ON_CHANGE_FN,
+                        (i64::from(gpio_id), level),
+                    )
+                    .map_err(|err| err.to_string())?;
+            }
+        }
+        thread::sleep(poll_interval);
+    }
+}
+
+/// Start every script in the scripts directory in its own thread, with its status in `registry`
+pub fn start_scripts(
+    config: &ScriptsConfig, host: &ScriptHost, registry: &ScriptRegistry,
+) -> Result<(), RpWebError> {
+    for path in script_files(Path::new(&config.directory))? {
+        let name = script_name(&path);
+        let source = fs::read_to_string(&path)?;
+        registry.lock().insert(
+            name.clone(),
+            ScriptStatus {
+                name: name.clone(),
+                state: "running".to_string(),
+                error: None,
+                started: current_time(),
+                finished: None,
+                subscriptions: vec![],
+                logs: vec![],
+            },
+        );
+
+        let (host, registry) = (host.clone(), registry.clone());
+        let max_operations = config.max_operations;
+        thread::Builder::new()
+            .name(format!("script-{}", name))
+            .spawn(move || {
+                info!("Starting script '{}'", name);
+                let subscriptions = Rc::new(RefCell::new(vec![]));
+                let mut engine = script_engine(&name, &registry, max_operations, &subscriptions);
+                register_gpio_api(&mut engine, &host);
+
+                let read_level = |gpio_id| host.read_pin(gpio_id);
+                let result =
+                    run_script(&engine, &source, &subscriptions, read_level, SCRIPT_POLL_INTERVAL);
+                if let Err(err) = &result {
+                    error!("Script '{}' failed: {}", name, err);
+                }
+                update_status(&registry, &name, |status| {
+                    status.state = if result.is_ok() { "finished" } else { "failed" }.to_string();
+                    status.error = result.err();
+                    status.finished = Some(current_time());
+                });
+            })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn registry_with(name: &str) -> ScriptRegistry {
+        let registry = new_script_registry();
+        registry.lock().insert(
+            name.to_string(),
+            ScriptStatus {
+                name: name.to_string(),
+                state: "running".to_string(),
+                error: None,
+                started: current_time(),
+                finished: None,
+                subscriptions: vec![],
+                logs: vec![],
+            },
+        );
+        registry
+    }
+
+    fn messages(registry: &ScriptRegistry, name: &str) -> Vec<String> {
+        registry.lock()[name]
+            .logs
+            .iter()
+            .map(|log| log.message.clone())
+            .collect()
+    }
+
+    fn no_pins(gpio_id: i32) -> Result<String, String> {
+        Err(format!("No GPIO #{}", gpio_id))
+    }
+
+    #[test]
+    fn printed_lines_must_be_logged_and_capped() {
+        let registry = registry_with("counter");
+        let subscriptions = Rc::new(RefCell::new(vec![]));
+        let engine = script_engine("counter", &registry, None, &subscriptions);
+
+        let source = "for i in 0..150 { print(`line ${i}`); }";
+        run_script(&engine, source, &subscriptions, no_pins, Duration::from_millis(0))
+            .expect("Test failed");
+
+        let logs = messages(&registry, "counter");
+        assert_eq!(logs.len(), SCRIPT_LOG_LIMIT);
+        assert_eq!(logs[0], "line 50");
+        assert_eq!(logs[SCRIPT_LOG_LIMIT - 1], "line 149");
+    }
+
+    #[test]
+    fn script_errors_must_be_returned() {
+        let registry = registry_with("broken");
+        let subscriptions = Rc::new(RefCell::new(vec![]));
+        let engine = script_engine("broken", &registry, Some(1_000), &subscriptions);
+        let run = |source| {
+            run_script(&engine, source, &subscriptions, no_pins, Duration::from_millis(0))
+        };
+
+        assert!(run("let x = ;").is_err());
+        assert!(run("throw \"boom\";").unwrap_err().contains("boom"));
+        assert!(run("eval(\"1\")").is_err());
+        assert!(run("import \"secrets\" as s;").is_err());
+        assert!(run("loop { }").is_err());
+    }
+
+    #[test]
+    fn on_change_must_be_called_for_changes() {
+        let registry = registry_with("door");
+        let subscriptions = Rc::new(RefCell::new(vec![]));
+        let engine = script_engine("door", &registry, None, &subscriptions);
+
+        let source = "subscribe(4); fn on_change(gpio, level) { print(`${gpio} ${level}`); }";
+        let mut readings = vec!["low", "high", "high", "low"].into_iter();
+        let read_level = |_| readings.next().map(str::to_string).ok_or("done".to_string());
+        let no_wait = Duration::from_millis(0);
+        let result = run_script(&engine, source, &subscriptions, read_level, no_wait);
+
+        assert_eq!(result, Err("done".to_string()));
+        assert_eq!(messages(&registry, "door"), vec!["4 high", "4 low"]);
+        assert_eq!(registry.lock()["door"].subscriptions, vec![4]);
+    }
+
+    #[test]
+    fn subscribe_without_on_change_must_fail() {
+        let registry = registry_with("deaf");
+        let subscriptions = Rc::new(RefCell::new(vec![]));
+        let engine = script_engine("deaf", &registry, None, &subscriptions);
+
+        let no_wait = Duration::from_millis(0);
+        assert!(run_script(&engine, "subscribe(4);", &subscriptions, no_pins, no_wait).is_err());
+    }
+}
@@ -0,0 +1,405 @@
+// Pulses and blinks: level changes on a server-side timer, so they finish even if the
+// client that started them goes away
+
+use crate::handlers::{DbExecutor, GpioId, SetGpioLevel};
+use crate::models;
+use crate::rpi::GpioArcMutex;
+use actix::fut::{self, wrap_future, ActorFuture};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, ResponseActFuture, SpawnHandle};
+use actix_web::{error, Error as actixError};
+use futures::Future;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+const MAX_DURATION_MS: u64 = 3_600_000;
+const MIN_PERIOD_MS: u64 = 20;
+const MAX_COUNT: u32 = 10_000;
+
+/// A sequence of levels for one GPIO
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// `level` for `duration`, then back
+    Pulse { level: String, duration: Duration },
+    /// `count` times 'high' for `duty_percent` of `period` and 'low' for the rest, then back
+    Blink {
+        period: Duration,
+        duty_percent: u32,
+        count: u32,
+    },
+}
+
+/// Set the GPIO to `level` `delay` after the previous step
+#[derive(Debug, Clone, PartialEq)]
+pub struct SequenceStep {
+    pub delay: Duration,
+    pub level: String,
+}
+
+impl SequenceStep {
+    fn new(delay: Duration, level: &str) -> SequenceStep {
+        SequenceStep {
+            delay,
+            level: level.to_string(),
+        }
+    }
+}
+
+impl Pattern {
+    pub fn pulse(level: &str, duration_ms: u64) -> Result<Pattern, actixError> {
+        let level = level.to_lowercase();
+        if level != "high" && level != "low" {
+            return Err(error::ErrorBadRequest(format!(
+                "Invalid level: '{}'",
+                level
+            )));
+        }
+        if !(1..=MAX_DURATION_MS).contains(&duration_ms) {
+            return Err(error::ErrorBadRequest(format!(
+                "A pulse lasts from 1 to {} ms",
+                MAX_DURATION_MS
+            )));
+        }
+
+        Ok(Pattern::Pulse {
+            level,
+            duration: Duration::from_millis(duration_ms),
+        })
+    }
+
+    pub fn blink(period_ms: u64, duty_percent: u32, count: u32) -> Result<Pattern, actixError> {
+        if !(MIN_PERIOD_MS..=MAX_DURATION_MS).contains(&period_ms) {
+            return Err(error::ErrorBadRequest(format!(
+                "The period of a blink is from {} to {} ms",
+                MIN_PERIOD_MS, MAX_DURATION_MS
+            )));
+        }
+        if !(1..=99).contains(&duty_percent) {
+            return Err(error::ErrorBadRequest(
+                "The duty of a blink is from 1 to 99 %",
+            ));
+        }
+        if !(1..=MAX_COUNT).contains(&count) {
+            return Err(error::ErrorBadRequest(format!(
+                "A blink has from 1 to {} periods",
+                MAX_COUNT
+            )));
+        }
+
+        Ok(Pattern::Blink {
+            period: Duration::from_millis(period_ms),
+            duty_percent,
+            count,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Pattern::Pulse { .. } => "pulse",
+            Pattern::Blink { .. } => "blink",
+        }
+    }
+
+    /// The steps of the pattern for a GPIO at `previous_level`. The first step has no delay,
+    /// and the last one returns the GPIO to `previous_level`.
+    pub fn steps(&self, previous_level: &str) -> VecDeque<SequenceStep> {
+        let mut steps = VecDeque::new();
+
+        match self {
+            Pattern::Pulse { level, duration } => {
+                steps.push_back(SequenceStep::new(Duration::from_millis(0), level));
+                steps.push_back(SequenceStep::new(*duration, previous_level));
+            }
+            Pattern::Blink {
+                period,
+                duty_percent,
+                count,
+            } => {
+                let on = *period * *duty_percent / 100;
+                let off = *period - on;
+                let mut delay = Duration::from_millis(0);
+                for _ in 0..*count {
+                    steps.push_back(SequenceStep::new(delay, "high"));
+                    steps.push_back(SequenceStep::new(on, "low"));
+                    delay = off;
+                }
+                if previous_level != "low" {
+                    steps.push_back(SequenceStep::new(off, previous_level));
+                }
+            }
+        }
+
+        steps
+    }
+}
+
+/// Set GPIO #`gpio_id` to `gpio_level` through the DbExecutor, like a request from a client
+pub fn set_level(
+    db: &Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex, gpio_id: i32, gpio_level: String,
+    lease_id: Option<String>,
+) -> impl Future<Item = models::Gpio, Error = actixError> {
+    db.send(SetGpioLevel {
+        gpio_id,
+        gpio_level,
+        expected_version: None,
+        lease_id,
+        gpio_arc_mutex,
+    })
+    .from_err()
+    .and_then(|res| res)
+}
+
+/// Start `pattern` on GPIO #`gpio_id`, replacing the one running on it
+pub struct StartSequence {
+    pub gpio_id: i32,
+    pub pattern: Pattern,
+    pub lease_id: Option<String>,
+}
+
+impl Message for StartSequence {
+    type Result = Result<models::Gpio, actixError>;
+}
+
+/// Stop the pattern running on GPIO #`gpio_id`, and set it to the level the pattern ends at
+pub struct CancelSequence {
+    pub gpio_id: i32,
+}
+
+impl Message for CancelSequence {
+    type Result = Result<models::Sequence, actixError>;
+}
+
+struct Running {
+    id: u64,
+    pattern: &'static str,
+    steps: VecDeque<SequenceStep>,
+    final_level: String,
+    lease_id: Option<String>,
+    handle: Option<SpawnHandle>,
+}
+
+/// Actor running the patterns, one at a time per GPIO. Every step goes through `SetGpioLevel`
+/// with the lease of the client that started the pattern.
+pub struct Sequencer {
+    db: Addr<DbExecutor>,
+    gpio_arc_mutex: GpioArcMutex,
+    running: HashMap<i32, Running>,
+    next_id: u64,
+}
+
+impl Sequencer {
+    pub fn new(db: Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex) -> Self {
+        Sequencer {
+            db,
+            gpio_arc_mutex,
+            running: HashMap::new(),
+            next_id: 0,
+        }
+    }
+
+    fn stop(&mut self, gpio_id: i32, ctx: &mut Context<Self>) -> Option<Running> {
+        let running = self.running.remove(&gpio_id)?;
+        if let Some(handle) = running.handle {
+            ctx.cancel_future(handle);
+        }
+        Some(running)
+    }
+
+    fn start(
+        &mut self, gpio_id: i32, pattern: &'static str, steps: VecDeque<SequenceStep>,
+        final_level: String, lease_id: Option<String>, ctx: &mut Context<Self>,
+    ) {
+        self.stop(gpio_id, ctx);
+        self.next_id += 1;
+        info!("Running {} on GPIO #{}", pattern, gpio_id);
+
+        self.running.insert(
+            gpio_id,
+            Running {
+                id: self.next_id,
+                pattern,
+                steps,
+                final_level,
+                lease_id,
+                handle: None,
+            },
+        );
+        self.schedule_next(gpio_id, ctx);
+    }
+
+    fn schedule_next(&mut self, gpio_id: i32, ctx: &mut Context<Self>) {
+        let running = match self.running.get_mut(&gpio_id) {
+            Some(running) => running,
+            None => return,
+        };
+        let step = match running.steps.pop_front() {
+            Some(step) => step,
+            None => {
+                info!("Finished {} on GPIO #{}", running.pattern, gpio_id);
+                self.running.remove(&gpio_id);
+                return;
+            }
+        };
+
+        let id = running.id;
+        running.handle = Some(ctx.run_later(step.delay, move |act, ctx| {
+            act.run_step(gpio_id, id, step.level, ctx)
+        }));
+    }
+
+    fn run_step(&mut self, gpio_id: i32, id: u64, level: String, ctx: &mut Context<Self>) {
+        let lease_id = self
+            .running
+            .get(&gpio_id)
+            .and_then(|running| running.lease_id.clone());
+        let set = set_level(
+            &self.db,
+            self.gpio_arc_mutex.clone(),
+            gpio_id,
+            level,
+            lease_id,
+        );
+
+        ctx.spawn(wrap_future::<_, Self>(set).then(move |res, act, ctx| {
+            // The pattern may have been cancelled or replaced in the meantime
+            if act.running.get(&gpio_id).map(|running| running.id) == Some(id) {
+                match res {
+                    Ok(_) => act.schedule_next(gpio_id, ctx),
+                    Err(err) => {
+                        error!("Stopped pattern on GPIO #{}: {}", gpio_id, err);
+                        act.running.remove(&gpio_id);
+                    }
+                }
+            }
+            fut::ok(())
+        }));
+    }
+}
+
+impl Actor for Sequencer {
+    type Context = Context<Self>;
+}
+
+impl Handler<StartSequence> for Sequencer {
+    type Result = ResponseActFuture<Self, models::Gpio, actixError>;
+
+    fn handle(&mut self, msg: StartSequence, ctx: &mut Self::Context) -> Self::Result {
+        let StartSequence {
+            gpio_id,
+            pattern,
+            lease_id,
+        } = msg;
+
+        // A pattern that is replaced does not get to its last step, so the new one returns
+        // to the level the old one would have ended at
+        let replaced_level = self.stop(gpio_id, ctx).map(|running| running.final_level);
+
+        let name = pattern.name();
+        let first_lease_id = lease_id.clone();
+        let db = self.db.clone();
+        let gpio_arc_mutex = self.gpio_arc_mutex.clone();
+        let start = self
+            .db
+            .send(GpioId { gpio_id })
+            .from_err()
+            .and_then(|res| res)
+            .and_then(move |gpio| {
+                let previous_level = replaced_level
+                    .or(gpio.gpio_level)
+                    .filter(|level| level == "high" || level == "low")
+                    .unwrap_or_else(|| "low".to_string());
+                let mut steps = pattern.steps(&previous_level);
+                let first = steps.pop_front().expect("A pattern has at least one step");
+
+                set_level(&db, gpio_arc_mutex, gpio_id, first.level, first_lease_id)
+                    .map(move |gpio| (gpio, steps, previous_level))
+            });
+
+        Box::new(wrap_future::<_, Self>(start).map(
+            move |(gpio, steps, previous_level), act, ctx| {
+                act.start(gpio_id, name, steps, previous_level, lease_id, ctx);
+                gpio
+            },
+        ))
+    }
+}
+
+impl Handler<CancelSequence> for Sequencer {
+    type Result = Result<models::Sequence, actixError>;
+
+    fn handle(&mut self, msg: CancelSequence, ctx: &mut Self::Context) -> Self::Result {
+        let running = self.stop(msg.gpio_id, ctx).ok_or_else(|| {
+            error::ErrorNotFound(format!(
+                "No pulse or blink is running on GPIO #{}",
+                msg.gpio_id
+            ))
+        })?;
+        info!("Cancelled {} on GPIO #{}", running.pattern, msg.gpio_id);
+
+        let gpio_id = msg.gpio_id;
+        let set = set_level(
+            &self.db,
+            self.gpio_arc_mutex.clone(),
+            gpio_id,
+            running.final_level.clone(),
+            running.lease_id,
+        )
+        .map(|_| ())
+        .map_err(move |err| error!("Failed to end pattern on GPIO #{}: {}", gpio_id, err));
+        ctx.spawn(wrap_future(set));
+
+        Ok(models::Sequence {
+            gpio_id,
+            pattern: running.pattern.to_string(),
+            final_level: running.final_level,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn pulse_steps_must_return_to_previous_level() {
+        let pattern = Pattern::pulse("HIGH", 500).unwrap();
+        let steps: Vec<SequenceStep> = pattern.steps("low").into_iter().collect();
+
+        assert_eq!(
+            steps,
+            vec![
+                SequenceStep::new(ms(0), "high"),
+                SequenceStep::new(ms(500), "low")
+            ]
+        );
+    }
+
+    #[test]
+    fn blink_steps_must_follow_duty() {
+        let pattern = Pattern::blink(1000, 25, 2).unwrap();
+        let steps: Vec<SequenceStep> = pattern.steps("high").into_iter().collect();
+
+        assert_eq!(
+            steps,
+            vec![
+                SequenceStep::new(ms(0), "high"),
+                SequenceStep::new(ms(250), "low"),
+                SequenceStep::new(ms(750), "high"),
+                SequenceStep::new(ms(250), "low"),
+                SequenceStep::new(ms(750), "high"),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_patterns_must_fail() {
+        assert!(Pattern::pulse("unknown_level", 500).is_err());
+        assert!(Pattern::pulse("high", 0).is_err());
+        assert!(Pattern::blink(1000, 100, 2).is_err());
+        assert!(Pattern::blink(1000, 50, 0).is_err());
+        assert!(Pattern::blink(1, 50, 2).is_err());
+    }
+}
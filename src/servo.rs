@@ -0,0 +1,160 @@
+// Hobby servos on PWM GPIOs, moved by angle: the angle is turned into the width of a pulse
+// sent every 20 ms
+
+use crate::errors::RpWebError;
+use crate::models::{Gpio, Servo, ServoStatus};
+use crate::rpi::{hardware_pwm_channel, set_gpio_pwm_rpi, GpioArcMutex};
+use crate::settings::PinConfig;
+use crate::utilities::set_gpio_pwm_db;
+use diesel::prelude::*;
+use std::time::Duration;
+
+/// Servos expect a pulse every 20 ms
+pub const SERVO_FREQUENCY: f64 = 50.0;
+
+/// The time between two pulses, and the longest pulse
+pub const SERVO_PERIOD_US: u32 = 20_000;
+
+pub const DEFAULT_MIN_PULSE_US: u32 = 1000;
+pub const DEFAULT_MAX_PULSE_US: u32 = 2000;
+pub const DEFAULT_MIN_ANGLE: f64 = 0.0;
+pub const DEFAULT_MAX_ANGLE: f64 = 180.0;
+
+/// The servo configured by `pin`, if it is of kind 'servo'. Unless configured otherwise,
+/// 0 degrees is a pulse of 1000 µs, 180 degrees a pulse of 2000 µs, and the speed is not
+/// limited.
+pub fn servo_from_pin(pin: &PinConfig) -> Option<Servo> {
+    match pin.kind.as_ref() {
+        Some(kind) if kind.to_lowercase() == "servo" => {}
+        _ => return None,
+    }
+
+    Some(Servo {
+        gpio_id: pin.gpio_id,
+        min_pulse_us: pin.min_pulse_us.unwrap_or(DEFAULT_MIN_PULSE_US) as i32,
+        max_pulse_us: pin.max_pulse_us.unwrap_or(DEFAULT_MAX_PULSE_US) as i32,
+        min_angle: pin.min_angle.unwrap_or(DEFAULT_MIN_ANGLE),
+        max_angle: pin.max_angle.unwrap_or(DEFAULT_MAX_ANGLE),
+        max_speed: pin.max_speed,
+    })
+}
+
+impl Servo {
+    pub fn check_angle(&self, angle: f64) -> Result<(), RpWebError> {
+        if !(self.min_angle..=self.max_angle).contains(&angle) {
+            let errs = format!(
+                "Invalid angle for servo on GPIO #{}: {} - use {} to {} degrees",
+                self.gpio_id, angle, self.min_angle, self.max_angle
+            );
+            return Err(RpWebError::new(&errs));
+        }
+        Ok(())
+    }
+
+    /// The PWM duty cycle in percent at `SERVO_FREQUENCY` for `angle`
+    pub fn duty_cycle(&self, angle: f64) -> f64 {
+        let part = (angle - self.min_angle) / (self.max_angle - self.min_angle);
+        let pulse_us = f64::from(self.min_pulse_us)
+            + part * f64::from(self.max_pulse_us - self.min_pulse_us);
+        pulse_us * 100.0 / f64::from(SERVO_PERIOD_US)
+    }
+
+    /// The angle of a GPIO running PWM at `frequency` and `duty_cycle`, if it is sending
+    /// pulses at `SERVO_FREQUENCY`
+    pub fn angle(&self, frequency: Option<f64>, duty_cycle: Option<f64>) -> Option<f64> {
+        let duty_cycle = duty_cycle.filter(|duty_cycle| *duty_cycle > 0.0)?;
+        if frequency != Some(SERVO_FREQUENCY) {
+            return None;
+        }
+        let pulse_us = duty_cycle * f64::from(SERVO_PERIOD_US) / 100.0;
+        let part = (pulse_us - f64::from(self.min_pulse_us))
+            / f64::from(self.max_pulse_us - self.min_pulse_us);
+        Some(self.min_angle + part * (self.max_angle - self.min_angle))
+    }
+
+    /// The time to turn from `from` to `to` degrees at `max_speed`, zero without a limit
+    pub fn travel_time(&self, from: f64, to: f64) -> Duration {
+        match self.max_speed {
+            Some(max_speed) => {
+                Duration::from_millis(((to - from).abs() * 1000.0 / max_speed).round() as u64)
+            }
+            None => Duration::from_millis(0),
+        }
+    }
+}
+
+/// `servo` on `gpio`, with the angle it is set to
+pub fn servo_status(servo: Servo, gpio: &Gpio) -> ServoStatus {
+    let angle = servo.angle(gpio.pwm_frequency, gpio.pwm_duty_cycle);
+    ServoStatus { servo, angle }
+}
+
+/// Store the servos of `pins`, and start their PWM at `SERVO_FREQUENCY` without pulses, so
+/// they hold still until they are first moved
+pub fn setup_servos(
+    pins: &[PinConfig], conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    use crate::schema::gpio_servos::dsl::*;
+
+    // Servos are set from the configuration file on every start
+    diesel::delete(gpio_servos).execute(conn)?;
+    for servo in pins.iter().filter_map(servo_from_pin) {
+        diesel::insert_into(gpio_servos).values(&servo).execute(conn)?;
+
+        let channel = hardware_pwm_channel(servo.gpio_id);
+        set_gpio_pwm_rpi(servo.gpio_id, SERVO_FREQUENCY, 0.0, gpio_arc_mutex.clone())?;
+        set_gpio_pwm_db(servo.gpio_id, Some(SERVO_FREQUENCY), Some(0.0), channel, conn)?;
+    }
+
+    Ok(())
+}
+
+/// The servo on GPIO #`id`, if it is one
+pub fn load_servo_db(id: i32, conn: &SqliteConnection) -> Result<Option<Servo>, RpWebError> {
+    use crate::schema::gpio_servos::dsl::*;
+
+    let servo = gpio_servos.filter(gpio_id.eq(id)).first::<Servo>(conn).optional()?;
+    Ok(servo)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn servo(max_speed: Option<f64>) -> Servo {
+        Servo {
+            gpio_id: 18,
+            min_pulse_us: 1000,
+            max_pulse_us: 2000,
+            min_angle: -90.0,
+            max_angle: 90.0,
+            max_speed,
+        }
+    }
+
+    #[test]
+    fn servo_angle_must_map_to_pulse_width() {
+        let servo = servo(None);
+
+        assert_eq!(servo.duty_cycle(-90.0), 5.0);
+        assert_eq!(servo.duty_cycle(0.0), 7.5);
+        assert_eq!(servo.duty_cycle(90.0), 10.0);
+        assert_eq!(servo.angle(Some(50.0), Some(7.5)), Some(0.0));
+        assert_eq!(servo.angle(Some(50.0), Some(10.0)), Some(90.0));
+        assert_eq!(servo.angle(Some(50.0), Some(0.0)), None);
+        assert_eq!(servo.angle(Some(1000.0), Some(7.5)), None);
+    }
+
+    #[test]
+    fn servo_travel_time_must_follow_max_speed() {
+        assert_eq!(servo(None).travel_time(0.0, 90.0), Duration::from_millis(0));
+        assert_eq!(servo(Some(60.0)).travel_time(30.0, -60.0), Duration::from_millis(1500));
+    }
+
+    #[test]
+    fn servo_angle_out_of_range_must_fail() {
+        assert!(servo(None).check_angle(90.0).is_ok());
+        assert!(servo(None).check_angle(91.0).is_err());
+        assert!(servo(None).check_angle(-90.5).is_err());
+    }
+}
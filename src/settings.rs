@@ -1,5 +1,7 @@
 use clap::ArgMatches;
 use config::{Config, ConfigError, File};
+use crate::models::{GpioIdentifier, RuleRequest};
+use std::collections::HashMap;
 
 // https://github.com/mehcode/config-rs/tree/master/examples/hierarchical-env
 #[derive(Debug, Serialize, Deserialize)]
@@ -18,15 +20,167 @@ pub struct GpioConfig {
     pub gpios_in_use: Option<Vec<i32>>,
     pub gpios_mode_output: Option<Vec<i32>>,
     pub gpios_mode_input: Option<Vec<i32>>,
+    // Outputs driven with PWM, by a hardware channel where the GPIO has one
+    pub gpios_mode_pwm: Option<Vec<i32>>,
     pub gpios_level_low: Option<Vec<i32>>,
     pub gpios_level_high: Option<Vec<i32>>,
 }
 
+/// Optional name, description and tags for a GPIO, from a `[[pins]]` entry. An output with
+/// `max_on_duration` (seconds) is forced back to `safe_level` ('low' by default) when it has
+/// been away from it for longer. A PWM GPIO of kind 'servo' is moved by angle, see
+/// `servo::servo_from_pin` for the defaults.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PinConfig {
+    pub gpio_id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub max_on_duration: Option<u32>,
+    pub safe_level: Option<String>,
+    pub kind: Option<String>,
+    pub min_pulse_us: Option<u32>,
+    pub max_pulse_us: Option<u32>,
+    pub min_angle: Option<f64>,
+    pub max_angle: Option<f64>,
+    pub max_speed: Option<f64>, // Degrees per second
+}
+
+/// Periodic check that the pins are at the level in the database, from the `[drift]` section.
+/// Mismatches are reported; for GPIOs in `correct_hardware` the pin is set to the level in
+/// the database, for GPIOs in `correct_database` the database is set to the level of the pin.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DriftConfig {
+    pub interval_seconds: u64,
+    pub correct_hardware: Option<Vec<i32>>,
+    pub correct_database: Option<Vec<i32>>,
+}
+
+/// An iCalendar file driving a GPIO or a group, from a `[[calendars]]` entry: the output
+/// is high during the events in the file and low otherwise
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CalendarConfig {
+    pub path: String,
+    pub gpio_id: Option<i32>,
+    pub group: Option<String>,
+}
+
+/// Presence simulation, from the `[vacation]` section: while it is on, the `gpios` are
+/// switched on and off at random within the daily `windows`, like '18:00-23:30'
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VacationConfig {
+    pub gpios: Vec<i32>,
+    pub windows: Vec<String>,
+    pub min_on_minutes: Option<u32>,
+    pub max_on_minutes: Option<u32>,
+    pub min_off_minutes: Option<u32>,
+    pub max_off_minutes: Option<u32>,
+}
+
+/// A rule from a `[[rules]]` entry, linking input GPIO `input` to output GPIO `output`.
+/// See `RuleRequest` for the triggers and actions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RuleConfig {
+    pub name: Option<String>,
+    pub input: i32,
+    pub trigger: String,
+    pub output: i32,
+    pub action: Option<String>,
+    pub level: Option<String>,
+    pub duration_ms: Option<u32>,
+    pub inverted: Option<bool>,
+}
+
+impl RuleConfig {
+    pub fn to_request(&self) -> RuleRequest {
+        RuleRequest {
+            name: self.name.clone(),
+            input: GpioIdentifier::Id(self.input),
+            trigger: self.trigger.clone(),
+            output: GpioIdentifier::Id(self.output),
+            action: self.action.clone(),
+            level: self.level.clone(),
+            duration_ms: self.duration_ms,
+            inverted: self.inverted,
+            enabled: Some(true),
+        }
+    }
+}
+
+/// Rhai scripts, from the `[scripts]` section: every `.rhai` file in `directory` is run in its
+/// own thread at startup. `max_operations` stops scripts that run for too long.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ScriptsConfig {
+    pub directory: String,
+    pub max_operations: Option<u64>,
+}
+
+/// A stepper motor driver, from a `[[steppers]]` entry: a pulse on output `step_pin` is one
+/// step, in the direction set on output `dir_pin` ('high' counts up). `enable_pin`, if any,
+/// is set to `enable_level` ('low' by default) while moving. `home_pin` is an input that
+/// goes high at position 0. Speeds are in steps per second, `acceleration` in steps per
+/// second squared, and `profile` is 'constant', 'trapezoidal' or 's-curve'.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepperConfig {
+    pub name: String,
+    pub step_pin: i32,
+    pub dir_pin: i32,
+    pub enable_pin: Option<i32>,
+    pub enable_level: Option<String>,
+    pub home_pin: Option<i32>,
+    pub steps_per_second: f64,
+    pub acceleration: Option<f64>,
+    pub profile: Option<String>,
+    pub min_position: Option<i32>,
+    pub max_position: Option<i32>,
+}
+
+/// A device built from several GPIOs, from a `[[devices]]` entry: `pins` maps the roles of
+/// its `kind` to GPIO ids. See `device::DeviceKind` for the kinds, their roles and their
+/// operations. The GPIOs are owned by the device, and cannot be set directly.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceConfig {
+    pub name: String,
+    pub kind: String,
+    pub pins: HashMap<String, i32>,
+}
+
+/// An interlock between outputs, from an `[[interlocks]]` entry. `kind` 'exclusive': at most
+/// one of `gpios` is high. 'requires': the first of two `gpios` is only high while the second
+/// is high. 'dead_time': the second of two `gpios` only goes high once the first has been low
+/// for `dead_time_ms`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InterlockConfig {
+    pub kind: String,
+    pub gpios: Vec<i32>,
+    pub dead_time_ms: Option<u32>,
+}
+
+/// Where the Pi is, from the `[location]` section, for schedules at sunrise and sunset.
+/// Degrees, north and east positive.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct LocationConfig {
+    pub latitude: f64,
+    pub longitude: f64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Settings {
     pub webserver: Webserver,
     pub database: Database,
     pub gpioconfig: GpioConfig,
+    pub pins: Option<Vec<PinConfig>>,
+    // Group name -> GPIO ids. Group names are lowercased when read.
+    pub groups: Option<HashMap<String, Vec<i32>>>,
+    pub drift: Option<DriftConfig>,
+    pub location: Option<LocationConfig>,
+    pub calendars: Option<Vec<CalendarConfig>>,
+    pub vacation: Option<VacationConfig>,
+    pub rules: Option<Vec<RuleConfig>>,
+    pub scripts: Option<ScriptsConfig>,
+    pub steppers: Option<Vec<StepperConfig>>,
+    pub devices: Option<Vec<DeviceConfig>>,
+    pub interlocks: Option<Vec<InterlockConfig>>,
 }
 
 impl Settings {
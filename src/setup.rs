@@ -1,12 +1,21 @@
+use crate::control::apply_planned_gpio_levels;
+use crate::device::check_gpio_owner_db;
 use crate::errors::RpWebError;
+use crate::leases::check_gpio_lease_db;
+use crate::models::{Gpio, LevelChange, StateChange};
 use crate::rpi::{
-    set_gpio_level_rpi, 
-    GpioArcMutex, 
-    reset_gpio_output_pin_rpi, 
-    set_reset_on_drop_false_for_output_pin_rpi};
-use crate::settings::GpioConfig;
-use crate::utilities::{set_gpio_in_use_db, set_gpio_level_db, set_gpio_mode_db};
-use diesel::SqliteConnection;
+    get_gpio_level_rpi, hardware_pwm_channel, reset_gpio_output_pin_rpi, set_gpio_level_rpi,
+    set_gpio_pwm_rpi, set_reset_on_drop_false_for_output_pin_rpi, stop_gpio_pwm_rpi, GpioArcMutex,
+    DEFAULT_PWM_FREQUENCY,
+};
+use crate::settings::{GpioConfig, PinConfig};
+use crate::utilities::{
+    add_gpio_group_member_db, set_gpio_in_use_db, set_gpio_level_db, set_gpio_mode_db,
+    set_gpio_name_db, set_gpio_pwm_db,
+};
+use crate::validation::vec_option_to_vec;
+use diesel::prelude::*;
+use std::collections::HashMap;
 
 pub fn setup_rpi_and_db(
     gpioconfig: &GpioConfig, conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
@@ -21,6 +30,7 @@ pub fn setup_rpi_and_db(
     // Should be set to OUTPUT
     if let Some(gpios_mode_output) = &gpioconfig.gpios_mode_output {
         for idx in gpios_mode_output.iter() {
+            stop_gpio_pwm(*idx, conn, gpio_arc_mutex.clone())?;
             set_gpio_mode_db(*idx, "output", conn)?;
 
             reset_gpio_output_pin_rpi(*idx, gpio_arc_mutex.clone())?;
@@ -31,10 +41,21 @@ pub fn setup_rpi_and_db(
     // Should be set to INPUT
     if let Some(gpios_mode_input) = &gpioconfig.gpios_mode_input {
         for idx in gpios_mode_input.iter() {
+            stop_gpio_pwm(*idx, conn, gpio_arc_mutex.clone())?;
             set_gpio_mode_db(*idx, "input", conn)?;
         }
     }
 
+    // Should be set to PWM, starting with a duty cycle of 0
+    if let Some(gpios_mode_pwm) = &gpioconfig.gpios_mode_pwm {
+        for idx in gpios_mode_pwm.iter() {
+            set_gpio_mode_db(*idx, "pwm", conn)?;
+            set_gpio_pwm_rpi(*idx, DEFAULT_PWM_FREQUENCY, 0.0, gpio_arc_mutex.clone())?;
+            let channel = hardware_pwm_channel(*idx);
+            set_gpio_pwm_db(*idx, Some(DEFAULT_PWM_FREQUENCY), Some(0.0), channel, conn)?;
+        }
+    }
+
     // Should be set to LOW
     if let Some(gpios_level_low) = &gpioconfig.gpios_level_low {
         for idx in gpios_level_low.iter() {
@@ -53,3 +74,156 @@ pub fn setup_rpi_and_db(
 
     Ok(())
 }
+
+/// Stop the PWM of GPIO #`id` if it was in mode 'pwm'
+fn stop_gpio_pwm(
+    id: i32, conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<(), RpWebError> {
+    use crate::schema::gpio_state::dsl::*;
+
+    let mode = gpio_state
+        .filter(gpio_id.eq(id))
+        .select(gpio_mode)
+        .first::<Option<String>>(conn)?;
+    if mode.as_deref() == Some("pwm") {
+        stop_gpio_pwm_rpi(id, gpio_arc_mutex)?;
+        set_gpio_pwm_db(id, None, None, None, conn)?;
+    }
+    Ok(())
+}
+
+/// Bring the GPIOs and the database to the state in `desired`, changing only what differs
+/// from `gpio_state`, or for the level of an output, from `gpio_state` or the pin. GPIOs not
+/// in `desired.gpios_in_use` are taken out of use. Nothing is changed if a GPIO that would
+/// change is leased or owned by a device. Use and modes are set with `setup_rpi_and_db`, and
+/// the levels with `apply_planned_gpio_levels` in the same transaction, so the pins are
+/// reverted if it fails. The changes are returned.
+pub fn reconcile_rpi_and_db(
+    desired: &GpioConfig, conn: &SqliteConnection, gpio_arc_mutex: GpioArcMutex,
+) -> Result<Vec<StateChange>, RpWebError> {
+    use crate::schema::gpio_state::dsl::*;
+
+    let gpios_in_use = vec_option_to_vec(&desired.gpios_in_use);
+    let gpios_mode_output = vec_option_to_vec(&desired.gpios_mode_output);
+    let gpios_mode_input = vec_option_to_vec(&desired.gpios_mode_input);
+    let gpios_mode_pwm = vec_option_to_vec(&desired.gpios_mode_pwm);
+    let gpios_level_low = vec_option_to_vec(&desired.gpios_level_low);
+    let gpios_level_high = vec_option_to_vec(&desired.gpios_level_high);
+
+    let mut changes = vec![];
+    let mut diff = GpioConfig {
+        gpios_in_use: Some(vec![]),
+        gpios_mode_output: Some(vec![]),
+        gpios_mode_input: Some(vec![]),
+        gpios_mode_pwm: Some(vec![]),
+        gpios_level_low: None,
+        gpios_level_high: None,
+    };
+    let mut gpios_not_in_use = vec![];
+
+    let plan = || {
+        let current = gpio_state.order(gpio_id.asc()).load::<Gpio>(conn)?;
+        let mut level_changes = vec![];
+
+        for gpio in current.iter() {
+            let idx = gpio.gpio_id;
+            let changes_before = changes.len();
+
+            let desired_in_use = gpios_in_use.contains(&idx);
+            if desired_in_use && gpio.in_use != 1 {
+                diff.gpios_in_use.get_or_insert_with(Vec::new).push(idx);
+                changes.push(StateChange::new(idx, "in_use", Some("0"), "1"));
+            } else if !desired_in_use && gpio.in_use == 1 {
+                gpios_not_in_use.push(idx);
+                changes.push(StateChange::new(idx, "in_use", Some("1"), "0"));
+            }
+
+            let desired_mode = if gpios_mode_output.contains(&idx) {
+                Some(("output", &mut diff.gpios_mode_output))
+            } else if gpios_mode_input.contains(&idx) {
+                Some(("input", &mut diff.gpios_mode_input))
+            } else if gpios_mode_pwm.contains(&idx) {
+                Some(("pwm", &mut diff.gpios_mode_pwm))
+            } else {
+                None
+            };
+            let mut mode_after = gpio.gpio_mode.as_deref();
+            if let Some((mode, diff_mode)) = desired_mode {
+                if gpio.gpio_mode.as_deref() != Some(mode) {
+                    diff_mode.get_or_insert_with(Vec::new).push(idx);
+                    let before = gpio.gpio_mode.as_deref();
+                    changes.push(StateChange::new(idx, "gpio_mode", before, mode));
+                }
+                mode_after = Some(mode);
+            }
+
+            let desired_level = if gpios_level_low.contains(&idx) {
+                Some("low")
+            } else if gpios_level_high.contains(&idx) {
+                Some("high")
+            } else {
+                None
+            };
+            if let Some(level) = desired_level {
+                // The pin of an output may have drifted from the database
+                let pin_level = match mode_after {
+                    Some("output") => Some(get_gpio_level_rpi(idx, gpio_arc_mutex.clone())?),
+                    _ => None,
+                };
+                let level_before = pin_level.as_deref().or(gpio.gpio_level.as_deref());
+                let db_differs = gpio.gpio_level.as_deref() != Some(level);
+                if db_differs || pin_level.as_deref().is_some_and(|pin| pin != level) {
+                    let before = if db_differs {
+                        gpio.gpio_level.as_deref()
+                    } else {
+                        level_before
+                    };
+                    changes.push(StateChange::new(idx, "gpio_level", before, level));
+                    level_changes.push(LevelChange::new(idx, level, level_before));
+                }
+            }
+
+            if changes.len() > changes_before {
+                check_gpio_lease_db(idx, None, conn)?;
+                check_gpio_owner_db(idx, conn)?;
+            }
+        }
+
+        setup_rpi_and_db(&diff, conn, gpio_arc_mutex.clone())?;
+        for idx in gpios_not_in_use.iter() {
+            stop_gpio_pwm(*idx, conn, gpio_arc_mutex.clone())?;
+            set_gpio_in_use_db(*idx, 0, conn)?;
+        }
+        Ok(level_changes)
+    };
+    apply_planned_gpio_levels(plan, conn, gpio_arc_mutex.clone())?;
+
+    Ok(changes)
+}
+
+pub fn setup_pin_names_db(pins: &[PinConfig], conn: &SqliteConnection) -> Result<(), RpWebError> {
+    for pin in pins.iter() {
+        let tags = pin.tags.as_ref().map(|tags| tags.join(","));
+        set_gpio_name_db(
+            pin.gpio_id,
+            &pin.name,
+            pin.description.as_deref(),
+            tags.as_deref(),
+            conn,
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn setup_gpio_groups_db(
+    groups: &HashMap<String, Vec<i32>>, conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    for (group_name, members) in groups.iter() {
+        for idx in members.iter() {
+            add_gpio_group_member_db(group_name, *idx, conn)?;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,212 @@
+// Sunrise and sunset computed from the `[location]` in the configuration, without any lookup,
+// using the sunrise equation: https://en.wikipedia.org/wiki/Sunrise_equation
+
+use crate::errors::RpWebError;
+use crate::models::SolarDay;
+use crate::settings::LocationConfig;
+use crate::utilities::{timestamp, utc_to_local};
+use chrono::{Duration as ChronoDuration, NaiveDate, NaiveDateTime};
+
+/// Julian day of 2000-01-01 12:00 UTC
+const J2000: f64 = 2_451_545.0;
+
+/// Julian day of 1970-01-01 00:00 UTC
+const UNIX_EPOCH_JD: f64 = 2_440_587.5;
+
+/// Altitude of the center of the sun at sunrise and sunset, for refraction and its radius
+const SUN_ALTITUDE_DEGREES: f64 = -0.833;
+
+/// Tilt of the earth's axis
+const OBLIQUITY_DEGREES: f64 = 23.44;
+
+/// Number of days searched for the next sunrise or sunset. Covers a polar night at the poles.
+const SEARCH_DAYS: i64 = 370;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SolarEvent {
+    Sunrise,
+    Sunset,
+}
+
+impl SolarEvent {
+    pub fn parse(name: &str) -> Result<SolarEvent, RpWebError> {
+        match name.to_lowercase().as_str() {
+            "sunrise" => Ok(SolarEvent::Sunrise),
+            "sunset" => Ok(SolarEvent::Sunset),
+            _ => Err(RpWebError::new(&format!(
+                "Invalid solar event '{}' - use 'sunrise' or 'sunset'",
+                name
+            ))),
+        }
+    }
+}
+
+/// Sunrise and sunset in UTC on a day, or the sun staying up or down all day
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Daylight {
+    Normal {
+        sunrise: NaiveDateTime,
+        sunset: NaiveDateTime,
+    },
+    PolarDay,
+    PolarNight,
+}
+
+/// Sunrise and sunset around solar noon on `date` at `location`, in UTC
+pub fn daylight_utc(location: &LocationConfig, date: NaiveDate) -> Daylight {
+    let days = (date - NaiveDate::from_ymd(2000, 1, 1)).num_days() as f64;
+
+    // Mean solar noon, anomaly, center and ecliptic longitude of the sun
+    let mean_noon = days - location.longitude / 360.0;
+    let anomaly = (357.5291 + 0.985_600_28 * mean_noon).rem_euclid(360.0).to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.02 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = J2000 + mean_noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic).sin();
+
+    let declination = (ecliptic.sin() * OBLIQUITY_DEGREES.to_radians().sin()).asin();
+    let latitude = location.latitude.to_radians();
+    let cos_hour_angle = (SUN_ALTITUDE_DEGREES.to_radians().sin()
+        - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+
+    if cos_hour_angle < -1.0 {
+        return Daylight::PolarDay;
+    }
+    if cos_hour_angle > 1.0 {
+        return Daylight::PolarNight;
+    }
+
+    let half_day = cos_hour_angle.acos().to_degrees() / 360.0;
+    Daylight::Normal {
+        sunrise: julian_to_utc(transit - half_day),
+        sunset: julian_to_utc(transit + half_day),
+    }
+}
+
+fn julian_to_utc(julian_day: f64) -> NaiveDateTime {
+    let millis = ((julian_day - UNIX_EPOCH_JD) * 86_400_000.0).round() as i64;
+    NaiveDateTime::from_timestamp(0, 0) + ChronoDuration::milliseconds(millis)
+}
+
+/// Sunrise and sunset on `date` at `location`, in local time
+pub fn solar_day(location: &LocationConfig, date: NaiveDate) -> SolarDay {
+    let (sunrise, sunset, polar) = match daylight_utc(location, date) {
+        Daylight::Normal { sunrise, sunset } => (
+            Some(timestamp(utc_to_local(sunrise))),
+            Some(timestamp(utc_to_local(sunset))),
+            None,
+        ),
+        Daylight::PolarDay => (None, None, Some("polar_day".to_string())),
+        Daylight::PolarNight => (None, None, Some("polar_night".to_string())),
+    };
+
+    SolarDay {
+        date: date.format("%Y-%m-%d").to_string(),
+        sunrise,
+        sunset,
+        polar,
+    }
+}
+
+/// The first `event` plus `offset_minutes` after `time`, in local time. Days without the
+/// event, during a polar day or night, are skipped.
+pub fn next_solar_event_after(
+    location: &LocationConfig, event: SolarEvent, offset_minutes: i32, time: NaiveDateTime,
+) -> Option<NaiveDateTime> {
+    let offset = ChronoDuration::minutes(i64::from(offset_minutes));
+
+    // From the day before, as a negative offset or the time zone can move the event a day
+    (-1..SEARCH_DAYS)
+        .map(|day| time.date() + ChronoDuration::days(day))
+        .filter_map(|date| match daylight_utc(location, date) {
+            Daylight::Normal { sunrise, sunset } => Some(match event {
+                SolarEvent::Sunrise => sunrise,
+                SolarEvent::Sunset => sunset,
+            }),
+            _ => None,
+        })
+        .map(|at| utc_to_local(at) + offset)
+        .find(|at| *at > time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> NaiveDate {
+        NaiveDate::parse_from_str(text, "%Y-%m-%d").unwrap()
+    }
+
+    fn time(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn assert_close(actual: NaiveDateTime, expected: &str) {
+        let difference = (actual - time(expected)).num_seconds().abs();
+        assert!(difference <= 120, "{} is not close to {}", actual, expected);
+    }
+
+    const COPENHAGEN: LocationConfig = LocationConfig {
+        latitude: 55.6761,
+        longitude: 12.5683,
+    };
+
+    const TROMSO: LocationConfig = LocationConfig {
+        latitude: 69.6492,
+        longitude: 18.9553,
+    };
+
+    #[test]
+    fn daylight_must_match_almanac() {
+        match daylight_utc(&COPENHAGEN, date("2019-06-21")) {
+            Daylight::Normal { sunrise, sunset } => {
+                assert_close(sunrise, "2019-06-21 02:25");
+                assert_close(sunset, "2019-06-21 19:57");
+            }
+            other => panic!("Expected sunrise and sunset, got {:?}", other),
+        }
+
+        match daylight_utc(&COPENHAGEN, date("2019-12-21")) {
+            Daylight::Normal { sunrise, sunset } => {
+                assert_close(sunrise, "2019-12-21 07:37");
+                assert_close(sunset, "2019-12-21 14:38");
+            }
+            other => panic!("Expected sunrise and sunset, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn daylight_beyond_polar_circle_must_be_polar() {
+        assert_eq!(daylight_utc(&TROMSO, date("2019-06-21")), Daylight::PolarDay);
+        assert_eq!(daylight_utc(&TROMSO, date("2019-12-21")), Daylight::PolarNight);
+    }
+
+    #[test]
+    fn next_solar_event_must_skip_polar_day() {
+        let next = next_solar_event_after(&TROMSO, SolarEvent::Sunset, 0, time("2019-06-21 12:00"))
+            .expect("Test failed");
+
+        // The midnight sun lasts until the end of July
+        assert!(next > time("2019-07-20 00:00"));
+        assert!(next < time("2019-07-30 00:00"));
+    }
+
+    #[test]
+    fn next_solar_event_must_add_offset() {
+        let now = time("2019-06-21 12:00");
+        let sunset = next_solar_event_after(&COPENHAGEN, SolarEvent::Sunset, 0, now).unwrap();
+        let later = next_solar_event_after(&COPENHAGEN, SolarEvent::Sunset, 30, now).unwrap();
+
+        assert_eq!(later - sunset, ChronoDuration::minutes(30));
+        assert!(sunset > now);
+    }
+
+    #[test]
+    fn invalid_solar_event_must_fail() {
+        assert_eq!(SolarEvent::parse("SUNSET").unwrap(), SolarEvent::Sunset);
+        assert!(SolarEvent::parse("noon").is_err());
+    }
+}
@@ -0,0 +1,556 @@
+// Stepper motor drivers: every move runs in its own thread, pulsing the step pin through the
+// rpi layer, and the position is saved in the database so it survives restarts
+
+use crate::errors::RpWebError;
+use crate::handlers::{CheckInterlocks, DbExecutor, SaveStepperPosition};
+use crate::models::{StepperMove, StepperPosition, StepperStatus};
+use crate::rpi::{get_gpio_level_rpi, set_gpio_level_rpi, GpioArcMutex};
+use crate::sequencer::set_level;
+use crate::settings::StepperConfig;
+use crate::utilities::current_time;
+use actix::Addr;
+use actix_web::{error, Error as actixError};
+use diesel::prelude::*;
+use futures::Future;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Fastest speed of a stepper; every step is timed by a sleeping thread
+pub const MAX_STEPS_PER_SECOND: f64 = 10_000.0;
+
+/// How long the step pin is high for one step
+const STEP_PULSE: Duration = Duration::from_micros(10);
+
+/// How often the position is saved while moving, besides at the end of a move
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How far homing looks for the home switch when the stepper has no soft limits
+const HOMING_MAX_STEPS: u32 = 100_000;
+
+/// Homing runs at a constant part of the speed of the stepper, so it stops close to the switch
+const HOMING_SPEED_DIVISOR: f64 = 4.0;
+
+/// How the speed of a move goes up from standstill and back down at the end
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepProfile {
+    /// Full speed from the first to the last step
+    Constant,
+    /// Speed up and slow down at a constant acceleration
+    Trapezoidal,
+    /// Like trapezoidal, but the acceleration itself eases in and out, for less jerk
+    SCurve,
+}
+
+impl StepProfile {
+    pub fn parse(name: &str) -> Result<StepProfile, RpWebError> {
+        match name.to_lowercase().as_str() {
+            "constant" => Ok(StepProfile::Constant),
+            "trapezoidal" => Ok(StepProfile::Trapezoidal),
+            "s-curve" => Ok(StepProfile::SCurve),
+            _ => {
+                let errs = format!(
+                    "Invalid profile: '{}', use 'constant', 'trapezoidal' or 's-curve'",
+                    name
+                );
+                Err(RpWebError::new(&errs))
+            }
+        }
+    }
+
+    /// The profile of `config`: trapezoidal if it has an acceleration, else constant
+    pub fn of(config: &StepperConfig) -> Result<StepProfile, RpWebError> {
+        match (&config.profile, config.acceleration) {
+            (Some(profile), _) => StepProfile::parse(profile),
+            (None, Some(_)) => Ok(StepProfile::Trapezoidal),
+            (None, None) => Ok(StepProfile::Constant),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            StepProfile::Constant => "constant",
+            StepProfile::Trapezoidal => "trapezoidal",
+            StepProfile::SCurve => "s-curve",
+        }
+    }
+
+    /// The speed in steps per second of step `step` (from 0) of a move of `steps`, at most
+    /// `max_speed`. Without an acceleration every profile is constant.
+    pub fn speed(self, step: u32, steps: u32, max_speed: f64, acceleration: Option<f64>) -> f64 {
+        let acceleration = match acceleration {
+            Some(acceleration) if self != StepProfile::Constant => acceleration,
+            _ => return max_speed,
+        };
+        // Steps from the nearest end of the move, counting the step itself
+        let from_end = f64::from((step + 1).min(steps.saturating_sub(step)).max(1));
+        let start_speed = (2.0 * acceleration).sqrt().min(max_speed);
+
+        let speed = match self {
+            StepProfile::SCurve => {
+                let ramp_steps = max_speed * max_speed / (2.0 * acceleration);
+                let t = (from_end / ramp_steps).min(1.0);
+                max_speed * t * t * (3.0 - 2.0 * t)
+            }
+            _ => (2.0 * acceleration * from_end).sqrt(),
+        };
+        speed.max(start_speed).min(max_speed)
+    }
+}
+
+/// A stepper with the position it is at, kept up to date while it moves
+#[derive(Debug)]
+pub struct StepperState {
+    pub config: StepperConfig,
+    pub profile: StepProfile,
+    pub position: i32,
+    pub homed: bool,
+    pub target: Option<i32>,
+    pub error: Option<String>,
+    stop: Arc<AtomicBool>,
+}
+
+impl StepperState {
+    fn status(&self) -> StepperStatus {
+        StepperStatus {
+            name: self.config.name.clone(),
+            position: self.position,
+            homed: self.homed,
+            target: self.target,
+            profile: self.profile.name().to_string(),
+            min_position: self.config.min_position,
+            max_position: self.config.max_position,
+            error: self.error.clone(),
+        }
+    }
+}
+
+pub type StepperRegistry = Arc<Mutex<BTreeMap<String, StepperState>>>;
+
+/// Keep the saved positions of the steppers in `configs`, starting new ones at 0, and forget
+/// the steppers no longer configured. Returns the positions.
+pub fn setup_steppers_db(
+    configs: &[StepperConfig], conn: &SqliteConnection,
+) -> Result<Vec<StepperPosition>, RpWebError> {
+    use crate::schema::stepper_positions::dsl::*;
+
+    let names: Vec<&str> = configs.iter().map(|config| config.name.as_str()).collect();
+    diesel::delete(stepper_positions.filter(name.ne_all(names))).execute(conn)?;
+
+    let mut positions = vec![];
+    for config in configs.iter() {
+        let saved = stepper_positions
+            .filter(name.eq(&config.name))
+            .first::<StepperPosition>(conn)
+            .optional()?;
+        match saved {
+            Some(saved) => positions.push(saved),
+            None => {
+                save_stepper_position_db(&config.name, 0, false, conn)?;
+                positions.push(stepper_positions.find(&config.name).first(conn)?);
+            }
+        }
+    }
+
+    Ok(positions)
+}
+
+pub fn save_stepper_position_db(
+    stepper: &str, steps: i32, is_homed: bool, conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    use crate::schema::stepper_positions::dsl::*;
+
+    diesel::replace_into(stepper_positions)
+        .values(&StepperPosition {
+            name: stepper.to_string(),
+            position: steps,
+            homed: is_homed as i32,
+            updated_at: Some(current_time()),
+        })
+        .execute(conn)?;
+    Ok(())
+}
+
+/// A move as the thread runs it
+struct Plan {
+    direction: i32, // 1 or -1
+    steps: u32,
+    profile: StepProfile,
+    max_speed: f64,
+    homing: bool,
+}
+
+/// Starts and stops the moves of the steppers. The direction and enable pins are set through
+/// the DbExecutor like requests from clients; the step pin is pulsed on the pin only, and is
+/// low between moves like in the database. The interlocks are checked for the step pin going
+/// high before a move and whenever the position is saved, and stop the move if broken.
+#[derive(Clone)]
+pub struct StepperDriver {
+    pub db: Addr<DbExecutor>,
+    pub gpio_arc_mutex: GpioArcMutex,
+    pub registry: StepperRegistry,
+}
+
+impl StepperDriver {
+    /// The driver for `configs`, at their saved `positions` or else at 0. The configurations
+    /// must have been validated.
+    pub fn new(
+        db: Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex, configs: &[StepperConfig],
+        positions: &[StepperPosition],
+    ) -> StepperDriver {
+        let mut registry = BTreeMap::new();
+        for config in configs.iter() {
+            let saved = positions.iter().find(|saved| saved.name == config.name);
+            registry.insert(
+                config.name.clone(),
+                StepperState {
+                    config: config.clone(),
+                    profile: StepProfile::of(config).unwrap_or(StepProfile::Constant),
+                    position: saved.map_or(0, |saved| saved.position),
+                    homed: saved.is_some_and(|saved| saved.homed == 1),
+                    target: None,
+                    error: None,
+                    stop: Arc::new(AtomicBool::new(false)),
+                },
+            );
+        }
+
+        StepperDriver {
+            db,
+            gpio_arc_mutex,
+            registry: Arc::new(Mutex::new(registry)),
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<StepperStatus> {
+        self.registry.lock().values().map(StepperState::status).collect()
+    }
+
+    pub fn status(&self, name: &str) -> Result<StepperStatus, actixError> {
+        self.registry
+            .lock()
+            .get(name)
+            .map(StepperState::status)
+            .ok_or_else(|| not_found(name))
+    }
+
+    /// Start moving stepper `name` by `request.steps` or to `request.position`
+    pub fn start_move(&self, name: &str, request: &StepperMove) -> Result<StepperStatus, actixError> {
+        let mut registry = self.registry.lock();
+        let state = registry.get_mut(name).ok_or_else(|| not_found(name))?;
+        check_idle(state)?;
+
+        let target = match (request.steps, request.position) {
+            (Some(steps), None) => state.position.checked_add(steps).ok_or_else(|| {
+                error::ErrorBadRequest(format!("Moving {} steps is too far", steps))
+            })?,
+            (None, Some(position)) => position,
+            _ => return Err(error::ErrorBadRequest("Give either 'steps' or 'position'")),
+        };
+        check_limits(&state.config, target)?;
+
+        let profile = match &request.profile {
+            Some(profile) => {
+                StepProfile::parse(profile).map_err(|err| error::ErrorBadRequest(err.to_string()))?
+            }
+            None => state.profile,
+        };
+        if profile != StepProfile::Constant && state.config.acceleration.is_none() {
+            return Err(error::ErrorBadRequest(format!(
+                "Stepper '{}' has no acceleration for profile '{}'",
+                name,
+                profile.name()
+            )));
+        }
+
+        let distance = target - state.position;
+        let plan = Plan {
+            direction: if distance < 0 { -1 } else { 1 },
+            steps: distance.unsigned_abs(),
+            profile,
+            max_speed: state.config.steps_per_second,
+            homing: false,
+        };
+        Ok(self.start(state, target, plan))
+    }
+
+    /// Start moving stepper `name` home: towards its home switch until it closes, which is
+    /// position 0, or else to position 0
+    pub fn start_home(&self, name: &str) -> Result<StepperStatus, actixError> {
+        let mut registry = self.registry.lock();
+        let state = registry.get_mut(name).ok_or_else(|| not_found(name))?;
+        check_idle(state)?;
+
+        let plan = match state.config.home_pin {
+            Some(_) => {
+                let steps = match (state.config.min_position, state.config.max_position) {
+                    (Some(min), Some(max)) => (max - min).unsigned_abs(),
+                    _ => HOMING_MAX_STEPS,
+                };
+                Plan {
+                    direction: -1,
+                    steps,
+                    profile: StepProfile::Constant,
+                    max_speed: state.config.steps_per_second / HOMING_SPEED_DIVISOR,
+                    homing: true,
+                }
+            }
+            None => Plan {
+                direction: if state.position > 0 { -1 } else { 1 },
+                steps: state.position.unsigned_abs(),
+                profile: state.profile,
+                max_speed: state.config.steps_per_second,
+                homing: false,
+            },
+        };
+        Ok(self.start(state, 0, plan))
+    }
+
+    /// Stop the move of stepper `name` after the step it is at
+    pub fn stop(&self, name: &str) -> Result<StepperStatus, actixError> {
+        let registry = self.registry.lock();
+        let state = registry.get(name).ok_or_else(|| not_found(name))?;
+        if state.target.is_none() {
+            return Err(error::ErrorNotFound(format!("Stepper '{}' is not moving", name)));
+        }
+        state.stop.store(true, Ordering::SeqCst);
+        info!("Stopping stepper '{}' at {}", name, state.position);
+
+        Ok(state.status())
+    }
+
+    fn start(&self, state: &mut StepperState, target: i32, plan: Plan) -> StepperStatus {
+        info!(
+            "Moving stepper '{}' from {} to {} ({})",
+            state.config.name,
+            state.position,
+            target,
+            plan.profile.name()
+        );
+        state.target = Some(target);
+        state.error = None;
+        state.stop = Arc::new(AtomicBool::new(false));
+
+        let (driver, config, stop) = (self.clone(), state.config.clone(), state.stop.clone());
+        let spawned = thread::Builder::new()
+            .name(format!("stepper-{}", config.name))
+            .spawn(move || driver.run(&config, &plan, &stop));
+        if let Err(err) = spawned {
+            error!("Could not start stepper '{}': {}", state.config.name, err);
+            state.target = None;
+            state.error = Some(err.to_string());
+        }
+
+        state.status()
+    }
+
+    /// Run `plan` and save where the stepper got to
+    fn run(&self, config: &StepperConfig, plan: &Plan, stop: &AtomicBool) {
+        let result = self.drive(config, plan, stop);
+        if let Some(enable_pin) = config.enable_pin {
+            let disable_level = if enable_level(config) == "low" { "high" } else { "low" };
+            if let Err(err) = self.set_level(enable_pin, disable_level) {
+                error!("Could not disable stepper '{}': {}", config.name, err);
+            }
+        }
+
+        let (position, homed) = {
+            let mut registry = self.registry.lock();
+            let state = match registry.get_mut(&config.name) {
+                Some(state) => state,
+                None => return,
+            };
+            match result {
+                Ok(true) => {
+                    state.position = 0;
+                    state.homed = true;
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    error!("Stepper '{}' stopped at {}: {}", config.name, state.position, err);
+                    state.error = Some(err);
+                }
+            }
+            state.target = None;
+            (state.position, state.homed)
+        };
+        info!("Stepper '{}' is at {}", config.name, position);
+        if let Err(err) = self.save(&config.name, position, homed) {
+            error!("Could not save the position of stepper '{}': {}", config.name, err);
+        }
+    }
+
+    /// Step through `plan` until it is done or `stop` is set. Returns true if homing found
+    /// the home switch.
+    fn drive(&self, config: &StepperConfig, plan: &Plan, stop: &AtomicBool) -> Result<bool, String> {
+        if let Some(enable_pin) = config.enable_pin {
+            self.set_level(enable_pin, &enable_level(config))?;
+        }
+        let dir_level = if plan.direction > 0 { "high" } else { "low" };
+        self.set_level(config.dir_pin, dir_level)?;
+        self.check_interlocks(config)?;
+
+        let mut last_save = Instant::now();
+        for step in 0..plan.steps {
+            if stop.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            if plan.homing && self.home_switch_closed(config)? {
+                return Ok(true);
+            }
+
+            self.pulse(config.step_pin)?;
+            let position = {
+                let mut registry = self.registry.lock();
+                let state = registry.get_mut(&config.name).ok_or("Stepper was removed")?;
+                state.position += plan.direction;
+                (state.position, state.homed)
+            };
+            if last_save.elapsed() >= SAVE_INTERVAL {
+                self.save(&config.name, position.0, position.1)?;
+                self.check_interlocks(config)?;
+                last_save = Instant::now();
+            }
+
+            let speed = plan.profile.speed(step, plan.steps, plan.max_speed, config.acceleration);
+            let period = Duration::from_secs_f64(1.0 / speed);
+            thread::sleep(period.checked_sub(STEP_PULSE).unwrap_or_default());
+        }
+
+        if plan.homing {
+            if self.home_switch_closed(config)? {
+                return Ok(true);
+            }
+            return Err(format!("Home switch not found after {} steps", plan.steps));
+        }
+        Ok(false)
+    }
+
+    fn pulse(&self, step_pin: i32) -> Result<(), String> {
+        set_gpio_level_rpi(step_pin, "high", self.gpio_arc_mutex.clone())
+            .map_err(|err| err.to_string())?;
+        thread::sleep(STEP_PULSE);
+        set_gpio_level_rpi(step_pin, "low", self.gpio_arc_mutex.clone())
+            .map_err(|err| err.to_string())
+    }
+
+    /// Check that the step pin may go high with the other GPIOs at their level
+    fn check_interlocks(&self, config: &StepperConfig) -> Result<(), String> {
+        self.db
+            .send(CheckInterlocks {
+                levels: vec![(config.step_pin, "high".to_string())],
+            })
+            .wait()
+            .map_err(|err| err.to_string())?
+            .map_err(|err| err.to_string())
+    }
+
+    fn home_switch_closed(&self, config: &StepperConfig) -> Result<bool, String> {
+        match config.home_pin {
+            Some(home_pin) => get_gpio_level_rpi(home_pin, self.gpio_arc_mutex.clone())
+                .map(|level| level == "high")
+                .map_err(|err| err.to_string()),
+            None => Ok(false),
+        }
+    }
+
+    /// Set GPIO #`gpio_id` to `level`, waiting for the DbExecutor
+    fn set_level(&self, gpio_id: i32, level: &str) -> Result<(), String> {
+        set_level(&self.db, self.gpio_arc_mutex.clone(), gpio_id, level.to_string(), None)
+            .wait()
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+
+    fn save(&self, name: &str, position: i32, homed: bool) -> Result<(), String> {
+        self.db
+            .send(SaveStepperPosition {
+                name: name.to_string(),
+                position,
+                homed,
+            })
+            .wait()
+            .map_err(|err| err.to_string())?
+            .map_err(|err| err.to_string())
+    }
+}
+
+fn enable_level(config: &StepperConfig) -> String {
+    config
+        .enable_level
+        .as_deref()
+        .unwrap_or("low")
+        .to_lowercase()
+}
+
+fn not_found(name: &str) -> actixError {
+    error::ErrorNotFound(format!("No stepper is named '{}'", name))
+}
+
+fn check_idle(state: &StepperState) -> Result<(), actixError> {
+    match state.target {
+        Some(target) => Err(error::ErrorConflict(format!(
+            "Stepper '{}' is moving to {}",
+            state.config.name, target
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Refuse a `target` outside the soft limits of the stepper
+fn check_limits(config: &StepperConfig, target: i32) -> Result<(), actixError> {
+    let below = config.min_position.is_some_and(|min| target < min);
+    let above = config.max_position.is_some_and(|max| target > max);
+    if below || above {
+        return Err(error::ErrorBadRequest(format!(
+            "Position {} is outside the soft limits of stepper '{}'",
+            target, config.name
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_profile_must_keep_speed() {
+        let profile = StepProfile::parse("Constant").unwrap();
+        for step in 0..10 {
+            assert_eq!(profile.speed(step, 10, 500.0, Some(1000.0)), 500.0);
+        }
+        assert_eq!(StepProfile::Trapezoidal.speed(0, 10, 500.0, None), 500.0);
+    }
+
+    #[test]
+    fn trapezoidal_profile_must_speed_up_and_slow_down() {
+        let profile = StepProfile::parse("trapezoidal").unwrap();
+        let speeds: Vec<f64> =
+            (0..1000).map(|step| profile.speed(step, 1000, 500.0, Some(1000.0))).collect();
+
+        // 2 * 1000 * 1 steps/s² at the first and last step, full speed after 125 steps
+        assert_eq!(speeds[0], 2000f64.sqrt());
+        assert_eq!(speeds[999], 2000f64.sqrt());
+        assert!(speeds[50] < speeds[100]);
+        assert_eq!(speeds[124], 500.0);
+        assert_eq!(speeds[500], 500.0);
+        assert!(speeds[950] < speeds[900]);
+    }
+
+    #[test]
+    fn s_curve_profile_must_ease_speed() {
+        let profile = StepProfile::parse("S-CURVE").unwrap();
+        let speed = |step| profile.speed(step, 1000, 500.0, Some(1000.0));
+
+        assert_eq!(speed(0), 2000f64.sqrt());
+        assert!(speed(0) < speed(40));
+        assert!(speed(40) < speed(100));
+        assert_eq!(speed(124), 500.0);
+        assert_eq!(speed(500), 500.0);
+        assert!(StepProfile::parse("bounce").is_err());
+    }
+}
@@ -0,0 +1,465 @@
+// Terminal dashboard for headless Raspberry Pis accessed over SSH
+// https://docs.rs/termion/1.5.1/termion/
+
+use crate::control::{apply_gpio_levels, lock_gpio_levels, toggle_gpio_level};
+use crate::errors::RpWebError;
+use crate::handlers::{check_gpio_level, check_gpio_toggle};
+use crate::models;
+use crate::rpi::{create_gpio_arc_mutex, GpioArcMutex};
+use crate::settings::Settings;
+use crate::utilities::open_database_lock;
+use actix::SystemRunner;
+use actix_web::{client, HttpMessage};
+use clap::ArgMatches;
+use diesel::prelude::*;
+use futures::Future;
+use serde::de::DeserializeOwned;
+use std::fs::{File, TryLockError};
+use std::io::{self, Write};
+use std::thread;
+use std::time::{Duration, Instant};
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::AlternateScreen;
+use termion::{clear, color, cursor, style};
+
+const DEFAULT_INTERVAL_MS: u64 = 1000;
+const KEY_POLL_INTERVAL_MS: u64 = 50;
+// Title, help and column header lines above the grid, status line below it
+const HEADER_LINES: u16 = 3;
+const FOOTER_LINES: u16 = 2;
+
+/// Options for the `tui` subcommand
+pub struct TuiOptions {
+    pub remote: Option<String>,
+    pub interval: Duration,
+}
+
+impl TuiOptions {
+    pub fn from_args(args: &ArgMatches) -> TuiOptions {
+        let interval_ms = args
+            .value_of("interval")
+            .and_then(|val| val.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_INTERVAL_MS);
+
+        TuiOptions {
+            remote: args.value_of("remote").map(|url| url.trim_end_matches('/').to_string()),
+            interval: Duration::from_millis(interval_ms),
+        }
+    }
+}
+
+/// Where the dashboard reads GPIO state from and sends level changes to
+pub trait GpioBackend {
+    fn description(&self) -> String;
+    fn load_gpios(&mut self) -> Result<Vec<models::Gpio>, RpWebError>;
+    fn set_gpio_level(&mut self, gpio_id: i32, level: &str) -> Result<models::Gpio, RpWebError>;
+    /// Invert the level of an output where it is known, so a change made in the meantime is
+    /// toggled and not overwritten
+    fn toggle(&mut self, gpio_id: i32) -> Result<models::Gpio, RpWebError>;
+}
+
+/// Works directly against the SQLite database and the GPIO pins of this Raspberry Pi.
+///
+/// A server using the same database holds its lock, and then only drives the pins: levels
+/// are only read, and setting or toggling one fails. Without a server, the lock is held
+/// while a level is changed, and the GPIO pins are only opened then.
+pub struct LocalBackend {
+    database_url: String,
+    connection: SqliteConnection,
+    database_lock: File,
+    gpio_arc_mutex: Option<GpioArcMutex>,
+}
+
+impl LocalBackend {
+    pub fn new(database_url: &str) -> Result<LocalBackend, RpWebError> {
+        let connection = SqliteConnection::establish(database_url).map_err(|err| {
+            RpWebError::new(&format!("Could not open database '{}': {}", database_url, err))
+        })?;
+
+        Ok(LocalBackend {
+            database_url: database_url.to_string(),
+            connection,
+            database_lock: open_database_lock(database_url)?,
+            gpio_arc_mutex: None,
+        })
+    }
+
+    /// Run `change` holding the database lock, or fail if a server holds it
+    fn without_server<T, F>(&mut self, change: F) -> Result<T, RpWebError>
+    where
+        F: FnOnce(&mut LocalBackend) -> Result<T, RpWebError>,
+    {
+        match self.database_lock.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                return Err(RpWebError::new(
+                    "A server is using the database, use --remote to switch GPIOs",
+                ))
+            }
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        }
+
+        let result = change(self);
+        self.database_lock.unlock()?;
+        result
+    }
+
+    fn gpio_arc_mutex(&mut self) -> Result<GpioArcMutex, RpWebError> {
+        if self.gpio_arc_mutex.is_none() {
+            self.gpio_arc_mutex = Some(create_gpio_arc_mutex()?);
+        }
+        Ok(self.gpio_arc_mutex.clone().unwrap())
+    }
+}
+
+impl GpioBackend for LocalBackend {
+    fn description(&self) -> String {
+        format!("local database {}", self.database_url)
+    }
+
+    fn load_gpios(&mut self) -> Result<Vec<models::Gpio>, RpWebError> {
+        use crate::schema::gpio_state::dsl::*;
+
+        let gpios = gpio_state
+            .order(gpio_id.asc())
+            .load::<models::Gpio>(&self.connection)?;
+        Ok(gpios)
+    }
+
+    fn set_gpio_level(&mut self, id: i32, level: &str) -> Result<models::Gpio, RpWebError> {
+        use crate::schema::gpio_state::dsl::*;
+
+        self.without_server(|backend| {
+            let gpio_arc_mutex = backend.gpio_arc_mutex()?;
+            let gpio_before = check_gpio_level(id, level, &backend.connection)
+                .map_err(|err| RpWebError::new(&err.to_string()))?;
+            let change = models::LevelChange::new(id, level, gpio_before.gpio_level.as_deref());
+            apply_gpio_levels(&[change], &backend.connection, gpio_arc_mutex)?;
+
+            let gpio = gpio_state
+                .filter(gpio_id.eq(id))
+                .first::<models::Gpio>(&backend.connection)?;
+            Ok(gpio)
+        })
+    }
+
+    fn toggle(&mut self, id: i32) -> Result<models::Gpio, RpWebError> {
+        use crate::schema::gpio_state::dsl::*;
+
+        self.without_server(|backend| {
+            let gpio_arc_mutex = backend.gpio_arc_mutex()?;
+            let _levels = lock_gpio_levels();
+            check_gpio_toggle(id, None, &backend.connection)
+                .map_err(|err| RpWebError::new(&err.to_string()))?;
+            toggle_gpio_level(id, None, None, &backend.connection, gpio_arc_mutex)?;
+
+            let gpio = gpio_state
+                .filter(gpio_id.eq(id))
+                .first::<models::Gpio>(&backend.connection)?;
+            Ok(gpio)
+        })
+    }
+}
+
+/// Works against the HTTP API of a running raspberry-web server
+pub struct RemoteBackend {
+    url: String,
+    sys: SystemRunner,
+}
+
+impl RemoteBackend {
+    pub fn new(url: &str) -> RemoteBackend {
+        RemoteBackend {
+            url: url.to_string(),
+            sys: actix::System::new("raspberry-web-tui"),
+        }
+    }
+
+    /// GET `path` on the server and parse the JSON response, or return the error body
+    fn get_json<T>(&mut self, path: &str) -> Result<T, RpWebError>
+    where
+        T: DeserializeOwned + 'static,
+    {
+        let request = client::get(format!("{}{}", self.url, path))
+            .finish()
+            .map_err(|err| RpWebError::new(&err.to_string()))?;
+
+        let response = request
+            .send()
+            .map_err(|err| RpWebError::new(&err.to_string()))
+            .and_then(|response| {
+                let status = response.status();
+                response
+                    .body()
+                    .map_err(|err| RpWebError::new(&err.to_string()))
+                    .and_then(move |body| {
+                        if status.is_success() {
+                            serde_json::from_slice::<T>(&body)
+                                .map_err(|err| RpWebError::new(&err.to_string()))
+                        } else {
+                            Err(RpWebError::new(&String::from_utf8_lossy(&body)))
+                        }
+                    })
+            });
+
+        self.sys.block_on(response)
+    }
+}
+
+impl GpioBackend for RemoteBackend {
+    fn description(&self) -> String {
+        format!("server {}", self.url)
+    }
+
+    fn load_gpios(&mut self) -> Result<Vec<models::Gpio>, RpWebError> {
+        self.get_json("/status")
+    }
+
+    fn set_gpio_level(&mut self, gpio_id: i32, level: &str) -> Result<models::Gpio, RpWebError> {
+        self.get_json(&format!("/set/level/{}/{}", gpio_id, level))
+    }
+
+    fn toggle(&mut self, gpio_id: i32) -> Result<models::Gpio, RpWebError> {
+        self.get_json(&format!("/toggle/{}", gpio_id))
+    }
+}
+
+/// One line of the grid, without styling
+pub fn format_row(gpio: &models::Gpio) -> String {
+    format!(
+        "{:>4}  {:<16}  {:<6}  {:<8}  {:<6}  {}",
+        gpio.gpio_id,
+        gpio.name.as_ref().map_or("", String::as_str),
+        if gpio.in_use == 1 { "yes" } else { "no" },
+        gpio.gpio_mode.as_ref().map_or("", String::as_str),
+        gpio.gpio_level.as_ref().map_or("", String::as_str),
+        gpio.last_change.as_ref().map_or("", String::as_str),
+    )
+}
+
+/// State of the dashboard between redraws
+struct Dashboard {
+    gpios: Vec<models::Gpio>,
+    selected: usize,
+    offset: usize,
+    message: String,
+}
+
+impl Dashboard {
+    fn new() -> Dashboard {
+        Dashboard {
+            gpios: vec![],
+            selected: 0,
+            offset: 0,
+            message: String::new(),
+        }
+    }
+
+    fn refresh(&mut self, backend: &mut dyn GpioBackend) {
+        match backend.load_gpios() {
+            Ok(gpios) => {
+                self.gpios = gpios;
+                if self.selected >= self.gpios.len() {
+                    self.selected = self.gpios.len().saturating_sub(1);
+                }
+            }
+            Err(err) => self.message = format!("Refresh failed: {}", err),
+        }
+    }
+
+    fn select_previous(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    fn select_next(&mut self) {
+        if self.selected + 1 < self.gpios.len() {
+            self.selected += 1;
+        }
+    }
+
+    fn set_selected_level(&mut self, backend: &mut dyn GpioBackend, level: &str) {
+        let gpio_id = match self.gpios.get(self.selected) {
+            Some(gpio) => gpio.gpio_id,
+            None => return,
+        };
+
+        self.message = match backend.set_gpio_level(gpio_id, level) {
+            Ok(gpio) => {
+                let message = format!("Set GPIO #{} to '{}'", gpio_id, level);
+                self.gpios[self.selected] = gpio;
+                message
+            }
+            Err(err) => format!("GPIO #{}: {}", gpio_id, err),
+        };
+    }
+
+    fn toggle_selected(&mut self, backend: &mut dyn GpioBackend) {
+        let gpio_id = match self.gpios.get(self.selected) {
+            Some(gpio) => gpio.gpio_id,
+            None => return,
+        };
+
+        self.message = match backend.toggle(gpio_id) {
+            Ok(gpio) => {
+                let level = gpio.gpio_level.clone().unwrap_or_default();
+                self.gpios[self.selected] = gpio;
+                format!("Toggled GPIO #{} to '{}'", gpio_id, level)
+            }
+            Err(err) => format!("GPIO #{}: {}", gpio_id, err),
+        };
+    }
+
+    fn draw<W: Write>(&mut self, out: &mut W, source: &str) -> Result<(), RpWebError> {
+        let (width, height) = termion::terminal_size()?;
+        let rows = height.saturating_sub(HEADER_LINES + FOOTER_LINES).max(1) as usize;
+
+        // Scroll so the selected GPIO stays visible
+        if self.selected < self.offset {
+            self.offset = self.selected;
+        } else if self.selected >= self.offset + rows {
+            self.offset = self.selected + 1 - rows;
+        }
+
+        write!(out, "{}{}", clear::All, cursor::Goto(1, 1))?;
+        write!(out, "{}raspberry-web - {}{}", style::Bold, source, style::Reset)?;
+        write!(
+            out,
+            "{}q: quit  up/down: select  space: toggle  h: high  l: low  r: refresh",
+            cursor::Goto(1, 2)
+        )?;
+        write!(
+            out,
+            "{}{}GPIO  NAME              IN USE  MODE      LEVEL   LAST CHANGE{}",
+            cursor::Goto(1, 3),
+            style::Underline,
+            style::Reset
+        )?;
+
+        let visible = self.gpios.iter().enumerate().skip(self.offset).take(rows);
+        for (line, (idx, gpio)) in visible.enumerate() {
+            let mut row = format_row(gpio);
+            row.truncate(width as usize);
+            write!(out, "{}", cursor::Goto(1, HEADER_LINES + 1 + line as u16))?;
+            if idx == self.selected {
+                write!(out, "{}", style::Invert)?;
+            }
+            if gpio.gpio_level.as_deref() == Some("high") {
+                write!(out, "{}{}{}", color::Fg(color::Green), row, color::Fg(color::Reset))?;
+            } else {
+                write!(out, "{}", row)?;
+            }
+            write!(out, "{}", style::Reset)?;
+        }
+
+        let mut message = self.message.clone();
+        message.truncate(width as usize);
+        write!(out, "{}{}", cursor::Goto(1, height), message)?;
+        out.flush()?;
+        Ok(())
+    }
+}
+
+/// Run the dashboard until the user quits
+pub fn run(settings: &Settings, options: TuiOptions) -> Result<(), RpWebError> {
+    let mut backend: Box<dyn GpioBackend> = match options.remote {
+        Some(ref url) => Box::new(RemoteBackend::new(url)),
+        None => Box::new(LocalBackend::new(&settings.database.database_url)?),
+    };
+    let source = backend.description();
+
+    let mut screen = AlternateScreen::from(io::stdout().into_raw_mode()?);
+    let mut keys = termion::async_stdin().keys();
+    let mut dashboard = Dashboard::new();
+    let mut next_refresh = Instant::now();
+    write!(screen, "{}", cursor::Hide)?;
+
+    loop {
+        let mut redraw = false;
+
+        if Instant::now() >= next_refresh {
+            dashboard.refresh(backend.as_mut());
+            next_refresh = Instant::now() + options.interval;
+            redraw = true;
+        }
+
+        for key in keys.by_ref() {
+            match key? {
+                Key::Char('q') | Key::Esc | Key::Ctrl('c') => {
+                    write!(screen, "{}", cursor::Show)?;
+                    screen.flush()?;
+                    return Ok(());
+                }
+                Key::Up | Key::Char('k') => dashboard.select_previous(),
+                Key::Down | Key::Char('j') => dashboard.select_next(),
+                Key::Char(' ') | Key::Char('\n') | Key::Char('t') => {
+                    dashboard.toggle_selected(backend.as_mut())
+                }
+                Key::Char('h') => dashboard.set_selected_level(backend.as_mut(), "high"),
+                Key::Char('l') => dashboard.set_selected_level(backend.as_mut(), "low"),
+                Key::Char('r') => next_refresh = Instant::now(),
+                _ => {}
+            }
+            redraw = true;
+        }
+
+        if redraw {
+            dashboard.draw(&mut screen, &source)?;
+        }
+        thread::sleep(Duration::from_millis(KEY_POLL_INTERVAL_MS));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs, process};
+
+    #[test]
+    fn local_backend_must_not_switch_gpios_while_server_runs() {
+        let path = env::temp_dir().join(format!("raspberry-web-tui-{}.sqlite", process::id()));
+        let database_url = path.to_string_lossy().to_string();
+        let server_lock = open_database_lock(&database_url).unwrap();
+        server_lock.lock().unwrap();
+
+        let mut backend = LocalBackend::new(&database_url).unwrap();
+        let toggled = backend.toggle(17);
+        let set = backend.set_gpio_level(17, "high");
+        let pins_opened = backend.gpio_arc_mutex.is_some();
+        fs::remove_file(&path).ok();
+        fs::remove_file(format!("{}.lock", database_url)).ok();
+
+        assert!(toggled.unwrap_err().to_string().contains("server"));
+        assert!(set.is_err());
+        assert!(!pins_opened);
+    }
+
+    #[test]
+    fn format_row_must_show_all_columns() {
+        let gpio = models::Gpio {
+            gpio_id: 17,
+            in_use: 1,
+            gpio_mode: Some("output".to_string()),
+            gpio_level: Some("high".to_string()),
+            last_change: Some("2019-02-18 21:19:31".to_string()),
+            name: Some("garage-door".to_string()),
+            description: None,
+            tags: None,
+            version: 3,
+            max_on_duration: None,
+            safe_level: None,
+            on_since: None,
+            forced_off_at: None,
+            pwm_frequency: None,
+            pwm_duty_cycle: None,
+            pwm_channel: None,
+        };
+
+        let row = format_row(&gpio);
+        assert!(row.starts_with("  17  garage-door       yes"));
+        assert!(row.contains("output"));
+        assert!(row.contains("high"));
+        assert!(row.ends_with("2019-02-18 21:19:31"));
+    }
+}
@@ -0,0 +1,31 @@
+// Browser dashboard. The assets are compiled into the binary, so the
+// installed package needs no extra files.
+
+use crate::app::AppState;
+use actix_web::{HttpRequest, HttpResponse};
+
+const INDEX_HTML: &str = include_str!("../assets/ui/index.html");
+const APP_JS: &str = include_str!("../assets/ui/app.js");
+const STYLE_CSS: &str = include_str!("../assets/ui/style.css");
+
+fn asset_response(content_type: &str, body: &'static str) -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type(content_type)
+        .header("Cache-Control", "no-cache")
+        .body(body)
+}
+
+/// Dashboard page
+pub fn ui_index_route(_req: &HttpRequest<AppState>) -> HttpResponse {
+    asset_response("text/html; charset=utf-8", INDEX_HTML)
+}
+
+/// Dashboard script
+pub fn ui_script_route(_req: &HttpRequest<AppState>) -> HttpResponse {
+    asset_response("application/javascript; charset=utf-8", APP_JS)
+}
+
+/// Dashboard stylesheet
+pub fn ui_style_route(_req: &HttpRequest<AppState>) -> HttpResponse {
+    asset_response("text/css; charset=utf-8", STYLE_CSS)
+}
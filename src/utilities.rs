@@ -1,11 +1,32 @@
 use crate::errors::RpWebError;
 use crate::models;
 use crate::schema::gpio_state::dsl::*;
-use chrono::Local;
+use chrono::{Local, NaiveDateTime, TimeZone};
 use diesel::prelude::*;
 use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
 use std::u8::{MAX, MIN};
 
+/// Timestamps of leases and guards have a fixed width, so they can be compared as text
+const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.3f";
+
+pub fn timestamp(time: NaiveDateTime) -> String {
+    time.format(TIMESTAMP_FORMAT).to_string()
+}
+
+pub fn parse_timestamp(text: &str) -> Result<NaiveDateTime, chrono::ParseError> {
+    NaiveDateTime::parse_from_str(text, TIMESTAMP_FORMAT)
+}
+
+pub fn current_time() -> String {
+    timestamp(Local::now().naive_local())
+}
+
+/// Local time of `time` in UTC
+pub fn utc_to_local(time: NaiveDateTime) -> NaiveDateTime {
+    Local.from_utc_datetime(&time).naive_local()
+}
+
 pub fn reset_table_gpio_state(connection: &SqliteConnection) -> Result<(), RpWebError> {
     info!("Resetting all fields in table 'gpio_state'...");
 
@@ -27,6 +48,18 @@ pub fn reset_table_gpio_state(connection: &SqliteConnection) -> Result<(), RpWeb
                 // These two next ones can be discussed
                 gpio_mode.eq(""),
                 gpio_level.eq(""),
+                // Names are set from the configuration file on every start
+                name.eq(None::<String>),
+                description.eq(None::<String>),
+                tags.eq(None::<String>),
+                max_on_duration.eq(None::<i32>),
+                safe_level.eq(None::<String>),
+                on_since.eq(None::<String>),
+                forced_off_at.eq(None::<String>),
+                pwm_frequency.eq(None::<f64>),
+                pwm_duty_cycle.eq(None::<f64>),
+                pwm_channel.eq(None::<i32>),
+                version.eq(version + 1),
             ))
             .execute(connection)?; // DatabaseError
 
@@ -44,6 +77,44 @@ pub fn reset_table_gpio_state(connection: &SqliteConnection) -> Result<(), RpWeb
     Ok(())
 }
 
+pub fn reset_table_gpio_groups(connection: &SqliteConnection) -> Result<(), RpWebError> {
+    use crate::schema::gpio_groups::dsl::*;
+    info!("Resetting table 'gpio_groups'...");
+
+    // Groups are set from the configuration file on every start
+    diesel::delete(gpio_groups).execute(connection)?;
+    Ok(())
+}
+
+pub fn reset_table_gpio_drift(connection: &SqliteConnection) -> Result<(), RpWebError> {
+    use crate::schema::gpio_drift::dsl::*;
+    info!("Resetting table 'gpio_drift'...");
+
+    diesel::delete(gpio_drift).execute(connection)?;
+    Ok(())
+}
+
+pub fn reset_table_gpio_leases(connection: &SqliteConnection) -> Result<(), RpWebError> {
+    use crate::schema::gpio_leases::dsl::*;
+    info!("Resetting table 'gpio_leases'...");
+
+    // Leases do not survive a restart, the GPIOs are set from the configuration file
+    diesel::delete(gpio_leases).execute(connection)?;
+    Ok(())
+}
+
+/// The file next to the database at `database_url` that a server keeps locked while it runs,
+/// so that the terminal dashboard does not drive the pins at the same time
+pub fn open_database_lock(database_url: &str) -> Result<File, RpWebError> {
+    let path = format!("{}.lock", database_url);
+    OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .map_err(|err| RpWebError::new(&format!("Could not open '{}': {}", path, err)))
+}
+
 pub fn get_allowed_states(
     connection: &SqliteConnection, desired_type: &str,
 ) -> Result<HashMap<&'static str, bool>, RpWebError> {
@@ -66,6 +137,7 @@ pub fn set_gpio_in_use_db(id: i32, state: i32, conn: &SqliteConnection) -> Resul
         .set((
             in_use.eq(state),
             last_change.eq(Local::now().naive_local().to_string()),
+            version.eq(version + 1),
         ))
         .execute(conn);
 
@@ -99,6 +171,7 @@ pub fn set_gpio_mode_db(id: i32, mode: &str, conn: &SqliteConnection) -> Result<
         .set((
             gpio_mode.eq(mode),
             last_change.eq(Local::now().naive_local().to_string()),
+            version.eq(version + 1),
         ))
         .execute(conn);
 
@@ -132,6 +205,7 @@ pub fn set_gpio_level_db(id: i32, level: &str, conn: &SqliteConnection) -> Resul
         .set((
             gpio_level.eq(level),
             last_change.eq(Local::now().naive_local().to_string()),
+            version.eq(version + 1),
         ))
         .execute(conn);
 
@@ -155,9 +229,210 @@ pub fn set_gpio_level_db(id: i32, level: &str, conn: &SqliteConnection) -> Resul
             Err(err)?;
         }
     }
+    set_gpio_on_since_db(id, level, conn)
+}
+
+/// Set the PWM of GPIO #`id`: frequency in Hz, duty cycle in percent and hardware channel.
+/// All None when the GPIO leaves mode 'pwm'.
+pub fn set_gpio_pwm_db(
+    id: i32, frequency: Option<f64>, duty_cycle: Option<f64>, channel: Option<i32>,
+    conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    let n_updated = diesel::update(gpio_state.filter(gpio_id.eq(id)))
+        .set((
+            pwm_frequency.eq(frequency),
+            pwm_duty_cycle.eq(duty_cycle),
+            pwm_channel.eq(channel),
+            last_change.eq(Local::now().naive_local().to_string()),
+            version.eq(version + 1),
+        ))
+        .execute(conn)?;
+
+    if n_updated != 1 {
+        let errs = format!("SQL statement 'pwm' for GPIO #{} affects {} rows", id, n_updated);
+        return Err(RpWebError::new(&errs));
+    }
+    info!("Set PWM {:?} Hz at {:?}% for GPIO #{}", frequency, duty_cycle, id);
     Ok(())
 }
 
+/// Track since when a GPIO with a maximum on-time is away from its safe level
+fn set_gpio_on_since_db(id: i32, level: &str, conn: &SqliteConnection) -> Result<(), RpWebError> {
+    let target = gpio_state.filter(gpio_id.eq(id));
+    let (max_on, safe) = target
+        .select((max_on_duration, safe_level))
+        .first::<(Option<i32>, Option<String>)>(conn)?;
+    if max_on.is_none() {
+        return Ok(());
+    }
+
+    if safe.as_deref() == Some(level) {
+        diesel::update(target).set(on_since.eq(None::<String>)).execute(conn)?;
+    } else {
+        // Changes between levels other than the safe one keep the time it was left
+        diesel::update(target.filter(on_since.is_null()))
+            .set(on_since.eq(current_time()))
+            .execute(conn)?;
+    }
+    Ok(())
+}
+
+/// Fail with `PreconditionFailed` unless GPIO #`id` has version `expected`
+pub fn check_gpio_version_db(
+    id: i32, expected: i32, conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    let current = gpio_state
+        .filter(gpio_id.eq(id))
+        .select(version)
+        .first::<i32>(conn)?;
+
+    if current != expected {
+        let errs = format!(
+            "GPIO #{} has version {}, not {}: it changed since it was read",
+            id, current, expected
+        );
+        info!("{}", errs);
+        return Err(RpWebError::PreconditionFailed(errs));
+    }
+    Ok(())
+}
+
+pub fn set_gpio_name_db(
+    id: i32, gpio_name: &str, gpio_description: Option<&str>, gpio_tags: Option<&str>,
+    conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    let target = gpio_state.filter(gpio_id.eq(id));
+
+    let result = diesel::update(target)
+        .set((
+            name.eq(gpio_name),
+            description.eq(gpio_description),
+            tags.eq(gpio_tags),
+            version.eq(version + 1),
+        ))
+        .execute(conn);
+
+    match result {
+        Ok(val) => {
+            if val == 1 {
+                info!("Set 'name={}' for GPIO #{}", gpio_name, id);
+            } else {
+                let errs = format!(
+                    "SQL statement 'name={}' for GPIO #{} affects {} rows",
+                    gpio_name, id, val
+                );
+                Err(RpWebError::new(&errs))?;
+            }
+        }
+        Err(err) => {
+            error!(
+                "Failed to update 'name={}' for GPIO #{}: {:?}",
+                gpio_name, id, err
+            );
+            Err(err)?;
+        }
+    }
+    Ok(())
+}
+
+/// The level an output should be switched to when toggled, if the current level is known
+pub fn toggled_level(level: Option<&str>) -> Option<&'static str> {
+    match level {
+        Some("high") => Some("low"),
+        Some("low") => Some("high"),
+        _ => None,
+    }
+}
+
+pub fn set_gpio_max_on_db(
+    id: i32, max_on: i32, safe: &str, conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    let target = gpio_state.filter(gpio_id.eq(id));
+
+    let n_updated = diesel::update(target)
+        .set((
+            max_on_duration.eq(max_on),
+            safe_level.eq(safe),
+            version.eq(version + 1),
+        ))
+        .execute(conn)?;
+
+    if n_updated != 1 {
+        let errs = format!(
+            "SQL statement 'max_on_duration={}' for GPIO #{} affects {} rows",
+            max_on, id, n_updated
+        );
+        return Err(RpWebError::new(&errs));
+    }
+    info!(
+        "Set 'max_on_duration={}' with safe level '{}' for GPIO #{}",
+        max_on, safe, id
+    );
+    Ok(())
+}
+
+/// Add an entry to the audit history of GPIO #`id`
+pub fn record_audit_db(
+    id: i32, audit_event: &str, level: Option<&str>, audit_detail: Option<&str>,
+    conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    use crate::schema::gpio_audit;
+
+    let entry = models::NewAuditEntry {
+        gpio_id: id,
+        event: audit_event,
+        gpio_level: level,
+        detail: audit_detail,
+        occurred_at: current_time(),
+    };
+    diesel::insert_into(gpio_audit::table)
+        .values(&entry)
+        .execute(conn)?;
+    Ok(())
+}
+
+pub fn add_gpio_group_member_db(
+    group: &str, id: i32, conn: &SqliteConnection,
+) -> Result<(), RpWebError> {
+    use crate::schema::gpio_groups;
+
+    let member = models::GpioGroupMember {
+        group_name: group.to_string(),
+        gpio_id: id,
+    };
+
+    diesel::insert_into(gpio_groups::table)
+        .values(&member)
+        .execute(conn)?;
+    info!("Added GPIO #{} to group '{}'", id, group);
+    Ok(())
+}
+
+/// Return the ids of the GPIOs in `group`, empty if there is no such group
+pub fn get_gpio_group_members(group: &str, conn: &SqliteConnection) -> Result<Vec<i32>, RpWebError> {
+    use crate::schema::gpio_groups::dsl::*;
+
+    let members = gpio_groups
+        .filter(group_name.eq(group))
+        .order(gpio_id.asc())
+        .select(gpio_id)
+        .load::<i32>(conn)?;
+    Ok(members)
+}
+
+/// Return the id of the GPIO named `gpio_name`, or None if no GPIO has that name
+pub fn get_gpio_id_by_name(
+    gpio_name: &str, conn: &SqliteConnection,
+) -> Result<Option<i32>, RpWebError> {
+    let id = gpio_state
+        .filter(name.eq(gpio_name))
+        .select(gpio_id)
+        .first::<i32>(conn)
+        .optional()?;
+
+    Ok(id)
+}
+
 /// Convert x: i32 to u8 if MIN(u8)=0 x <= x <= MAX(u8)=255
 pub fn i32_to_u8(x: i32) -> Result<u8, RpWebError> {
     if i32::from(MIN) <= x && x <= i32::from(MAX) {
@@ -188,4 +463,16 @@ mod tests {
         let resu8 = i32_to_u8(xi32).unwrap();
         assert_eq!(xi32 as u8, resu8);
     }
+
+    #[test]
+    pub fn toggled_level_inverts_known_levels() {
+        assert_eq!(toggled_level(Some("high")), Some("low"));
+        assert_eq!(toggled_level(Some("low")), Some("high"));
+    }
+
+    #[test]
+    pub fn toggled_level_unknown_must_be_none() {
+        assert_eq!(toggled_level(Some("")), None);
+        assert_eq!(toggled_level(None), None);
+    }
 }
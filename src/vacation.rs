@@ -0,0 +1,489 @@
+// Presence simulation while the building is empty: lights switched on and off at random
+// within the configured windows, like somebody moving between rooms
+
+use crate::errors::RpWebError;
+use crate::handlers::{DbExecutor, LoadVacation, SaveVacation};
+use crate::models::{self, SimulatedGpio, VacationStatus};
+use crate::rpi::GpioArcMutex;
+use crate::sequencer::set_level;
+use crate::settings::VacationConfig;
+use crate::utilities::timestamp;
+use actix::fut::{self, wrap_future, ActorFuture};
+use actix::{Actor, Addr, AsyncContext, Context, Handler, Message, ResponseActFuture};
+use actix_web::{error, Error as actixError};
+use chrono::{Duration as ChronoDuration, Local, NaiveDateTime, NaiveTime};
+use diesel::prelude::*;
+use futures::Future;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::time::Duration;
+
+/// How often the simulated lights are looked at
+const VACATION_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Time a light stays on or off, in minutes, unless configured
+const DEFAULT_ON_MINUTES: (u32, u32) = (15, 90);
+const DEFAULT_OFF_MINUTES: (u32, u32) = (5, 45);
+
+impl VacationConfig {
+    /// Shortest and longest time a light stays on, in minutes
+    pub fn on_minutes(&self) -> (u32, u32) {
+        (
+            self.min_on_minutes.unwrap_or(DEFAULT_ON_MINUTES.0),
+            self.max_on_minutes.unwrap_or(DEFAULT_ON_MINUTES.1),
+        )
+    }
+
+    /// Shortest and longest time a light stays off within a window, in minutes
+    pub fn off_minutes(&self) -> (u32, u32) {
+        (
+            self.min_off_minutes.unwrap_or(DEFAULT_OFF_MINUTES.0),
+            self.max_off_minutes.unwrap_or(DEFAULT_OFF_MINUTES.1),
+        )
+    }
+}
+
+/// A daily window like '18:00-23:30'. It ends the next day if the end is before the start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Window {
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+pub fn parse_window(text: &str) -> Result<Window, RpWebError> {
+    let invalid =
+        || RpWebError::new(&format!("Invalid window '{}' - use e.g. '18:00-23:30'", text));
+    let mut times = text.split('-');
+    let mut next_time = || {
+        times
+            .next()
+            .and_then(|time| NaiveTime::parse_from_str(time.trim(), "%H:%M").ok())
+            .ok_or_else(invalid)
+    };
+    let window = Window {
+        start: next_time()?,
+        end: next_time()?,
+    };
+
+    if times.next().is_some() || window.start == window.end {
+        return Err(invalid());
+    }
+    Ok(window)
+}
+
+impl Window {
+    /// The start and end of the window on the day of `start`
+    fn on_day(&self, start: NaiveDateTime) -> (NaiveDateTime, NaiveDateTime) {
+        let start = start.date().and_time(self.start);
+        let end = if self.end > self.start {
+            start.date().and_time(self.end)
+        } else {
+            (start.date() + ChronoDuration::days(1)).and_time(self.end)
+        };
+        (start, end)
+    }
+
+    /// The end of the window if `time` is in it
+    fn end_after(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        [time - ChronoDuration::days(1), time]
+            .iter()
+            .map(|day| self.on_day(*day))
+            .find(|(start, end)| *start <= time && time < *end)
+            .map(|(_, end)| end)
+    }
+
+    fn next_start(&self, time: NaiveDateTime) -> NaiveDateTime {
+        let (start, _) = self.on_day(time);
+        if start > time {
+            start
+        } else {
+            start + ChronoDuration::days(1)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Light {
+    gpio_id: i32,
+    level: &'static str,
+    next_change: NaiveDateTime,
+}
+
+/// The random on and off times of the simulated lights. The same seed and configuration give
+/// the same times.
+pub struct Simulation {
+    seed: u32,
+    rng: StdRng,
+    windows: Vec<Window>,
+    on_seconds: (i64, i64),
+    off_seconds: (i64, i64),
+    lights: Vec<Light>,
+}
+
+impl Simulation {
+    /// Simulation of the lights in `config` from `now`, with all lights off. The windows must
+    /// have been checked.
+    pub fn new(config: &VacationConfig, seed: u32, now: NaiveDateTime) -> Simulation {
+        let minutes_to_seconds =
+            |(min, max): (u32, u32)| (i64::from(min) * 60, i64::from(max) * 60);
+        let mut simulation = Simulation {
+            seed,
+            rng: StdRng::seed_from_u64(u64::from(seed)),
+            windows: config
+                .windows
+                .iter()
+                .filter_map(|window| parse_window(window).ok())
+                .collect(),
+            on_seconds: minutes_to_seconds(config.on_minutes()),
+            off_seconds: minutes_to_seconds(config.off_minutes()),
+            lights: vec![],
+        };
+
+        for gpio_id in config.gpios.iter() {
+            let next_change = simulation.next_on(now);
+            simulation.lights.push(Light {
+                gpio_id: *gpio_id,
+                level: "low",
+                next_change,
+            });
+        }
+        simulation
+    }
+
+    fn random_seconds(&mut self, (min, max): (i64, i64)) -> ChronoDuration {
+        ChronoDuration::seconds(self.rng.gen_range(min, max + 1))
+    }
+
+    fn window_end(&self, time: NaiveDateTime) -> Option<NaiveDateTime> {
+        self.windows
+            .iter()
+            .filter_map(|window| window.end_after(time))
+            .max()
+    }
+
+    /// When a light that is off at `time` is switched on: after a random time off, in a window
+    fn next_on(&mut self, time: NaiveDateTime) -> NaiveDateTime {
+        let off = self.random_seconds(self.off_seconds);
+        match self.window_end(time) {
+            Some(end) if time + off < end => time + off,
+            _ => {
+                let start = self
+                    .windows
+                    .iter()
+                    .map(|window| window.next_start(time))
+                    .min()
+                    .unwrap_or_else(|| time + ChronoDuration::days(1));
+                start + self.random_seconds((0, self.off_seconds.1))
+            }
+        }
+    }
+
+    /// The lights to switch at `now`, and the levels to switch them to
+    pub fn due(&mut self, now: NaiveDateTime) -> Vec<(i32, &'static str)> {
+        let mut changes = vec![];
+
+        for idx in 0..self.lights.len() {
+            if self.lights[idx].next_change > now {
+                continue;
+            }
+
+            let (level, next_change) = match (self.lights[idx].level, self.window_end(now)) {
+                ("low", Some(end)) => {
+                    let on = self.random_seconds(self.on_seconds);
+                    ("high", std::cmp::min(now + on, end))
+                }
+                _ => ("low", self.next_on(now)),
+            };
+
+            let light = &mut self.lights[idx];
+            if light.level != level {
+                changes.push((light.gpio_id, level));
+            }
+            light.level = level;
+            light.next_change = next_change;
+        }
+
+        changes
+    }
+
+    /// The lights that are on
+    pub fn lights_on(&self) -> Vec<i32> {
+        self.lights
+            .iter()
+            .filter(|light| light.level == "high")
+            .map(|light| light.gpio_id)
+            .collect()
+    }
+
+    pub fn status(&self) -> VacationStatus {
+        VacationStatus {
+            enabled: true,
+            seed: Some(self.seed),
+            gpios: self
+                .lights
+                .iter()
+                .map(|light| SimulatedGpio {
+                    gpio_id: light.gpio_id,
+                    gpio_level: light.level.to_string(),
+                    next_change: timestamp(light.next_change),
+                })
+                .collect(),
+        }
+    }
+}
+
+pub fn load_vacation_db(conn: &SqliteConnection) -> Result<models::VacationMode, RpWebError> {
+    use crate::schema::vacation_mode::dsl::*;
+
+    let mode = vacation_mode.first::<models::VacationMode>(conn)?;
+    Ok(mode)
+}
+
+pub fn save_vacation_db(
+    is_enabled: bool, new_seed: Option<u32>, conn: &SqliteConnection,
+) -> Result<models::VacationMode, RpWebError> {
+    use crate::schema::vacation_mode::dsl::*;
+
+    diesel::update(vacation_mode)
+        .set((
+            enabled.eq(is_enabled as i32),
+            seed.eq(new_seed.map(i64::from)),
+        ))
+        .execute(conn)?;
+    load_vacation_db(conn)
+}
+
+/// Turn the presence simulation on, with `seed` or a random one, or off
+pub struct SetVacation {
+    pub enabled: bool,
+    pub seed: Option<u32>,
+}
+
+impl Message for SetVacation {
+    type Result = Result<VacationStatus, actixError>;
+}
+
+pub struct GetVacation;
+
+impl Message for GetVacation {
+    type Result = Result<VacationStatus, actixError>;
+}
+
+/// Actor switching the simulated lights through the DbExecutor, like requests from clients,
+/// so every change is in `gpio_state`. The mode is stored in the database and picked up again
+/// after a restart.
+pub struct VacationSimulator {
+    db: Addr<DbExecutor>,
+    gpio_arc_mutex: GpioArcMutex,
+    config: Option<VacationConfig>,
+    simulation: Option<Simulation>,
+}
+
+impl VacationSimulator {
+    pub fn new(
+        db: Addr<DbExecutor>, gpio_arc_mutex: GpioArcMutex, config: Option<VacationConfig>,
+    ) -> Self {
+        VacationSimulator {
+            db,
+            gpio_arc_mutex,
+            config,
+            simulation: None,
+        }
+    }
+
+    fn set(&mut self, gpio_id: i32, level: &str, ctx: &mut Context<Self>) {
+        info!("Vacation mode sets GPIO #{} '{}'", gpio_id, level);
+        let set = set_level(&self.db, self.gpio_arc_mutex.clone(), gpio_id, level.to_string(), None)
+            .map(|_| ())
+            .map_err(move |err| error!("Vacation mode failed to set GPIO #{}: {}", gpio_id, err));
+        ctx.spawn(wrap_future(set));
+    }
+
+    /// Start the simulation, or stop it and switch off the lights it switched on
+    fn switch(&mut self, enabled: bool, seed: Option<u32>, ctx: &mut Context<Self>) {
+        if let Some(simulation) = self.simulation.take() {
+            for gpio_id in simulation.lights_on() {
+                self.set(gpio_id, "low", ctx);
+            }
+        }
+
+        if let (true, Some(config)) = (enabled, self.config.as_ref()) {
+            let seed = seed.unwrap_or_else(rand::random);
+            info!("Vacation mode on, seed {}", seed);
+            self.simulation = Some(Simulation::new(config, seed, Local::now().naive_local()));
+        } else {
+            info!("Vacation mode off");
+        }
+    }
+
+    fn status(&self) -> VacationStatus {
+        match &self.simulation {
+            Some(simulation) => simulation.status(),
+            None => VacationStatus {
+                enabled: false,
+                seed: None,
+                gpios: vec![],
+            },
+        }
+    }
+}
+
+impl Actor for VacationSimulator {
+    type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        if self.config.is_none() {
+            return;
+        }
+
+        // Pick up the mode from before a restart
+        let load = self.db.send(LoadVacation).from_err::<actixError>().and_then(|res| res);
+        ctx.spawn(
+            wrap_future::<_, Self>(load)
+                .map(|mode, act, ctx| {
+                    if mode.enabled == 1 {
+                        act.switch(true, mode.seed.map(|seed| seed as u32), ctx);
+                    }
+                })
+                .map_err(|err, _, _| error!("Loading vacation mode failed: {}", err)),
+        );
+
+        ctx.run_interval(VACATION_INTERVAL, |act, ctx| {
+            let now = Local::now().naive_local();
+            let changes = match act.simulation.as_mut() {
+                Some(simulation) => simulation.due(now),
+                None => return,
+            };
+            for (gpio_id, level) in changes {
+                act.set(gpio_id, level, ctx);
+            }
+        });
+    }
+}
+
+impl Handler<SetVacation> for VacationSimulator {
+    type Result = ResponseActFuture<Self, VacationStatus, actixError>;
+
+    fn handle(&mut self, msg: SetVacation, ctx: &mut Self::Context) -> Self::Result {
+        if self.config.is_none() {
+            return Box::new(fut::err(error::ErrorNotFound(
+                "No [vacation] in the configuration",
+            )));
+        }
+
+        self.switch(msg.enabled, msg.seed, ctx);
+        let status = self.status();
+        let save = self
+            .db
+            .send(SaveVacation {
+                enabled: status.enabled,
+                seed: status.seed,
+            })
+            .from_err()
+            .and_then(|res| res)
+            .map(|_| status);
+        Box::new(wrap_future(save))
+    }
+}
+
+impl Handler<GetVacation> for VacationSimulator {
+    type Result = Result<VacationStatus, actixError>;
+
+    fn handle(&mut self, _: GetVacation, _: &mut Self::Context) -> Self::Result {
+        if self.config.is_none() {
+            return Err(error::ErrorNotFound("No [vacation] in the configuration"));
+        }
+        Ok(self.status())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    fn config(windows: &[&str]) -> VacationConfig {
+        VacationConfig {
+            gpios: vec![5, 6],
+            windows: windows.iter().map(|window| window.to_string()).collect(),
+            min_on_minutes: Some(10),
+            max_on_minutes: Some(30),
+            min_off_minutes: Some(5),
+            max_off_minutes: Some(20),
+        }
+    }
+
+    /// The changes of the simulation, minute by minute, from `start` for `hours`
+    fn run(
+        simulation: &mut Simulation, start: NaiveDateTime, hours: i64,
+    ) -> Vec<(NaiveDateTime, i32, &'static str)> {
+        (0..hours * 60)
+            .map(|minute| start + ChronoDuration::minutes(minute))
+            .flat_map(|now| {
+                simulation
+                    .due(now)
+                    .into_iter()
+                    .map(move |(gpio_id, level)| (now, gpio_id, level))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn window_over_midnight_must_end_next_day() {
+        let window = parse_window("22:00-01:30").unwrap();
+
+        assert_eq!(window.end_after(time("2019-06-08 23:00")), Some(time("2019-06-09 01:30")));
+        assert_eq!(window.end_after(time("2019-06-09 01:00")), Some(time("2019-06-09 01:30")));
+        assert_eq!(window.end_after(time("2019-06-09 12:00")), None);
+        assert_eq!(window.next_start(time("2019-06-09 12:00")), time("2019-06-09 22:00"));
+    }
+
+    #[test]
+    fn invalid_window_must_fail() {
+        assert!(parse_window("18:00").is_err());
+        assert!(parse_window("18:00-18:00").is_err());
+        assert!(parse_window("evening").is_err());
+    }
+
+    #[test]
+    fn same_seed_must_give_same_changes() {
+        let config = config(&["18:00-23:00"]);
+        let start = time("2019-06-08 12:00");
+
+        let first = run(&mut Simulation::new(&config, 7, start), start, 24);
+        let second = run(&mut Simulation::new(&config, 7, start), start, 24);
+        let other = run(&mut Simulation::new(&config, 8, start), start, 24);
+
+        assert!(!first.is_empty());
+        assert_eq!(first, second);
+        assert_ne!(first, other);
+    }
+
+    #[test]
+    fn lights_must_only_be_on_in_windows() {
+        let config = config(&["06:30-07:30", "18:00-23:00"]);
+        let start = time("2019-06-08 00:00");
+        let mut simulation = Simulation::new(&config, 42, start);
+        let changes = run(&mut simulation, start, 72);
+
+        for gpio_id in config.gpios.iter() {
+            let mut on_since = None;
+            for (at, _, level) in changes.iter().filter(|(_, idx, _)| idx == gpio_id) {
+                match (*level, on_since) {
+                    ("high", None) => {
+                        assert!(simulation.window_end(*at).is_some(), "On at {}", at);
+                        on_since = Some(*at);
+                    }
+                    ("low", Some(since)) => {
+                        assert!(*at - since <= ChronoDuration::minutes(31), "On at {}", since);
+                        on_since = None;
+                    }
+                    _ => panic!("GPIO #{} switched to '{}' twice at {}", gpio_id, level, at),
+                }
+            }
+        }
+    }
+}
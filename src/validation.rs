@@ -1,6 +1,17 @@
+use crate::device::DeviceKind;
 use crate::errors::RpWebError;
-use crate::settings::GpioConfig;
-//use std::collections::HashMap;
+use crate::interlocks::{new_interlocks, EXCLUSIVE, REQUIRES};
+use crate::settings::{
+    CalendarConfig, DeviceConfig, DriftConfig, GpioConfig, InterlockConfig, LocationConfig,
+    PinConfig, RuleConfig, ScriptsConfig, StepperConfig, VacationConfig,
+};
+use crate::rpi::hardware_pwm_channel;
+use crate::rules::new_rule;
+use crate::servo::{servo_from_pin, SERVO_PERIOD_US};
+use crate::stepper::{StepProfile, MAX_STEPS_PER_SECOND};
+use crate::vacation::parse_window;
+use std::collections::HashMap;
+use std::path::Path;
 
 /// Return a copy of the vec in Option(vec), or an empty vector for None
 pub fn vec_option_to_vec(option: &Option<Vec<i32>>) -> Vec<i32> {
@@ -39,6 +50,7 @@ pub fn validate_setup(gpioconfig: &GpioConfig) -> Result<(), RpWebError> {
     let gpios_in_use = vec_option_to_vec(&gpioconfig.gpios_in_use);
     let gpios_mode_output = vec_option_to_vec(&gpioconfig.gpios_mode_output);
     let gpios_mode_input = vec_option_to_vec(&gpioconfig.gpios_mode_input);
+    let gpios_mode_pwm = vec_option_to_vec(&gpioconfig.gpios_mode_pwm);
     let gpios_level_low = vec_option_to_vec(&gpioconfig.gpios_level_low);
     let gpios_level_high = vec_option_to_vec(&gpioconfig.gpios_level_high);
 
@@ -100,6 +112,656 @@ pub fn validate_setup(gpioconfig: &GpioConfig) -> Result<(), RpWebError> {
         return Err(RpWebError::new(&errs));
     }
 
+    // GPIOs in mode_pwm must be in use, in no other mode, and not share a hardware channel
+    for (pos, idx) in gpios_mode_pwm.iter().enumerate() {
+        if !gpios_in_use.contains(idx) {
+            let errs = format!(
+                "Invalid configuration: GPIO #{} is in gpios_mode_pwm, but is not in_use",
+                idx
+            );
+            return Err(RpWebError::new(&errs));
+        }
+        if gpios_mode_input.contains(idx) || gpios_mode_output.contains(idx) {
+            let errs = format!(
+                "Invalid configuration: GPIO #{} is in gpios_mode_pwm and in another mode",
+                idx
+            );
+            return Err(RpWebError::new(&errs));
+        }
+        if let Some(channel) = hardware_pwm_channel(*idx) {
+            let sharing = gpios_mode_pwm[..pos]
+                .iter()
+                .find(|other| hardware_pwm_channel(**other) == Some(channel));
+            if let Some(other) = sharing {
+                let errs = format!(
+                    "Invalid configuration: GPIO #{} and #{} in gpios_mode_pwm share PWM channel {}",
+                    other, idx, channel
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// GPIO names are used in place of ids in routes, so they must be valid path segments
+/// and must not be mistaken for an id
+pub fn is_valid_gpio_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.parse::<i32>().is_err()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+pub fn validate_pins(pins: &[PinConfig]) -> Result<(), RpWebError> {
+    let mut named_gpios: Vec<i32> = vec![];
+    let mut names: Vec<&str> = vec![];
+
+    for pin in pins.iter() {
+        if !is_valid_gpio_name(&pin.name) {
+            let errs = format!(
+                "Invalid configuration: '{}' is not a valid name for GPIO #{} - use letters, digits, '-' and '_', and not only digits",
+                pin.name, pin.gpio_id
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        if named_gpios.contains(&pin.gpio_id) {
+            let errs = format!(
+                "Invalid configuration: GPIO #{} has more than one [[pins]] entry",
+                pin.gpio_id
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        if names.contains(&pin.name.as_str()) {
+            let errs = format!(
+                "Invalid configuration: name '{}' is used for more than one GPIO",
+                pin.name
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        // Tags are stored comma separated
+        let mut tags = pin.tags.iter().flatten();
+        if let Some(tag) = tags.find(|tag| tag.is_empty() || tag.contains(',')) {
+            let errs = format!(
+                "Invalid configuration: tag '{}' for GPIO #{} must be non-empty and without commas",
+                tag, pin.gpio_id
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        named_gpios.push(pin.gpio_id);
+        names.push(&pin.name);
+    }
+
+    Ok(())
+}
+
+pub fn validate_groups(
+    groups: &HashMap<String, Vec<i32>>, gpioconfig: &GpioConfig,
+) -> Result<(), RpWebError> {
+    let gpios_in_use = vec_option_to_vec(&gpioconfig.gpios_in_use);
+
+    for (group_name, members) in groups.iter() {
+        if !is_valid_gpio_name(group_name) {
+            let errs = format!(
+                "Invalid configuration: '{}' is not a valid group name - use letters, digits, '-' and '_', and not only digits",
+                group_name
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        if members.is_empty() {
+            let errs = format!("Invalid configuration: group '{}' is empty", group_name);
+            return Err(RpWebError::new(&errs));
+        }
+
+        for (idx, gpio_id) in members.iter().enumerate() {
+            if members[..idx].contains(gpio_id) {
+                let errs = format!(
+                    "Invalid configuration: GPIO #{} is listed more than once in group '{}'",
+                    gpio_id, group_name
+                );
+                return Err(RpWebError::new(&errs));
+            }
+
+            if !gpios_in_use.contains(gpio_id) {
+                let errs = format!(
+                    "Invalid configuration: GPIO #{} in group '{}' is not in gpios_in_use",
+                    gpio_id, group_name
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_drift(drift: &DriftConfig, gpioconfig: &GpioConfig) -> Result<(), RpWebError> {
+    let gpios_mode_output = vec_option_to_vec(&gpioconfig.gpios_mode_output);
+    let correct_hardware = vec_option_to_vec(&drift.correct_hardware);
+    let correct_database = vec_option_to_vec(&drift.correct_database);
+
+    if drift.interval_seconds == 0 {
+        return Err(RpWebError::new(
+            "Invalid configuration: drift interval_seconds must be at least 1",
+        ));
+    }
+
+    // Only outputs have a level in the database to compare with
+    for idx in correct_hardware.iter().chain(correct_database.iter()) {
+        if !gpios_mode_output.contains(idx) {
+            let errs = format!(
+                "Invalid configuration: GPIO #{} has a drift policy, but is not configured to OUTPUT",
+                idx
+            );
+            return Err(RpWebError::new(&errs));
+        }
+    }
+
+    if let Some(idx) = elements_in_both_vecs(&correct_hardware, &correct_database) {
+        let errs = format!(
+            "Invalid configuration: GPIO(s) {:?} are in both correct_hardware and correct_database",
+            idx
+        );
+        return Err(RpWebError::new(&errs));
+    }
+
+    Ok(())
+}
+
+pub fn validate_max_on(pins: &[PinConfig], gpioconfig: &GpioConfig) -> Result<(), RpWebError> {
+    let gpios_mode_output = vec_option_to_vec(&gpioconfig.gpios_mode_output);
+
+    for pin in pins.iter() {
+        let max_on = match pin.max_on_duration {
+            Some(max_on) => max_on,
+            None => {
+                if pin.safe_level.is_some() {
+                    let errs = format!(
+                        "Invalid configuration: GPIO #{} has a safe_level, but no max_on_duration",
+                        pin.gpio_id
+                    );
+                    return Err(RpWebError::new(&errs));
+                }
+                continue;
+            }
+        };
+
+        // Stored as an INTEGER column
+        if !(1..=i32::MAX as u32).contains(&max_on) {
+            let errs = format!(
+                "Invalid configuration: max_on_duration of GPIO #{} must be from 1 to {} seconds",
+                pin.gpio_id,
+                i32::MAX
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        // Only outputs are on
+        if !gpios_mode_output.contains(&pin.gpio_id) {
+            let errs = format!(
+                "Invalid configuration: GPIO #{} has a max_on_duration, but is not configured to OUTPUT",
+                pin.gpio_id
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        if let Some(level) = &pin.safe_level {
+            let level = level.to_lowercase();
+            if level != "low" && level != "high" {
+                let errs = format!(
+                    "Invalid configuration: safe_level '{}' of GPIO #{} must be 'low' or 'high'",
+                    level, pin.gpio_id
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_servos(pins: &[PinConfig], gpioconfig: &GpioConfig) -> Result<(), RpWebError> {
+    let gpios_mode_pwm = vec_option_to_vec(&gpioconfig.gpios_mode_pwm);
+
+    for pin in pins.iter() {
+        let servo = match servo_from_pin(pin) {
+            Some(servo) => servo,
+            None => {
+                if let Some(kind) = &pin.kind {
+                    let errs = format!(
+                        "Invalid configuration: kind '{}' of GPIO #{} must be 'servo'",
+                        kind, pin.gpio_id
+                    );
+                    return Err(RpWebError::new(&errs));
+                }
+                let servo_settings = pin.min_pulse_us.is_some()
+                    || pin.max_pulse_us.is_some()
+                    || pin.min_angle.is_some()
+                    || pin.max_angle.is_some()
+                    || pin.max_speed.is_some();
+                if servo_settings {
+                    let errs = format!(
+                        "Invalid configuration: GPIO #{} has servo settings, but is not of kind 'servo'",
+                        pin.gpio_id
+                    );
+                    return Err(RpWebError::new(&errs));
+                }
+                continue;
+            }
+        };
+
+        // Servos are driven by PWM
+        if !gpios_mode_pwm.contains(&servo.gpio_id) {
+            let errs = format!(
+                "Invalid configuration: GPIO #{} is a servo, but is not configured to PWM",
+                servo.gpio_id
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        let mut pulses = pin.min_pulse_us.into_iter().chain(pin.max_pulse_us);
+        if pulses.any(|pulse| !(1..=SERVO_PERIOD_US).contains(&pulse))
+            || servo.min_pulse_us >= servo.max_pulse_us
+        {
+            let errs = format!(
+                "Invalid configuration: pulses of servo on GPIO #{} must be from 1 to {} µs, and min_pulse_us less than max_pulse_us",
+                servo.gpio_id, SERVO_PERIOD_US
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        if !(servo.min_angle.is_finite() && servo.max_angle.is_finite())
+            || servo.min_angle >= servo.max_angle
+        {
+            let errs = format!(
+                "Invalid configuration: min_angle of servo on GPIO #{} must be less than max_angle",
+                servo.gpio_id
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        if let Some(max_speed) = servo.max_speed {
+            if !(max_speed > 0.0 && max_speed.is_finite()) {
+                let errs = format!(
+                    "Invalid configuration: max_speed of servo on GPIO #{} must be more than 0 degrees per second",
+                    servo.gpio_id
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_location(location: &LocationConfig) -> Result<(), RpWebError> {
+    if !(-90.0..=90.0).contains(&location.latitude) {
+        let errs = format!(
+            "Invalid configuration: latitude {} is not from -90 to 90 degrees",
+            location.latitude
+        );
+        return Err(RpWebError::new(&errs));
+    }
+    if !(-180.0..=180.0).contains(&location.longitude) {
+        let errs = format!(
+            "Invalid configuration: longitude {} is not from -180 to 180 degrees",
+            location.longitude
+        );
+        return Err(RpWebError::new(&errs));
+    }
+
+    Ok(())
+}
+
+pub fn validate_calendars(
+    calendars: &[CalendarConfig], groups: &HashMap<String, Vec<i32>>, gpioconfig: &GpioConfig,
+) -> Result<(), RpWebError> {
+    let gpios_mode_output = vec_option_to_vec(&gpioconfig.gpios_mode_output);
+    let mut targets = vec![];
+
+    for calendar in calendars.iter() {
+        let target = match (calendar.gpio_id, calendar.group.as_ref()) {
+            (Some(idx), None) => {
+                if !gpios_mode_output.contains(&idx) {
+                    let errs = format!(
+                        "Invalid configuration: GPIO #{} has calendar '{}', but is not configured to OUTPUT",
+                        idx, calendar.path
+                    );
+                    return Err(RpWebError::new(&errs));
+                }
+                format!("GPIO #{}", idx)
+            }
+            (None, Some(group)) => {
+                let group = group.to_lowercase();
+                if !groups.keys().any(|name| name.to_lowercase() == group) {
+                    let errs = format!(
+                        "Invalid configuration: calendar '{}' is for group '{}', which is not in [groups]",
+                        calendar.path, group
+                    );
+                    return Err(RpWebError::new(&errs));
+                }
+                format!("group '{}'", group)
+            }
+            _ => {
+                let errs = format!(
+                    "Invalid configuration: calendar '{}' must have either a gpio_id or a group",
+                    calendar.path
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        };
+
+        // Two calendars on the same output would switch it back and forth
+        if targets.contains(&target) {
+            let errs = format!("Invalid configuration: {} has more than one calendar", target);
+            return Err(RpWebError::new(&errs));
+        }
+        targets.push(target);
+    }
+
+    Ok(())
+}
+
+pub fn validate_vacation(
+    vacation: &VacationConfig, gpioconfig: &GpioConfig,
+) -> Result<(), RpWebError> {
+    let gpios_mode_output = vec_option_to_vec(&gpioconfig.gpios_mode_output);
+
+    if vacation.gpios.is_empty() || vacation.windows.is_empty() {
+        return Err(RpWebError::new(
+            "Invalid configuration: vacation mode needs gpios and windows",
+        ));
+    }
+
+    for idx in vacation.gpios.iter() {
+        if !gpios_mode_output.contains(idx) {
+            let errs = format!(
+                "Invalid configuration: GPIO #{} is in vacation mode, but is not configured to OUTPUT",
+                idx
+            );
+            return Err(RpWebError::new(&errs));
+        }
+    }
+
+    for window in vacation.windows.iter() {
+        parse_window(window).map_err(|err| {
+            RpWebError::new(&format!("Invalid configuration: {}", err))
+        })?;
+    }
+
+    for (what, (min, max)) in [("on", vacation.on_minutes()), ("off", vacation.off_minutes())].iter() {
+        if *min == 0 || min > max {
+            let errs = format!(
+                "Invalid configuration: vacation {} minutes must be at least 1, and min not above max",
+                what
+            );
+            return Err(RpWebError::new(&errs));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn validate_rules(rules: &[RuleConfig], gpioconfig: &GpioConfig) -> Result<(), RpWebError> {
+    let gpios_in_use = vec_option_to_vec(&gpioconfig.gpios_in_use);
+    let gpios_mode_output = vec_option_to_vec(&gpioconfig.gpios_mode_output);
+
+    for rule in rules.iter() {
+        if !gpios_in_use.contains(&rule.input) {
+            let errs = format!(
+                "Invalid configuration: GPIO #{} is the input of a rule, but is not in use",
+                rule.input
+            );
+            return Err(RpWebError::new(&errs));
+        }
+        if !gpios_mode_output.contains(&rule.output) {
+            let errs = format!(
+                "Invalid configuration: GPIO #{} is the output of a rule, but is not configured to OUTPUT",
+                rule.output
+            );
+            return Err(RpWebError::new(&errs));
+        }
+        new_rule(&rule.to_request(), rule.input, rule.output, true)
+            .map_err(|err| RpWebError::new(&format!("Invalid configuration: {}", err)))?;
+    }
+
+    Ok(())
+}
+
+pub fn validate_scripts(scripts: &ScriptsConfig) -> Result<(), RpWebError> {
+    if !Path::new(&scripts.directory).is_dir() {
+        let errs = format!(
+            "Invalid configuration: scripts directory '{}' does not exist",
+            scripts.directory
+        );
+        return Err(RpWebError::new(&errs));
+    }
+    if scripts.max_operations == Some(0) {
+        return Err(RpWebError::new(
+            "Invalid configuration: scripts max_operations must be at least 1",
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn validate_steppers(
+    steppers: &[StepperConfig], gpioconfig: &GpioConfig,
+) -> Result<(), RpWebError> {
+    let gpios_in_use = vec_option_to_vec(&gpioconfig.gpios_in_use);
+    let gpios_mode_output = vec_option_to_vec(&gpioconfig.gpios_mode_output);
+    let gpios_mode_input = vec_option_to_vec(&gpioconfig.gpios_mode_input);
+    let mut names: Vec<&str> = vec![];
+    let mut used_gpios: Vec<i32> = vec![];
+
+    for stepper in steppers.iter() {
+        if !is_valid_gpio_name(&stepper.name) || names.contains(&stepper.name.as_str()) {
+            let errs = format!(
+                "Invalid configuration: '{}' is not a valid name for a stepper, or is used for more than one",
+                stepper.name
+            );
+            return Err(RpWebError::new(&errs));
+        }
+        names.push(&stepper.name);
+
+        // The step, direction and enable pins are outputs of this stepper only
+        let outputs = [Some(stepper.step_pin), Some(stepper.dir_pin), stepper.enable_pin];
+        for gpio in outputs.iter().flatten() {
+            if !gpios_mode_output.contains(gpio) || used_gpios.contains(gpio) {
+                let errs = format!(
+                    "Invalid configuration: GPIO #{} of stepper '{}' must be an OUTPUT used by no other pin of a stepper",
+                    gpio, stepper.name
+                );
+                return Err(RpWebError::new(&errs));
+            }
+            used_gpios.push(*gpio);
+        }
+
+        if let Some(level) = &stepper.enable_level {
+            let level = level.to_lowercase();
+            if level != "low" && level != "high" {
+                let errs = format!(
+                    "Invalid configuration: enable_level '{}' of stepper '{}' must be 'low' or 'high'",
+                    level, stepper.name
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+
+        if let Some(home_pin) = stepper.home_pin {
+            if !gpios_in_use.contains(&home_pin) || !gpios_mode_input.contains(&home_pin) {
+                let errs = format!(
+                    "Invalid configuration: home_pin #{} of stepper '{}' must be an INPUT in use",
+                    home_pin, stepper.name
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+
+        if !(stepper.steps_per_second > 0.0 && stepper.steps_per_second <= MAX_STEPS_PER_SECOND) {
+            let errs = format!(
+                "Invalid configuration: steps_per_second of stepper '{}' must be more than 0 and at most {}",
+                stepper.name, MAX_STEPS_PER_SECOND
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        if let Some(acceleration) = stepper.acceleration {
+            if !(acceleration > 0.0 && acceleration.is_finite()) {
+                let errs = format!(
+                    "Invalid configuration: acceleration of stepper '{}' must be more than 0",
+                    stepper.name
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+
+        let profile = StepProfile::of(stepper)
+            .map_err(|err| RpWebError::new(&format!("Invalid configuration: {}", err)))?;
+        if profile != StepProfile::Constant && stepper.acceleration.is_none() {
+            let errs = format!(
+                "Invalid configuration: stepper '{}' needs an acceleration for profile '{}'",
+                stepper.name,
+                profile.name()
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        if let (Some(min), Some(max)) = (stepper.min_position, stepper.max_position) {
+            if min >= max {
+                let errs = format!(
+                    "Invalid configuration: min_position of stepper '{}' must be less than max_position",
+                    stepper.name
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Devices need a pin for every role of their kind, in the mode of the role. A GPIO can
+/// belong to one device only, and not also be a servo or a pin of a stepper.
+pub fn validate_devices(
+    devices: &[DeviceConfig], pins: &[PinConfig], steppers: &[StepperConfig],
+    gpioconfig: &GpioConfig,
+) -> Result<(), RpWebError> {
+    let gpios_in_use = vec_option_to_vec(&gpioconfig.gpios_in_use);
+    let gpios_mode_output = vec_option_to_vec(&gpioconfig.gpios_mode_output);
+    let gpios_mode_pwm = vec_option_to_vec(&gpioconfig.gpios_mode_pwm);
+    let mut names: Vec<&str> = vec![];
+    let mut used_gpios: Vec<i32> =
+        pins.iter().filter_map(servo_from_pin).map(|servo| servo.gpio_id).collect();
+    for stepper in steppers.iter() {
+        used_gpios.extend([stepper.step_pin, stepper.dir_pin]);
+        used_gpios.extend(stepper.enable_pin.into_iter().chain(stepper.home_pin));
+    }
+
+    for device in devices.iter() {
+        if !is_valid_gpio_name(&device.name) || names.contains(&device.name.as_str()) {
+            let errs = format!(
+                "Invalid configuration: '{}' is not a valid name for a device, or is used for more than one",
+                device.name
+            );
+            return Err(RpWebError::new(&errs));
+        }
+        names.push(&device.name);
+
+        let kind = DeviceKind::parse(&device.kind)
+            .map_err(|err| RpWebError::new(&format!("Invalid configuration: {}", err)))?;
+        let roles = kind.roles();
+        let unknown_role = device
+            .pins
+            .keys()
+            .any(|role| !roles.iter().any(|(known, _)| *known == role.to_lowercase()));
+        if unknown_role || device.pins.len() != roles.len() {
+            let role_names: Vec<&str> = roles.iter().map(|(role, _)| *role).collect();
+            let errs = format!(
+                "Invalid configuration: device '{}' of kind '{}' needs the pins {}",
+                device.name,
+                kind.name(),
+                role_names.join(", ")
+            );
+            return Err(RpWebError::new(&errs));
+        }
+
+        for (role, gpio) in device.pins.iter() {
+            let role = role.to_lowercase();
+            let mode = roles.iter().find(|(known, _)| *known == role).map_or("", |(_, mode)| *mode);
+            let gpios_in_mode = if mode == "pwm" { &gpios_mode_pwm } else { &gpios_mode_output };
+            if !gpios_in_use.contains(gpio)
+                || !gpios_in_mode.contains(gpio)
+                || used_gpios.contains(gpio)
+            {
+                let errs = format!(
+                    "Invalid configuration: GPIO #{} ('{}' of device '{}') must be in use in mode '{}', and not be a servo or belong to a stepper or another device",
+                    gpio, role, device.name, mode
+                );
+                return Err(RpWebError::new(&errs));
+            }
+            used_gpios.push(*gpio);
+        }
+    }
+
+    Ok(())
+}
+
+/// Interlocks are between different outputs in use, and the levels at startup must keep them
+pub fn validate_interlocks(
+    interlocks: &[InterlockConfig], gpioconfig: &GpioConfig,
+) -> Result<(), RpWebError> {
+    let gpios_in_use = vec_option_to_vec(&gpioconfig.gpios_in_use);
+    let gpios_mode_output = vec_option_to_vec(&gpioconfig.gpios_mode_output);
+    let gpios_level_high = vec_option_to_vec(&gpioconfig.gpios_level_high);
+
+    for interlock in interlocks.iter() {
+        let pairs = new_interlocks(interlock)
+            .map_err(|err| RpWebError::new(&format!("Invalid configuration: {}", err)))?;
+
+        for (index, gpio) in interlock.gpios.iter().enumerate() {
+            if !gpios_in_use.contains(gpio)
+                || !gpios_mode_output.contains(gpio)
+                || interlock.gpios[..index].contains(gpio)
+            {
+                let errs = format!(
+                    "Invalid configuration: GPIO #{} of interlock '{}' must be an OUTPUT in use, and appear once",
+                    gpio, interlock.kind
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+
+        if interlock.dead_time_ms == Some(0) {
+            let errs = "Invalid configuration: dead_time_ms of an interlock must be more than 0";
+            return Err(RpWebError::new(errs));
+        }
+
+        for pair in pairs.iter() {
+            let gpio_high = gpios_level_high.contains(&pair.gpio_id);
+            let other_high = gpios_level_high.contains(&pair.other_gpio);
+            let broken = match pair.kind.as_str() {
+                EXCLUSIVE => gpio_high && other_high,
+                REQUIRES => gpio_high && !other_high,
+                _ => false,
+            };
+            if broken {
+                let errs = format!(
+                    "Invalid configuration: the levels of GPIO #{} and GPIO #{} at startup break their '{}' interlock",
+                    pair.gpio_id, pair.other_gpio, pair.kind
+                );
+                return Err(RpWebError::new(&errs));
+            }
+        }
+    }
+
     Ok(())
 }
 
@@ -113,6 +775,7 @@ mod tests {
             gpios_in_use: None,
             gpios_mode_output: Some(vec![1]),
             gpios_mode_input: None,
+            gpios_mode_pwm: None,
             gpios_level_low: Some(vec![1]),
             gpios_level_high: None,
         };
@@ -127,6 +790,7 @@ mod tests {
             gpios_in_use: Some(vec![2]),
             gpios_mode_output: Some(vec![2]),
             gpios_mode_input: None,
+            gpios_mode_pwm: None,
             gpios_level_low: Some(vec![1]),
             gpios_level_high: None,
         };
@@ -141,6 +805,7 @@ mod tests {
             gpios_in_use: Some(vec![1]),
             gpios_mode_output: None,
             gpios_mode_input: None,
+            gpios_mode_pwm: None,
             gpios_level_low: Some(vec![1]),
             gpios_level_high: None,
         };
@@ -155,6 +820,7 @@ mod tests {
             gpios_in_use: Some(vec![1]),
             gpios_mode_output: Some(vec![2]),
             gpios_mode_input: None,
+            gpios_mode_pwm: None,
             gpios_level_low: Some(vec![1]),
             gpios_level_high: None,
         };
@@ -169,6 +835,7 @@ mod tests {
             gpios_in_use: Some(vec![1, 2]),
             gpios_mode_output: Some(vec![1, 2, 3]),
             gpios_mode_input: None,
+            gpios_mode_pwm: None,
             gpios_level_low: Some(vec![3]),
             gpios_level_high: None,
         };
@@ -183,6 +850,7 @@ mod tests {
             gpios_in_use: Some(vec![1, 2, 3]),
             gpios_mode_output: Some(vec![1, 2]),
             gpios_mode_input: None,
+            gpios_mode_pwm: None,
             gpios_level_low: Some(vec![3]),
             gpios_level_high: None,
         };
@@ -197,6 +865,7 @@ mod tests {
             gpios_in_use: Some(vec![1, 2]),
             gpios_mode_output: Some(vec![1, 2]),
             gpios_mode_input: None,
+            gpios_mode_pwm: None,
             gpios_level_low: Some(vec![1]),
             gpios_level_high: Some(vec![1]),
         };
@@ -211,6 +880,7 @@ mod tests {
             gpios_in_use: Some(vec![1]),
             gpios_mode_output: Some(vec![1]),
             gpios_mode_input: Some(vec![1]),
+            gpios_mode_pwm: None,
             gpios_level_low: None,
             gpios_level_high: None,
         };
@@ -225,6 +895,7 @@ mod tests {
             gpios_in_use: Some(vec![1, 2, 3]),
             gpios_mode_output: Some(vec![1, 2, 3]),
             gpios_mode_input: None,
+            gpios_mode_pwm: None,
             gpios_level_low: Some(vec![1]),
             gpios_level_high: Some(vec![2, 3]),
         };
@@ -233,4 +904,532 @@ mod tests {
 
         assert!(res.is_ok());
     }
+
+    #[test]
+    fn validation_pwm_must_be_in_use_and_no_other_mode() {
+        let mut gpioconfig = GpioConfig {
+            gpios_in_use: Some(vec![1, 18]),
+            gpios_mode_output: Some(vec![1]),
+            gpios_mode_input: None,
+            gpios_mode_pwm: Some(vec![18]),
+            gpios_level_low: Some(vec![1]),
+            gpios_level_high: None,
+        };
+        assert!(validate_setup(&gpioconfig).is_ok());
+
+        gpioconfig.gpios_mode_pwm = Some(vec![18, 19]);
+        assert!(validate_setup(&gpioconfig).is_err());
+
+        gpioconfig.gpios_mode_pwm = Some(vec![1, 18]);
+        assert!(validate_setup(&gpioconfig).is_err());
+    }
+
+    #[test]
+    fn validation_pwm_sharing_hardware_channel_must_fail() {
+        let mut gpioconfig = GpioConfig {
+            gpios_in_use: Some(vec![12, 13, 18, 19]),
+            gpios_mode_output: None,
+            gpios_mode_input: None,
+            gpios_mode_pwm: Some(vec![12, 19]),
+            gpios_level_low: None,
+            gpios_level_high: None,
+        };
+        assert!(validate_setup(&gpioconfig).is_ok());
+
+        gpioconfig.gpios_mode_pwm = Some(vec![12, 18]);
+        assert!(validate_setup(&gpioconfig).is_err());
+
+        gpioconfig.gpios_mode_pwm = Some(vec![13, 18, 19]);
+        assert!(validate_setup(&gpioconfig).is_err());
+    }
+
+    fn pin(gpio_id: i32, name: &str) -> PinConfig {
+        PinConfig {
+            gpio_id,
+            name: name.to_string(),
+            description: None,
+            tags: None,
+            max_on_duration: None,
+            safe_level: None,
+            kind: None,
+            min_pulse_us: None,
+            max_pulse_us: None,
+            min_angle: None,
+            max_angle: None,
+            max_speed: None,
+        }
+    }
+
+    #[test]
+    fn gpio_name_numeric_must_be_invalid() {
+        assert!(!is_valid_gpio_name("17"));
+        assert!(!is_valid_gpio_name("-17"));
+        assert!(!is_valid_gpio_name(""));
+        assert!(!is_valid_gpio_name("garage door"));
+        assert!(!is_valid_gpio_name("garage/door"));
+    }
+
+    #[test]
+    fn gpio_name_valid_must_succeed() {
+        assert!(is_valid_gpio_name("garage-door"));
+        assert!(is_valid_gpio_name("relay_17"));
+    }
+
+    #[test]
+    fn validation_pins_duplicate_name_must_fail() {
+        let pins = vec![pin(17, "garage-door"), pin(27, "garage-door")];
+        assert!(validate_pins(&pins).is_err());
+    }
+
+    #[test]
+    fn validation_pins_duplicate_gpio_must_fail() {
+        let pins = vec![pin(17, "garage-door"), pin(17, "relay")];
+        assert!(validate_pins(&pins).is_err());
+    }
+
+    #[test]
+    fn validation_pins_tag_with_comma_must_fail() {
+        let mut garage = pin(17, "garage-door");
+        garage.tags = Some(vec!["outdoor,relay".to_string()]);
+        assert!(validate_pins(&[garage]).is_err());
+    }
+
+    #[test]
+    fn validation_pins_valid_must_succeed() {
+        let mut garage = pin(17, "garage-door");
+        garage.tags = Some(vec!["outdoor".to_string(), "relay".to_string()]);
+        let pins = vec![garage, pin(27, "porch-light")];
+        assert!(validate_pins(&pins).is_ok());
+    }
+
+    fn gpioconfig_in_use(gpios_in_use: Vec<i32>) -> GpioConfig {
+        GpioConfig {
+            gpios_in_use: Some(gpios_in_use.clone()),
+            gpios_mode_output: Some(gpios_in_use),
+            gpios_mode_input: None,
+            gpios_mode_pwm: None,
+            gpios_level_low: None,
+            gpios_level_high: None,
+        }
+    }
+
+    #[test]
+    fn validation_groups_member_not_in_use_must_fail() {
+        let mut groups = HashMap::new();
+        groups.insert("zone-a".to_string(), vec![5, 6, 13]);
+
+        let res = validate_groups(&groups, &gpioconfig_in_use(vec![5, 6]));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn validation_groups_duplicate_member_must_fail() {
+        let mut groups = HashMap::new();
+        groups.insert("zone-a".to_string(), vec![5, 6, 5]);
+
+        let res = validate_groups(&groups, &gpioconfig_in_use(vec![5, 6]));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn validation_groups_empty_must_fail() {
+        let mut groups = HashMap::new();
+        groups.insert("zone-a".to_string(), vec![]);
+
+        let res = validate_groups(&groups, &gpioconfig_in_use(vec![5, 6]));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn validation_groups_valid_must_succeed() {
+        let mut groups = HashMap::new();
+        groups.insert("zone-a".to_string(), vec![5, 6, 13]);
+        groups.insert("zone-b".to_string(), vec![13]);
+
+        let res = validate_groups(&groups, &gpioconfig_in_use(vec![5, 6, 13]));
+        assert!(res.is_ok());
+    }
+
+    fn drift(correct_hardware: Vec<i32>, correct_database: Vec<i32>) -> DriftConfig {
+        DriftConfig {
+            interval_seconds: 60,
+            correct_hardware: Some(correct_hardware),
+            correct_database: Some(correct_database),
+        }
+    }
+
+    #[test]
+    fn validation_drift_must_succeed() {
+        let res = validate_drift(&drift(vec![5], vec![6]), &gpioconfig_in_use(vec![5, 6]));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn validation_drift_not_output_must_fail() {
+        let res = validate_drift(&drift(vec![5, 7], vec![]), &gpioconfig_in_use(vec![5, 6]));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn validation_drift_both_policies_must_fail() {
+        let res = validate_drift(&drift(vec![5], vec![5]), &gpioconfig_in_use(vec![5, 6]));
+        assert!(res.is_err());
+    }
+
+    fn guarded_pin(gpio_id: i32, max_on_duration: u32, safe_level: Option<&str>) -> PinConfig {
+        PinConfig {
+            max_on_duration: Some(max_on_duration),
+            safe_level: safe_level.map(str::to_string),
+            ..pin(gpio_id, "heater")
+        }
+    }
+
+    #[test]
+    fn validation_max_on_must_succeed() {
+        let pins = vec![guarded_pin(5, 600, Some("HIGH"))];
+        let res = validate_max_on(&pins, &gpioconfig_in_use(vec![5, 6]));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn validation_max_on_not_output_must_fail() {
+        let pins = vec![guarded_pin(7, 600, None)];
+        let res = validate_max_on(&pins, &gpioconfig_in_use(vec![5, 6]));
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn validation_max_on_invalid_must_fail() {
+        let gpioconfig = gpioconfig_in_use(vec![5, 6]);
+        assert!(validate_max_on(&[guarded_pin(5, 0, None)], &gpioconfig).is_err());
+        assert!(validate_max_on(&[guarded_pin(5, 600, Some("off"))], &gpioconfig).is_err());
+    }
+
+    fn servo_pin(gpio_id: i32, min_pulse_us: u32, max_pulse_us: u32) -> PinConfig {
+        PinConfig {
+            kind: Some("Servo".to_string()),
+            min_pulse_us: Some(min_pulse_us),
+            max_pulse_us: Some(max_pulse_us),
+            max_speed: Some(60.0),
+            ..pin(gpio_id, "pan")
+        }
+    }
+
+    fn gpioconfig_pwm(gpios: Vec<i32>) -> GpioConfig {
+        GpioConfig {
+            gpios_mode_output: None,
+            gpios_mode_pwm: Some(gpios.clone()),
+            ..gpioconfig_in_use(gpios)
+        }
+    }
+
+    #[test]
+    fn validation_servos_must_succeed() {
+        let pins = vec![servo_pin(18, 500, 2500), pin(5, "relay")];
+        assert!(validate_servos(&pins, &gpioconfig_pwm(vec![18])).is_ok());
+    }
+
+    #[test]
+    fn validation_servo_not_pwm_must_fail() {
+        let pins = vec![servo_pin(18, 500, 2500)];
+        assert!(validate_servos(&pins, &gpioconfig_in_use(vec![18])).is_err());
+    }
+
+    #[test]
+    fn validation_servo_invalid_must_fail() {
+        let gpioconfig = gpioconfig_pwm(vec![18]);
+        let valid = || servo_pin(18, 1000, 2000);
+        let reversed_pulses = servo_pin(18, 2000, 1000);
+        let long_pulse = servo_pin(18, 1000, 25_000);
+        let reversed_angles = PinConfig {
+            min_angle: Some(90.0),
+            max_angle: Some(-90.0),
+            ..valid()
+        };
+        let standing_still = PinConfig {
+            max_speed: Some(0.0),
+            ..valid()
+        };
+        let unknown_kind = PinConfig {
+            kind: Some("dimmer".to_string()),
+            ..valid()
+        };
+        let no_kind = PinConfig {
+            kind: None,
+            ..valid()
+        };
+        for pin in [
+            reversed_pulses,
+            long_pulse,
+            reversed_angles,
+            standing_still,
+            unknown_kind,
+            no_kind,
+        ] {
+            assert!(validate_servos(&[pin], &gpioconfig).is_err());
+        }
+    }
+
+    fn stepper(name: &str, step_pin: i32, dir_pin: i32) -> StepperConfig {
+        StepperConfig {
+            name: name.to_string(),
+            step_pin,
+            dir_pin,
+            enable_pin: None,
+            enable_level: None,
+            home_pin: None,
+            steps_per_second: 800.0,
+            acceleration: Some(2000.0),
+            profile: None,
+            min_position: Some(0),
+            max_position: Some(4000),
+        }
+    }
+
+    #[test]
+    fn validation_steppers_must_succeed() {
+        let steppers = vec![
+            stepper("slider", 5, 6),
+            StepperConfig {
+                enable_pin: Some(13),
+                profile: Some("S-Curve".to_string()),
+                ..stepper("tilt", 7, 8)
+            },
+        ];
+        let res = validate_steppers(&steppers, &gpioconfig_in_use(vec![5, 6, 7, 8, 13]));
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn validation_stepper_pins_must_be_own_outputs() {
+        let gpioconfig = gpioconfig_in_use(vec![5, 6, 7]);
+        let shared = vec![stepper("slider", 5, 6), stepper("tilt", 6, 7)];
+        assert!(validate_steppers(&shared, &gpioconfig).is_err());
+        let same_pin = vec![stepper("slider", 5, 5)];
+        assert!(validate_steppers(&same_pin, &gpioconfig).is_err());
+        let not_output = vec![stepper("slider", 5, 9)];
+        assert!(validate_steppers(&not_output, &gpioconfig).is_err());
+        let home_not_input = vec![StepperConfig {
+            home_pin: Some(7),
+            ..stepper("slider", 5, 6)
+        }];
+        assert!(validate_steppers(&home_not_input, &gpioconfig).is_err());
+    }
+
+    #[test]
+    fn validation_stepper_invalid_must_fail() {
+        let gpioconfig = gpioconfig_in_use(vec![5, 6]);
+        let valid = || stepper("slider", 5, 6);
+        let too_fast = StepperConfig {
+            steps_per_second: 20_000.0,
+            ..valid()
+        };
+        let profile_without_acceleration = StepperConfig {
+            acceleration: None,
+            profile: Some("trapezoidal".to_string()),
+            ..valid()
+        };
+        let reversed_limits = StepperConfig {
+            min_position: Some(10),
+            max_position: Some(-10),
+            ..valid()
+        };
+        let unknown_profile = StepperConfig {
+            profile: Some("bounce".to_string()),
+            ..valid()
+        };
+        for stepper in [too_fast, profile_without_acceleration, reversed_limits, unknown_profile] {
+            assert!(validate_steppers(&[stepper], &gpioconfig).is_err());
+        }
+    }
+
+    fn device(name: &str, kind: &str, pins: &[(&str, i32)]) -> DeviceConfig {
+        DeviceConfig {
+            name: name.to_string(),
+            kind: kind.to_string(),
+            pins: pins.iter().map(|(role, gpio)| (role.to_string(), *gpio)).collect(),
+        }
+    }
+
+    fn gpioconfig_devices() -> GpioConfig {
+        GpioConfig {
+            gpios_in_use: Some(vec![5, 6, 12, 13, 18, 19]),
+            gpios_mode_output: Some(vec![5, 6]),
+            gpios_mode_pwm: Some(vec![12, 13, 18, 19]),
+            ..gpioconfig_in_use(vec![])
+        }
+    }
+
+    #[test]
+    fn validation_devices_must_succeed() {
+        let devices = vec![
+            device("lamp", "rgb_led", &[("red", 12), ("green", 13), ("Blue", 18)]),
+            device("fan", "h_bridge", &[("in1", 5), ("in2", 6), ("enable", 19)]),
+        ];
+        assert!(validate_devices(&devices, &[], &[], &gpioconfig_devices()).is_ok());
+    }
+
+    #[test]
+    fn validation_device_pins_must_match_kind() {
+        let gpioconfig = gpioconfig_devices();
+        let missing_role = device("lamp", "rgb_led", &[("red", 12), ("green", 13)]);
+        let unknown_role = device("lamp", "rgb_led", &[("red", 12), ("green", 13), ("white", 18)]);
+        let output_not_pwm = device("lamp", "rgb_led", &[("red", 5), ("green", 13), ("blue", 18)]);
+        let unknown_kind = device("lamp", "lava_lamp", &[]);
+        for device in [missing_role, unknown_role, output_not_pwm, unknown_kind] {
+            assert!(validate_devices(&[device], &[], &[], &gpioconfig).is_err());
+        }
+    }
+
+    #[test]
+    fn validation_device_pins_must_not_be_shared() {
+        let gpioconfig = gpioconfig_devices();
+        let lamp = || device("lamp", "rgb_led", &[("red", 12), ("green", 13), ("blue", 18)]);
+        let spot = device("spot", "rgb_led", &[("red", 19), ("green", 13), ("blue", 12)]);
+        assert!(validate_devices(&[lamp(), spot], &[], &[], &gpioconfig).is_err());
+        assert!(validate_devices(&[lamp(), lamp()], &[], &[], &gpioconfig).is_err());
+        let servo = servo_pin(18, 1000, 2000);
+        assert!(validate_devices(&[lamp()], &[servo], &[], &gpioconfig).is_err());
+        let fan = device("fan", "h_bridge", &[("in1", 5), ("in2", 6), ("enable", 19)]);
+        assert!(validate_devices(&[fan], &[], &[stepper("slider", 5, 6)], &gpioconfig).is_err());
+    }
+
+    fn interlock(kind: &str, gpios: Vec<i32>, dead_time_ms: Option<u32>) -> InterlockConfig {
+        InterlockConfig {
+            kind: kind.to_string(),
+            gpios,
+            dead_time_ms,
+        }
+    }
+
+    #[test]
+    fn validation_interlocks_must_succeed() {
+        let interlocks = vec![
+            interlock("exclusive", vec![5, 6], None),
+            interlock("requires", vec![5, 13], None),
+            interlock("dead_time", vec![6, 5], Some(200)),
+        ];
+        let gpioconfig = GpioConfig {
+            gpios_level_high: Some(vec![13]),
+            ..gpioconfig_in_use(vec![5, 6, 13])
+        };
+        assert!(validate_interlocks(&interlocks, &gpioconfig).is_ok());
+    }
+
+    #[test]
+    fn validation_interlock_invalid_must_fail() {
+        let gpioconfig = gpioconfig_in_use(vec![5, 6]);
+        let not_output = interlock("exclusive", vec![5, 7], None);
+        let same_gpio = interlock("exclusive", vec![5, 5], None);
+        let no_dead_time = interlock("dead_time", vec![5, 6], Some(0));
+        let unknown_kind = interlock("before", vec![5, 6], None);
+        for interlock in [not_output, same_gpio, no_dead_time, unknown_kind] {
+            assert!(validate_interlocks(&[interlock], &gpioconfig).is_err());
+        }
+    }
+
+    #[test]
+    fn validation_interlock_broken_at_startup_must_fail() {
+        let gpioconfig = GpioConfig {
+            gpios_level_high: Some(vec![5, 6]),
+            ..gpioconfig_in_use(vec![5, 6, 13])
+        };
+        let exclusive = interlock("exclusive", vec![5, 6], None);
+        assert!(validate_interlocks(&[exclusive], &gpioconfig).is_err());
+        let requires = interlock("requires", vec![5, 13], None);
+        assert!(validate_interlocks(&[requires], &gpioconfig).is_err());
+    }
+
+    #[test]
+    fn validation_location_must_check_range() {
+        let location = |latitude, longitude| LocationConfig {
+            latitude,
+            longitude,
+        };
+        assert!(validate_location(&location(55.68, 12.57)).is_ok());
+        assert!(validate_location(&location(91.0, 12.57)).is_err());
+        assert!(validate_location(&location(55.68, -180.5)).is_err());
+    }
+
+    fn calendar(gpio_id: Option<i32>, group: Option<&str>) -> CalendarConfig {
+        CalendarConfig {
+            path: "opening-hours.ics".to_string(),
+            gpio_id,
+            group: group.map(str::to_string),
+        }
+    }
+
+    #[test]
+    fn validation_calendars_must_check_targets() {
+        let gpioconfig = gpioconfig_in_use(vec![5, 6]);
+        let mut groups = HashMap::new();
+        groups.insert("zone-a".to_string(), vec![5, 6]);
+
+        let calendars = vec![calendar(Some(5), None), calendar(None, Some("Zone-A"))];
+        assert!(validate_calendars(&calendars, &groups, &gpioconfig).is_ok());
+        assert!(validate_calendars(&[calendar(Some(7), None)], &groups, &gpioconfig).is_err());
+        assert!(validate_calendars(&[calendar(None, Some("zone-b"))], &groups, &gpioconfig).is_err());
+        assert!(validate_calendars(&[calendar(Some(5), Some("zone-a"))], &groups, &gpioconfig).is_err());
+        let twice = vec![calendar(Some(5), None), calendar(Some(5), None)];
+        assert!(validate_calendars(&twice, &groups, &gpioconfig).is_err());
+    }
+
+    fn vacation(gpios: Vec<i32>, window: &str, min_on_minutes: u32) -> VacationConfig {
+        VacationConfig {
+            gpios,
+            windows: vec![window.to_string()],
+            min_on_minutes: Some(min_on_minutes),
+            max_on_minutes: Some(60),
+            min_off_minutes: None,
+            max_off_minutes: None,
+        }
+    }
+
+    #[test]
+    fn validation_vacation_must_check_config() {
+        let gpioconfig = gpioconfig_in_use(vec![5, 6]);
+
+        assert!(validate_vacation(&vacation(vec![5, 6], "18:00-23:30", 10), &gpioconfig).is_ok());
+        assert!(validate_vacation(&vacation(vec![7], "18:00-23:30", 10), &gpioconfig).is_err());
+        assert!(validate_vacation(&vacation(vec![5], "evening", 10), &gpioconfig).is_err());
+        assert!(validate_vacation(&vacation(vec![5], "18:00-23:30", 90), &gpioconfig).is_err());
+    }
+
+    fn rule(input: i32, trigger: &str, output: i32, action: Option<&str>) -> RuleConfig {
+        RuleConfig {
+            name: None,
+            input,
+            trigger: trigger.to_string(),
+            output,
+            action: action.map(str::to_string),
+            level: Some("high".to_string()),
+            duration_ms: None,
+            inverted: None,
+        }
+    }
+
+    #[test]
+    fn validation_rules_must_check_gpios_and_actions() {
+        let mut gpioconfig = gpioconfig_in_use(vec![5, 6]);
+        gpioconfig.gpios_in_use = Some(vec![4, 5, 6]);
+
+        let rules = vec![rule(4, "rising", 5, Some("set_level")), rule(4, "mirror", 6, None)];
+        assert!(validate_rules(&rules, &gpioconfig).is_ok());
+        assert!(validate_rules(&[rule(7, "mirror", 5, None)], &gpioconfig).is_err());
+        assert!(validate_rules(&[rule(5, "mirror", 4, None)], &gpioconfig).is_err());
+        assert!(validate_rules(&[rule(5, "mirror", 5, None)], &gpioconfig).is_err());
+        assert!(validate_rules(&[rule(4, "rising", 5, Some("pulse"))], &gpioconfig).is_err());
+    }
+
+    #[test]
+    fn validation_scripts_must_check_directory() {
+        let scripts = |directory: &str, max_operations| ScriptsConfig {
+            directory: directory.to_string(),
+            max_operations,
+        };
+
+        assert!(validate_scripts(&scripts("src", None)).is_ok());
+        assert!(validate_scripts(&scripts("no-such-directory", None)).is_err());
+        assert!(validate_scripts(&scripts("src", Some(0))).is_err());
+    }
 }
@@ -3,19 +3,41 @@ extern crate diesel_migrations;
 extern crate log;
 extern crate raspberry_web;
 
-use actix::SyncArbiter;
-use actix_web::http;
+use actix::{Actor, SyncArbiter};
+use actix_web::{http, HttpMessage};
 use actix_web::test::TestServer;
 use diesel::prelude::*;
 use diesel::{r2d2::ConnectionManager, r2d2::Pool, SqliteConnection};
 use diesel_migrations::RunMigrationsError;
 use dotenv::dotenv;
 use std::sync::{Once, ONCE_INIT};
+use std::time::Duration;
+use std::{env, fs, process, thread};
 
-use raspberry_web::app::{gpio_status_route, set_gpio_level_route, AppState};
+use raspberry_web::app::{
+    acquire_lease_route, audit_route, batch_route, create_schedule_route, schedule_status_route, create_rule_route, disable_rule_route, scripts_route, script_status_route, solar_route, set_vacation_route, vacation_status_route, blink_route, cancel_sequence_route, desired_state_route, drift_route, gpio_status_all_route,
+    gpio_status_route, group_status_route, pulse_route, set_gpio_level_route, set_group_level_route, set_gpio_pwm_duty_route,
+    set_gpio_pwm_frequency_route, toggle_gpio_level_route, ramp_route, eased_ramp_route,
+    cancel_ramp_route, servo_angle_route, servo_status_route, steppers_route, stepper_status_route,
+    stepper_move_route, stepper_home_route, stepper_stop_route, devices_route, device_status_route,
+    device_operation_route, device_operation_value_route, interlocks_route,
+    AppState,
+};
+use raspberry_web::calendar::CalendarMonitor;
+use raspberry_web::guards::GuardMonitor;
 use raspberry_web::handlers::DbExecutor;
+use raspberry_web::models;
 use raspberry_web::rpi::create_gpio_arc_mutex;
 use raspberry_web::schema;
+use raspberry_web::ramp::Ramper;
+use raspberry_web::sequencer::Sequencer;
+use raspberry_web::scripting::{new_script_registry, start_scripts, ScriptHost};
+use raspberry_web::settings::{
+    CalendarConfig, LocationConfig, ScriptsConfig, StepperConfig, VacationConfig,
+};
+use raspberry_web::stepper::StepperDriver;
+use raspberry_web::vacation::VacationSimulator;
+use raspberry_web::ui::ui_index_route;
 
 embed_migrations!("migrations");
 static INIT: Once = ONCE_INIT;
@@ -45,9 +67,14 @@ fn setup_db_for_tests(connection: &SqliteConnection) -> Result<(), diesel::resul
     // http://diesel.rs/guides/all-about-inserts/
     use crate::schema::gpio_state::dsl::*;
 
-    // gpio #1: can be polled and updated
+    // gpio #1: can be polled and updated, also by name
     diesel::update(gpio_state)
-        .set((in_use.eq(1), gpio_mode.eq("output"), gpio_level.eq("low")))
+        .set((
+            in_use.eq(1),
+            gpio_mode.eq("output"),
+            gpio_level.eq("low"),
+            name.eq("relay"),
+        ))
         .filter(gpio_id.eq(1))
         .execute(connection)?;
 
@@ -63,6 +90,131 @@ fn setup_db_for_tests(connection: &SqliteConnection) -> Result<(), diesel::resul
         .filter(gpio_id.eq(3))
         .execute(connection)?;
 
+    // gpio #4: in use, mode is output
+    diesel::update(gpio_state)
+        .set((in_use.eq(1), gpio_mode.eq("output"), gpio_level.eq("low")))
+        .filter(gpio_id.eq(4))
+        .execute(connection)?;
+
+    // gpio #7: in use, mode is output, driven by a calendar
+    diesel::update(gpio_state)
+        .set((in_use.eq(1), gpio_mode.eq("output"), gpio_level.eq("low")))
+        .filter(gpio_id.eq(7))
+        .execute(connection)?;
+
+    // gpio #18: in use, mode is pwm on a hardware channel
+    diesel::update(gpio_state)
+        .set((
+            in_use.eq(1),
+            gpio_mode.eq("pwm"),
+            pwm_frequency.eq(1000.0),
+            pwm_duty_cycle.eq(0.0),
+            pwm_channel.eq(0),
+        ))
+        .filter(gpio_id.eq(18))
+        .execute(connection)?;
+
+    // gpio #13: in use, a servo turning at most 180 degrees per second
+    diesel::update(gpio_state)
+        .set((
+            in_use.eq(1),
+            gpio_mode.eq("pwm"),
+            pwm_frequency.eq(50.0),
+            pwm_duty_cycle.eq(0.0),
+            pwm_channel.eq(1),
+        ))
+        .filter(gpio_id.eq(13))
+        .execute(connection)?;
+    {
+        use crate::schema::gpio_servos::dsl::*;
+        diesel::insert_into(gpio_servos)
+            .values(&models::Servo {
+                gpio_id: 13,
+                min_pulse_us: 1000,
+                max_pulse_us: 2000,
+                min_angle: 0.0,
+                max_angle: 180.0,
+                max_speed: Some(180.0),
+            })
+            .execute(connection)?;
+    }
+
+    // gpio #20 and #21: step and direction pins of stepper 'slider'
+    diesel::update(gpio_state)
+        .set((in_use.eq(1), gpio_mode.eq("output"), gpio_level.eq("low")))
+        .filter(gpio_id.eq_any(vec![20, 21]))
+        .execute(connection)?;
+
+    // gpio #22, #23 and #24: direction and enable pins of H-bridge 'fan', with software PWM
+    diesel::update(gpio_state)
+        .set((in_use.eq(1), gpio_mode.eq("output"), gpio_level.eq("low")))
+        .filter(gpio_id.eq_any(vec![22, 23]))
+        .execute(connection)?;
+    diesel::update(gpio_state)
+        .set((
+            in_use.eq(1),
+            gpio_mode.eq("pwm"),
+            pwm_frequency.eq(1000.0),
+            pwm_duty_cycle.eq(0.0),
+        ))
+        .filter(gpio_id.eq(24))
+        .execute(connection)?;
+    {
+        use crate::schema::gpio_devices::dsl::*;
+        let pin = |id: i32, pin_role: &str| models::DevicePin {
+            gpio_id: id,
+            device_name: "fan".to_string(),
+            kind: "h_bridge".to_string(),
+            role: pin_role.to_string(),
+        };
+        diesel::insert_into(gpio_devices)
+            .values(&vec![pin(22, "in1"), pin(23, "in2"), pin(24, "enable")])
+            .execute(connection)?;
+    }
+
+    // gpio #5, #6 and #16: motor relays forward and reverse, never on together and with a
+    // dead-time before reverse, and a brake release that needs forward. Forward is on for at
+    // most 1 s.
+    diesel::update(gpio_state)
+        .set((in_use.eq(1), gpio_mode.eq("output"), gpio_level.eq("low")))
+        .filter(gpio_id.eq_any(vec![5, 6, 16]))
+        .execute(connection)?;
+    diesel::update(gpio_state)
+        .set((max_on_duration.eq(1), safe_level.eq("low")))
+        .filter(gpio_id.eq(5))
+        .execute(connection)?;
+    {
+        use crate::schema::gpio_interlocks::dsl::*;
+        let interlock = |interlock_kind: &str, id: i32, other: i32, dead_time| {
+            models::NewInterlock {
+                kind: interlock_kind.to_string(),
+                gpio_id: id,
+                other_gpio: other,
+                dead_time_ms: dead_time,
+            }
+        };
+        diesel::insert_into(gpio_interlocks)
+            .values(&vec![
+                interlock("exclusive", 5, 6, None),
+                interlock("dead_time", 6, 5, Some(300)),
+                interlock("requires", 16, 5, None),
+            ])
+            .execute(connection)?;
+    }
+
+    // group 'outputs' can be switched, group 'mixed' has an input
+    {
+        use crate::schema::gpio_groups::dsl::*;
+        diesel::insert_into(gpio_groups)
+            .values(&vec![
+                (group_name.eq("outputs"), gpio_id.eq(1)),
+                (group_name.eq("outputs"), gpio_id.eq(4)),
+                (group_name.eq("mixed"), gpio_id.eq(1)),
+                (group_name.eq("mixed"), gpio_id.eq(3)),
+            ])
+            .execute(connection)?;
+    }
+
     Ok(())
 }
 
@@ -75,8 +227,9 @@ fn get_testserver_with_state() -> TestServer {
     // https://github.com/actix/actix-website/blob/master/content/docs/testing.md
     let test_server = TestServer::build_with_state(|| {
         let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
-        // we can start diesel actors
-        let addr = SyncArbiter::start(3, || {
+        // we can start diesel actors. Each has its own in-memory database, so a single one
+        // lets tests make several requests against the same state.
+        let addr = SyncArbiter::start(1, || {
             DbExecutor({
                 let pool = get_pool_after_migrations().expect("Could not run migrations");
                 let connection = pool.get().expect("Failed to acquire connection");
@@ -84,20 +237,181 @@ fn get_testserver_with_state() -> TestServer {
                 pool.clone()
             })
         });
+        let sequencer = Sequencer::new(addr.clone(), gpio_arc_mutex.clone()).start();
+        let ramper = Ramper::new(addr.clone(), gpio_arc_mutex.clone()).start();
+        let vacation = VacationSimulator::new(
+            addr.clone(),
+            gpio_arc_mutex.clone(),
+            Some(VacationConfig {
+                gpios: vec![1, 4],
+                windows: vec!["18:00-23:30".to_string()],
+                min_on_minutes: None,
+                max_on_minutes: None,
+                min_off_minutes: None,
+                max_off_minutes: None,
+            }),
+        )
+        .start();
+        // a script reading an input and a script making a forbidden change
+        let scripts = new_script_registry();
+        let directory = env::temp_dir().join(format!("raspberry-web-scripts-{}", process::id()));
+        fs::create_dir_all(&directory).expect("Could not create scripts directory");
+        fs::write(directory.join("reader.rhai"), "print(read_pin(1));").expect("Test failed");
+        fs::write(directory.join("writer.rhai"), "set_level(3, \"high\");").expect("Test failed");
+        let host = ScriptHost {
+            db: addr.clone(),
+            gpio_arc_mutex: gpio_arc_mutex.clone(),
+            gpios_in_use: vec![1, 3, 4],
+        };
+        let config = ScriptsConfig {
+            directory: directory.to_string_lossy().to_string(),
+            max_operations: None,
+        };
+        start_scripts(&config, &host, &scripts).expect("Could not start scripts");
+        let stepper = StepperConfig {
+            name: "slider".to_string(),
+            step_pin: 20,
+            dir_pin: 21,
+            enable_pin: None,
+            enable_level: None,
+            home_pin: None,
+            steps_per_second: 1000.0,
+            acceleration: Some(20_000.0),
+            profile: None,
+            min_position: Some(0),
+            max_position: Some(1000),
+        };
+        let steppers = StepperDriver::new(addr.clone(), gpio_arc_mutex.clone(), &[stepper], &[]);
+        GuardMonitor::new(addr.clone(), gpio_arc_mutex.clone()).start();
+        // a calendar with an all-day event every day of the year since 2019, for gpio #7
+        let calendar = directory.join("every-day.ics");
+        fs::write(
+            &calendar,
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//test//EN\r\n\
+             BEGIN:VEVENT\r\nUID:every-day\r\nDTSTART;VALUE=DATE:20190101\r\n\
+             RRULE:FREQ=YEARLY;BYDAY=MO,TU,WE,TH,FR,SA,SU\r\nEND:VEVENT\r\nEND:VCALENDAR\r\n",
+        )
+        .expect("Test failed");
+        let calendars = [CalendarConfig {
+            path: calendar.to_string_lossy().to_string(),
+            gpio_id: Some(7),
+            group: None,
+        }];
+        CalendarMonitor::new(addr.clone(), gpio_arc_mutex.clone(), &calendars).start();
         // then we can construct custom state, or it could be `()`
         AppState {
             db: addr.clone(),
+            sequencer,
+            ramper,
+            vacation,
             gpio_arc_mutex: gpio_arc_mutex.clone(),
+            location: Some(LocationConfig {
+                latitude: 55.68,
+                longitude: 12.57,
+            }),
+            scripts,
+            steppers,
         }
     })
     // register server handlers and start test server
     .start(|app| {
-        app.resource("/status/{id}", |r| {
+        app.resource("/status", |r| {
+            r.method(http::Method::GET).with(gpio_status_all_route)
+        })
+        .resource("/status/{id}", |r| {
             r.method(http::Method::GET).with(gpio_status_route)
         })
         .resource("/set/level/{id}/{level}", |r| {
             r.method(http::Method::GET).with(set_gpio_level_route)
-        });
+        })
+        .resource("/toggle/{id}", |r| {
+            r.method(http::Method::GET).with(toggle_gpio_level_route)
+        })
+        .resource("/pwm/{id}/frequency/{frequency}", |r| {
+            r.method(http::Method::GET).with(set_gpio_pwm_frequency_route)
+        })
+        .resource("/pwm/{id}/duty/{duty_cycle}", |r| {
+            r.method(http::Method::GET).with(set_gpio_pwm_duty_route)
+        })
+        .resource("/ramp/{id}/{duty_cycle}/{duration_ms}", |r| {
+            r.method(http::Method::GET).with(ramp_route)
+        })
+        .resource("/ramp/{id}/{duty_cycle}/{duration_ms}/{easing}", |r| {
+            r.method(http::Method::GET).with(eased_ramp_route)
+        })
+        .resource("/ramp/{id}", |r| {
+            r.method(http::Method::DELETE).with(cancel_ramp_route)
+        })
+        .resource("/servo/{id}", |r| {
+            r.method(http::Method::GET).with(servo_status_route)
+        })
+        .resource("/servo/{id}/angle/{angle}", |r| {
+            r.method(http::Method::GET).with(servo_angle_route)
+        })
+        .resource("/steppers", |r| {
+            r.method(http::Method::GET).with(steppers_route)
+        })
+        .resource("/steppers/{name}", |r| {
+            r.method(http::Method::GET).with(stepper_status_route)
+        })
+        .resource("/steppers/{name}/move", |r| {
+            r.method(http::Method::POST).with(stepper_move_route)
+        })
+        .resource("/steppers/{name}/home", |r| {
+            r.method(http::Method::POST).with(stepper_home_route)
+        })
+        .resource("/steppers/{name}/stop", |r| {
+            r.method(http::Method::POST).with(stepper_stop_route)
+        })
+        .resource("/pulse/{id}/{level}/{duration_ms}", |r| {
+            r.method(http::Method::GET).with(pulse_route)
+        })
+        .resource("/blink/{id}/{period_ms}/{duty_percent}/{count}", |r| {
+            r.method(http::Method::GET).with(blink_route)
+        })
+        .resource("/sequence/{id}", |r| {
+            r.method(http::Method::DELETE).with(cancel_sequence_route)
+        })
+        .resource("/group/{name}", |r| {
+            r.method(http::Method::GET).with(group_status_route)
+        })
+        .resource("/group/{name}/set/level/{level}", |r| {
+            r.method(http::Method::GET).with(set_group_level_route)
+        })
+        .resource("/devices", |r| r.method(http::Method::GET).with(devices_route))
+        .resource("/devices/{name}", |r| {
+            r.method(http::Method::GET).with(device_status_route)
+        })
+        .resource("/devices/{name}/{operation}", |r| {
+            r.method(http::Method::GET).with(device_operation_route)
+        })
+        .resource("/devices/{name}/{operation}/{value}", |r| {
+            r.method(http::Method::GET).with(device_operation_value_route)
+        })
+        .resource("/batch", |r| r.method(http::Method::POST).with(batch_route))
+        .resource("/state", |r| r.method(http::Method::PUT).with(desired_state_route))
+        .resource("/drift", |r| r.method(http::Method::GET).with(drift_route))
+        .resource("/audit", |r| r.method(http::Method::GET).with(audit_route))
+        .resource("/interlocks", |r| {
+            r.method(http::Method::GET).with(interlocks_route)
+        })
+        .resource("/solar/{days}", |r| r.method(http::Method::GET).with(solar_route))
+        .resource("/vacation", |r| {
+            r.method(http::Method::GET).with(vacation_status_route);
+            r.method(http::Method::PUT).with(set_vacation_route)
+        })
+        .resource("/schedules", |r| r.method(http::Method::POST).with(create_schedule_route))
+        .resource("/schedules/{schedule_id}", |r| {
+            r.method(http::Method::GET).with(schedule_status_route)
+        })
+        .resource("/rules", |r| r.method(http::Method::POST).with(create_rule_route))
+        .resource("/rules/{rule_id}/disable", |r| {
+            r.method(http::Method::POST).with(disable_rule_route)
+        })
+        .resource("/scripts", |r| r.method(http::Method::GET).with(scripts_route))
+        .resource("/scripts/{name}", |r| r.method(http::Method::GET).with(script_status_route))
+        .resource("/lease", |r| r.method(http::Method::POST).with(acquire_lease_route))
+        .resource("/ui", |r| r.method(http::Method::GET).f(ui_index_route));
     });
     test_server
 }
@@ -118,6 +432,54 @@ fn check_status_succes() {
     assert!(response.status().is_success())
 }
 
+#[test]
+fn check_status_all_succes() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/status")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success())
+}
+
+#[test]
+fn check_status_by_name_succes() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/status/relay")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success())
+}
+
+#[test]
+fn check_status_unknown_name_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/status/no-such-gpio")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::NOT_FOUND)
+}
+
 #[test]
 fn check_status_gpio_nonexistant_failure() {
     // given
@@ -150,6 +512,22 @@ fn set_gpio_level_success() {
     assert!(response.status().is_success())
 }
 
+#[test]
+fn set_gpio_level_by_name_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/set/level/relay/high")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success())
+}
+
 #[test]
 fn set_gpio_level_gpio_nonexistant_failure() {
     // given
@@ -213,3 +591,1324 @@ fn set_gpio_level_unknown_level_failure() {
     // then
     assert_eq!(response.status(), http::StatusCode::NOT_FOUND)
 }
+
+#[test]
+fn check_status_etag_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server.client(http::Method::GET, "/status/1").finish().unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(http::header::ETAG).unwrap(), "\"0\"");
+}
+
+#[test]
+fn set_gpio_level_if_match_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/set/level/1/high")
+        .header(http::header::IF_MATCH, "\"0\"")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then: the version was incremented
+    assert!(response.status().is_success());
+    assert_eq!(response.headers().get(http::header::ETAG).unwrap(), "\"1\"");
+}
+
+#[test]
+fn set_gpio_level_if_match_stale_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/set/level/1/high")
+        .header(http::header::IF_MATCH, "\"7\"")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::PRECONDITION_FAILED)
+}
+
+#[test]
+fn set_gpio_level_if_match_weak_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when: the version matches, but only weakly
+    let request = test_server
+        .client(http::Method::GET, "/set/level/1/high")
+        .header(http::header::IF_MATCH, "W/\"0\"")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::PRECONDITION_FAILED);
+    assert_ne!(gpio_status(&mut test_server, 1).gpio_level.as_deref(), Some("high"));
+}
+
+#[test]
+fn check_group_status_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/group/outputs")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success())
+}
+
+#[test]
+fn check_group_status_nonexistant_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/group/nothing")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::NOT_FOUND)
+}
+
+#[test]
+fn set_group_level_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/group/outputs/set/level/high")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success())
+}
+
+#[test]
+fn set_group_level_member_not_output_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/group/mixed/set/level/high")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN)
+}
+
+#[test]
+fn batch_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!([
+        {"id": 1, "level": "high"},
+        {"id": "4", "level": "high"},
+    ]);
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/batch")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let batch: models::BatchResult = serde_json::from_slice(&bytes).unwrap();
+    assert!(batch.applied);
+    assert!(batch.results.iter().all(|item| item.status == 200));
+}
+
+#[test]
+fn batch_one_operation_forbidden_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!([
+        {"id": "relay", "level": "high"},
+        {"id": 3, "level": "high"},
+    ]);
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/batch")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN);
+    let bytes = test_server.execute(response.body()).unwrap();
+    let batch: models::BatchResult = serde_json::from_slice(&bytes).unwrap();
+    assert!(!batch.applied);
+    assert_eq!(batch.results[0].status, 424);
+    assert_eq!(batch.results[1].status, 403);
+}
+
+#[test]
+fn batch_stale_version_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!([
+        {"id": 1, "level": "high", "version": 0},
+        {"id": 4, "level": "high", "version": 7},
+    ]);
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/batch")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::PRECONDITION_FAILED);
+    let bytes = test_server.execute(response.body()).unwrap();
+    let batch: models::BatchResult = serde_json::from_slice(&bytes).unwrap();
+    assert!(!batch.applied);
+    assert_eq!(batch.results[1].status, 412);
+}
+
+#[test]
+fn batch_duplicate_gpio_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!([
+        {"id": 1, "level": "high"},
+        {"id": "relay", "level": "low"},
+    ]);
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/batch")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::BAD_REQUEST)
+}
+
+#[test]
+fn desired_state_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"gpios": [
+        {"gpio_id": 1, "in_use": 1, "gpio_mode": "output", "gpio_level": "high"},
+        {"gpio_id": 3, "in_use": 1, "gpio_mode": "input"},
+        {"gpio_id": 4, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 5, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 6, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 7, "in_use": 1, "gpio_mode": "output"},
+        {"gpio_id": 13, "in_use": 1, "gpio_mode": "pwm"},
+        {"gpio_id": 16, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 18, "in_use": 1, "gpio_mode": "pwm"},
+        {"gpio_id": 20, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 21, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 22, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 23, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 24, "in_use": 1, "gpio_mode": "pwm"},
+    ]});
+
+    // when
+    let request = test_server
+        .client(http::Method::PUT, "/state")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then: only the level of gpio #1 differs
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let changes: Vec<models::StateChange> = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(changes.len(), 1);
+    assert_eq!(changes[0].gpio_id, 1);
+    assert_eq!(changes[0].field, "gpio_level");
+    assert_eq!(changes[0].after, "high");
+}
+
+#[test]
+fn desired_state_level_for_input_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"gpios": [
+        {"gpio_id": 3, "in_use": 1, "gpio_mode": "input", "gpio_level": "high"},
+    ]});
+
+    // when
+    let request = test_server
+        .client(http::Method::PUT, "/state")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::BAD_REQUEST)
+}
+
+#[test]
+fn desired_state_device_gpio_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"gpios": [
+        {"gpio_id": 1, "in_use": 1, "gpio_mode": "output", "gpio_level": "high"},
+        {"gpio_id": 3, "in_use": 1, "gpio_mode": "input"},
+        {"gpio_id": 4, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 5, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 6, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 7, "in_use": 1, "gpio_mode": "output"},
+        {"gpio_id": 13, "in_use": 1, "gpio_mode": "pwm"},
+        {"gpio_id": 16, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 18, "in_use": 1, "gpio_mode": "pwm"},
+        {"gpio_id": 20, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 21, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 22, "in_use": 1, "gpio_mode": "output", "gpio_level": "high"},
+        {"gpio_id": 23, "in_use": 1, "gpio_mode": "output", "gpio_level": "low"},
+        {"gpio_id": 24, "in_use": 1, "gpio_mode": "pwm"},
+    ]});
+
+    // when: gpio #22 is owned by H-bridge 'fan'
+    let request = test_server
+        .client(http::Method::PUT, "/state")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then: gpio #1 is not changed either
+    assert_eq!(response.status(), http::StatusCode::LOCKED);
+    assert_ne!(gpio_status(&mut test_server, 1).gpio_level.as_deref(), Some("high"));
+}
+
+#[test]
+fn drift_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server.client(http::Method::GET, "/drift").finish().unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then: no drift check has run
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let drifts: Vec<models::GpioDrift> = serde_json::from_slice(&bytes).unwrap();
+    assert!(drifts.is_empty());
+}
+
+/// Lease gpio #1 'relay' for a minute and return the lease id
+fn acquire_relay_lease(test_server: &mut TestServer) -> String {
+    let body = serde_json::json!({"gpio": "relay", "duration_seconds": 60});
+    let request = test_server
+        .client(http::Method::POST, "/lease")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    let lease: models::Lease = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(lease.gpios, vec![1]);
+    lease.lease_id
+}
+
+#[test]
+fn set_gpio_level_leased_without_lease_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    acquire_relay_lease(&mut test_server);
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/set/level/1/high")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::LOCKED)
+}
+
+#[test]
+fn set_gpio_level_leased_with_lease_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let lease_id = acquire_relay_lease(&mut test_server);
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/set/level/1/high")
+        .header("X-Lease-Id", lease_id)
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success())
+}
+
+#[test]
+fn acquire_lease_input_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"gpio": 3, "duration_seconds": 60});
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/lease")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN)
+}
+
+fn toggle(test_server: &mut TestServer, id: &str) -> models::Gpio {
+    let request = test_server
+        .client(http::Method::GET, &format!("/toggle/{}", id))
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+#[test]
+fn toggle_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let first = toggle(&mut test_server, "relay");
+    let second = toggle(&mut test_server, "1");
+
+    // then
+    assert_eq!(first.gpio_level.as_deref(), Some("high"));
+    assert_eq!(second.gpio_level.as_deref(), Some("low"));
+    assert_eq!(second.version, first.version + 1);
+}
+
+#[test]
+fn toggle_gpio_mode_not_output_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/toggle/3")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN)
+}
+
+#[test]
+fn pwm_duty_and_frequency_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/pwm/18/duty/25.5")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+    let request = test_server
+        .client(http::Method::GET, "/pwm/18/frequency/50")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    // then
+    let bytes = test_server.execute(response.body()).unwrap();
+    let gpio: models::Gpio = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(gpio.pwm_frequency, Some(50.0));
+    assert_eq!(gpio.pwm_duty_cycle, Some(25.5));
+    assert_eq!(gpio.pwm_channel, Some(0));
+}
+
+#[test]
+fn pwm_duty_out_of_range_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/pwm/18/duty/150")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::BAD_REQUEST)
+}
+
+#[test]
+fn pwm_gpio_mode_not_pwm_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let pwm_on_output = test_server
+        .client(http::Method::GET, "/pwm/4/duty/50")
+        .finish()
+        .unwrap();
+    let pwm_on_output = test_server.execute(pwm_on_output.send()).unwrap();
+    let level_on_pwm = test_server
+        .client(http::Method::GET, "/set/level/18/high")
+        .finish()
+        .unwrap();
+    let level_on_pwm = test_server.execute(level_on_pwm.send()).unwrap();
+
+    // then
+    assert_eq!(pwm_on_output.status(), http::StatusCode::FORBIDDEN);
+    assert_eq!(level_on_pwm.status(), http::StatusCode::FORBIDDEN)
+}
+
+fn pwm_status(test_server: &mut TestServer, id: &str) -> models::Gpio {
+    let request = test_server
+        .client(http::Method::GET, &format!("/status/{}", id))
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+#[test]
+fn ramp_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/ramp/18/80/200/ease-in-out")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+    thread::sleep(Duration::from_millis(600));
+
+    // then
+    let gpio = pwm_status(&mut test_server, "18");
+    assert_eq!(gpio.pwm_duty_cycle, Some(80.0));
+}
+
+#[test]
+fn ramp_cancelled_by_later_write() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let request = test_server
+        .client(http::Method::GET, "/ramp/18/100/10000")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/pwm/18/duty/10")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+    thread::sleep(Duration::from_millis(300));
+
+    // then
+    let gpio = pwm_status(&mut test_server, "18");
+    assert_eq!(gpio.pwm_duty_cycle, Some(10.0));
+    let request = test_server
+        .client(http::Method::DELETE, "/ramp/18")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert_eq!(response.status(), http::StatusCode::NOT_FOUND)
+}
+
+#[test]
+fn cancel_ramp_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let request = test_server
+        .client(http::Method::GET, "/ramp/18/100/10000/ease-out")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+    thread::sleep(Duration::from_millis(300));
+
+    // when
+    let request = test_server
+        .client(http::Method::DELETE, "/ramp/18")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let ramp: models::Ramp = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(ramp.easing, "ease-out");
+    assert_eq!(ramp.target_duty_cycle, 100.0);
+    assert!(ramp.duty_cycle > 0.0 && ramp.duty_cycle < 100.0);
+    thread::sleep(Duration::from_millis(100));
+    let before = pwm_status(&mut test_server, "18");
+    thread::sleep(Duration::from_millis(200));
+    let after = pwm_status(&mut test_server, "18");
+    assert_eq!(before.version, after.version);
+}
+
+#[test]
+fn ramp_invalid_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let bad_easing = test_server
+        .client(http::Method::GET, "/ramp/18/50/1000/bounce")
+        .finish()
+        .unwrap();
+    let bad_easing = test_server.execute(bad_easing.send()).unwrap();
+    let not_pwm = test_server
+        .client(http::Method::GET, "/ramp/4/50/1000")
+        .finish()
+        .unwrap();
+    let not_pwm = test_server.execute(not_pwm.send()).unwrap();
+
+    // then
+    assert_eq!(bad_easing.status(), http::StatusCode::BAD_REQUEST);
+    assert_eq!(not_pwm.status(), http::StatusCode::FORBIDDEN)
+}
+
+fn servo_status(test_server: &mut TestServer, id: &str) -> models::ServoStatus {
+    let request = test_server
+        .client(http::Method::GET, &format!("/servo/{}", id))
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+fn move_servo(test_server: &mut TestServer, id: &str, angle: f64) -> models::Gpio {
+    let request = test_server
+        .client(http::Method::GET, &format!("/servo/{}/angle/{}", id, angle))
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+#[test]
+fn servo_angle_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    assert_eq!(servo_status(&mut test_server, "13").angle, None);
+
+    // when: the first move goes straight to the angle, the second at 180 degrees per second
+    let gpio = move_servo(&mut test_server, "13", 90.0);
+    assert_eq!(gpio.pwm_frequency, Some(50.0));
+    assert_eq!(gpio.pwm_duty_cycle, Some(7.5));
+    move_servo(&mut test_server, "13", 180.0);
+    thread::sleep(Duration::from_millis(200));
+    let halfway = servo_status(&mut test_server, "13").angle.unwrap();
+    thread::sleep(Duration::from_millis(600));
+
+    // then
+    assert!(halfway > 90.0 && halfway < 180.0);
+    let status = servo_status(&mut test_server, "13");
+    assert_eq!(status.angle, Some(180.0));
+    assert_eq!(status.servo.max_speed, Some(180.0));
+}
+
+#[test]
+fn servo_invalid_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let out_of_range = test_server
+        .client(http::Method::GET, "/servo/13/angle/200")
+        .finish()
+        .unwrap();
+    let out_of_range = test_server.execute(out_of_range.send()).unwrap();
+    let not_servo = test_server
+        .client(http::Method::GET, "/servo/18/angle/90")
+        .finish()
+        .unwrap();
+    let not_servo = test_server.execute(not_servo.send()).unwrap();
+
+    // then
+    assert_eq!(out_of_range.status(), http::StatusCode::BAD_REQUEST);
+    assert_eq!(not_servo.status(), http::StatusCode::NOT_FOUND)
+}
+
+fn get_status(test_server: &mut TestServer, path: &str) -> http::StatusCode {
+    let request = test_server.client(http::Method::GET, path).finish().unwrap();
+    test_server.execute(request.send()).unwrap().status()
+}
+
+fn gpio_status(test_server: &mut TestServer, id: i32) -> models::Gpio {
+    let path = format!("/status/{}", id);
+    let request = test_server.client(http::Method::GET, &path).finish().unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+fn device_status(test_server: &mut TestServer, path: &str) -> models::DeviceStatus {
+    let request = test_server.client(http::Method::GET, path).finish().unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+#[test]
+fn device_operation_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let coast = device_status(&mut test_server, "/devices/fan");
+
+    // when
+    let reverse = device_status(&mut test_server, "/devices/fan/reverse/60");
+    let brake = device_status(&mut test_server, "/devices/fan/brake");
+
+    // then
+    assert_eq!((coast.kind.as_str(), coast.state.as_deref()), ("h_bridge", Some("coast")));
+    assert_eq!((reverse.state.as_deref(), reverse.speed), (Some("reverse"), Some(60.0)));
+    assert_eq!(reverse.pins["in1"].gpio_level.as_deref(), Some("low"));
+    assert_eq!(reverse.pins["in2"].gpio_level.as_deref(), Some("high"));
+    assert_eq!(reverse.pins["enable"].pwm_duty_cycle, Some(60.0));
+    assert_eq!(brake.state.as_deref(), Some("brake"));
+    assert_eq!(brake.pins["enable"].pwm_duty_cycle, Some(100.0));
+}
+
+#[test]
+fn device_owned_pins_locked_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let set_level = get_status(&mut test_server, "/set/level/22/high");
+    let toggle = get_status(&mut test_server, "/toggle/23");
+    let pwm = get_status(&mut test_server, "/pwm/24/duty/50");
+
+    // then: the pins are unchanged
+    assert_eq!(set_level, http::StatusCode::LOCKED);
+    assert_eq!(toggle, http::StatusCode::LOCKED);
+    assert_eq!(pwm, http::StatusCode::LOCKED);
+    let fan = device_status(&mut test_server, "/devices/fan");
+    assert_eq!(fan.state.as_deref(), Some("coast"));
+    assert_eq!(fan.pins["enable"].pwm_duty_cycle, Some(0.0));
+}
+
+#[test]
+fn device_invalid_operation_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let unknown = get_status(&mut test_server, "/devices/fan/spin");
+    let too_fast = get_status(&mut test_server, "/devices/fan/forward/150");
+    let no_device = get_status(&mut test_server, "/devices/lamp/off");
+
+    // then
+    assert_eq!(unknown, http::StatusCode::BAD_REQUEST);
+    assert_eq!(too_fast, http::StatusCode::BAD_REQUEST);
+    assert_eq!(no_device, http::StatusCode::NOT_FOUND);
+    let request = test_server.client(http::Method::GET, "/devices").finish().unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    let bytes = test_server.execute(response.body()).unwrap();
+    let devices: Vec<models::DeviceStatus> = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(devices.len(), 1);
+}
+
+#[test]
+fn interlock_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when: forward, with the brake released, then off again, which applies the brake
+    let forward = get_status(&mut test_server, "/set/level/5/high");
+    let brake_released = get_status(&mut test_server, "/set/level/16/high");
+    let stopped = get_status(&mut test_server, "/set/level/5/low");
+    let brake = gpio_status(&mut test_server, 16);
+    let brake_without_forward = get_status(&mut test_server, "/set/level/16/high");
+    let reverse_too_soon = get_status(&mut test_server, "/set/level/6/high");
+    thread::sleep(Duration::from_millis(350));
+    let reverse = get_status(&mut test_server, "/set/level/6/high");
+    let forward_while_reverse = get_status(&mut test_server, "/toggle/5");
+
+    // then
+    assert!(forward.is_success());
+    assert!(brake_released.is_success());
+    assert!(stopped.is_success());
+    assert_eq!(brake.gpio_level.as_deref(), Some("low"));
+    assert_eq!(brake_without_forward, http::StatusCode::CONFLICT);
+    assert_eq!(reverse_too_soon, http::StatusCode::CONFLICT);
+    assert!(reverse.is_success());
+    assert_eq!(forward_while_reverse, http::StatusCode::CONFLICT);
+}
+
+#[test]
+fn interlock_max_on_guard_on_required_gpio_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when: forward, with the brake released, for longer than forward may be on
+    let forward = get_status(&mut test_server, "/set/level/5/high");
+    let brake_released = get_status(&mut test_server, "/set/level/16/high");
+    thread::sleep(Duration::from_millis(2500));
+
+    // then: forward is forced off, and the brake is applied with it
+    assert!(forward.is_success());
+    assert!(brake_released.is_success());
+    let forward = gpio_status(&mut test_server, 5);
+    assert_eq!(forward.gpio_level.as_deref(), Some("low"));
+    assert!(forward.forced_off_at.is_some());
+    assert_eq!(gpio_status(&mut test_server, 16).gpio_level.as_deref(), Some("low"));
+}
+
+#[test]
+fn interlock_batch_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!([{"id": 5, "level": "high"}, {"id": 6, "level": "high"}]);
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/batch")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    let status = response.status();
+    let bytes = test_server.execute(response.body()).unwrap();
+    let batch: models::BatchResult = serde_json::from_slice(&bytes).unwrap();
+    let request = test_server.client(http::Method::GET, "/interlocks").finish().unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    let bytes = test_server.execute(response.body()).unwrap();
+    let interlocks: Vec<models::Interlock> = serde_json::from_slice(&bytes).unwrap();
+
+    // then: neither relay is switched
+    assert_eq!(status, http::StatusCode::CONFLICT);
+    assert!(!batch.applied);
+    assert!(batch.results.iter().all(|item| item.gpio.is_none()));
+    assert_eq!(interlocks.len(), 3);
+    assert_eq!(get_status(&mut test_server, "/set/level/5/high"), http::StatusCode::OK);
+}
+
+fn stepper_status(test_server: &mut TestServer) -> models::StepperStatus {
+    let request = test_server
+        .client(http::Method::GET, "/steppers/slider")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    let bytes = test_server.execute(response.body()).unwrap();
+    serde_json::from_slice(&bytes).unwrap()
+}
+
+fn stepper_post(
+    test_server: &mut TestServer, path: &str, body: serde_json::Value,
+) -> http::StatusCode {
+    let request = test_server
+        .client(http::Method::POST, path)
+        .json(body)
+        .unwrap();
+    test_server.execute(request.send()).unwrap().status()
+}
+
+#[test]
+fn stepper_move_and_home_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"steps": 100});
+
+    // when
+    let status = stepper_post(&mut test_server, "/steppers/slider/move", body);
+    assert!(status.is_success());
+    let moving = stepper_status(&mut test_server);
+    thread::sleep(Duration::from_millis(500));
+    let moved = stepper_status(&mut test_server);
+    let body = serde_json::json!({"position": 30, "profile": "s-curve"});
+    let status = stepper_post(&mut test_server, "/steppers/slider/move", body);
+    assert!(status.is_success());
+    thread::sleep(Duration::from_millis(500));
+    let back = stepper_status(&mut test_server);
+    let status = stepper_post(&mut test_server, "/steppers/slider/home", serde_json::json!({}));
+    assert!(status.is_success());
+    thread::sleep(Duration::from_millis(500));
+
+    // then
+    assert_eq!(moving.target, Some(100));
+    assert_eq!((moved.position, moved.target), (100, None));
+    assert_eq!((back.position, back.target), (30, None));
+    assert_eq!(stepper_status(&mut test_server).position, 0);
+}
+
+#[test]
+fn stepper_move_refused_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let past_limit = serde_json::json!({"position": 1001});
+    let past_limit = stepper_post(&mut test_server, "/steppers/slider/move", past_limit);
+    let below_limit = serde_json::json!({"steps": -1});
+    let below_limit = stepper_post(&mut test_server, "/steppers/slider/move", below_limit);
+    let both = serde_json::json!({"steps": 10, "position": 10});
+    let both = stepper_post(&mut test_server, "/steppers/slider/move", both);
+    let unknown = serde_json::json!({"steps": 10});
+    let unknown = stepper_post(&mut test_server, "/steppers/unknown/move", unknown);
+
+    // then
+    assert_eq!(past_limit, http::StatusCode::BAD_REQUEST);
+    assert_eq!(below_limit, http::StatusCode::BAD_REQUEST);
+    assert_eq!(both, http::StatusCode::BAD_REQUEST);
+    assert_eq!(unknown, http::StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn stepper_busy_and_stop() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"position": 1000, "profile": "constant"});
+    assert!(stepper_post(&mut test_server, "/steppers/slider/move", body).is_success());
+
+    // when
+    let body = serde_json::json!({"steps": 10});
+    let busy = stepper_post(&mut test_server, "/steppers/slider/move", body);
+    thread::sleep(Duration::from_millis(100));
+    let stop = stepper_post(&mut test_server, "/steppers/slider/stop", serde_json::json!({}));
+    thread::sleep(Duration::from_millis(100));
+
+    // then
+    assert_eq!(busy, http::StatusCode::CONFLICT);
+    assert!(stop.is_success());
+    let stopped = stepper_status(&mut test_server);
+    assert_eq!(stopped.target, None);
+    assert!(stopped.position > 0 && stopped.position < 1000);
+}
+
+#[test]
+fn pulse_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/pulse/relay/high/60000")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let gpio: models::Gpio = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(gpio.gpio_level.as_deref(), Some("high"));
+}
+
+#[test]
+fn pulse_gpio_mode_not_output_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/pulse/3/high/500")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN)
+}
+
+#[test]
+fn blink_invalid_duty_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/blink/1/1000/100/3")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::BAD_REQUEST)
+}
+
+#[test]
+fn cancel_sequence_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let request = test_server
+        .client(http::Method::GET, "/blink/1/1000/50/100")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+
+    // when
+    let request = test_server
+        .client(http::Method::DELETE, "/sequence/1")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let sequence: models::Sequence = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(sequence.pattern, "blink");
+    assert_eq!(sequence.final_level, "low");
+}
+
+#[test]
+fn cancel_sequence_none_running_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::DELETE, "/sequence/1")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::NOT_FOUND)
+}
+
+#[test]
+fn audit_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/audit")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let entries: Vec<models::AuditEntry> = serde_json::from_slice(&bytes).unwrap();
+    assert!(entries.is_empty());
+}
+
+#[test]
+fn create_schedule_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"cron": "30 7 * * 1-5", "gpio": "relay", "action": "set_level", "level": "high"});
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/schedules")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let schedule: models::Schedule = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(schedule.gpio_id, Some(1));
+    assert!(schedule.next_run.is_some());
+
+    let request = test_server
+        .client(http::Method::GET, &format!("/schedules/{}", schedule.schedule_id))
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+}
+
+#[test]
+fn create_solar_schedule_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"solar_event": "sunset", "offset_minutes": 30, "gpio": 1, "action": "set_level", "level": "high"});
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/schedules")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let schedule: models::Schedule = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(schedule.cron, None);
+    assert_eq!(schedule.solar_event.as_deref(), Some("sunset"));
+    assert_eq!(schedule.offset_minutes, 30);
+    assert!(schedule.next_run.is_some());
+}
+
+#[test]
+fn solar_days_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/solar/3")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let days: Vec<models::SolarDay> = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(days.len(), 3);
+    assert!(days.iter().all(|day| day.sunrise.is_some() && day.sunset.is_some()));
+}
+
+#[test]
+fn set_vacation_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"enabled": true, "seed": 7});
+
+    // when
+    let request = test_server
+        .client(http::Method::PUT, "/vacation")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let request = test_server
+        .client(http::Method::GET, "/vacation")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let status: models::VacationStatus = serde_json::from_slice(&bytes).unwrap();
+    assert!(status.enabled);
+    assert_eq!(status.seed, Some(7));
+    let gpio_ids: Vec<i32> = status.gpios.iter().map(|gpio| gpio.gpio_id).collect();
+    assert_eq!(gpio_ids, vec![1, 4]);
+}
+
+#[test]
+fn calendar_event_sets_gpio_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when: the calendar is read and its event, going on all day, has started
+    thread::sleep(Duration::from_millis(1500));
+
+    // then
+    assert_eq!(gpio_status(&mut test_server, 7).gpio_level.as_deref(), Some("high"));
+}
+
+#[test]
+fn create_schedule_invalid_cron_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"cron": "every morning", "gpio": 1, "action": "toggle"});
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/schedules")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::BAD_REQUEST)
+}
+
+#[test]
+fn schedule_status_nonexistant_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/schedules/42")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::NOT_FOUND)
+}
+
+#[test]
+fn create_and_disable_rule_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"input": 3, "trigger": "mirror", "output": "relay", "inverted": true});
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/rules")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let rule: models::Rule = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(rule.output_gpio, 1);
+    assert_eq!(rule.action, "mirror");
+    assert_eq!(rule.inverted, 1);
+    assert_eq!(rule.enabled, 1);
+
+    let request = test_server
+        .client(http::Method::POST, &format!("/rules/{}/disable", rule.rule_id))
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+    assert!(response.status().is_success());
+    let bytes = test_server.execute(response.body()).unwrap();
+    let rule: models::Rule = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(rule.enabled, 0);
+}
+
+#[test]
+fn create_rule_input_not_in_use_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+    let body = serde_json::json!({"input": 2, "trigger": "rising", "output": 1, "action": "toggle"});
+
+    // when
+    let request = test_server
+        .client(http::Method::POST, "/rules")
+        .json(body)
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::FORBIDDEN)
+}
+
+/// Status of the scripts once they have all stopped running
+fn finished_scripts(test_server: &mut TestServer) -> Vec<models::ScriptStatus> {
+    for _ in 0..50 {
+        let request = test_server
+            .client(http::Method::GET, "/scripts")
+            .finish()
+            .unwrap();
+        let response = test_server.execute(request.send()).unwrap();
+        assert!(response.status().is_success());
+        let bytes = test_server.execute(response.body()).unwrap();
+        let scripts: Vec<models::ScriptStatus> = serde_json::from_slice(&bytes).unwrap();
+        if scripts.iter().all(|script| script.state != "running") {
+            return scripts;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    panic!("Scripts did not finish")
+}
+
+#[test]
+fn scripts_status_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let scripts = finished_scripts(&mut test_server);
+
+    // then
+    assert_eq!(scripts.len(), 2);
+    assert_eq!(scripts[0].name, "reader");
+    assert_eq!(scripts[0].state, "finished");
+    assert_eq!(scripts[0].logs[0].message, "low");
+    assert_eq!(scripts[1].name, "writer");
+    assert_eq!(scripts[1].state, "failed");
+    assert!(scripts[1].error.as_ref().unwrap().contains("input"));
+}
+
+#[test]
+fn script_status_nonexistant_failure() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/scripts/nothing")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert_eq!(response.status(), http::StatusCode::NOT_FOUND)
+}
+
+#[test]
+fn ui_index_success() {
+    // given
+    let mut test_server = get_testserver_with_state();
+
+    // when
+    let request = test_server
+        .client(http::Method::GET, "/ui")
+        .finish()
+        .unwrap();
+    let response = test_server.execute(request.send()).unwrap();
+
+    // then
+    assert!(response.status().is_success());
+    let content_type = response.headers().get(http::header::CONTENT_TYPE).unwrap();
+    assert!(content_type.to_str().unwrap().starts_with("text/html"));
+}
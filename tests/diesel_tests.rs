@@ -5,15 +5,42 @@ extern crate raspberry_web;
 use diesel::prelude::*;
 use diesel::{r2d2::ConnectionManager, r2d2::Pool, SqliteConnection};
 use diesel_migrations::RunMigrationsError;
+use raspberry_web::control::{apply_gpio_levels, apply_gpio_pwm, toggle_gpio_level};
+use raspberry_web::device::{check_gpio_owner_db, load_device_db, setup_devices_db};
+use raspberry_web::drift::{check_drift, DriftPolicy};
+use raspberry_web::errors::RpWebError;
+use raspberry_web::guards::{enforce_max_on, MAX_ON_EVENT};
+use raspberry_web::interlocks::setup_interlocks_db;
+use raspberry_web::leases::{acquire_lease, expire_leases, release_lease};
 use raspberry_web::models;
+use raspberry_web::models::LevelChange;
+use raspberry_web::rpi::{create_gpio_arc_mutex, get_gpio_level_rpi, get_gpio_pwm_rpi};
+use raspberry_web::rules::{
+    enable_rule_db, load_rule_db, record_rule_firing_db, setup_rules_db, RULE_EVENT,
+};
+use raspberry_web::scheduler::{claim_due_schedules, create_schedule_db};
 use raspberry_web::schema;
+use raspberry_web::servo::{load_servo_db, setup_servos};
+use raspberry_web::settings::{
+    DeviceConfig, GpioConfig, InterlockConfig, PinConfig, RuleConfig, StepperConfig,
+};
+use raspberry_web::stepper::{save_stepper_position_db, setup_steppers_db};
+use raspberry_web::vacation::{load_vacation_db, save_vacation_db};
+use raspberry_web::setup::reconcile_rpi_and_db;
 use raspberry_web::utilities::{
+    add_gpio_group_member_db,
     get_allowed_states,
+    get_gpio_group_members,
+    get_gpio_id_by_name,
     reset_table_gpio_state,
     set_gpio_in_use_db,
+    set_gpio_level_db,
+    set_gpio_max_on_db,
     set_gpio_mode_db,
+    set_gpio_name_db,
     //set_gpio_mode_level_db
 };
+use std::collections::HashMap;
 
 embed_migrations!("migrations");
 
@@ -111,6 +138,7 @@ fn reset_table_gpio_state_after_update_must_succeed() {
     assert_eq!(gpio_reset.in_use, 0);
     assert_eq!(gpio_reset.gpio_mode, Some("".to_string()));
     assert_eq!(gpio_reset.gpio_level, Some("".to_string()));
+    assert_eq!(gpio_reset.name, None);
 }
 
 #[test]
@@ -228,3 +256,812 @@ fn set_existing_gpio_to_forbidden_level_db_must_fail() {
     assert!(true);
 }
 */
+
+#[test]
+fn set_gpio_name_db_must_succeed() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+
+    let res = set_gpio_name_db(17, "garage-door", Some("Door opener"), Some("garage,relay"), &connection);
+    assert!(res.is_ok());
+
+    let gpio_changed = gpio_state
+        .filter(gpio_id.eq(17))
+        .load::<models::Gpio>(&connection)
+        .expect("Test failed")
+        .pop()
+        .expect("Test failed");
+
+    assert_eq!(gpio_changed.name, Some("garage-door".to_string()));
+    assert_eq!(gpio_changed.description, Some("Door opener".to_string()));
+    assert_eq!(gpio_changed.tags, Some("garage,relay".to_string()));
+}
+
+#[test]
+fn set_duplicate_gpio_name_db_must_fail() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+
+    set_gpio_name_db(17, "garage-door", None, None, &connection).expect("Test failed");
+    let res = set_gpio_name_db(27, "garage-door", None, None, &connection);
+    assert!(res.is_err());
+}
+
+#[test]
+fn get_gpio_id_by_name_must_succeed() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+
+    set_gpio_name_db(17, "garage-door", None, None, &connection).expect("Test failed");
+
+    let found = get_gpio_id_by_name("garage-door", &connection).expect("Test failed");
+    let missing = get_gpio_id_by_name("porch-light", &connection).expect("Test failed");
+    assert_eq!(found, Some(17));
+    assert_eq!(missing, None);
+}
+
+#[test]
+fn get_gpio_group_members_must_succeed() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+
+    add_gpio_group_member_db("zone-a", 13, &connection).expect("Test failed");
+    add_gpio_group_member_db("zone-a", 5, &connection).expect("Test failed");
+    add_gpio_group_member_db("zone-b", 6, &connection).expect("Test failed");
+
+    let members = get_gpio_group_members("zone-a", &connection).expect("Test failed");
+    let missing = get_gpio_group_members("zone-c", &connection).expect("Test failed");
+    assert_eq!(members, vec![5, 13]);
+    assert!(missing.is_empty());
+}
+
+#[test]
+fn apply_gpio_levels_must_succeed() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+
+    let changes = vec![
+        LevelChange::new(5, "high", None),
+        LevelChange::new(6, "high", None),
+    ];
+    let res = apply_gpio_levels(&changes, &connection, gpio_arc_mutex);
+    assert!(res.is_ok());
+
+    let levels = gpio_state
+        .filter(gpio_id.eq_any(vec![5, 6]))
+        .select(gpio_level)
+        .load::<Option<String>>(&connection)
+        .expect("Test failed");
+    assert_eq!(levels, vec![Some("high".to_string()), Some("high".to_string())]);
+}
+
+#[test]
+fn apply_gpio_levels_breaking_interlock_must_change_nothing() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    let exclusive = InterlockConfig {
+        kind: "exclusive".to_string(),
+        gpios: vec![5, 6, 13],
+        dead_time_ms: None,
+    };
+    setup_interlocks_db(&[exclusive], &connection).expect("Test failed");
+
+    let changes = vec![
+        LevelChange::new(5, "high", None),
+        LevelChange::new(13, "high", None),
+    ];
+    let res = apply_gpio_levels(&changes, &connection, gpio_arc_mutex.clone());
+    match res {
+        Err(RpWebError::Interlock(errs)) => assert!(errs.contains("GPIO #13")),
+        other => panic!("The interlock must be kept, got {:?}", other),
+    }
+    let switch_over = vec![
+        LevelChange::new(5, "low", None),
+        LevelChange::new(6, "high", None),
+    ];
+    assert!(apply_gpio_levels(&switch_over, &connection, gpio_arc_mutex).is_ok());
+
+    let levels = gpio_state
+        .filter(gpio_id.eq_any(vec![5, 6, 13]))
+        .select(gpio_level)
+        .load::<Option<String>>(&connection)
+        .expect("Test failed");
+    let levels: Vec<Option<&str>> = levels.iter().map(Option::as_deref).collect();
+    assert_eq!(levels, vec![Some("low"), Some("high"), None]);
+}
+
+#[test]
+fn apply_gpio_levels_nonexisting_gpio_must_change_nothing() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+
+    let changes = vec![
+        LevelChange::new(5, "high", None),
+        LevelChange::new(42, "high", None),
+    ];
+    let res = apply_gpio_levels(&changes, &connection, gpio_arc_mutex);
+    assert!(res.is_err());
+
+    let level = gpio_state
+        .filter(gpio_id.eq(5))
+        .select(gpio_level)
+        .first::<Option<String>>(&connection)
+        .expect("Test failed");
+    assert_eq!(level, None);
+}
+
+#[test]
+fn apply_gpio_levels_hardware_failure_must_change_nothing() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+
+    // The second GPIO can not be driven to an unknown level
+    let changes = vec![
+        LevelChange::new(5, "high", Some("low")),
+        LevelChange::new(6, "unknown_level", Some("low")),
+    ];
+    let res = apply_gpio_levels(&changes, &connection, gpio_arc_mutex);
+    assert!(res.is_err());
+
+    let level = gpio_state
+        .filter(gpio_id.eq(5))
+        .select(gpio_level)
+        .first::<Option<String>>(&connection)
+        .expect("Test failed");
+    assert_eq!(level, None);
+}
+
+#[test]
+fn set_level_db_must_increment_version() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+
+    set_gpio_level_db(5, "high", &connection).expect("Test failed");
+    set_gpio_level_db(5, "low", &connection).expect("Test failed");
+
+    let version_5 = gpio_state
+        .filter(gpio_id.eq(5))
+        .select(version)
+        .first::<i32>(&connection)
+        .expect("Test failed");
+    assert_eq!(version_5, 2);
+}
+
+#[test]
+fn apply_gpio_levels_stale_version_must_change_nothing() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+
+    let changes = vec![
+        LevelChange::new(5, "high", None).expecting_version(Some(0)),
+        LevelChange::new(6, "high", None).expecting_version(Some(3)),
+    ];
+    let res = apply_gpio_levels(&changes, &connection, gpio_arc_mutex);
+    match res {
+        Err(RpWebError::PreconditionFailed(_)) => {}
+        other => panic!("Expected PreconditionFailed, got {:?}", other),
+    }
+
+    let level = gpio_state
+        .filter(gpio_id.eq(5))
+        .select(gpio_level)
+        .first::<Option<String>>(&connection)
+        .expect("Test failed");
+    assert_eq!(level, None);
+}
+
+#[test]
+fn reconcile_rpi_and_db_must_be_idempotent() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    set_gpio_in_use_db(7, 1, &connection).expect("Test failed");
+
+    let desired = GpioConfig {
+        gpios_in_use: Some(vec![5, 6]),
+        gpios_mode_output: Some(vec![5]),
+        gpios_mode_input: Some(vec![6]),
+        gpios_mode_pwm: None,
+        gpios_level_low: None,
+        gpios_level_high: Some(vec![5]),
+    };
+
+    // in_use, mode and level for #5, in_use and mode for #6, in_use for #7
+    let changes =
+        reconcile_rpi_and_db(&desired, &connection, gpio_arc_mutex.clone()).expect("Test failed");
+    assert_eq!(changes.len(), 6);
+
+    let gpio = gpio_state
+        .filter(gpio_id.eq(5))
+        .first::<models::Gpio>(&connection)
+        .expect("Test failed");
+    assert_eq!(gpio.in_use, 1);
+    assert_eq!(gpio.gpio_mode, Some("output".to_string()));
+    assert_eq!(gpio.gpio_level, Some("high".to_string()));
+
+    let in_use_7 = gpio_state
+        .filter(gpio_id.eq(7))
+        .select(in_use)
+        .first::<i32>(&connection)
+        .expect("Test failed");
+    assert_eq!(in_use_7, 0);
+
+    let changes = reconcile_rpi_and_db(&desired, &connection, gpio_arc_mutex).expect("Test failed");
+    assert!(changes.is_empty());
+}
+
+#[test]
+fn reconcile_rpi_and_db_must_correct_pin() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    setup_drift(&connection);
+
+    // #5 and #6 are 'high' in the database, but 'low' on the pin
+    let desired = GpioConfig {
+        gpios_in_use: Some(vec![5, 6]),
+        gpios_mode_output: Some(vec![5, 6]),
+        gpios_mode_input: None,
+        gpios_mode_pwm: None,
+        gpios_level_low: Some(vec![6]),
+        gpios_level_high: Some(vec![5]),
+    };
+    let changes =
+        reconcile_rpi_and_db(&desired, &connection, gpio_arc_mutex.clone()).expect("Test failed");
+
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[0].gpio_id, 5);
+    assert_eq!(changes[0].before.as_deref(), Some("low"));
+    assert_eq!(get_gpio_level_rpi(5, gpio_arc_mutex.clone()).unwrap(), "high");
+    assert_eq!(changes[1].gpio_id, 6);
+    assert_eq!(changes[1].before.as_deref(), Some("high"));
+    let changes = reconcile_rpi_and_db(&desired, &connection, gpio_arc_mutex).expect("Test failed");
+    assert!(changes.is_empty());
+}
+
+#[test]
+fn reconcile_rpi_and_db_leased_gpio_must_change_nothing() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    acquire_lease(&[6], 60, None, &connection).expect("Test failed");
+
+    let desired = GpioConfig {
+        gpios_in_use: Some(vec![5, 6]),
+        gpios_mode_output: Some(vec![5, 6]),
+        gpios_mode_input: None,
+        gpios_mode_pwm: None,
+        gpios_level_low: None,
+        gpios_level_high: Some(vec![5, 6]),
+    };
+    match reconcile_rpi_and_db(&desired, &connection, gpio_arc_mutex.clone()) {
+        Err(RpWebError::Locked(errs)) => assert!(errs.contains("GPIO #6")),
+        other => panic!("The leased GPIO must not change, got {:?}", other),
+    }
+
+    let in_use_5 = gpio_state
+        .filter(gpio_id.eq(5))
+        .select(in_use)
+        .first::<i32>(&connection)
+        .expect("Test failed");
+    assert_eq!(in_use_5, 0);
+    assert_eq!(get_gpio_level_rpi(5, gpio_arc_mutex).unwrap(), "low");
+}
+
+#[test]
+fn pwm_must_be_set_up_and_applied() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+
+    let desired = GpioConfig {
+        gpios_in_use: Some(vec![18, 20]),
+        gpios_mode_output: None,
+        gpios_mode_input: None,
+        gpios_mode_pwm: Some(vec![18, 20]),
+        gpios_level_low: None,
+        gpios_level_high: None,
+    };
+    reconcile_rpi_and_db(&desired, &connection, gpio_arc_mutex.clone()).expect("Test failed");
+
+    let gpio = gpio_state
+        .filter(gpio_id.eq(18))
+        .first::<models::Gpio>(&connection)
+        .expect("Test failed");
+    assert_eq!(gpio.gpio_mode, Some("pwm".to_string()));
+    assert_eq!(gpio.pwm_frequency, Some(1000.0));
+    assert_eq!(gpio.pwm_duty_cycle, Some(0.0));
+    assert_eq!(gpio.pwm_channel, Some(0));
+    let software = gpio_state
+        .filter(gpio_id.eq(20))
+        .select(pwm_channel)
+        .first::<Option<i32>>(&connection)
+        .expect("Test failed");
+    assert_eq!(software, None);
+    let pwm = get_gpio_pwm_rpi(18, gpio_arc_mutex.clone()).expect("Test failed");
+    assert_eq!(pwm, Some((1000.0, 0.0)));
+
+    apply_gpio_pwm(18, 200.0, 40.0, Some(gpio.version), None, &connection, gpio_arc_mutex.clone())
+        .expect("Test failed");
+    let gpio = gpio_state
+        .filter(gpio_id.eq(18))
+        .first::<models::Gpio>(&connection)
+        .expect("Test failed");
+    assert_eq!(gpio.pwm_frequency, Some(200.0));
+    assert_eq!(gpio.pwm_duty_cycle, Some(40.0));
+    let pwm = get_gpio_pwm_rpi(18, gpio_arc_mutex.clone()).expect("Test failed");
+    assert_eq!(pwm, Some((200.0, 40.0)));
+
+    // Taking the GPIO out of use stops the PWM
+    let desired = GpioConfig {
+        gpios_in_use: Some(vec![20]),
+        gpios_mode_pwm: Some(vec![20]),
+        ..desired
+    };
+    reconcile_rpi_and_db(&desired, &connection, gpio_arc_mutex.clone()).expect("Test failed");
+    let pwm = get_gpio_pwm_rpi(18, gpio_arc_mutex).expect("Test failed");
+    assert_eq!(pwm, None);
+}
+
+#[test]
+fn servos_must_be_set_up_from_pins() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    let pins = vec![PinConfig {
+        gpio_id: 12,
+        name: "pan".to_string(),
+        description: None,
+        tags: None,
+        max_on_duration: None,
+        safe_level: None,
+        kind: Some("servo".to_string()),
+        min_pulse_us: Some(500),
+        max_pulse_us: None,
+        min_angle: None,
+        max_angle: Some(270.0),
+        max_speed: None,
+    }];
+
+    setup_servos(&pins, &connection, gpio_arc_mutex.clone()).expect("Test failed");
+    // Set up again on a restart
+    setup_servos(&pins, &connection, gpio_arc_mutex.clone()).expect("Test failed");
+
+    let servo = load_servo_db(12, &connection).expect("Test failed").expect("Test failed");
+    assert_eq!((servo.min_pulse_us, servo.max_pulse_us), (500, 2000));
+    assert_eq!((servo.min_angle, servo.max_angle), (0.0, 270.0));
+    assert!(load_servo_db(13, &connection).expect("Test failed").is_none());
+
+    let gpio = gpio_state
+        .filter(gpio_id.eq(12))
+        .first::<models::Gpio>(&connection)
+        .expect("Test failed");
+    assert_eq!(gpio.pwm_frequency, Some(50.0));
+    assert_eq!(gpio.pwm_duty_cycle, Some(0.0));
+    let pwm = get_gpio_pwm_rpi(12, gpio_arc_mutex).expect("Test failed");
+    assert_eq!(pwm, Some((50.0, 0.0)));
+}
+
+/// GPIO #5 and #6 are outputs that are 'high' in the database, but were never set on the pin
+fn setup_drift(connection: &SqliteConnection) {
+    for idx in [5, 6].iter() {
+        set_gpio_in_use_db(*idx, 1, connection).expect("Test failed");
+        set_gpio_mode_db(*idx, "output", connection).expect("Test failed");
+        set_gpio_level_db(*idx, "high", connection).expect("Test failed");
+    }
+}
+
+#[test]
+fn check_drift_must_report_mismatches() {
+    use crate::schema::gpio_drift::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    setup_drift(&connection);
+
+    let drifts = check_drift(&HashMap::new(), &connection, gpio_arc_mutex).expect("Test failed");
+    assert_eq!(drifts.len(), 2);
+    assert_eq!(drifts[0].db_level, "high");
+    assert_eq!(drifts[0].hw_level, "low");
+    assert_eq!(drifts[0].correction, None);
+
+    let recorded = gpio_drift
+        .load::<models::GpioDrift>(&connection)
+        .expect("Test failed");
+    assert_eq!(recorded.len(), 2);
+}
+
+#[test]
+fn check_drift_must_correct_according_to_policy() {
+    use crate::schema::gpio_state::dsl::*;
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    setup_drift(&connection);
+
+    let mut policies = HashMap::new();
+    policies.insert(5, DriftPolicy::CorrectHardware);
+    policies.insert(6, DriftPolicy::CorrectDatabase);
+    let drifts = check_drift(&policies, &connection, gpio_arc_mutex.clone()).expect("Test failed");
+    assert_eq!(drifts.len(), 2);
+
+    // The pin of #5 was set to the database level, the database level of #6 to the pin
+    assert_eq!(get_gpio_level_rpi(5, gpio_arc_mutex.clone()).unwrap(), "high");
+    let level_6 = gpio_state
+        .filter(gpio_id.eq(6))
+        .select(gpio_level)
+        .first::<Option<String>>(&connection)
+        .expect("Test failed");
+    assert_eq!(level_6, Some("low".to_string()));
+
+    let drifts = check_drift(&policies, &connection, gpio_arc_mutex).expect("Test failed");
+    assert!(drifts.is_empty());
+}
+
+#[test]
+fn check_drift_breaking_interlock_must_not_correct() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    setup_drift(&connection);
+    set_gpio_level_db(13, "high", &connection).expect("Test failed");
+    let exclusive = InterlockConfig {
+        kind: "exclusive".to_string(),
+        gpios: vec![5, 13],
+        dead_time_ms: None,
+    };
+    setup_interlocks_db(&[exclusive], &connection).expect("Test failed");
+
+    let mut policies = HashMap::new();
+    policies.insert(5, DriftPolicy::CorrectHardware);
+    let drifts = check_drift(&policies, &connection, gpio_arc_mutex.clone()).expect("Test failed");
+
+    // #5 may not go high while #13 is high, so its pin is left low
+    assert_eq!(drifts[0].gpio_id, 5);
+    assert_eq!(drifts[0].correction.as_deref(), Some("refused"));
+    assert_eq!(get_gpio_level_rpi(5, gpio_arc_mutex).unwrap(), "low");
+}
+
+#[test]
+fn lease_must_lock_out_other_clients() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+
+    let lease = acquire_lease(&[5, 6], 60, None, &connection).expect("Test failed");
+    assert_eq!(lease.gpios, vec![5, 6]);
+    assert!(acquire_lease(&[6], 60, None, &connection).is_err());
+
+    // Without the lease id
+    let changes = vec![LevelChange::new(6, "high", None)];
+    match apply_gpio_levels(&changes, &connection, gpio_arc_mutex.clone()) {
+        Err(RpWebError::Locked(_)) => {}
+        other => panic!("Expected Locked, got {:?}", other),
+    }
+
+    // With the lease id
+    let changes = vec![LevelChange::new(6, "high", None).with_lease(Some(&lease.lease_id))];
+    assert!(apply_gpio_levels(&changes, &connection, gpio_arc_mutex.clone()).is_ok());
+
+    // After release
+    release_lease(&lease.lease_id, &connection).expect("Test failed");
+    let changes = vec![LevelChange::new(6, "low", None)];
+    assert!(apply_gpio_levels(&changes, &connection, gpio_arc_mutex).is_ok());
+}
+
+#[test]
+fn expire_leases_must_run_expiry_action() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    set_gpio_level_db(5, "high", &connection).expect("Test failed");
+
+    {
+        use crate::schema::gpio_leases::dsl::*;
+        diesel::insert_into(gpio_leases)
+            .values(&models::GpioLease {
+                gpio_id: 5,
+                lease_id: "expired".to_string(),
+                expires_at: "2019-01-01 00:00:00.000".to_string(),
+                on_expiry: Some("low".to_string()),
+            })
+            .execute(&connection)
+            .expect("Test failed");
+    }
+
+    let expired = expire_leases(&connection, gpio_arc_mutex).expect("Test failed");
+    assert_eq!(expired.len(), 1);
+    assert_eq!(expired[0].lease_id, "expired");
+
+    use crate::schema::gpio_state::dsl::*;
+    let level = gpio_state
+        .filter(gpio_id.eq(5))
+        .select(gpio_level)
+        .first::<Option<String>>(&connection)
+        .expect("Test failed");
+    assert_eq!(level, Some("low".to_string()));
+}
+
+#[test]
+fn set_gpio_level_must_track_time_away_from_safe_level() {
+    use crate::schema::gpio_state::dsl::*;
+
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    set_gpio_max_on_db(5, 60, "low", &connection).expect("Test failed");
+    let get_on_since = || {
+        gpio_state
+            .filter(gpio_id.eq(5))
+            .select(on_since)
+            .first::<Option<String>>(&connection)
+            .expect("Test failed")
+    };
+
+    set_gpio_level_db(5, "high", &connection).expect("Test failed");
+    let since = get_on_since();
+    assert!(since.is_some());
+
+    set_gpio_level_db(5, "high", &connection).expect("Test failed");
+    assert_eq!(get_on_since(), since);
+
+    set_gpio_level_db(5, "low", &connection).expect("Test failed");
+    assert_eq!(get_on_since(), None);
+}
+
+#[test]
+fn enforce_max_on_must_force_safe_level_and_audit() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    set_gpio_max_on_db(5, 60, "low", &connection).expect("Test failed");
+    set_gpio_max_on_db(6, 60, "low", &connection).expect("Test failed");
+    set_gpio_level_db(5, "high", &connection).expect("Test failed");
+    set_gpio_level_db(6, "high", &connection).expect("Test failed");
+
+    {
+        use crate::schema::gpio_state::dsl::*;
+        diesel::update(gpio_state.filter(gpio_id.eq(5)))
+            .set(on_since.eq("2019-01-01 00:00:00.000"))
+            .execute(&connection)
+            .expect("Test failed");
+    }
+
+    let forced = enforce_max_on(&connection, gpio_arc_mutex).expect("Test failed");
+    assert_eq!(forced.len(), 1);
+    assert_eq!(forced[0].gpio_id, 5);
+    assert_eq!(forced[0].gpio_level, Some("low".to_string()));
+    assert_eq!(forced[0].on_since, None);
+    assert!(forced[0].forced_off_at.is_some());
+
+    use crate::schema::gpio_audit::dsl::*;
+    let entries = gpio_audit
+        .load::<models::AuditEntry>(&connection)
+        .expect("Test failed");
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].gpio_id, 5);
+    assert_eq!(entries[0].event, MAX_ON_EVENT);
+}
+
+#[test]
+fn toggle_gpio_level_must_invert_level() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    set_gpio_level_db(5, "low", &connection).expect("Test failed");
+
+    let level = toggle_gpio_level(5, None, None, &connection, gpio_arc_mutex.clone())
+        .expect("Test failed");
+    assert_eq!(level, "high");
+    assert_eq!(
+        get_gpio_level_rpi(5, gpio_arc_mutex).expect("Test failed"),
+        "high"
+    );
+}
+
+#[test]
+fn toggle_gpio_level_stale_version_must_fail() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let gpio_arc_mutex = create_gpio_arc_mutex().expect("Could not acquire GPIO");
+    set_gpio_level_db(5, "low", &connection).expect("Test failed");
+
+    let res = toggle_gpio_level(5, Some(-1), None, &connection, gpio_arc_mutex);
+    match res {
+        Err(RpWebError::PreconditionFailed(_)) => {}
+        other => panic!("Expected PreconditionFailed, got {:?}", other),
+    }
+}
+
+fn due_schedule(missed_runs: &str, next_run: &str) -> models::NewSchedule {
+    models::NewSchedule {
+        cron: Some("0 7 * * *".to_string()),
+        gpio_id: Some(5),
+        group_name: None,
+        action: "set_level".to_string(),
+        gpio_level: Some("high".to_string()),
+        duration_ms: None,
+        missed_runs: missed_runs.to_string(),
+        enabled: 1,
+        next_run: Some(next_run.to_string()),
+        solar_event: None,
+        offset_minutes: 0,
+    }
+}
+
+#[test]
+fn claim_due_schedules_must_handle_missed_runs() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+    let now = chrono::NaiveDateTime::parse_from_str("2019-06-01 07:00:30", "%Y-%m-%d %H:%M:%S")
+        .expect("Test failed");
+
+    let on_time = create_schedule_db(&due_schedule("skip", "2019-06-01 07:00:00.000"), &connection)
+        .expect("Test failed");
+    let skipped = create_schedule_db(&due_schedule("skip", "2019-05-31 07:00:00.000"), &connection)
+        .expect("Test failed");
+    let caught_up =
+        create_schedule_db(&due_schedule("run_once", "2019-05-30 07:00:00.000"), &connection)
+            .expect("Test failed");
+    create_schedule_db(&due_schedule("skip", "2019-06-02 07:00:00.000"), &connection)
+        .expect("Test failed");
+
+    let due = claim_due_schedules(now, None, &connection).expect("Test failed");
+    let due_ids: Vec<i32> = due.iter().map(|schedule| schedule.schedule_id).collect();
+    assert_eq!(due_ids, vec![on_time.schedule_id, caught_up.schedule_id]);
+
+    use crate::schema::schedules::dsl::*;
+    let skipped = schedules
+        .filter(schedule_id.eq(skipped.schedule_id))
+        .first::<models::Schedule>(&connection)
+        .expect("Test failed");
+    assert_eq!(skipped.next_run.as_deref(), Some("2019-06-02 07:00:00.000"));
+    assert_eq!(skipped.last_run, None);
+
+    // Claimed schedules are not due again
+    assert!(claim_due_schedules(now, None, &connection)
+        .expect("Test failed")
+        .is_empty());
+}
+
+#[test]
+fn save_vacation_must_be_loaded_again() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+
+    let mode = load_vacation_db(&connection).expect("Test failed");
+    assert_eq!(mode.enabled, 0);
+
+    save_vacation_db(true, Some(7), &connection).expect("Test failed");
+    let mode = load_vacation_db(&connection).expect("Test failed");
+    assert_eq!(mode.enabled, 1);
+    assert_eq!(mode.seed, Some(7));
+
+    save_vacation_db(false, None, &connection).expect("Test failed");
+    let mode = load_vacation_db(&connection).expect("Test failed");
+    assert_eq!(mode.enabled, 0);
+    assert_eq!(mode.seed, None);
+}
+
+#[test]
+fn rule_firing_must_be_counted_and_audited() {
+    let pool = get_pool_after_migrations().expect("Failed to create r2d2 pool.");
+    let connection = pool.get().expect("Failed to acquire connection");
+
+    let config = RuleConfig {
+        name: Some("door".to_string()),
+        input: 4,
+        trigger: "rising".to_string(),
+        output: 17,
+        action: Some("pulse".to_string()),
+        level: Some("high".to_string()),
+        duration_ms: Some(30_000),
+        inverted: None,
+    };
+    // Set up twice, as on a restart: the rules from the configuration are replaced
+    let configs = vec![config];
+    setup_rules_db(&configs, &connection).expect("Test failed");
+    setup_rules_db(&configs, &connection).expect("Test failed");
+
+    let rules = schema::rules::table
+        .load::<models::Rule>(&connection)
+        .expect("Test failed");
+    assert_eq!(rules.len(), 1);
+    assert_eq!(rules[0].from_config, 1);
+
+    record_rule_firing_db(&rules[0], Some("high"), &connection).expect("Test failed");
+    let rule = load_rule_db(rules[0].rule_id, &connection).expect("Test failed");
+    assert_eq!(rule.fire_count, 1);
+    assert!(rule.last_fired.is_some());
+
+    let rule = enable_rule_db(rule.rule_id, false, &connection).expect("Test failed");
+    assert_eq!(rule.enabled, 0);
+    assert!(enable_rule_db(rule.rule_id + 1, false, &connection).is_err());
+
+    use crate::schema::gpio_audit::dsl::*;
+    let entries = gpio_audit
+        .load::<models::AuditEntry>(&connection)
+        .expect("Test failed");
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].gpio_id, 17);
+    assert_eq!(entries[0].event, RULE_EVENT);
+}
+
+fn stepper_config(name: &str) -> StepperConfig {
+    StepperConfig {
+        name: name.to_string(),
+        step_pin: 20,
+        dir_pin: 21,
+        enable_pin: None,
+        enable_level: None,
+        home_pin: None,
+        steps_per_second: 500.0,
+        acceleration: None,
+        profile: None,
+        min_position: None,
+        max_position: None,
+    }
+}
+
+#[test]
+fn stepper_position_must_survive_setup() {
+    let pool = get_pool_after_migrations().unwrap();
+    let connection = pool.get().unwrap();
+    let configs = vec![stepper_config("slider"), stepper_config("focus")];
+
+    let positions = setup_steppers_db(&configs, &connection).expect("Test failed");
+    assert_eq!(positions.len(), 2);
+    assert!(positions.iter().all(|saved| saved.position == 0 && saved.homed == 0));
+
+    save_stepper_position_db("slider", 250, true, &connection).expect("Test failed");
+    let positions = setup_steppers_db(&configs[..1], &connection).expect("Test failed");
+    assert_eq!(positions.len(), 1);
+    assert_eq!((positions[0].position, positions[0].homed), (250, 1));
+
+    let saved = schema::stepper_positions::table
+        .load::<models::StepperPosition>(&connection)
+        .expect("Test failed");
+    assert_eq!(saved.len(), 1);
+    assert_eq!(saved[0].name, "slider");
+}
+
+#[test]
+fn device_must_own_its_pins() {
+    let pool = get_pool_after_migrations().unwrap();
+    let connection = pool.get().unwrap();
+    let pins = [("Red", 17), ("yellow", 27), ("green", 22)];
+    let traffic_light = DeviceConfig {
+        name: "crossing".to_string(),
+        kind: "traffic_light".to_string(),
+        pins: pins.iter().map(|(role, id)| (role.to_string(), *id)).collect(),
+    };
+
+    setup_devices_db(&[traffic_light], &connection).expect("Test failed");
+
+    match check_gpio_owner_db(17, &connection) {
+        Err(RpWebError::Locked(errs)) => assert!(errs.contains("crossing")),
+        other => panic!("GPIO #17 must be owned, got {:?}", other),
+    }
+    assert!(check_gpio_owner_db(5, &connection).is_ok());
+    let device = load_device_db("crossing", &connection).expect("Test failed");
+    assert_eq!(device.kind, "traffic_light");
+    assert_eq!(device.pins["red"].gpio_id, 17);
+
+    setup_devices_db(&[], &connection).expect("Test failed");
+    assert!(check_gpio_owner_db(17, &connection).is_ok());
+}